@@ -0,0 +1,71 @@
+//! What a broadcast costs the server: encoding one frame, reading it back,
+//! and fanning it out to many clients by encoding it for each recipient or
+//! once for all of them, as `broadcast` does
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use uuid::Uuid;
+use ycnbts::shared::{
+    framing::{self, WireFormat},
+    messages::{ClientBoundMessage, ClientDescription},
+};
+
+fn new_client() -> ClientBoundMessage {
+    ClientBoundMessage::NewClient(ClientDescription::new("alice".to_string(), Uuid::new_v4()))
+}
+
+fn frames(c: &mut Criterion) {
+    let message = new_client();
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut group = c.benchmark_group("frame");
+    for format in [WireFormat::Bincode, WireFormat::Json] {
+        let frame = framing::encode_frame(format, &message).unwrap();
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_function(BenchmarkId::new("encode", format!("{:?}", format)), |b| {
+            b.iter(|| framing::encode_frame(format, &message).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("decode", format!("{:?}", format)), |b| {
+            b.to_async(&runtime).iter(|| async {
+                let body = framing::read_frame(&mut frame.as_slice()).await.unwrap().unwrap();
+                format.decode::<ClientBoundMessage>(&body).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn fan_out(c: &mut Criterion) {
+    let message = new_client();
+    let mut group = c.benchmark_group("broadcast");
+    for recipients in [10, 100, 1000] {
+        group.throughput(Throughput::Elements(recipients as u64));
+        group.bench_with_input(
+            BenchmarkId::new("per_recipient", recipients),
+            &recipients,
+            |b, &recipients| {
+                b.iter(|| {
+                    let encode = || framing::encode_frame(WireFormat::Bincode, &message).unwrap();
+                    (0..recipients)
+                        .map(|_| encode().into())
+                        .collect::<Vec<Arc<[u8]>>>()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("encoded_once", recipients),
+            &recipients,
+            |b, &recipients| {
+                b.iter(|| {
+                    let frame: Arc<[u8]> =
+                        framing::encode_frame(WireFormat::Bincode, &message).unwrap().into();
+                    (0..recipients).map(|_| frame.clone()).collect::<Vec<_>>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frames, fan_out);
+criterion_main!(benches);
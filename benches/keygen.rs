@@ -0,0 +1,23 @@
+//! What generating an identity key costs at startup, for each key type and
+//! RSA size `--key-bits` allows
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ycnbts::shared::crypto::{IdentityKey, KeyType};
+
+fn keygen(c: &mut Criterion) {
+    let mut group = c.benchmark_group("keygen");
+    // RSA keygen takes long and varies a lot between runs
+    group.sample_size(10);
+    for bits in [2048, 3072, 4096] {
+        group.bench_with_input(BenchmarkId::new("rsa", bits), &bits, |b, &bits| {
+            b.iter(|| IdentityKey::generate(KeyType::Rsa, bits).unwrap())
+        });
+    }
+    group.bench_function("ed25519", |b| {
+        b.iter(|| IdentityKey::generate(KeyType::Ed25519, 0).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, keygen);
+criterion_main!(benches);
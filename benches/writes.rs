@@ -0,0 +1,96 @@
+//! What a burst of small frames costs to write to a socket: flushed one at
+//! a time, as an unbuffered write half does, or through a `BufWriter`
+//! flushed once per batch, as the server's writer task does
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    net::{TcpListener, TcpStream},
+};
+use ycnbts::shared::{
+    framing::{self, WireFormat},
+    messages::ClientBoundMessage,
+};
+
+/// Frames in one burst
+const BURST: usize = 1000;
+
+/// Frames written between flushes when buffered, as the server batches them
+const BATCH: usize = 64;
+
+/// The server's default `--write-buffer`
+const BUFFER: usize = 16 * 1024;
+
+/// A loopback connection, with the far end read and thrown away so the
+/// socket never fills
+async fn connected() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (near, far) = tokio::join!(TcpStream::connect(address), listener.accept());
+    let (mut far, _) = far.unwrap();
+    tokio::spawn(async move {
+        let mut sink = vec![0; 64 * 1024];
+        while far.read(&mut sink).await.is_ok_and(|n| n > 0) {}
+    });
+    let near = near.unwrap();
+    near.set_nodelay(true).unwrap();
+    near
+}
+
+fn burst(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_io()
+        .build()
+        .unwrap();
+    let frames: Vec<Vec<u8>> = (0..BURST as u64)
+        .map(ClientBoundMessage::Pong)
+        .map(|pong| framing::encode_frame(WireFormat::Bincode, &pong).unwrap())
+        .collect();
+    let mut group = c.benchmark_group("burst");
+    group.throughput(Throughput::Elements(BURST as u64));
+
+    let mut stream = runtime.block_on(connected());
+    group.bench_function(BenchmarkId::new("unbuffered", BURST), |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    for frame in &frames {
+                        framing::write_encoded(&mut stream, frame).await.unwrap();
+                    }
+                }
+                start.elapsed()
+            })
+        })
+    });
+
+    let mut stream = BufWriter::with_capacity(BUFFER, runtime.block_on(connected()));
+    group.bench_function(BenchmarkId::new("buffered", BURST), |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    for batch in frames.chunks(BATCH) {
+                        for frame in batch {
+                            framing::write_encoded_unflushed(&mut stream, frame).await.unwrap();
+                        }
+                        stream.flush().await.unwrap();
+                    }
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // Bursts are quick, but socket writes vary more than encoding does
+    config = Criterion::default().measurement_time(Duration::from_secs(10));
+    targets = burst
+}
+criterion_main!(benches);
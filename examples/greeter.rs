@@ -0,0 +1,45 @@
+//! A bot that accepts every connection request and greets the peer.
+//!
+//! Takes the same options as `ycnbts client`:
+//!
+//! ```text
+//! cargo run --example greeter -- --address 127.0.0.1 --port 8080
+//! ```
+
+use std::sync::Arc;
+
+use clap::Parser;
+use tokio::sync::broadcast::error::RecvError;
+use ycnbts::{
+    client::{Args, Client, ClientEvent},
+    shared::Result,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let client = Arc::new(Client::new(Args::parse()).await?);
+    let mut events = client.subscribe();
+    let mut handler = tokio::spawn({
+        let client = client.clone();
+        async move { client.run_connection().await }
+    });
+    client.advertise("Greeter".to_string()).await?;
+
+    loop {
+        let event = tokio::select! {
+            result = &mut handler => {
+                return result.expect("handler task panicked");
+            }
+            event = events.recv() => event,
+        };
+        match event {
+            Ok(ClientEvent::ConnectionRequested { from, .. }) => {
+                client.accept(from.uuid).await?;
+                let greeting = format!("Hello, {}!", from.display_name());
+                client.send_to(from.uuid, &greeting, None).await?;
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
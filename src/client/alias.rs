@@ -0,0 +1,161 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use super::completion::VERBS;
+use crate::shared::{Error, Result};
+
+/// How many aliases one action may pass through before it's taken to be a
+/// loop, in case the check for repeats misses one
+const MAX_EXPANSIONS: usize = 16;
+
+/// Location of the alias dotfile (`~/.ycnbts_aliases`)
+pub fn aliases_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".ycnbts_aliases"))
+}
+
+/// Shortcuts for actions, such as `w` for `msg`. An expansion may use `$1`
+/// to `$9` for the arguments the alias was given and `$*` for all of them.
+/// Without any, the arguments are appended.
+#[derive(Default)]
+pub struct Aliases {
+    expansions: BTreeMap<String, String>,
+}
+
+impl Aliases {
+    /// Reads `alias <name> = <expansion>` lines from the alias dotfile.
+    /// Lines starting with `#` are comments. Invalid lines are reported and
+    /// skipped.
+    pub fn load() -> Self {
+        let mut aliases = Aliases::default();
+        let Some(path) = aliases_path() else {
+            return aliases;
+        };
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let defined = match line.strip_prefix("alias ") {
+                Some(definition) => aliases.define(definition),
+                None => Err(Error::Protocol("expected `alias <name> = <expansion>`".to_string())),
+            };
+            if let Err(e) = defined {
+                eprintln!("Skipping line {} of {}: {}", number + 1, path.display(), e);
+            }
+        }
+        aliases
+    }
+
+    /// Defines an alias from `<name> <expansion>` or `<name> = <expansion>`,
+    /// replacing any earlier one with that name
+    pub fn define(&mut self, definition: &str) -> Result<()> {
+        let Some((name, expansion)) = definition.trim().split_once(char::is_whitespace) else {
+            return Err(Error::Protocol("expected `alias <name> <expansion>`".to_string()));
+        };
+        let expansion = expansion.trim_start();
+        let expansion = expansion.strip_prefix('=').unwrap_or(expansion).trim();
+        if expansion.is_empty() {
+            return Err(Error::Protocol(format!("`{}` needs an expansion", name)));
+        }
+        if VERBS.contains(&name) || name.starts_with("send!") {
+            return Err(Error::Protocol(format!("`{}` is already an action", name)));
+        }
+        self.expansions.insert(name.to_string(), expansion.to_string());
+        Ok(())
+    }
+
+    /// Every alias as `(name, expansion)`, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.expansions.iter().map(|(name, expansion)| (name.as_str(), expansion.as_str()))
+    }
+
+    /// Rewrites `action` until its verb isn't an alias. Fails if an alias
+    /// leads back to itself or is short of the arguments it refers to.
+    pub fn expand(&self, action: &str) -> Result<String> {
+        let mut action = action.to_string();
+        let mut seen: Vec<String> = Vec::new();
+        loop {
+            let (verb, rest) = action.split_once(' ').unwrap_or((&action, ""));
+            let Some(expansion) = self.expansions.get(verb) else {
+                return Ok(action);
+            };
+            if seen.iter().any(|name| name == verb) || seen.len() >= MAX_EXPANSIONS {
+                seen.push(verb.to_string());
+                return Err(Error::Protocol(format!("alias loop: {}", seen.join(" → "))));
+            }
+            seen.push(verb.to_string());
+            action = substitute(verb, expansion, rest.trim())?;
+        }
+    }
+}
+
+/// Fills `$1`-`$9` and `$*` in `expansion` from `arguments`, or appends
+/// them if it has neither
+fn substitute(name: &str, expansion: &str, arguments: &str) -> Result<String> {
+    let words: Vec<&str> = arguments.split_whitespace().collect();
+    let mut expanded = String::new();
+    let mut placeholders = false;
+    let mut chars = expansion.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('$', Some('*')) => {
+                chars.next();
+                placeholders = true;
+                expanded.push_str(arguments);
+            }
+            ('$', Some(digit @ '1'..='9')) => {
+                let index = *digit as usize - '1' as usize;
+                chars.next();
+                placeholders = true;
+                let Some(word) = words.get(index) else {
+                    return Err(Error::Protocol(format!(
+                        "`{}` needs at least {} argument(s)",
+                        name,
+                        index + 1
+                    )));
+                };
+                expanded.push_str(word);
+            }
+            _ => expanded.push(c),
+        }
+    }
+    if !placeholders && !arguments.is_empty() {
+        expanded.push(' ');
+        expanded.push_str(arguments);
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_alias_expands_with_its_arguments_substituted() {
+        let mut aliases = Aliases::default();
+        aliases.define("w msg $*").unwrap();
+        aliases.define("tell msg $2 $1 says hi").unwrap();
+        aliases.define("hi = w $1 hello").unwrap();
+        aliases.define("o open").unwrap();
+
+        assert_eq!(aliases.expand("w bob see you").unwrap(), "msg bob see you");
+        assert_eq!(aliases.expand("tell alice bob").unwrap(), "msg bob alice says hi");
+        // Expanded again until the verb is a real action
+        assert_eq!(aliases.expand("hi alice").unwrap(), "msg alice hello");
+        // Arguments go on the end when the expansion doesn't place them
+        assert_eq!(aliases.expand("o carol").unwrap(), "open carol");
+        assert_eq!(aliases.expand("list").unwrap(), "list");
+        assert!(aliases.expand("tell alice").is_err(), "`$2` was left unfilled");
+    }
+
+    #[test]
+    fn an_alias_leading_back_to_itself_is_an_error() {
+        let mut aliases = Aliases::default();
+        aliases.define("a b").unwrap();
+        aliases.define("b a").unwrap();
+        let Err(Error::Protocol(error)) = aliases.expand("a") else {
+            panic!("the loop should be refused");
+        };
+        assert_eq!(error, "alias loop: a → b → a");
+    }
+}
@@ -0,0 +1,140 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::shared::messages::MessageChunk;
+
+/// Messages longer than this many bytes are split across several payloads
+pub const CHUNK_SIZE: usize = 4096;
+
+/// Largest number of chunks we're willing to buffer for a single message
+const MAX_CHUNKS: u32 = 256;
+
+/// Largest number of incomplete messages buffered per peer
+const MAX_PARTIAL_MESSAGES: usize = 16;
+
+pub fn split(message_id: u64, text: &str, expires_in: Option<Duration>) -> Vec<MessageChunk> {
+    let bytes = text.as_bytes();
+    let total = bytes.len().div_ceil(CHUNK_SIZE).max(1) as u32;
+    (0..total)
+        .map(|seq| {
+            let start = seq as usize * CHUNK_SIZE;
+            let end = (start + CHUNK_SIZE).min(bytes.len());
+            MessageChunk {
+                message_id,
+                seq,
+                total,
+                data: bytes[start..end].to_vec(),
+                expires_in,
+            }
+        })
+        .collect()
+}
+
+/// Collects chunks from one peer until a message is complete
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<u64, Vec<Option<Vec<u8>>>>,
+}
+
+impl Reassembler {
+    /// Adds a chunk, returning the whole message once every chunk has arrived.
+    /// Chunks may arrive in any order; malformed ones are discarded.
+    pub fn add(&mut self, chunk: MessageChunk) -> Option<String> {
+        if chunk.total == 0 || chunk.total > MAX_CHUNKS || chunk.seq >= chunk.total {
+            return None;
+        }
+        if chunk.total == 1 {
+            return Some(String::from_utf8_lossy(&chunk.data).into_owned());
+        }
+
+        if !self.partial.contains_key(&chunk.message_id)
+            && self.partial.len() >= MAX_PARTIAL_MESSAGES
+        {
+            self.partial.clear();
+        }
+        let parts = self
+            .partial
+            .entry(chunk.message_id)
+            .or_insert_with(|| vec![None; chunk.total as usize]);
+        if parts.len() != chunk.total as usize {
+            self.partial.remove(&chunk.message_id);
+            return None;
+        }
+        parts[chunk.seq as usize] = Some(chunk.data);
+
+        if parts.iter().any(|part| part.is_none()) {
+            return None;
+        }
+        let parts = self.partial.remove(&chunk.message_id)?;
+        let bytes: Vec<u8> = parts.into_iter().flatten().flatten().collect();
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_messages_are_one_chunk() {
+        let chunks = split(1, "hi", None);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(Reassembler::default().add(chunks[0].clone()).as_deref(), Some("hi"));
+    }
+
+    #[test]
+    fn reassembles_chunks_in_order() {
+        let text = "é".repeat(CHUNK_SIZE * 2);
+        let chunks = split(7, &text, None);
+        assert_eq!(chunks.len(), 4);
+        let mut reassembler = Reassembler::default();
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert_eq!(reassembler.add(chunk.clone()), None);
+        }
+        // A character split across two chunks comes back whole
+        assert_eq!(reassembler.add(last.clone()), Some(text));
+    }
+
+    #[test]
+    fn reassembles_chunks_out_of_order_and_interleaved() {
+        let first = "a".repeat(CHUNK_SIZE * 3);
+        let second = "b".repeat(CHUNK_SIZE + 1);
+        let mut first_chunks = split(1, &first, None);
+        let mut second_chunks = split(2, &second, None);
+        first_chunks.reverse();
+        second_chunks.reverse();
+
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.add(first_chunks[0].clone()), None);
+        assert_eq!(reassembler.add(second_chunks[0].clone()), None);
+        assert_eq!(reassembler.add(first_chunks[1].clone()), None);
+        assert_eq!(reassembler.add(second_chunks[1].clone()), Some(second));
+        assert_eq!(reassembler.add(first_chunks[2].clone()), Some(first));
+    }
+
+    #[test]
+    fn discards_malformed_chunks() {
+        let mut reassembler = Reassembler::default();
+        let mut chunk = split(1, &"x".repeat(CHUNK_SIZE + 1), None).remove(0);
+        chunk.seq = chunk.total;
+        assert_eq!(reassembler.add(chunk.clone()), None);
+        chunk.seq = 0;
+        chunk.total = MAX_CHUNKS + 1;
+        assert_eq!(reassembler.add(chunk.clone()), None);
+        chunk.total = 0;
+        assert_eq!(reassembler.add(chunk), None);
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn a_chunk_disagreeing_on_the_total_drops_the_message() {
+        let mut reassembler = Reassembler::default();
+        let chunks = split(1, &"x".repeat(CHUNK_SIZE * 2 + 1), None);
+        assert_eq!(reassembler.add(chunks[0].clone()), None);
+        let mut liar = chunks[1].clone();
+        liar.total = 2;
+        assert_eq!(reassembler.add(liar), None);
+        assert_eq!(reassembler.add(chunks[1].clone()), None);
+        assert_eq!(reassembler.add(chunks[2].clone()), None);
+    }
+}
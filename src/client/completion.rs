@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use inquire::{autocompletion::Replacement, Autocomplete, CustomUserError};
+use tokio::sync::Mutex;
+
+use crate::shared::messages::ClientDescription;
+
+/// Actions understood by the action prompt
+pub const VERBS: &[&str] = &[
+    "exit",
+    "help",
+    "uuid",
+    "list",
+    "unread",
+    "ping",
+    "stats",
+    "server",
+    "open",
+    "accept",
+    "close",
+    "cancel",
+    "send",
+    "msg",
+    "broadcast",
+    "react",
+    "direct",
+    "sendfile",
+    "acceptfile",
+    "verify",
+    "export",
+    "import",
+    "history",
+    "clearhistory",
+    "alias",
+    "receipts",
+    "quiet",
+    "status",
+    "profile",
+];
+
+/// Completes action verbs and peer uuids, and recalls previously entered
+/// commands (most recent first) with the up arrow
+#[derive(Clone)]
+pub struct ActionCompleter {
+    history: Arc<Mutex<Vec<String>>>,
+    peer_list: Arc<Mutex<Vec<ClientDescription>>>,
+}
+
+impl ActionCompleter {
+    pub fn new(
+        history: Arc<Mutex<Vec<String>>>,
+        peer_list: Arc<Mutex<Vec<ClientDescription>>>,
+    ) -> Self {
+        ActionCompleter { history, peer_list }
+    }
+}
+
+impl Autocomplete for ActionCompleter {
+    fn get_suggestions(&mut self, input: &str) -> Result<Vec<String>, CustomUserError> {
+        // The prompt runs synchronously, so never wait on the locks here
+        let history = self
+            .history
+            .try_lock()
+            .map(|history| history.clone())
+            .unwrap_or_default();
+        let peers = self
+            .peer_list
+            .try_lock()
+            .map(|peers| peers.clone())
+            .unwrap_or_default();
+        Ok(complete(input, &history, &peers))
+    }
+
+    fn get_completion(
+        &mut self,
+        input: &str,
+        highlighted_suggestion: Option<String>,
+    ) -> Result<Replacement, CustomUserError> {
+        if highlighted_suggestion.is_some() {
+            return Ok(highlighted_suggestion);
+        }
+        // Without a highlighted entry, tab completes to the only candidate
+        let suggestions = self.get_suggestions(input)?;
+        match suggestions.as_slice() {
+            [only] => Ok(Some(only.clone())),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Returns full-line suggestions for `input`: verbs or peer uuids for the
+/// token being typed, followed by matching history entries
+pub fn complete(input: &str, history: &[String], peers: &[ClientDescription]) -> Vec<String> {
+    let mut suggestions: Vec<String> = Vec::new();
+
+    match input.split_once(' ') {
+        None if !input.is_empty() => {
+            for verb in VERBS {
+                if verb.starts_with(input) && *verb != input {
+                    suggestions.push(verb.to_string());
+                }
+            }
+        }
+        Some((verb @ ("open" | "close" | "cancel" | "msg" | "direct" | "history" | "verify"), partial)) if !partial.contains(' ') => {
+            for peer in peers {
+                let uuid = peer.uuid.to_string();
+                if uuid.starts_with(partial) || peer.name.starts_with(partial) {
+                    suggestions.push(format!("{} {}", verb, uuid));
+                }
+            }
+        }
+        _ => {}
+    }
+
+    for entry in history.iter().rev() {
+        if entry.starts_with(input) && entry != input && !suggestions.contains(entry) {
+            suggestions.push(entry.clone());
+        }
+    }
+
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn peers() -> Vec<ClientDescription> {
+        vec![
+            ClientDescription::new(
+                "alice".to_string(),
+                Uuid::parse_str("a1000000-0000-4000-8000-000000000000").unwrap(),
+            ),
+            ClientDescription::new(
+                "bob".to_string(),
+                Uuid::parse_str("b2000000-0000-4000-8000-000000000000").unwrap(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn completes_verbs_by_prefix() {
+        assert_eq!(complete("ac", &[], &peers()), ["accept", "acceptfile"]);
+        assert_eq!(complete("hist", &[], &peers()), ["history"]);
+        // A finished verb isn't suggested again
+        assert_eq!(complete("accept", &[], &peers()), ["acceptfile"]);
+        assert!(complete("", &[], &peers()).is_empty());
+    }
+
+    #[test]
+    fn completes_peer_uuids_by_uuid_or_name() {
+        assert_eq!(
+            complete("open a1", &[], &peers()),
+            ["open a1000000-0000-4000-8000-000000000000"]
+        );
+        assert_eq!(
+            complete("msg bo", &[], &peers()),
+            ["msg b2000000-0000-4000-8000-000000000000"]
+        );
+        assert_eq!(complete("close ", &[], &peers()).len(), 2);
+        // Verbs that take no peer, and finished uuids, get no suggestions
+        assert!(complete("send a", &[], &peers()).is_empty());
+        assert!(complete("msg b2000000-0000-4000-8000-000000000000 hi", &[], &peers()).is_empty());
+    }
+
+    #[test]
+    fn suggests_history_most_recent_first_after_completions() {
+        let history = ["open abc".to_string(), "list".to_string(), "open xyz".to_string()];
+        assert_eq!(complete("open ", &history, &[]), ["open xyz", "open abc"]);
+        assert_eq!(complete("li", &history, &peers()), ["list"]);
+    }
+}
@@ -0,0 +1,272 @@
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+use crate::shared::{
+    crypto::IdentityKey,
+    framing::{self, WireFormat},
+    messages::{ClientBoundMessage, ClientDescription, EncryptedPayload, ServerBoundMessage},
+    Error, Result,
+};
+
+use super::{latency::Latency, session::Session};
+
+/// How long an offered address waits for the peer to connect
+const OFFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long dialing a peer's address, or waiting for its hello, may take
+const DIAL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Plaintext each side seals with the session as its first frame. Only the
+/// peer holds the session key, so a valid one proves who is on the other end.
+const HELLO: &[u8] = b"ycnbts direct link";
+
+pub type Sessions = Arc<Mutex<HashMap<Uuid, Session>>>;
+
+/// Reported by the tasks behind direct links to `Client::handle`, which owns
+/// the state they change
+pub enum DirectEvent {
+    /// A stream to the peer on which both sides proved themselves
+    Connected(Uuid, TcpStream),
+    /// The address the peer offered couldn't be reached
+    DialFailed(Uuid),
+    /// Nobody proved to be the peer before our offer ran out
+    OfferExpired(Uuid),
+    /// The peer sent this over the link, in a frame this many bytes long.
+    /// Boxed since messages are far larger than the other events.
+    Frame(Uuid, Box<ClientBoundMessage>, usize),
+    /// The link closed or sent something unreadable
+    Closed(Uuid),
+}
+
+/// An established direct connection to a peer
+pub struct DirectLink {
+    writeable_half: OwnedWriteHalf,
+    reader: JoinHandle<()>,
+    pub latency: Latency,
+}
+
+impl DirectLink {
+    /// Starts forwarding what the peer sends to `events`
+    pub fn new(
+        peer: Uuid,
+        stream: TcpStream,
+        wire_format: WireFormat,
+        events: mpsc::Sender<DirectEvent>,
+    ) -> Self {
+        let (mut readable_half, writeable_half) = stream.into_split();
+        let reader = tokio::spawn(async move {
+            loop {
+                let frame = match framing::read_frame(&mut readable_half).await {
+                    Ok(Some(frame)) => frame,
+                    // Read whole, so the next frame can still be found
+                    Err(Error::Corrupt(_)) => continue,
+                    _ => break,
+                };
+                let Ok(message) = wire_format.decode(&frame) else {
+                    break;
+                };
+                let len = framing::frame_len(frame.len());
+                if events.send(DirectEvent::Frame(peer, Box::new(message), len)).await.is_err() {
+                    return;
+                }
+            }
+            let _ = events.send(DirectEvent::Closed(peer)).await;
+        });
+        DirectLink {
+            writeable_half,
+            reader,
+            latency: Latency::default(),
+        }
+    }
+
+    /// Writes `message` to the peer, returning the frame's length
+    pub async fn send(&mut self, wire_format: WireFormat, message: &ClientBoundMessage) -> Result<usize> {
+        let frame = framing::encode_frame(wire_format, message)?;
+        framing::write_encoded(&mut self.writeable_half, &frame).await?;
+        Ok(frame.len())
+    }
+}
+
+impl Drop for DirectLink {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// The peer a relayed message is addressed to, for the messages that may
+/// take a direct link instead
+pub fn recipient(message: &ServerBoundMessage) -> Option<Uuid> {
+    match message {
+        ServerBoundMessage::Message(to, _)
+        | ServerBoundMessage::FileOffer(to, _)
+        | ServerBoundMessage::FileResponse(to, _)
+        | ServerBoundMessage::FileChunk(to, _)
+        | ServerBoundMessage::FileAck(to, _)
+        | ServerBoundMessage::CloseConnection(to) => Some(to.uuid),
+        ServerBoundMessage::ReadReceipt(to, _) | ServerBoundMessage::React(to, ..) => Some(*to),
+        _ => None,
+    }
+}
+
+/// The peer a delivered message came from, for the messages that may take
+/// a direct link
+pub fn sender(message: &ClientBoundMessage) -> Option<Uuid> {
+    match message {
+        ClientBoundMessage::Message(from, _)
+        | ClientBoundMessage::FileOffer(from, _)
+        | ClientBoundMessage::FileResponse(from, _)
+        | ClientBoundMessage::FileChunk(from, _)
+        | ClientBoundMessage::FileAck(from, _)
+        | ClientBoundMessage::ReadReceipt(from, _)
+        | ClientBoundMessage::Reaction(from, ..) => Some(from.uuid),
+        ClientBoundMessage::ChannelClosed(from) => Some(*from),
+        _ => None,
+    }
+}
+
+/// What the server would have delivered for `message`, which must have a
+/// `recipient`
+pub fn as_delivered(message: ServerBoundMessage, from: ClientDescription) -> Option<ClientBoundMessage> {
+    match message {
+        ServerBoundMessage::Message(_, payload) => Some(ClientBoundMessage::Message(from, payload)),
+        ServerBoundMessage::FileOffer(_, payload) => Some(ClientBoundMessage::FileOffer(from, payload)),
+        ServerBoundMessage::FileResponse(_, payload) => {
+            Some(ClientBoundMessage::FileResponse(from, payload))
+        }
+        ServerBoundMessage::FileChunk(_, payload) => Some(ClientBoundMessage::FileChunk(from, payload)),
+        ServerBoundMessage::FileAck(_, payload) => Some(ClientBoundMessage::FileAck(from, payload)),
+        ServerBoundMessage::CloseConnection(_) => Some(ClientBoundMessage::ChannelClosed(from.uuid)),
+        ServerBoundMessage::ReadReceipt(_, message_id) => {
+            Some(ClientBoundMessage::ReadReceipt(from, message_id))
+        }
+        ServerBoundMessage::React(_, message_id, emoji) => {
+            Some(ClientBoundMessage::Reaction(from, message_id, emoji))
+        }
+        _ => None,
+    }
+}
+
+/// Replaces the sender named in a message that came over the link to `peer`
+/// with `peer` itself, since only the server can vouch for the former.
+/// Returns `None` for messages that have no business on a direct link.
+pub fn from_peer(message: ClientBoundMessage, peer: ClientDescription) -> Option<ClientBoundMessage> {
+    match message {
+        ClientBoundMessage::Message(_, payload) => Some(ClientBoundMessage::Message(peer, payload)),
+        ClientBoundMessage::FileOffer(_, payload) => Some(ClientBoundMessage::FileOffer(peer, payload)),
+        ClientBoundMessage::FileResponse(_, payload) => {
+            Some(ClientBoundMessage::FileResponse(peer, payload))
+        }
+        ClientBoundMessage::FileChunk(_, payload) => Some(ClientBoundMessage::FileChunk(peer, payload)),
+        ClientBoundMessage::FileAck(_, payload) => Some(ClientBoundMessage::FileAck(peer, payload)),
+        ClientBoundMessage::ChannelClosed(_) => Some(ClientBoundMessage::ChannelClosed(peer.uuid)),
+        ClientBoundMessage::ReadReceipt(_, message_id) => {
+            Some(ClientBoundMessage::ReadReceipt(peer, message_id))
+        }
+        ClientBoundMessage::Reaction(_, message_id, emoji) => {
+            Some(ClientBoundMessage::Reaction(peer, message_id, emoji))
+        }
+        _ => None,
+    }
+}
+
+/// Waits on `listener` for `peer` to connect and prove itself, skipping
+/// anyone else who connects. Runs until `OFFER_TIMEOUT` unless aborted.
+pub fn offer(
+    peer: Uuid,
+    listener: TcpListener,
+    wire_format: WireFormat,
+    sessions: Sessions,
+    private_key: Arc<IdentityKey>,
+    events: mpsc::Sender<DirectEvent>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let accept = async {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return None;
+                };
+                if verify_hello(&mut stream, wire_format, &sessions, peer).await.is_err() {
+                    continue;
+                }
+                if send_hello(&mut stream, wire_format, &sessions, &private_key, peer)
+                    .await
+                    .is_ok()
+                {
+                    return Some(stream);
+                }
+            }
+        };
+        let event = match tokio::time::timeout(OFFER_TIMEOUT, accept).await {
+            Ok(Some(stream)) => DirectEvent::Connected(peer, stream),
+            _ => DirectEvent::OfferExpired(peer),
+        };
+        let _ = events.send(event).await;
+    })
+}
+
+/// Connects to the address `peer` offered and exchanges hellos
+pub fn dial(
+    peer: Uuid,
+    address: SocketAddr,
+    wire_format: WireFormat,
+    sessions: Sessions,
+    private_key: Arc<IdentityKey>,
+    events: mpsc::Sender<DirectEvent>,
+) {
+    tokio::spawn(async move {
+        let connect = async {
+            let mut stream = TcpStream::connect(address).await?;
+            send_hello(&mut stream, wire_format, &sessions, &private_key, peer).await?;
+            verify_hello(&mut stream, wire_format, &sessions, peer).await?;
+            Ok::<_, Error>(stream)
+        };
+        let event = match tokio::time::timeout(DIAL_TIMEOUT, connect).await {
+            Ok(Ok(stream)) => DirectEvent::Connected(peer, stream),
+            _ => DirectEvent::DialFailed(peer),
+        };
+        let _ = events.send(event).await;
+    });
+}
+
+async fn send_hello(
+    stream: &mut TcpStream,
+    wire_format: WireFormat,
+    sessions: &Sessions,
+    private_key: &IdentityKey,
+    peer: Uuid,
+) -> Result<()> {
+    let payload = match sessions.lock().await.get_mut(&peer) {
+        Some(session) => session.seal(private_key, HELLO)?,
+        None => return Err(Error::Protocol(format!("no open connection to {}", peer))),
+    };
+    framing::write_frame(stream, wire_format, &payload).await
+}
+
+async fn verify_hello(
+    stream: &mut TcpStream,
+    wire_format: WireFormat,
+    sessions: &Sessions,
+    peer: Uuid,
+) -> Result<()> {
+    let frame = tokio::time::timeout(DIAL_TIMEOUT, framing::read_frame(stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let Some(frame) = frame else {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    };
+    let payload: EncryptedPayload = wire_format.decode(&frame)?;
+    let mut sessions = sessions.lock().await;
+    let Some(session) = sessions.get_mut(&peer) else {
+        return Err(Error::Protocol(format!("no open connection to {}", peer)));
+    };
+    match session.open(&payload)? {
+        (plaintext, true) if plaintext == HELLO => Ok(()),
+        _ => Err(Error::Protocol("the direct link hello didn't check out".to_string())),
+    }
+}
@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::shared::messages::{ClientDescription, Presence, Profile};
+
+/// Something that happened on the connection. `Client::handle` publishes
+/// these rather than printing, and the terminal UI is one subscriber.
+///
+/// With `--json-events` each one is written as
+/// `{"event":"message_received","data":{...}}`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum ClientEvent {
+    /// A client joined the server, or stopped being invisible
+    PeerJoined(ClientDescription),
+    PeerLeft(Uuid),
+    PeerRenamed(Uuid, String),
+    PresenceChanged(Uuid, Presence),
+    ProfileChanged(Uuid, Profile),
+    /// `pending` counts every request waiting to be accepted, this one included
+    ConnectionRequested {
+        from: ClientDescription,
+        pending: usize,
+    },
+    /// A peer accepted our connection request
+    ConnectionAccepted(ClientDescription),
+    /// A peer closed its session with us
+    ChannelClosed { uuid: Uuid, name: String },
+    /// Messages with this peer now skip the server
+    DirectConnected { uuid: Uuid, name: String },
+    /// Neither side could reach the other directly, so messages still go
+    /// through the server
+    DirectFailed { uuid: Uuid, name: String },
+    /// A direct link went down and messages go through the server again
+    DirectClosed { uuid: Uuid, name: String },
+    /// A request we sent got no answer in time and was forgotten
+    RequestTimedOut { uuid: Uuid, name: String },
+    /// A peer turned a request we sent away unseen
+    RequestRejected { uuid: Uuid, name: String },
+    /// A request we received wasn't accepted in time and was forgotten
+    RequestExpired(ClientDescription),
+    /// A peer cancelled a request it had sent us
+    RequestWithdrawn(ClientDescription),
+    MessageReceived {
+        from: ClientDescription,
+        /// The sender's name from the peer list
+        name: String,
+        text: String,
+        verified: bool,
+        time: DateTime<Local>,
+    },
+    /// We sent a message, echoed so it shows up alongside the replies
+    MessageSent {
+        to: Uuid,
+        /// The recipient's name from the peer list
+        name: String,
+        text: String,
+        time: DateTime<Local>,
+    },
+    /// A disappearing message's time ran out
+    MessageExpired { from: Uuid, name: String },
+    /// The server couldn't deliver the message we sealed with this counter,
+    /// because the peer is no longer connected
+    RecipientUnavailable { uuid: Uuid, name: String, counter: u64 },
+    /// The message we sealed with this counter never reached the server
+    /// before the connection dropped, so the peer didn't get it
+    MessageLost { uuid: Uuid, name: String, counter: u64 },
+    /// A peer displayed a message we sent
+    MessageSeen(ClientDescription),
+    /// A peer reacted to a message. `text` is the message's, if it's in the
+    /// history.
+    ReactionReceived {
+        from: ClientDescription,
+        name: String,
+        emoji: String,
+        text: Option<String>,
+    },
+    /// Answer it with `Client::answer_file` and this `id`
+    FileOffered {
+        from: ClientDescription,
+        id: u64,
+        name: String,
+        size: u64,
+    },
+    /// An offer was declined without asking because the file is too large
+    FileTooLarge {
+        from: ClientDescription,
+        name: String,
+        size: u64,
+    },
+    /// A peer declined a file we offered
+    FileDeclined { by: ClientDescription, path: PathBuf },
+    /// Every chunk of a file we sent has been written at the other end
+    FileSent { to: ClientDescription, path: PathBuf },
+    FileSaved { from: ClientDescription, path: PathBuf },
+    /// Receiving a file failed and the partial file was deleted
+    FileFailed { name: String, error: String },
+    /// Something was dropped, rejected or failed. Worth telling the user, but
+    /// the client carries on.
+    Warning(String),
+    ServerShutdown,
+    /// The server turned away the name we advertised because another client
+    /// has it. We keep the name we had before, if any.
+    NameTaken(String),
+    /// A notice from the server's operator, with control characters removed
+    ServerAnnouncement(String),
+    /// The server's answer to `server`. `version` has control characters
+    /// removed.
+    ServerInfo {
+        version: String,
+        uptime_secs: u64,
+        connected_clients: u64,
+    },
+    /// The server closed the connection
+    Disconnected,
+    /// The connection is down and the client is trying to reopen it
+    Reconnecting,
+    /// The connection is back. If the server resumed our uuid, open sessions
+    /// carry on; otherwise they were lost. Pending requests are lost either way.
+    Reconnected { resumed: bool },
+}
+
+impl ClientEvent {
+    /// Whether this only reports on the state of connections, and can be
+    /// left unprinted with `--quiet`. Messages, files and anything that went
+    /// wrong are always shown.
+    pub fn is_notice(&self) -> bool {
+        matches!(
+            self,
+            ClientEvent::PeerJoined(_)
+                | ClientEvent::PeerLeft(_)
+                | ClientEvent::PeerRenamed(..)
+                | ClientEvent::PresenceChanged(..)
+                | ClientEvent::ProfileChanged(..)
+                | ClientEvent::ConnectionRequested { .. }
+                | ClientEvent::ConnectionAccepted(_)
+                | ClientEvent::ChannelClosed { .. }
+                | ClientEvent::DirectConnected { .. }
+                | ClientEvent::DirectClosed { .. }
+                | ClientEvent::RequestExpired(_)
+                | ClientEvent::RequestWithdrawn(_)
+                | ClientEvent::MessageSeen(_)
+                | ClientEvent::Reconnecting
+                | ClientEvent::Reconnected { resumed: true }
+        )
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::shared::{
+    messages::{FileAck, FileChunk, FileOffer},
+    Error, Result,
+};
+
+/// Largest file that can be sent or accepted
+pub const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Bytes of file data per chunk
+const FILE_CHUNK_SIZE: usize = 32 * 1024;
+
+/// Most chunks sent ahead of the recipient's acks. The server disconnects a
+/// client that falls `SEND_QUEUE_LEN` frames behind, so a whole file sent
+/// at once would cut off any recipient slower than the sender.
+const FILE_WINDOW: u32 = 16;
+
+/// The recipient acks after every this many chunks, and after the last
+const ACK_EVERY: u32 = 4;
+
+/// Reads a file to send, checking it isn't too large
+pub async fn read(path: &Path) -> Result<Vec<u8>> {
+    let size = fs::metadata(path).await?.len();
+    if size > MAX_FILE_SIZE {
+        return Err(Error::Protocol(format!(
+            "{} is {} bytes, the limit is {}",
+            path.display(),
+            size,
+            MAX_FILE_SIZE
+        )));
+    }
+    Ok(fs::read(path).await?)
+}
+
+/// Describes `data` for the recipient to accept or decline
+pub fn offer(id: u64, path: &Path, data: &[u8]) -> FileOffer {
+    FileOffer {
+        id,
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size: data.len() as u64,
+        sha256: Sha256::digest(data).into(),
+    }
+}
+
+/// Always returns at least one chunk, so empty files complete too
+pub fn split(id: u64, data: &[u8]) -> Vec<FileChunk> {
+    if data.is_empty() {
+        return vec![FileChunk {
+            id,
+            seq: 0,
+            data: Vec::new(),
+        }];
+    }
+    data.chunks(FILE_CHUNK_SIZE)
+        .enumerate()
+        .map(|(seq, data)| FileChunk {
+            id,
+            seq: seq as u32,
+            data: data.to_vec(),
+        })
+        .collect()
+}
+
+/// Where an accepted offer will be saved. Only the last component of the
+/// offered name is used, so a peer can't write outside `dir`.
+pub fn destination(dir: &Path, offer: &FileOffer) -> Result<PathBuf> {
+    match Path::new(&offer.name).file_name() {
+        Some(name) => Ok(dir.join(name)),
+        None => Err(Error::Protocol(format!(
+            "invalid file name: {:?}",
+            offer.name
+        ))),
+    }
+}
+
+/// A file the peer accepted, sent a window of chunks at a time
+pub struct OutgoingFile {
+    pub path: PathBuf,
+    unsent: VecDeque<FileChunk>,
+    total: u32,
+    sent: u32,
+    acked: u32,
+}
+
+impl OutgoingFile {
+    pub fn new(id: u64, path: PathBuf, data: &[u8]) -> Self {
+        let unsent: VecDeque<FileChunk> = split(id, data).into();
+        OutgoingFile {
+            path,
+            total: unsent.len() as u32,
+            unsent,
+            sent: 0,
+            acked: 0,
+        }
+    }
+
+    /// The chunks the window has room for, taken out to be sent
+    pub fn next_chunks(&mut self) -> Vec<FileChunk> {
+        let room = (self.acked + FILE_WINDOW).saturating_sub(self.sent);
+        let chunks: Vec<FileChunk> = (0..room).map_while(|_| self.unsent.pop_front()).collect();
+        self.sent += chunks.len() as u32;
+        chunks
+    }
+
+    /// Records that the recipient has written the first `received` chunks.
+    /// Acks for chunks never sent are ignored.
+    pub fn acked(&mut self, received: u32) {
+        if received <= self.sent {
+            self.acked = self.acked.max(received);
+        }
+    }
+
+    /// Whether every chunk has been written at the other end
+    pub fn is_done(&self) -> bool {
+        self.acked == self.total
+    }
+}
+
+pub enum Progress {
+    InProgress,
+    /// Every byte arrived and the checksum matched
+    Done(PathBuf),
+}
+
+/// An accepted file being written to disk. Data goes to a `.part` file that
+/// is renamed once the whole file has arrived and its hash checks out.
+pub struct IncomingFile {
+    offer: FileOffer,
+    destination: PathBuf,
+    part_path: PathBuf,
+    file: fs::File,
+    hasher: Sha256,
+    received: u64,
+    next_seq: u32,
+}
+
+impl IncomingFile {
+    pub async fn create(offer: FileOffer, destination: PathBuf) -> Result<Self> {
+        let mut part_path = destination.clone().into_os_string();
+        part_path.push(".part");
+        let part_path = PathBuf::from(part_path);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)
+            .await?;
+
+        Ok(IncomingFile {
+            offer,
+            destination,
+            part_path,
+            file,
+            hasher: Sha256::new(),
+            received: 0,
+            next_seq: 0,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.offer.name
+    }
+
+    /// The ack owed to the sender after the chunk just written, if any
+    pub fn ack(&self, progress: &Progress) -> Option<FileAck> {
+        let due = matches!(progress, Progress::Done(_)) || self.next_seq.is_multiple_of(ACK_EVERY);
+        due.then_some(FileAck {
+            id: self.offer.id,
+            received: self.next_seq,
+        })
+    }
+
+    /// Appends a chunk. On error the transfer can't continue and the caller
+    /// should `discard` it.
+    pub async fn write(&mut self, chunk: FileChunk) -> Result<Progress> {
+        if chunk.seq != self.next_seq {
+            return Err(Error::Protocol(format!(
+                "expected chunk {}, got {}",
+                self.next_seq, chunk.seq
+            )));
+        }
+        if self.received + chunk.data.len() as u64 > self.offer.size {
+            return Err(Error::Protocol(
+                "received more data than was offered".to_string(),
+            ));
+        }
+        self.file.write_all(&chunk.data).await?;
+        self.hasher.update(&chunk.data);
+        self.received += chunk.data.len() as u64;
+        self.next_seq += 1;
+
+        if self.received < self.offer.size {
+            return Ok(Progress::InProgress);
+        }
+
+        self.file.flush().await?;
+        let digest: [u8; 32] = std::mem::take(&mut self.hasher).finalize().into();
+        if digest != self.offer.sha256 {
+            return Err(Error::Protocol("SHA-256 mismatch".to_string()));
+        }
+        fs::rename(&self.part_path, &self.destination).await?;
+        Ok(Progress::Done(self.destination.clone()))
+    }
+
+    /// Deletes whatever was written so far
+    pub async fn discard(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.part_path).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn receive(dir: &Path, offer: FileOffer, data: &[u8]) -> (PathBuf, Result<Progress>) {
+        let destination = destination(dir, &offer).unwrap();
+        let mut incoming = IncomingFile::create(offer, destination.clone()).await.unwrap();
+        let mut progress = Ok(Progress::InProgress);
+        for chunk in split(1, data) {
+            progress = incoming.write(chunk).await;
+            if progress.is_err() {
+                incoming.discard().await;
+                break;
+            }
+        }
+        (destination, progress)
+    }
+
+    #[tokio::test]
+    async fn saves_a_file_whose_hash_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![7u8; FILE_CHUNK_SIZE * 2 + 5];
+        let offer = offer(1, Path::new("/elsewhere/notes.txt"), &data);
+        let (destination, progress) = receive(dir.path(), offer, &data).await;
+        assert!(matches!(progress, Ok(Progress::Done(path)) if path == destination));
+        assert_eq!(destination, dir.path().join("notes.txt"));
+        assert_eq!(fs::read(&destination).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn a_hash_mismatch_deletes_the_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![7u8; FILE_CHUNK_SIZE + 5];
+        let offer = offer(1, Path::new("notes.txt"), &data);
+        let mut tampered = data.clone();
+        tampered[FILE_CHUNK_SIZE] ^= 1;
+
+        let (destination, progress) = receive(dir.path(), offer, &tampered).await;
+        let error = progress.err().expect("the hash shouldn't match");
+        assert!(error.to_string().contains("SHA-256 mismatch"));
+        assert!(!destination.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_more_data_than_was_offered() {
+        let dir = tempfile::tempdir().unwrap();
+        let offer = offer(1, Path::new("notes.txt"), b"short");
+        let (destination, progress) = receive(dir.path(), offer, b"much longer").await;
+        assert!(progress.is_err());
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn sends_only_a_window_ahead_of_the_acks() {
+        let data = vec![0u8; FILE_CHUNK_SIZE * (FILE_WINDOW as usize * 2 + 1)];
+        let mut outgoing = OutgoingFile::new(1, PathBuf::from("big"), &data);
+        assert_eq!(outgoing.next_chunks().len(), FILE_WINDOW as usize);
+        assert!(outgoing.next_chunks().is_empty());
+
+        // An ack for chunks never sent changes nothing
+        outgoing.acked(FILE_WINDOW + 1);
+        assert!(outgoing.next_chunks().is_empty());
+
+        outgoing.acked(ACK_EVERY);
+        let chunks = outgoing.next_chunks();
+        assert_eq!(chunks.len(), ACK_EVERY as usize);
+        assert_eq!(chunks[0].seq, FILE_WINDOW);
+
+        outgoing.acked(FILE_WINDOW + ACK_EVERY);
+        assert_eq!(outgoing.next_chunks().len(), FILE_WINDOW as usize - ACK_EVERY as usize + 1);
+        assert!(!outgoing.is_done());
+        outgoing.acked(FILE_WINDOW * 2 + 1);
+        assert!(outgoing.is_done());
+    }
+}
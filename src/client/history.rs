@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use super::alias::Aliases;
+
+/// Maximum number of commands kept in memory and written to the history file
+pub const MAX_HISTORY: usize = 200;
+
+/// Location of the history dotfile (`~/.ycnbts_history`)
+pub fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".ycnbts_history"))
+}
+
+pub fn load_history() -> Vec<String> {
+    let Some(path) = history_path() else {
+        return Vec::new();
+    };
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    let mut history: Vec<String> = contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect();
+    if history.len() > MAX_HISTORY {
+        history.drain(..history.len() - MAX_HISTORY);
+    }
+    history
+}
+
+/// Writes the history to disk, leaving out `send`, `msg` and `broadcast` commands unless `persist_send`
+/// is set. Disappearing (`send!`) messages are never written. Aliases are
+/// judged by what they expand to.
+pub fn save_history(history: &[String], persist_send: bool, aliases: &Aliases) {
+    let Some(path) = history_path() else {
+        return;
+    };
+    let contents = history
+        .iter()
+        .filter(|line| {
+            let expanded = aliases.expand(line).unwrap_or_else(|_| line.to_string());
+            !is_disappearing_send(&expanded) && (persist_send || !is_send_command(&expanded))
+        })
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+    if let Err(e) = std::fs::write(path, contents) {
+        eprintln!("Failed to save command history: {}", e);
+    }
+}
+
+fn is_send_command(line: &str) -> bool {
+    ["send", "msg", "broadcast"].iter().any(|verb| {
+        line.strip_prefix(verb)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+    })
+}
+
+pub fn is_disappearing_send(line: &str) -> bool {
+    line.starts_with("send!")
+}
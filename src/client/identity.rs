@@ -0,0 +1,134 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use zeroize::Zeroizing;
+
+use crate::shared::{crypto, crypto::IdentityKey, Error, Result};
+
+/// Start of every identity file, followed by the passphrase salt
+const MAGIC: &[u8; 8] = b"YCNBKEY1";
+
+const SALT_LEN: usize = 16;
+
+/// Writes `key` to `path`, sealed with a key derived from `passphrase`.
+/// The file is written beside `path` first and renamed over it, so an
+/// interrupted export never leaves half a file where the old one was.
+pub fn export(path: &Path, key: &IdentityKey, passphrase: &str) -> Result<()> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let file_key = crypto::passphrase_key(passphrase, &salt)?;
+    let sealed = crypto::seal_at_rest(&file_key, &key.to_bytes()?)?;
+    let contents = [
+        MAGIC.as_slice(),
+        &salt,
+        &(sealed.len() as u32).to_le_bytes(),
+        &sealed,
+    ]
+    .concat();
+
+    let partial = partial_path(path);
+    let written = private_file(&partial)
+        .and_then(|mut file| file.write_all(&contents).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&partial, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    Ok(written?)
+}
+
+/// Reads the key `export` wrote to `path`. Fails with a message saying
+/// which if the file is cut short or the passphrase is wrong.
+pub fn import(path: &Path, passphrase: &str) -> Result<IdentityKey> {
+    let contents = fs::read(path)
+        .map_err(|e| Error::Protocol(format!("couldn't read {}: {}", path.display(), e)))?;
+    let Some(rest) = contents.strip_prefix(MAGIC.as_slice()) else {
+        return Err(Error::Protocol(format!(
+            "{} isn't an identity file",
+            path.display()
+        )));
+    };
+    let truncated = || Error::Protocol(format!("{} is truncated", path.display()));
+    if rest.len() < SALT_LEN + 4 {
+        return Err(truncated());
+    }
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (len, sealed) = rest.split_at(4);
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if sealed.len() < len {
+        return Err(truncated());
+    }
+
+    let file_key = crypto::passphrase_key(passphrase, salt)?;
+    let bytes = crypto::open_at_rest(&file_key, &sealed[..len])
+        .map(Zeroizing::new)
+        .map_err(|_| {
+            Error::Crypto(format!(
+                "wrong passphrase, or {} has been tampered with",
+                path.display()
+            ))
+        })?;
+    IdentityKey::from_bytes(&bytes)
+}
+
+/// Where `export` writes before renaming over `path`
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+/// Creates a file only its owner can read, replacing any left by an
+/// earlier interrupted export
+fn private_file(path: &Path) -> std::io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    options.create(true).truncate(true).write(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::crypto::KeyType;
+
+    #[test]
+    fn an_imported_key_is_the_one_exported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity");
+        let key = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+        export(&path, &key, "hunter2").unwrap();
+        assert!(!partial_path(&path).exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let imported = import(&path, "hunter2").unwrap();
+        assert_eq!(imported.public().fingerprint(), key.public().fingerprint());
+        assert_eq!(*imported.to_bytes().unwrap(), *key.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn a_wrong_passphrase_or_a_cut_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity");
+        let key = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+        export(&path, &key, "hunter2").unwrap();
+
+        assert!(matches!(import(&path, "hunter3"), Err(Error::Crypto(_))));
+
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() - 1]).unwrap();
+        let error = import(&path, "hunter2").err().unwrap();
+        assert!(error.to_string().ends_with("is truncated"), "{}", error);
+
+        fs::write(&path, b"not a key").unwrap();
+        let error = import(&path, "hunter2").err().unwrap();
+        assert!(error.to_string().ends_with("isn't an identity file"), "{}", error);
+    }
+}
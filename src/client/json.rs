@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::broadcast,
+};
+use uuid::Uuid;
+
+use super::{Client, ClientEvent};
+use crate::shared::Result;
+
+/// A command read from stdin by `run_json`, one JSON object per line, e.g.
+/// `{"cmd":"send","to":"<uuid>","text":"hi"}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum JsonCommand {
+    /// Sends over an open session. With `expires_in_secs` set the message
+    /// disappears like one sent with `send!`.
+    Send {
+        to: Uuid,
+        text: String,
+        #[serde(default)]
+        expires_in_secs: Option<u64>,
+    },
+    Open { to: Uuid },
+    Accept { from: Uuid },
+    Advertise { name: String },
+    Quit,
+}
+
+impl Client {
+    /// Runs the client without the interactive prompt: every `ClientEvent`
+    /// is written to stdout as a line of JSON, and commands are read from
+    /// stdin as `JsonCommand`s. Returns on `quit` or when stdin closes.
+    ///
+    /// Failed or malformed commands are reported as `warning` events.
+    pub async fn run_json(&self) -> Result<()> {
+        let mut events = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => match serde_json::to_string(&event) {
+                        Ok(line) => println!("{}", line),
+                        Err(e) => eprintln!("Failed to encode an event: {}", e),
+                    },
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let command = match serde_json::from_str::<JsonCommand>(&line) {
+                Ok(command) => command,
+                Err(e) => {
+                    self.emit(ClientEvent::Warning(format!("Invalid command: {}", e)));
+                    continue;
+                }
+            };
+            let result = match command {
+                JsonCommand::Send {
+                    to,
+                    text,
+                    expires_in_secs,
+                } => {
+                    self.send_to(to, &text, expires_in_secs.map(Duration::from_secs))
+                        .await
+                }
+                JsonCommand::Open { to } => self.request_connection(to).await.map(|_| ()),
+                JsonCommand::Accept { from } => self.accept(from).await,
+                JsonCommand::Advertise { name } => self.advertise(name).await,
+                JsonCommand::Quit => return Ok(()),
+            };
+            if let Err(e) = result {
+                self.emit(ClientEvent::Warning(e.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shared::messages::ClientDescription;
+
+    use super::*;
+
+    #[test]
+    fn reads_a_send_command() {
+        let to = Uuid::new_v4();
+        let line = format!(r#"{{"cmd":"send","to":"{}","text":"hi","expires_in_secs":5}}"#, to);
+        let JsonCommand::Send {
+            to: parsed,
+            text,
+            expires_in_secs,
+        } = serde_json::from_str(&line).unwrap()
+        else {
+            panic!("expected a send");
+        };
+        assert_eq!((parsed, text.as_str(), expires_in_secs), (to, "hi", Some(5)));
+
+        let line = format!(r#"{{"cmd":"send","to":"{}","text":"hi"}}"#, to);
+        let command: JsonCommand = serde_json::from_str(&line).unwrap();
+        assert!(matches!(command, JsonCommand::Send { expires_in_secs: None, .. }));
+        assert!(serde_json::from_str::<JsonCommand>(r#"{"cmd":"shout"}"#).is_err());
+    }
+
+    #[test]
+    fn writes_an_event_as_tagged_json() {
+        let from = ClientDescription::new("alice".to_string(), Uuid::new_v4());
+        let event = ClientEvent::MessageReceived {
+            from: from.clone(),
+            name: "alice".to_string(),
+            text: "hello".to_string(),
+            verified: true,
+            time: chrono::Local::now(),
+        };
+        let line = serde_json::to_string(&event).unwrap();
+        assert!(!line.contains('\n'), "one event per line");
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "message_received");
+        assert_eq!(value["data"]["from"]["uuid"], from.uuid.to_string());
+        assert_eq!(value["data"]["text"], "hello");
+        assert_eq!(value["data"]["verified"], true);
+    }
+}
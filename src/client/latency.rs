@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+
+/// Round-trip time to the server, or to a peer over a direct link, measured
+/// by the heartbeat pings
+#[derive(Debug, Default)]
+pub struct Latency {
+    /// The ping waiting for its pong. A newer ping replaces it, so a lost
+    /// pong doesn't count as a huge sample later.
+    outstanding: Option<(u64, Instant)>,
+    /// Smoothed like TCP's SRTT: each sample moves it an eighth of the way
+    average: Option<Duration>,
+}
+
+impl Latency {
+    /// Notes a ping sent at `now`, returning the id to put in it
+    pub fn ping(&mut self, now: Instant) -> u64 {
+        let id = rand::random();
+        self.outstanding = Some((id, now));
+        id
+    }
+
+    /// Takes the round trip for the pong with `id` received at `now` into the
+    /// average. Pongs that don't match the last ping are ignored.
+    pub fn pong(&mut self, id: u64, now: Instant) {
+        let Some((expected, sent)) = self.outstanding else {
+            return;
+        };
+        if id != expected {
+            return;
+        }
+        self.outstanding = None;
+        let sample = now.saturating_duration_since(sent);
+        self.average = Some(match self.average {
+            Some(average) => (average * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        self.average
+    }
+}
+
+/// Formats a round trip for display, e.g. `12 ms`
+pub fn describe(rtt: Option<Duration>) -> String {
+    match rtt {
+        Some(rtt) => format!("{} ms", rtt.as_millis()),
+        None => "not measured yet".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn round_trips_are_averaged_an_eighth_at_a_time() {
+        let start = Instant::now();
+        let mut latency = Latency::default();
+        assert_eq!(latency.average(), None);
+
+        let id = latency.ping(start);
+        latency.pong(id, start + ms(80));
+        assert_eq!(latency.average(), Some(ms(80)));
+
+        let id = latency.ping(start + ms(1000));
+        latency.pong(id, start + ms(1160));
+        assert_eq!(latency.average(), Some(ms(90)));
+        assert_eq!(describe(latency.average()), "90 ms");
+    }
+
+    #[test]
+    fn pongs_for_other_pings_are_ignored() {
+        let start = Instant::now();
+        let mut latency = Latency::default();
+        latency.pong(1, start);
+
+        let lost = latency.ping(start);
+        let id = latency.ping(start + ms(5000));
+        // The pong of a ping since replaced would be a huge sample
+        latency.pong(lost, start + ms(5010));
+        assert_eq!(latency.average(), None);
+        latency.pong(id, start + ms(5020));
+        assert_eq!(latency.average(), Some(ms(20)));
+        // Each pong counts once
+        latency.pong(id, start + ms(9000));
+        assert_eq!(latency.average(), Some(ms(20)));
+        assert_eq!(describe(None), "not measured yet");
+    }
+}
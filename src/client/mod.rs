@@ -1,367 +1,3964 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::Future,
+    hash::Hash,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use aes_gcm::{aead::Aead, AeadCore, Aes256Gcm, Key, KeyInit};
 use clap::Parser;
-use inquire::{Confirm, Select, Text};
-use rand::{rngs::OsRng, RngCore};
-use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use inquire::{Confirm, InquireError, Password, Select, Text};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-    sync::Mutex,
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc, watch, Mutex},
+    task::JoinHandle,
 };
 use uuid::Uuid;
+use x25519_dalek::ReusableSecret;
+
+use crate::shared::{
+    crypto::{self, IdentityKey, KeyType, PublicIdentity},
+    framing::{self, Traffic, WireFormat},
+    messages::{
+        is_valid_reaction, ClientBoundMessage, ClientDescription, EncryptedPayload, FileChunk,
+        FileAck, FileOffer, FileResponse, Handshake, MessageChunk, Presence, Profile, ResumeToken,
+        ServerBoundMessage, MAX_STATUS_TEXT_LEN, PROTOCOL_VERSION,
+    },
+    socket, suite,
+    transport::{ClientTransport, ReadHalf, WriteHalf},
+    Error, Result,
+};
+#[cfg(feature = "tls")]
+use crate::shared::tls::{self, TlsConnector};
+pub use events::ClientEvent;
+pub use json::JsonCommand;
+pub use proxy::Proxy;
+pub use transcript::{Entry, Reaction};
+use alias::Aliases;
+use direct::{DirectEvent, DirectLink};
+use latency::Latency;
+use output::{say, say_error};
+use session::Session;
+use transcript::Transcript;
 
-use crate::shared::messages::{ClientBoundMessage, ClientDescription, ServerBoundMessage};
+mod alias;
+mod chunks;
+mod completion;
+mod direct;
+mod events;
+mod files;
+mod history;
+mod identity;
+mod json;
+mod latency;
+mod output;
+mod proxy;
+mod script;
+pub(crate) mod session;
+mod transcript;
+mod tui;
 
 pub struct Client {
-    readonly_half: Arc<Mutex<OwnedReadHalf>>,
-    writeable_half: Arc<Mutex<OwnedWriteHalf>>,
+    readonly_half: Arc<Mutex<ReadHalf>>,
+    writeable_half: Arc<Mutex<WriteHalf>>,
+    /// Our end's IP on the connection to the server
+    local_ip: Arc<Mutex<IpAddr>>,
     peer_list: Arc<Mutex<Vec<ClientDescription>>>,
+    /// Peers from `ClientListPart`s, until the closing `ClientList`
+    /// replaces `peer_list` with them
+    incoming_peers: Arc<Mutex<Vec<ClientDescription>>>,
     uuid: Arc<Mutex<Option<Uuid>>>,
-    connection_requests: Arc<Mutex<HashMap<ClientDescription, RsaPublicKey>>>,
-    open_connections: Arc<Mutex<HashMap<Uuid, RsaPublicKey>>>,
+    connection_requests: Arc<Mutex<HashMap<ClientDescription, (PublicIdentity, Handshake)>>>,
+    /// When a request from each peer was last taken, for
+    /// `MIN_REREQUEST_INTERVAL`
+    request_times: Arc<Mutex<HashMap<Uuid, Instant>>>,
+    pending_handshakes: Arc<Mutex<HashMap<Uuid, (ReusableSecret, Handshake)>>>,
+    open_connections: Arc<Mutex<HashMap<Uuid, Session>>>,
     current_channel: Arc<Mutex<Option<Uuid>>>,
-    private_key: Arc<RsaPrivateKey>,
-    public_key: Arc<RsaPublicKey>,
+    /// Round-trip time to the server, reset with every connection
+    server_latency: Arc<Mutex<Latency>>,
+    /// Peers we talk to over our own connection instead of the relay
+    direct_links: Arc<Mutex<HashMap<Uuid, DirectLink>>>,
+    /// Addresses we're listening on for a peer to connect to, by peer
+    direct_offers: Arc<Mutex<HashMap<Uuid, JoinHandle<()>>>>,
+    /// Where the tasks behind direct links report, drained by `handle`
+    direct_events: mpsc::Sender<DirectEvent>,
+    direct_inbox: Arc<Mutex<mpsc::Receiver<DirectEvent>>>,
+    /// Refuse direct connections, keeping our address from peers
+    no_direct: bool,
+    /// Refuse to send messages to keys that haven't been verified
+    strict_verify: bool,
+    /// Whether someone is at the prompt to answer questions, rather than
+    /// the client running a script or JSON commands
+    interactive: bool,
+    /// Messages sent and received, unless history is turned off
+    transcript: Option<Arc<Mutex<Transcript>>>,
+    /// Age at which messages are pruned from `transcript`, if they are
+    history_ttl: Option<Duration>,
+    /// Frames and bytes sent to and received from each peer, counting
+    /// only what belongs to conversations rather than setting them up
+    traffic: Arc<std::sync::Mutex<HashMap<Uuid, Traffic>>>,
+    /// Recipient and counter of messages sent through the server that it
+    /// hasn't acknowledged with a `Delivered`, oldest first
+    in_flight: Arc<Mutex<VecDeque<(Uuid, u64)>>>,
+    /// What we asked about with `QueryAcks` on resuming, until `Acked`
+    /// answers
+    queried_acks: Arc<Mutex<Vec<(Uuid, u64)>>>,
+    /// Messages received from each peer while another channel was current,
+    /// cleared by opening that peer's channel
+    unread: Arc<Mutex<HashMap<Uuid, usize>>>,
+    /// Replaced by `import`, so read through `private_key()`
+    private_key: Arc<std::sync::Mutex<Arc<IdentityKey>>>,
+    public_key: Arc<std::sync::Mutex<Arc<PublicIdentity>>>,
+    history: Arc<Mutex<Vec<String>>>,
+    /// Shortcuts expanded before an action is run
+    aliases: Arc<std::sync::Mutex<Aliases>>,
+    persist_send_history: bool,
+    max_message_len: usize,
+    output: output::Output,
+    wire_format: WireFormat,
+    /// Whether to tell peers when their messages have been displayed
+    receipts: Arc<Mutex<bool>>,
+    /// Whether notices about connections are left unprinted (toggle with
+    /// `quiet on|off`)
+    quiet: Arc<Mutex<bool>>,
+    /// Whether `run_ui` prints above a plain prompt rather than taking over
+    /// the screen
+    simple_ui: bool,
+    /// Where accepted files are saved
+    download_dir: PathBuf,
+    /// What `handle` saw happen, for `subscribe`rs
+    events: broadcast::Sender<ClientEvent>,
+    /// Where to reconnect to. `None` for clients built with `from_stream`,
+    /// which can't reconnect.
+    server_address: Option<ServerAddress>,
+    /// SOCKS5 proxy the server is reached through
+    proxy: Option<Proxy>,
+    /// False from when `handle` loses the server until `run_connection`
+    /// reconnects
+    connected: Arc<watch::Sender<bool>>,
+    /// Last name sent with `advertise`, repeated after reconnecting
+    advertised_name: Arc<Mutex<Option<String>>>,
+    /// Set when the server turns away a name we advertised, until the
+    /// prompt asks for another
+    name_taken: Arc<Mutex<bool>>,
+    /// Presented when reconnecting to keep our uuid
+    resume_token: Arc<Mutex<Option<ResumeToken>>>,
+    tcp_keepalive_secs: u64,
+    /// Status we last set, including an automatic Away
+    presence: Arc<Mutex<Presence>>,
+    /// Whether `presence` is Away only because we went idle, so the next
+    /// action should bring us back online
+    idle_away: Arc<Mutex<bool>>,
+    /// Profile we last set, sent again if a reconnect doesn't resume
+    profile: Arc<Mutex<Profile>>,
+    /// When a command was last entered at the prompt
+    last_action: Arc<std::sync::Mutex<Instant>>,
+    /// Idle time after which the prompt sets us Away, if enabled
+    idle_away_after: Option<Duration>,
 }
 
-impl Client {
-    pub async fn new(host: String, port: u16) -> Self {
-        let stream = tokio::net::TcpStream::connect(format!("{}:{}", host, port))
-            .await
-            .unwrap();
-        let (readable_half, writeable_half) = stream.into_split();
+/// How long a connection request waits for an answer. Both sides forget the
+/// request after this, so a stale one can't be accepted.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
-        let mut rng = rand::thread_rng();
-        let private_key = RsaPrivateKey::new(&mut rng, 2048).unwrap();
-        let public_key = RsaPublicKey::from(&private_key);
+/// Connection requests that may wait to be accepted at once. Any more are
+/// turned away, so a flood of peers can't bury the accept menu.
+const MAX_PENDING_REQUESTS: usize = 50;
 
-        Client {
-            readonly_half: Arc::new(Mutex::new(readable_half)),
-            writeable_half: Arc::new(Mutex::new(writeable_half)),
-            peer_list: Arc::new(Mutex::new(Vec::new())),
-            uuid: Arc::new(Mutex::new(None)),
-            connection_requests: Arc::new(Mutex::new(HashMap::new())),
-            open_connections: Arc::new(Mutex::new(HashMap::new())),
-            current_channel: Arc::new(Mutex::new(None)),
-            private_key: Arc::new(private_key),
-            public_key: Arc::new(public_key),
+/// How soon after one of its requests is taken a peer may ask again.
+/// Sooner requests are turned away.
+const MIN_REREQUEST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Peers shown per page by `list`, and at once by the peer selection menu
+const PEERS_PER_PAGE: usize = 20;
+
+/// Wait before the first reconnection attempt, doubled after each failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Events a subscriber can fall behind by before it starts missing them
+const SUBSCRIBER_BUFFER: usize = 256;
+
+/// Messages sent through the server that are remembered until it says it
+/// relayed them. The server remembers as many per client.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// Reports from direct links that can wait on `handle`, which is paused
+/// while reconnecting to the server
+const DIRECT_EVENT_BUFFER: usize = 64;
+
+/// How often the server and direct peers are pinged to measure latency
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often the prompt checks whether we've gone idle
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often messages older than `--history-ttl-days` are looked for
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long to wait for the server to list us under a name we advertised,
+/// if it doesn't say the name is taken. Only runs out when it doesn't list
+/// us at all, as when we're invisible.
+const NAME_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What the prompt loop should do after an action
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Continue,
+    Exit,
+}
+
+/// How the connection to the server ended, when it didn't fail outright
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Closed {
+    /// The server said it was shutting down before closing the connection
+    Shutdown,
+    /// The connection reached a clean end of stream with no word from the
+    /// server. A crashed or restarted server closes its sockets just the
+    /// same, so this is a drop like any other.
+    Dropped,
+}
+
+/// Adds `peer` to the list, replacing any entry with the same uuid so a
+/// re-announced client never shows up twice
+pub fn upsert_peer(peer_list: &mut Vec<ClientDescription>, peer: ClientDescription) {
+    match peer_list.iter_mut().find(|existing| existing.uuid == peer.uuid) {
+        Some(existing) => *existing = peer,
+        None => peer_list.push(peer),
+    }
+}
+
+/// Removes the request under `key` if it's still the one whose handshake
+/// used `ephemeral_key`, so the timer for an old request never forgets a
+/// newer one to or from the same peer
+fn forget_request<K: Eq + Hash, V>(
+    requests: &mut HashMap<K, (V, Handshake)>,
+    key: &K,
+    ephemeral_key: [u8; 32],
+) -> bool {
+    let current = requests
+        .get(key)
+        .is_some_and(|(_, handshake)| handshake.ephemeral_key == ephemeral_key);
+    if current {
+        requests.remove(key);
+    }
+    current
+}
+
+/// Sanitizes every peer name in a message from the server before anything
+/// stores or prints it, since the server relays names as clients chose them
+fn sanitize_names(message: &mut ClientBoundMessage) {
+    match message {
+        ClientBoundMessage::ClientList(peers) | ClientBoundMessage::ClientListPart(peers) => {
+            for peer in peers {
+                sanitize_peer(peer);
+            }
         }
+        ClientBoundMessage::NewClient(peer)
+        | ClientBoundMessage::ConnectionRequest(peer, _, _)
+        | ClientBoundMessage::ConnectionResponse(peer, _, _)
+        | ClientBoundMessage::Message(peer, _)
+        | ClientBoundMessage::ReadReceipt(peer, _)
+        | ClientBoundMessage::FileOffer(peer, _)
+        | ClientBoundMessage::FileResponse(peer, _)
+        | ClientBoundMessage::FileChunk(peer, _)
+        | ClientBoundMessage::FileAck(peer, _)
+        | ClientBoundMessage::DirectRequest(peer, _)
+        | ClientBoundMessage::RequestRejected(peer)
+        | ClientBoundMessage::Reaction(peer, ..) => sanitize_peer(peer),
+        ClientBoundMessage::ClientRenamed(_, name) => *name = output::sanitize_name(name),
+        ClientBoundMessage::ProfileChanged(_, profile) => sanitize_profile(profile),
+        _ => {}
     }
+}
 
-    pub async fn send_message(&self, message: crate::shared::messages::ServerBoundMessage) {
-        let mut buffer = Vec::new();
-        bincode::serialize_into(&mut buffer, &message).unwrap();
+fn sanitize_peer(peer: &mut ClientDescription) {
+    peer.name = output::sanitize_name(&peer.name);
+    sanitize_profile(&mut peer.profile);
+}
 
-        let mut buffer_with_length = Vec::new();
-        bincode::serialize_into(&mut buffer_with_length, &(buffer.len() as u64)).unwrap();
-        buffer_with_length.extend(buffer);
+/// Applies the server's profile checks again, since only the server's word
+/// says they were made
+fn sanitize_profile(profile: &mut Profile) {
+    if profile.emoji.as_deref().is_some_and(|emoji| !is_valid_reaction(emoji)) {
+        profile.emoji = None;
+    }
+    if let Some(text) = &mut profile.status_text {
+        *text = output::sanitize_text(text).chars().take(MAX_STATUS_TEXT_LEN).collect();
+    }
+}
 
-        self.writeable_half
-            .lock()
-            .await
-            .write_all(&buffer_with_length)
-            .await
-            .unwrap();
+/// Peers whose name or uuid contains `filter`, sorted by name then uuid.
+/// Names are matched and sorted ignoring case.
+pub fn sorted_peers<'a>(peers: &'a [ClientDescription], filter: &str) -> Vec<&'a ClientDescription> {
+    let filter = filter.to_lowercase();
+    let mut matching: Vec<&ClientDescription> = peers
+        .iter()
+        .filter(|peer| {
+            peer.name.to_lowercase().contains(&filter) || peer.uuid.to_string().contains(&filter)
+        })
+        .collect();
+    matching.sort_by_cached_key(|peer| (peer.name.to_lowercase(), peer.uuid));
+    matching
+}
+
+/// Forgets messages older than `ttl` every `HISTORY_PRUNE_INTERVAL`,
+/// starting at once
+async fn prune_history(
+    transcript: Arc<Mutex<Transcript>>,
+    ttl: Duration,
+    events: broadcast::Sender<ClientEvent>,
+) {
+    let mut ticks = tokio::time::interval(HISTORY_PRUNE_INTERVAL);
+    loop {
+        ticks.tick().await;
+        let Ok(ttl) = chrono::Duration::from_std(ttl) else {
+            return;
+        };
+        let cutoff = chrono::Local::now() - ttl;
+        if let Err(e) = transcript.lock().await.prune(cutoff) {
+            let _ = events.send(ClientEvent::Warning(format!(
+                "Failed to prune message history: {}",
+                e
+            )));
+        }
     }
+}
 
-    pub async fn handle(&self) {
-        loop {
-            let mut length_buf = [0u8; 8];
-            if self
-                .readonly_half
-                .lock()
-                .await
-                .read_exact(&mut length_buf)
-                .await
-                .is_err()
-            {
-                break;
-            }
+/// The entries of the conversation with `uuid`, or with `name`
+fn history_with(transcript: &Transcript, uuid: Option<Uuid>, name: &str) -> Vec<Entry> {
+    transcript
+        .entries()
+        .iter()
+        .filter(|entry| entry.is_with(uuid, name))
+        .cloned()
+        .collect()
+}
 
-            let message_len: u64 = match bincode::deserialize_from(&length_buf[..]) {
-                Ok(len) => len,
-                Err(_) => break,
-            };
+/// Sets up message history as `args` asks, prompting for the history
+/// file's passphrase if there is one
+async fn open_transcript(args: &Args) -> Result<Option<Transcript>> {
+    if args.no_history {
+        return Ok(None);
+    }
+    let Some(path) = args.history_file.clone() else {
+        return Ok(Some(Transcript::in_memory()));
+    };
+    let prompt = Password::new("History passphrase:");
+    let prompt = if path.exists() {
+        prompt.without_confirmation()
+    } else {
+        prompt.with_custom_confirmation_message("Choose it again to confirm:")
+    };
+    let passphrase = tui::suspended(|| prompt.prompt())
+        .map_err(|e| Error::Protocol(format!("no history passphrase given: {}", e)))?;
+    // Key derivation is deliberately slow
+    let open = tokio::task::spawn_blocking(move || Transcript::open(&path, &passphrase));
+    output::spinner("Unlocking history", open)
+        .await
+        .map_err(|e| Error::Crypto(e.to_string()))?
+        .map(Some)
+}
 
-            let mut buffer = vec![0u8; message_len as usize];
-            if self
-                .readonly_half
-                .lock()
-                .await
-                .read_exact(&mut buffer)
-                .await
-                .is_err()
-            {
-                break;
-            }
+/// Generates our identity key behind a spinner. A large RSA key takes long
+/// enough to stall the runtime, so it's generated off it.
+async fn generate_key(key_type: KeyType, key_bits: usize) -> Result<IdentityKey> {
+    let keygen = tokio::task::spawn_blocking(move || IdentityKey::generate(key_type, key_bits));
+    output::spinner("Generating keys", keygen)
+        .await
+        .map_err(|e| Error::Crypto(e.to_string()))?
+}
 
-            match bincode::deserialize_from::<&[u8], ClientBoundMessage>(&buffer[..]) {
-                Ok(message) => match message {
-                    ClientBoundMessage::SetUuid(uuid) => {
-                        *self.uuid.lock().await = Some(uuid);
-                    }
-                    ClientBoundMessage::ClientList(client_description) => {
-                        *self.peer_list.lock().await = client_description;
-                    }
-                    ClientBoundMessage::NewClient(client_description) => {
-                        self.peer_list.lock().await.push(client_description);
-                    }
-                    ClientBoundMessage::ClientDisconnected(uuid) => {
-                        let mut peer_list = self.peer_list.lock().await;
-                        peer_list.retain(|(_, id)| *id != uuid);
-                    }
-                    ClientBoundMessage::ConnectionRequest(client_description, public_key) => {
-                        let mut connection_requests = self.connection_requests.lock().await;
-                        if !connection_requests
-                            .iter()
-                            .any(|((_, id), _)| *id == client_description.1)
-                        {
-                            connection_requests.insert(client_description, public_key);
-                            println!("\n\r\n You have a new connection request. Type 'accept' to view and accept it.\n\r");
-                        }
-                    }
-                    ClientBoundMessage::ConnectionResponse(client_description, public_key) => {
-                        let mut open_connections = self.open_connections.lock().await;
-                        open_connections.insert(client_description.1, public_key);
-                        println!("\n\r\n Connection accepted.Type 'open' again to choose channel.\n\r");
-                    }
-                    ClientBoundMessage::Message(client_description, (encrypted_key, nonce, ciphertext)) => {
-                        let name = self
-                            .peer_list
-                            .lock()
-                            .await
-                            .iter()
-                            .find(|(_, id)| *id == client_description.1)
-                            .map(|(name, _)| name.clone())
-                            .unwrap_or("Unknown".to_string());
+/// One line of `list` output
+fn describe_peer(peer: &ClientDescription, unread: &HashMap<Uuid, usize>) -> String {
+    let name = output::describe_profile(&peer.name, &peer.profile);
+    match unread.get(&peer.uuid) {
+        Some(count) => format!("{}: {} ({}, {} unread)", peer.uuid, name, peer.presence, count),
+        None => format!("{}: {} ({})", peer.uuid, name, peer.presence),
+    }
+}
 
-                        let session_key = self.private_key.decrypt(Pkcs1v15Encrypt, &encrypted_key).unwrap();
+/// Where the server listens
+#[derive(Clone)]
+enum ServerAddress {
+    Tcp(String, u16),
+    /// TCP with TLS on top, checking the server's certificate is for the host
+    #[cfg(feature = "tls")]
+    Tls(String, u16, TlsConnector),
+    Unix(PathBuf),
+}
 
-                        let key = Key::<Aes256Gcm>::from_slice(&session_key);
-                        let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
+impl ServerAddress {
+    /// Where `args` say the server is
+    fn from_args(args: &Args) -> Result<Self> {
+        if let Some(path) = &args.socket {
+            return Ok(ServerAddress::Unix(path.clone()));
+        }
+        // Accept IPv6 literals with or without the brackets used in URLs
+        let host = args.address.trim_start_matches('[').trim_end_matches(']').to_string();
+        #[cfg(feature = "tls")]
+        if args.tls {
+            let connector = tls::connector(args.tls_ca.as_deref())?;
+            return Ok(ServerAddress::Tls(host, args.port, connector));
+        }
+        Ok(ServerAddress::Tcp(host, args.port))
+    }
+}
 
-                        let message = cipher.decrypt((&*nonce).into(), &*ciphertext).unwrap();
-                        let message = String::from_utf8(message).unwrap();
+/// Opens TCP to `host:port` directly, or through `proxy` if there is one
+async fn connect_tcp(host: &str, port: u16, proxy: Option<&Proxy>) -> Result<TcpStream> {
+    match proxy {
+        Some(proxy) => proxy.connect(host, port).await,
+        None => Ok(TcpStream::connect((host, port)).await?),
+    }
+}
 
-                        println!("\n\r\n{}: {}\n\r", name, message);
-                    }
-                },
-                Err(e) => {
-                    eprintln!("Failed to deserialize message: {}", e);
-                }
-            };
+/// Connects to the server directly, or through `proxy` if there is one
+async fn connect(address: &ServerAddress, proxy: Option<&Proxy>) -> Result<Box<dyn ClientTransport>> {
+    match (address, proxy) {
+        (ServerAddress::Tcp(host, port), proxy) => {
+            Ok(Box::new(connect_tcp(host, *port, proxy).await?))
         }
+        #[cfg(feature = "tls")]
+        (ServerAddress::Tls(host, port, connector), proxy) => {
+            let stream = connect_tcp(host, *port, proxy).await?;
+            Ok(Box::new(tls::connect(connector, host, stream).await?))
+        }
+        #[cfg(unix)]
+        (ServerAddress::Unix(path), _) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        (ServerAddress::Unix(_), _) => Err(Error::Protocol(
+            "Unix sockets aren't supported on this platform".to_string(),
+        )),
     }
 }
 
+/// A connection that has exchanged hellos with the server
+struct Greeting {
+    readable_half: ReadHalf,
+    writeable_half: WriteHalf,
+    local_ip: IpAddr,
+    /// The wire format the server chose
+    wire_format: WireFormat,
+    uuid: Uuid,
+    resume_token: ResumeToken,
+}
+
+/// Reads the server's hello and answers with ours, presenting `resume_token`
+/// to take back an earlier connection's uuid, then reads the uuid the
+/// server assigned
+async fn greet(
+    stream: Box<dyn ClientTransport>,
+    keepalive_secs: u64,
+    resume_token: Option<ResumeToken>,
+) -> Result<Greeting> {
+    stream.configure(keepalive_secs)?;
+    let local_ip = stream.local_ip()?;
+    let (mut readable_half, mut writeable_half) = stream.into_split();
+
+    let Some(greeting) = framing::read_hello_frame(&mut readable_half).await? else {
+        return Err(Error::Protocol(
+            "server closed the connection without a hello".to_string(),
+        ));
+    };
+    let wire_format = match framing::decode_hello(&greeting) {
+        Ok(ClientBoundMessage::ServerHello {
+            protocol_version: PROTOCOL_VERSION,
+            wire_format,
+        }) => wire_format,
+        Ok(ClientBoundMessage::ServerHello {
+            protocol_version, ..
+        }) => {
+            return Err(Error::Rejected(format!(
+                "server speaks protocol version {}, this client speaks {}",
+                protocol_version, PROTOCOL_VERSION
+            )))
+        }
+        _ => {
+            return Err(Error::Protocol(
+                "server didn't send a hello, it may be running an older version".to_string(),
+            ))
+        }
+    };
+
+    let hello = ServerBoundMessage::ClientHello {
+        protocol_version: PROTOCOL_VERSION,
+        resume_token,
+    };
+    framing::write_frame(&mut writeable_half, wire_format, &hello).await?;
+    let Some(frame) = framing::read_frame(&mut readable_half).await? else {
+        return Err(Error::Protocol(
+            "server closed the connection before assigning a uuid".to_string(),
+        ));
+    };
+    let (uuid, resume_token) = match wire_format.decode(&frame)? {
+        ClientBoundMessage::SetUuid(uuid, resume_token) => (uuid, resume_token),
+        // The server turned us away, and says why
+        ClientBoundMessage::ProtocolError(feedback) => return Err(Error::Rejected(feedback)),
+        // It may be back shortly, so this isn't a refusal
+        ClientBoundMessage::ServerShutdown => {
+            return Err(Error::Protocol("the server is shutting down".to_string()))
+        }
+        _ => {
+            return Err(Error::Protocol(
+                "server didn't assign a uuid after the hello".to_string(),
+            ))
+        }
+    };
+    Ok(Greeting {
+        readable_half,
+        writeable_half,
+        local_ip,
+        wire_format,
+        uuid,
+        resume_token,
+    })
+}
+
+/// Whether an action talks to the server, and so can't run while disconnected
+fn needs_connection(action: &str) -> bool {
+    let verb = action.split_whitespace().next().unwrap_or("");
+    history::is_disappearing_send(verb)
+        || matches!(
+            verb,
+            "open"
+                | "accept"
+                | "close"
+                | "cancel"
+                | "send"
+                | "msg"
+                | "broadcast"
+                | "direct"
+                | "sendfile"
+                | "acceptfile"
+                | "status"
+                | "server"
+                | "profile"
+        )
+}
+
 impl Client {
-    pub async fn run_ui(&self) {
-        let set_friendly_name = Confirm::new("Set friendly name?")
-            .with_default(true)
-            .prompt()
-            .unwrap();
-        if set_friendly_name {
-            let friendly_name = Text::new("Friendly name")
-                .with_placeholder("Enter a name that other clients will see")
-                .with_default("Anonymous Turtle 🐢")
-                .prompt()
-                .unwrap();
-            let message = crate::shared::messages::ServerBoundMessage::Advertise(friendly_name);
-            self.send_message(message).await;
-        } else {
-            println!(
-                "\n\n No friendly name set. Your uuid will not be displayed to other clients.\n"
-            );
-            print!("Anyone who wants to connect to you will need to know your uuid. Type 'uuid' to view it.\n\n");
+    /// Connects to the server named in `args`
+    pub async fn new(args: Args) -> Result<Self> {
+        let server_address = ServerAddress::from_args(&args)?;
+        let proxy = args.proxy.clone();
+        let stream = connect(&server_address, proxy.as_ref()).await?;
+        let mut client = Self::from_transport(stream, args).await?;
+        client.server_address = Some(server_address);
+        client.proxy = proxy;
+        Ok(client)
+    }
+
+    /// Sets up a client over an already connected stream. `args.address`,
+    /// `args.port` and `args.socket` are ignored, and the client can't
+    /// reconnect.
+    pub async fn from_stream(stream: impl ClientTransport, args: Args) -> Result<Self> {
+        Self::from_transport(Box::new(stream), args).await
+    }
+
+    async fn from_transport(stream: Box<dyn ClientTransport>, args: Args) -> Result<Self> {
+        let greeting = greet(stream, args.tcp_keepalive_secs, None).await?;
+
+        let private_key = generate_key(args.key_type, args.key_bits).await?;
+        let public_key = private_key.public();
+        let transcript = open_transcript(&args)
+            .await?
+            .map(|transcript| Arc::new(Mutex::new(transcript)));
+        let history_ttl = (args.history_ttl_days > 0)
+            .then(|| Duration::from_secs(args.history_ttl_days * 24 * 60 * 60));
+        let events = broadcast::channel(SUBSCRIBER_BUFFER).0;
+        if let (Some(transcript), Some(history_ttl)) = (&transcript, history_ttl) {
+            tokio::spawn(prune_history(transcript.clone(), history_ttl, events.clone()));
         }
-        loop {
-            println!();
-            let action = Text::new("Action")
-                .with_placeholder("Type 'exit' to exit or 'help' to view available actions")
-                .prompt()
-                .unwrap();
-            match action.as_str() {
-                "exit" => break,
-                "help" => Self::display_help().await,
-                "uuid" => self.display_uuid().await,
-                "list" => self.list_peers().await,
-                "open" => self.open_connection(None).await,
-                "accept" => self.accept_connection().await,
-                "" => {}
-                _ => {
-                    if action.starts_with("open") {
-                        let uuid = action
-                            .split_whitespace()
-                            .nth(1)
-                            .map(|uuid| Uuid::parse_str(uuid).unwrap());
-                        self.open_connection(uuid).await;
-                    } else if action.starts_with("send") {
-                        let message = action
-                            .split_once(' ')
-                            .map(|x| x.1)
-                            .unwrap_or("")
-                            .to_string();
-                        self.ui_send_message(message).await;
-                    } else {
-                        println!("Unknown action: {}", action);
-                    }
-                }
-            }
+        let (direct_events, direct_inbox) = mpsc::channel(DIRECT_EVENT_BUFFER);
+
+        let client = Client {
+            readonly_half: Arc::new(Mutex::new(greeting.readable_half)),
+            writeable_half: Arc::new(Mutex::new(greeting.writeable_half)),
+            local_ip: Arc::new(Mutex::new(greeting.local_ip)),
+            peer_list: Arc::new(Mutex::new(Vec::new())),
+            incoming_peers: Arc::new(Mutex::new(Vec::new())),
+            uuid: Arc::new(Mutex::new(Some(greeting.uuid))),
+            connection_requests: Arc::new(Mutex::new(HashMap::new())),
+            request_times: Arc::new(Mutex::new(HashMap::new())),
+            pending_handshakes: Arc::new(Mutex::new(HashMap::new())),
+            open_connections: Arc::new(Mutex::new(HashMap::new())),
+            current_channel: Arc::new(Mutex::new(None)),
+            server_latency: Arc::new(Mutex::new(Latency::default())),
+            direct_links: Arc::new(Mutex::new(HashMap::new())),
+            direct_offers: Arc::new(Mutex::new(HashMap::new())),
+            direct_events,
+            direct_inbox: Arc::new(Mutex::new(direct_inbox)),
+            no_direct: args.no_direct,
+            strict_verify: args.strict_verify,
+            interactive: args.script.is_none() && !args.json_events,
+            transcript,
+            history_ttl,
+            traffic: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(VecDeque::new())),
+            queried_acks: Arc::new(Mutex::new(Vec::new())),
+            unread: Arc::new(Mutex::new(HashMap::new())),
+            private_key: Arc::new(std::sync::Mutex::new(Arc::new(private_key))),
+            public_key: Arc::new(std::sync::Mutex::new(Arc::new(public_key))),
+            history: Arc::new(Mutex::new(history::load_history())),
+            aliases: Arc::new(std::sync::Mutex::new(Aliases::load())),
+            persist_send_history: args.persist_send_history,
+            max_message_len: args.max_message_len,
+            output: output::Output::new(args.no_color),
+            wire_format: greeting.wire_format,
+            receipts: Arc::new(Mutex::new(args.receipts)),
+            quiet: Arc::new(Mutex::new(args.quiet)),
+            simple_ui: args.simple_ui || !std::io::IsTerminal::is_terminal(&std::io::stdout()),
+            download_dir: args.download_dir,
+            events,
+            server_address: None,
+            proxy: None,
+            connected: Arc::new(watch::channel(true).0),
+            advertised_name: Arc::new(Mutex::new(None)),
+            name_taken: Arc::new(Mutex::new(false)),
+            resume_token: Arc::new(Mutex::new(Some(greeting.resume_token))),
+            tcp_keepalive_secs: args.tcp_keepalive_secs,
+            presence: Arc::new(Mutex::new(Presence::Online)),
+            idle_away: Arc::new(Mutex::new(false)),
+            profile: Arc::new(Mutex::new(Profile::default())),
+            last_action: Arc::new(std::sync::Mutex::new(Instant::now())),
+            idle_away_after: (args.idle_away_mins > 0)
+                .then(|| Duration::from_secs(args.idle_away_mins * 60)),
+        };
+        if let Some(name) = args.name {
+            client.advertise(name).await?;
         }
+        Ok(client)
     }
 
-    async fn display_help() {
-        println!();
-        println!("Available actions:");
-        println!("exit: Exit the program");
-        println!("help: Display this help message");
-        println!("uuid: Display your uuid");
-        println!("list: List available peers");
-        println!("open (uuid?): Open a connection to a peer");
-        println!("close: Close a connection to a peer");
-        println!("accept: View pending connection requests");
-        println!("send <message>: Send a message to current channel");
+    /// Receives events from `handle`, each sent once the client's state
+    /// reflects it
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
     }
 
-    async fn display_uuid(&self) {
-        let uuid = self.uuid.lock().await;
-        println!();
-        println!("Your uuid is: {}", uuid.unwrap());
+    fn emit(&self, event: ClientEvent) {
+        // No subscribers is fine, there's just nobody to tell
+        let _ = self.events.send(event);
     }
 
-    async fn list_peers(&self) {
-        let peer_list = self.peer_list.lock().await;
-        println!();
-        println!("Available peers:");
-        for (name, uuid) in peer_list.iter() {
-            println!("{}: {}", uuid, name);
-        }
+    /// Our uuid, once the server has assigned it
+    pub async fn uuid(&self) -> Option<Uuid> {
+        *self.uuid.lock().await
     }
 
-    async fn open_connection(&self, uuid: Option<Uuid>) {
-        let open_connections = self.open_connections.lock().await;
-        let mut current_channel = self.current_channel.lock().await;
+    /// The peers the server has listed to us
+    pub async fn peers(&self) -> Vec<ClientDescription> {
+        self.peer_list.lock().await.clone()
+    }
 
-        if let Some(uuid) = uuid {
-            if open_connections.contains_key(&uuid) {
-                if *current_channel == Some(uuid) {
-                    println!("\n\r\n You are already connected to this channel.\n\r");
-                } else {
-                    println!("\n\r\n You are now connected to this channel.\n\r");
-                    *current_channel = Some(uuid);
-                }
-                return;
-            }
+    /// The peer `send` goes to, chosen with `open`
+    pub async fn current_channel(&self) -> Option<Uuid> {
+        *self.current_channel.lock().await
+    }
 
-            let message = ServerBoundMessage::ConnectionRequest(("".to_string(), uuid), (*self.public_key).clone());
-            self.send_message(message).await;
-            return;
+    /// Whether a connection request we sent `uuid` is still waiting for an
+    /// answer, as the prompt shows
+    pub async fn is_awaiting(&self, uuid: Uuid) -> bool {
+        self.pending_handshakes.lock().await.contains_key(&uuid)
+    }
+
+    /// Messages from `uuid` that arrived while another channel was open,
+    /// since we last opened its channel or history
+    pub async fn unread(&self, uuid: Uuid) -> usize {
+        self.unread.lock().await.get(&uuid).copied().unwrap_or(0)
+    }
+
+    /// Sets the name other clients see us as
+    pub async fn advertise(&self, name: String) -> Result<()> {
+        *self.advertised_name.lock().await = Some(name.clone());
+        self.send_message(ServerBoundMessage::Advertise(name)).await
+    }
+
+    /// Whether the connection to the server is up
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    /// Sends `message` to the server, or straight to its recipient if we
+    /// have a direct link to them
+    pub async fn send_message(&self, message: ServerBoundMessage) -> Result<()> {
+        if let Some(peer) = direct::recipient(&message) {
+            if self.send_direct(peer, &message).await {
+                return Ok(());
+            }
+        }
+        if !self.is_connected() {
+            return Err(Error::Protocol("not connected to the server".to_string()));
+        }
+        let frame = framing::encode_frame(self.wire_format, &message)?;
+        // Remembered before it's written, since the server may acknowledge
+        // it before the write returns
+        let sealed = match &message {
+            ServerBoundMessage::Message(to, payload) => Some((to.uuid, payload.counter)),
+            _ => None,
+        };
+        if let Some(sealed) = sealed {
+            let mut in_flight = self.in_flight.lock().await;
+            if in_flight.len() == MAX_IN_FLIGHT {
+                in_flight.pop_front();
+            }
+            in_flight.push_back(sealed);
         }
+        let mut writeable_half = self.writeable_half.lock().await;
+        let result = framing::write_encoded(&mut *writeable_half, &frame).await;
+        if let Err(Error::PartialWrite { .. }) = result {
+            // The server now has half a frame. Close our side so it drops
+            // the connection, and `handle` then reconnects.
+            let _ = writeable_half.shutdown().await;
+        }
+        drop(writeable_half);
+        if let (Ok(()), Some(peer)) = (&result, direct::recipient(&message)) {
+            self.count_sent(peer, frame.len());
+        }
+        // Unless some of it went out, the server can't have relayed it
+        if let (Err(e), Some(sealed)) = (&result, sealed) {
+            if !matches!(e, Error::PartialWrite { .. }) {
+                self.in_flight.lock().await.retain(|message| *message != sealed);
+            }
+        }
+        result
+    }
 
-        let peer_list = self.peer_list.lock().await;
-        let options = peer_list
-            .iter()
-            .map(|(name, uuid)| {
-                if open_connections.contains_key(uuid) {
-                    format!("{}: {} (Connected)", uuid, name)
-                } else {
-                    format!("{}: {}", uuid, name)
+    fn count_sent(&self, peer: Uuid, len: usize) {
+        self.traffic.lock().unwrap().entry(peer).or_default().sent(len);
+    }
+
+    fn count_received(&self, peer: Uuid, len: usize) {
+        self.traffic.lock().unwrap().entry(peer).or_default().received(len);
+    }
+
+    /// Runs `handle`, reconnecting whenever the connection drops. Returns
+    /// once the server shuts down, since there's nothing to reconnect to.
+    /// Keeps trying while the server is unreachable or the handshake goes
+    /// wrong, and fails only once the server rejects the client.
+    pub async fn run_connection(&self) -> Result<()> {
+        loop {
+            let result = self.handle().await;
+            self.connected.send_replace(false);
+            match result {
+                Ok(Closed::Shutdown) => return Ok(()),
+                // `handle_server` already said the server closed it
+                Ok(Closed::Dropped) => {}
+                Err(e) => self.emit(ClientEvent::Warning(format!(
+                    "Lost connection to the server: {}",
+                    e
+                ))),
+            }
+            let Some(server_address) = &self.server_address else {
+                return Err(Error::Protocol(
+                    "the connection closed and can't be reopened".to_string(),
+                ));
+            };
+            self.emit(ClientEvent::Reconnecting);
+
+            let mut delay = RECONNECT_DELAY;
+            loop {
+                tokio::time::sleep(delay).await;
+                match self.reconnect(server_address).await {
+                    Ok(resumed) => {
+                        self.emit(ClientEvent::Reconnected { resumed });
+                        break;
+                    }
+                    Err(e @ Error::Rejected(_)) => return Err(e),
+                    // A server restarting may accept the connection and
+                    // drop it mid-handshake, so only a refusal is final
+                    Err(_) => delay = (delay * 2).min(MAX_RECONNECT_DELAY),
                 }
-            })
-            .collect::<Vec<_>>();
+            }
+        }
+    }
 
-        let selection = Select::new("Select a peer", options).prompt();
-        if selection.is_err() {
-            return;
+    /// Opens a new connection, asking the server to resume our old uuid.
+    /// Returns whether it did: if so, peers never saw us leave and open
+    /// sessions carry on. Otherwise we start over as a fresh client.
+    async fn reconnect(&self, server_address: &ServerAddress) -> Result<bool> {
+        let stream = connect(server_address, self.proxy.as_ref()).await?;
+        let resume_token = *self.resume_token.lock().await;
+        let greeting = greet(stream, self.tcp_keepalive_secs, resume_token).await?;
+        if greeting.wire_format != self.wire_format {
+            return Err(Error::Rejected(format!(
+                "server switched from {:?} to {:?} frames",
+                self.wire_format, greeting.wire_format
+            )));
         }
-        let selection = selection.unwrap();
 
-        let selected_peer = peer_list
-            .iter()
-            .find(|(name, uuid)| {
-                format!("{}: {}", uuid, name) == selection
-                    || format!("{}: {} (Connected)", uuid, name) == selection
-            })
-            .unwrap();
+        *self.readonly_half.lock().await = greeting.readable_half;
+        *self.writeable_half.lock().await = greeting.writeable_half;
+        *self.local_ip.lock().await = greeting.local_ip;
+        let resumed = self.uuid.lock().await.replace(greeting.uuid) == Some(greeting.uuid);
+        *self.resume_token.lock().await = Some(greeting.resume_token);
+        // The server sends a fresh list, and requests in flight were lost
+        self.peer_list.lock().await.clear();
+        self.incoming_peers.lock().await.clear();
+        self.connection_requests.lock().await.clear();
+        self.pending_handshakes.lock().await.clear();
+        if !resumed {
+            // Their sessions are gone, so there's nothing to resend them in
+            self.in_flight.lock().await.clear();
+            self.queried_acks.lock().await.clear();
+            self.open_connections.lock().await.clear();
+            *self.current_channel.lock().await = None;
+            self.unread.lock().await.clear();
+            self.direct_links.lock().await.clear();
+            for (_, offer) in self.direct_offers.lock().await.drain() {
+                offer.abort();
+            }
+            // The server starts every connection online
+            *self.presence.lock().await = Presence::Online;
+            *self.idle_away.lock().await = false;
+        }
+        self.connected.send_replace(true);
 
-        if open_connections.contains_key(&selected_peer.1) {
-            if *current_channel == Some(selected_peer.1) {
-                println!("\n\r\n You are already connected to this channel.\n\r");
-            } else {
-                println!("\n\r\n You are now connected to this channel.\n\r");
-                *current_channel = Some(selected_peer.1);
+        if resumed {
+            // Acknowledgements lost with the old connection are asked for
+            // again, so messages that did get through aren't reported lost
+            let queried: Vec<_> = self.in_flight.lock().await.drain(..).collect();
+            if !queried.is_empty() {
+                *self.queried_acks.lock().await = queried.clone();
+                self.send_message(ServerBoundMessage::QueryAcks(queried)).await?;
+            }
+        } else {
+            if let Some(name) = self.advertised_name.lock().await.clone() {
+                self.send_message(ServerBoundMessage::Advertise(name)).await?;
+            }
+            let profile = self.profile.lock().await.clone();
+            if profile != Profile::default() {
+                self.send_message(ServerBoundMessage::SetProfile(profile)).await?;
             }
-            return;
         }
+        Ok(resumed)
+    }
 
-        let message = ServerBoundMessage::ConnectionRequest(selected_peer.clone(), (*self.public_key).clone());
-        self.send_message(message).await;
+    /// Tells the server we're leaving on purpose, so it drops us right away
+    /// instead of holding our uuid open for a resume
+    pub async fn leave(&self) -> Result<()> {
+        self.send_message(ServerBoundMessage::Leave).await
     }
 
-    async fn accept_connection(&self) {
-        let connection_requests = self.connection_requests.lock().await;
-        let options = connection_requests
-            .iter()
-            .map(|((name, uuid), _)| format!("{}: {}", uuid, name))
-            .collect::<Vec<_>>();
+    /// Leaves and closes our half of the connection, so the server sees a
+    /// clean end of stream rather than a reset when the process exits.
+    /// Best effort: if `Leave` doesn't arrive the server just holds our uuid
+    /// a little longer.
+    pub async fn shut_down(&self) {
+        let _ = self.leave().await;
+        let _ = self.writeable_half.lock().await.shutdown().await;
+    }
 
-        let selection = Select::new("Select a peer", options).prompt();
-        if selection.is_err() {
-            return;
+    /// Runs `run`, such as the UI or a script, alongside the connection
+    /// until it returns or `stop` resolves, then shuts down the same way
+    /// either way
+    pub async fn run_until(
+        self: &Arc<Self>,
+        run: impl Future<Output = Result<()>>,
+        stop: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let client = self.clone();
+        let connection = tokio::spawn(async move {
+            if let Err(e) = client.run_connection().await {
+                eprintln!("\n\r\n Lost connection to the server: {}\n\r", e);
+            }
+        });
+        let result = tokio::select! {
+            result = run => result,
+            _ = stop => Ok(()),
+        };
+        self.shut_down().await;
+        connection.abort();
+        result
+    }
+
+    /// Processes messages from the server, and from peers over direct links,
+    /// until the connection to the server is closed. Fails if it broke
+    /// partway through a frame, or the stream stopped making sense.
+    pub async fn handle(&self) -> Result<Closed> {
+        *self.server_latency.lock().await = Latency::default();
+        tokio::select! {
+            result = self.handle_server() => result,
+            result = self.handle_direct() => result.map(|()| Closed::Dropped),
+            result = self.heartbeat() => result.map(|()| Closed::Dropped),
         }
-        let selection = selection.unwrap();
+    }
 
-        let selected_peer = connection_requests
-            .iter()
-            .find(|((name, uuid), _)| format!("{}: {}", uuid, name) == selection);
+    /// Pings the server and every direct peer each `HEARTBEAT_INTERVAL`,
+    /// starting straight away. Returns only if pinging the server fails.
+    async fn heartbeat(&self) -> Result<()> {
+        let mut ticks = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let id = self.server_latency.lock().await.ping(Instant::now());
+            self.send_message(ServerBoundMessage::Ping(id)).await?;
 
-        if selected_peer.is_none() {
-            println!("\n\r\n Invalid selection.\n\r");
-            return;
+            for link in self.direct_links.lock().await.values_mut() {
+                let id = link.latency.ping(Instant::now());
+                // A broken link is noticed and dropped by its reader
+                let _ = link.send(self.wire_format, &ClientBoundMessage::Ping(id)).await;
+            }
         }
+    }
 
-        self.open_connections.lock().await.insert(selected_peer.unwrap().0 .1, selected_peer.unwrap().1.clone());
+    async fn handle_server(&self) -> Result<Closed> {
+        loop {
+            let frame = match framing::read_frame(&mut *self.readonly_half.lock().await).await {
+                Ok(Some(frame)) => Ok(frame),
+                Ok(None) => {
+                    self.emit(ClientEvent::Disconnected);
+                    return Ok(Closed::Dropped);
+                }
+                // Read whole, so the next frame can still be found
+                Err(e @ Error::Corrupt(_)) => Err(e),
+                Err(e) => return Err(e),
+            };
+            let decoded = frame.and_then(|frame| {
+                let message = self.wire_format.decode::<ClientBoundMessage>(&frame)?;
+                Ok((message, framing::frame_len(frame.len())))
+            });
 
-        let message = ServerBoundMessage::ConnectionResponse(selected_peer.unwrap().0.clone(), (*self.public_key).clone());
-        self.send_message(message).await;
+            match decoded {
+                Ok((mut message, len)) => {
+                    if let Some(peer) = direct::sender(&message) {
+                        self.count_received(peer, len);
+                    }
+                    sanitize_names(&mut message);
+                    if self.dispatch(message).await? == Action::Exit {
+                        // Hanging up lets the server close without waiting
+                        // out its drain
+                        let _ = self.writeable_half.lock().await.shutdown().await;
+                        return Ok(Closed::Shutdown);
+                    }
+                }
+                Err(e) if e.is_unknown_variant() => {
+                    self.emit(ClientEvent::Warning(format!(
+                        "Skipping a frame from the server with a message this client doesn't know. \
+                         The server may be running newer code under the same protocol version ({}): {}",
+                        PROTOCOL_VERSION, e
+                    )));
+                }
+                Err(e) => {
+                    self.emit(ClientEvent::Warning(format!(
+                        "Skipping a malformed frame from the server: {}",
+                        e
+                    )));
+                }
+            };
+        }
     }
 
-    async fn ui_send_message(&self, message: String) {
-        let current_channel = self.current_channel.lock().await;
-        if let Some(current_channel) = *current_channel {
-            let mut rng = OsRng;
-            let mut session_key = [0u8; 32]; 
-            rng.fill_bytes(&mut session_key);
-
-            let open_connections = self.open_connections.lock().await;
-            let remote_public_key = open_connections.get(&current_channel).unwrap();
-            let encrypted_key = remote_public_key.encrypt(&mut rng, Pkcs1v15Encrypt, &session_key).unwrap();
+    /// Acts on what the tasks behind direct links report. Never returns
+    /// while the connection to the server is up, or `dispatch` succeeds.
+    async fn handle_direct(&self) -> Result<()> {
+        let mut inbox = self.direct_inbox.lock().await;
+        while let Some(event) = inbox.recv().await {
+            match event {
+                DirectEvent::Connected(peer, stream) => self.attach_direct(peer, stream).await,
+                DirectEvent::DialFailed(peer) => {
+                    // If we offered first, the peer couldn't reach us either.
+                    // Otherwise let it try reaching us instead.
+                    if let Some(offer) = self.direct_offers.lock().await.remove(&peer) {
+                        offer.abort();
+                    } else if self.offer_direct(peer).await.is_ok() {
+                        continue;
+                    }
+                    let name = self.peer_name(peer).await;
+                    self.emit(ClientEvent::DirectFailed { uuid: peer, name });
+                }
+                DirectEvent::OfferExpired(peer) => {
+                    self.direct_offers.lock().await.remove(&peer);
+                    let name = self.peer_name(peer).await;
+                    self.emit(ClientEvent::DirectFailed { uuid: peer, name });
+                }
+                DirectEvent::Frame(peer, message, len) => {
+                    let mut direct_links = self.direct_links.lock().await;
+                    // Left over from a link we already dropped
+                    let Some(link) = direct_links.get_mut(&peer) else {
+                        continue;
+                    };
+                    match *message {
+                        ClientBoundMessage::Ping(id) => {
+                            let pong = ClientBoundMessage::Pong(id);
+                            let _ = link.send(self.wire_format, &pong).await;
+                            continue;
+                        }
+                        ClientBoundMessage::Pong(id) => {
+                            link.latency.pong(id, Instant::now());
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    drop(direct_links);
+                    self.count_received(peer, len);
+                    let description = self
+                        .peer_list
+                        .lock()
+                        .await
+                        .iter()
+                        .find(|description| description.uuid == peer)
+                        .cloned()
+                        .unwrap_or(ClientDescription::to(peer));
+                    match direct::from_peer(*message, description) {
+                        Some(message) => {
+                            self.dispatch(message).await?;
+                        }
+                        None => self.emit(ClientEvent::Warning(format!(
+                            "Ignored an unexpected message over the direct link to {}.",
+                            self.peer_name(peer).await
+                        ))),
+                    }
+                }
+                DirectEvent::Closed(peer) => {
+                    if self.direct_links.lock().await.remove(&peer).is_some() {
+                        let name = self.peer_name(peer).await;
+                        self.emit(ClientEvent::DirectClosed { uuid: peer, name });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-            let nonce = Aes256Gcm::generate_nonce(&mut rng);
+    /// Starts using a stream to `peer` on which both sides proved themselves
+    async fn attach_direct(&self, peer: Uuid, stream: TcpStream) {
+        if !self.open_connections.lock().await.contains_key(&peer) {
+            return;
+        }
+        if let Some(offer) = self.direct_offers.lock().await.remove(&peer) {
+            offer.abort();
+        }
+        let _ = socket::configure(&stream, self.tcp_keepalive_secs);
+        let link = DirectLink::new(peer, stream, self.wire_format, self.direct_events.clone());
+        self.direct_links.lock().await.insert(peer, link);
+        let name = self.peer_name(peer).await;
+        self.emit(ClientEvent::DirectConnected { uuid: peer, name });
+    }
 
-            let key = Key::<Aes256Gcm>::from_slice(&session_key);
-            let cipher = Aes256Gcm::new_from_slice(&key).unwrap();
+    /// Sends `message` over the direct link to `peer`, if there is one.
+    /// Returns false if there isn't, or it failed and was dropped, in which
+    /// case the message should be relayed instead.
+    async fn send_direct(&self, peer: Uuid, message: &ServerBoundMessage) -> bool {
+        let mut direct_links = self.direct_links.lock().await;
+        let Some(link) = direct_links.get_mut(&peer) else {
+            return false;
+        };
+        let from = ClientDescription::to(self.uuid().await.unwrap_or_default());
+        let Some(delivered) = direct::as_delivered(message.clone(), from) else {
+            return false;
+        };
+        if let Ok(len) = link.send(self.wire_format, &delivered).await {
+            self.count_sent(peer, len);
+            return true;
+        }
+        direct_links.remove(&peer);
+        drop(direct_links);
+        let name = self.peer_name(peer).await;
+        self.emit(ClientEvent::DirectClosed { uuid: peer, name });
+        false
+    }
 
-            let ciphertext = cipher.encrypt(&nonce, message.as_bytes()).unwrap();
+    /// Asks `uuid`, which we must have an open session with, to talk over a
+    /// direct connection instead of through the server. The outcome arrives
+    /// as a `DirectConnected` or `DirectFailed` event.
+    pub async fn request_direct(&self, uuid: Uuid) -> Result<()> {
+        if !self.open_connections.lock().await.contains_key(&uuid) {
+            return Err(Error::Protocol(format!(
+                "you have no open connection to {}",
+                self.peer_name(uuid).await
+            )));
+        }
+        if self.direct_links.lock().await.contains_key(&uuid) {
+            return Err(Error::Protocol(format!(
+                "you're already connected directly to {}",
+                self.peer_name(uuid).await
+            )));
+        }
+        self.offer_direct(uuid).await
+    }
 
-            let message = ServerBoundMessage::Message(("".to_string(), current_channel), (encrypted_key, nonce.to_vec(), ciphertext));
-            self.send_message(message).await;
-        } else {
-            println!("\n\r\n You are not connected to a channel.\n\r");
+    /// Listens for `peer` to connect and sends it the address through the
+    /// server
+    async fn offer_direct(&self, peer: Uuid) -> Result<()> {
+        if self.no_direct {
+            return Err(Error::Protocol("direct connections are turned off".to_string()));
+        }
+        // The interface that reaches the server is the likeliest to reach the peer
+        let ip = *self.local_ip.lock().await;
+        let listener = TcpListener::bind((ip, 0)).await?;
+        let address = listener.local_addr()?;
+        let offer = direct::offer(
+            peer,
+            listener,
+            self.wire_format,
+            self.open_connections.clone(),
+            self.private_key(),
+            self.direct_events.clone(),
+        );
+        if let Some(previous) = self.direct_offers.lock().await.insert(peer, offer) {
+            previous.abort();
         }
+        self.send_message(ServerBoundMessage::RequestDirect(ClientDescription::to(peer), address))
+            .await
     }
-}
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-pub(crate) struct Args {
-    /// Address to bind to
-    #[arg(short, long, default_value = "127.0.0.1")]
-    pub address: String,
+    /// Stops talking to `peer` directly, for when the session with it ends
+    async fn drop_direct(&self, peer: Uuid) {
+        self.direct_links.lock().await.remove(&peer);
+        if let Some(offer) = self.direct_offers.lock().await.remove(&peer) {
+            offer.abort();
+        }
+    }
 
-    /// Port to bind to
-    #[arg(short, long, default_value_t = 8080)]
-    pub port: u16,
+    /// Applies one message from the server. Returns `Action::Exit` if the
+    /// server is going away.
+    async fn dispatch(&self, message: ClientBoundMessage) -> Result<Action> {
+        match message {
+            ClientBoundMessage::SetUuid(uuid, resume_token) => {
+                *self.uuid.lock().await = Some(uuid);
+                *self.resume_token.lock().await = Some(resume_token);
+            }
+            ClientBoundMessage::ClientListPart(client_descriptions) => {
+                self.incoming_peers.lock().await.extend(client_descriptions);
+            }
+            ClientBoundMessage::ClientList(client_descriptions) => {
+                let mut incoming_peers = std::mem::take(&mut *self.incoming_peers.lock().await);
+                incoming_peers.extend(client_descriptions);
+                let mut peer_list = Vec::with_capacity(incoming_peers.len());
+                for client_description in incoming_peers {
+                    upsert_peer(&mut peer_list, client_description);
+                }
+                *self.peer_list.lock().await = peer_list;
+            }
+            ClientBoundMessage::NewClient(client_description) => {
+                upsert_peer(&mut *self.peer_list.lock().await, client_description.clone());
+                self.emit(ClientEvent::PeerJoined(client_description));
+            }
+            ClientBoundMessage::ClientRenamed(uuid, name) => {
+                let mut peer_list = self.peer_list.lock().await;
+                if let Some(peer) = peer_list.iter_mut().find(|peer| peer.uuid == uuid) {
+                    peer.name = name.clone();
+                }
+                self.emit(ClientEvent::PeerRenamed(uuid, name));
+            }
+            ClientBoundMessage::ClientDisconnected(uuid) => {
+                let mut peer_list = self.peer_list.lock().await;
+                peer_list.retain(|peer| peer.uuid != uuid);
+                self.emit(ClientEvent::PeerLeft(uuid));
+            }
+            ClientBoundMessage::PresenceChanged(uuid, presence) => {
+                let mut peer_list = self.peer_list.lock().await;
+                if let Some(peer) = peer_list.iter_mut().find(|peer| peer.uuid == uuid) {
+                    peer.presence = presence;
+                }
+                self.emit(ClientEvent::PresenceChanged(uuid, presence));
+            }
+            ClientBoundMessage::ProfileChanged(uuid, profile) => {
+                let mut peer_list = self.peer_list.lock().await;
+                if let Some(peer) = peer_list.iter_mut().find(|peer| peer.uuid == uuid) {
+                    peer.profile = profile.clone();
+                }
+                self.emit(ClientEvent::ProfileChanged(uuid, profile));
+            }
+            ClientBoundMessage::ConnectionRequest(client_description, public_key, handshake) => {
+                if !crypto::verify_handshake(&public_key, &handshake) {
+                    self.emit(ClientEvent::Warning(
+                        "Rejected a connection request with an invalid handshake signature.".to_string(),
+                    ));
+                    return Ok(Action::Continue);
+                }
+                if let Err(e) = suite::lookup(handshake.suite) {
+                    self.emit(ClientEvent::Warning(format!(
+                        "Rejected a connection request from {}: {}",
+                        client_description.display_name(),
+                        e
+                    )));
+                    return Ok(Action::Continue);
+                }
+                // Told, so it doesn't wait for the request to time out
+                if let Err(e) = crypto::check_key_type(self.private_key().key_type(), &public_key) {
+                    self.emit(ClientEvent::Warning(format!(
+                        "Rejected a connection request from {}: {}",
+                        client_description.display_name(),
+                        e
+                    )));
+                    let rejection = ServerBoundMessage::RejectRequest(client_description);
+                    self.send_message(rejection).await?;
+                    return Ok(Action::Continue);
+                }
+                // We asked this peer just as it asked us. Both sides keep the
+                // request from the lower uuid, so they end up with the same
+                // one session: the higher side answers it as if accepted.
+                let peer = client_description.uuid;
+                if self.pending_handshakes.lock().await.contains_key(&peer) {
+                    if self.uuid().await.is_some_and(|ours| ours < peer) {
+                        return Ok(Action::Continue);
+                    }
+                    self.pending_handshakes.lock().await.remove(&peer);
+                    self.answer(client_description.clone(), public_key, handshake)
+                        .await?;
+                    self.emit(ClientEvent::ConnectionAccepted(client_description));
+                    return Ok(Action::Continue);
+                }
+                let now = Instant::now();
+                let mut request_times = self.request_times.lock().await;
+                request_times.retain(|_, taken| now - *taken < MIN_REREQUEST_INTERVAL);
+                let mut connection_requests = self.connection_requests.lock().await;
+                let replaces = connection_requests
+                    .keys()
+                    .any(|peer| peer.uuid == client_description.uuid);
+                let full = !replaces && connection_requests.len() >= MAX_PENDING_REQUESTS;
+                if full || request_times.contains_key(&client_description.uuid) {
+                    drop(connection_requests);
+                    drop(request_times);
+                    let rejection = ServerBoundMessage::RejectRequest(client_description);
+                    self.send_message(rejection).await?;
+                    return Ok(Action::Continue);
+                }
+                request_times.insert(client_description.uuid, now);
+                drop(request_times);
+                // A newer request from the same peer replaces the old one,
+                // whose handshake the peer no longer holds
+                connection_requests.retain(|peer, _| peer.uuid != client_description.uuid);
+                self.expire_request(client_description.clone(), handshake.ephemeral_key);
+                connection_requests.insert(client_description.clone(), (public_key, handshake));
+                let pending = connection_requests.len();
+                drop(connection_requests);
+                self.emit(ClientEvent::ConnectionRequested {
+                    from: client_description,
+                    pending,
+                });
+                if pending == MAX_PENDING_REQUESTS {
+                    self.emit(ClientEvent::Warning(format!(
+                        "{} connection requests are waiting. Any more will be turned away until some are accepted or expire.",
+                        MAX_PENDING_REQUESTS
+                    )));
+                }
+            }
+            ClientBoundMessage::ConnectionResponse(client_description, public_key, handshake) => {
+                let Some((secret, local_handshake)) =
+                    self.pending_handshakes.lock().await.remove(&client_description.uuid)
+                else {
+                    // Cancelled or timed out, but the peer has opened its end,
+                    // so have it close that again
+                    let message = ServerBoundMessage::CloseConnection(client_description);
+                    self.send_message(message).await?;
+                    return Ok(Action::Continue);
+                };
+                if !crypto::verify_handshake(&public_key, &handshake) {
+                    self.emit(ClientEvent::Warning(
+                        "Rejected a connection response with an invalid handshake signature.".to_string(),
+                    ));
+                    return Ok(Action::Continue);
+                }
+                if let Err(e) = crypto::check_key_type(self.private_key().key_type(), &public_key) {
+                    self.emit(ClientEvent::Warning(format!(
+                        "Rejected the connection response from {}: {}",
+                        client_description.display_name(),
+                        e
+                    )));
+                    let message = ServerBoundMessage::CloseConnection(client_description);
+                    self.send_message(message).await?;
+                    return Ok(Action::Continue);
+                }
+                let session = crypto::derive_session_key(&secret, &local_handshake, &handshake)
+                    .and_then(|key| Session::new(public_key, key, secret, &local_handshake, &handshake));
+                let session = match session {
+                    Ok(session) => session,
+                    Err(e) => {
+                        self.emit(ClientEvent::Warning(format!(
+                            "Key exchange with {} failed: {}",
+                            client_description.uuid, e
+                        )));
+                        return Ok(Action::Continue);
+                    }
+                };
+                let mut open_connections = self.open_connections.lock().await;
+                open_connections.insert(client_description.uuid, session);
+                drop(open_connections);
+                self.emit(ClientEvent::ConnectionAccepted(client_description));
+            }
+            ClientBoundMessage::ChannelClosed(uuid) => {
+                let removed = self.open_connections.lock().await.remove(&uuid).is_some();
+                let mut current_channel = self.current_channel.lock().await;
+                if *current_channel == Some(uuid) {
+                    *current_channel = None;
+                }
+                drop(current_channel);
+                self.unread.lock().await.remove(&uuid);
+                self.drop_direct(uuid).await;
+                if removed {
+                    let name = self.peer_name(uuid).await;
+                    self.emit(ClientEvent::ChannelClosed { uuid, name });
+                }
+                // A closed request as opposed to a session: the peer withdrew it
+                let mut connection_requests = self.connection_requests.lock().await;
+                let withdrawn = connection_requests.keys().find(|peer| peer.uuid == uuid).cloned();
+                if let Some(peer) = withdrawn {
+                    connection_requests.remove(&peer);
+                    drop(connection_requests);
+                    self.emit(ClientEvent::RequestWithdrawn(peer));
+                }
+            }
+            ClientBoundMessage::ServerShutdown => {
+                self.emit(ClientEvent::ServerShutdown);
+                return Ok(Action::Exit);
+            }
+            // Only meaningful as the first frame, which `new` consumes
+            ClientBoundMessage::ServerHello { .. } => {}
+            ClientBoundMessage::Pong(id) => {
+                self.server_latency.lock().await.pong(id, Instant::now());
+            }
+            // Only meaningful over a direct link, where `handle_direct` answers it
+            ClientBoundMessage::Ping(_) => {}
+            ClientBoundMessage::RequestRejected(client_description) => {
+                let uuid = client_description.uuid;
+                if self.pending_handshakes.lock().await.remove(&uuid).is_some() {
+                    let name = self.peer_name(uuid).await;
+                    self.emit(ClientEvent::RequestRejected { uuid, name });
+                }
+            }
+            ClientBoundMessage::Delivered(uuid, counter) => {
+                self.in_flight.lock().await.retain(|message| *message != (uuid, counter));
+            }
+            ClientBoundMessage::Acked(delivered) => {
+                let queried = std::mem::take(&mut *self.queried_acks.lock().await);
+                for (uuid, counter) in queried {
+                    if !delivered.contains(&(uuid, counter)) {
+                        let name = self.peer_name(uuid).await;
+                        self.emit(ClientEvent::MessageLost { uuid, name, counter });
+                    }
+                }
+            }
+            ClientBoundMessage::RecipientUnavailable(uuid, counter) => {
+                self.in_flight.lock().await.retain(|message| *message != (uuid, counter));
+                let name = self.peer_name(uuid).await;
+                self.emit(ClientEvent::RecipientUnavailable { uuid, name, counter });
+            }
+            ClientBoundMessage::NameTaken(name) => {
+                *self.name_taken.lock().await = true;
+                self.emit(ClientEvent::NameTaken(output::sanitize_name(&name)));
+            }
+            ClientBoundMessage::ServerInfo {
+                version,
+                uptime_secs,
+                connected_clients,
+            } => {
+                self.emit(ClientEvent::ServerInfo {
+                    version: output::sanitize_text(&version),
+                    uptime_secs,
+                    connected_clients,
+                });
+            }
+            ClientBoundMessage::ServerAnnouncement(text) => {
+                self.emit(ClientEvent::ServerAnnouncement(output::sanitize_text(&text)));
+            }
+            ClientBoundMessage::ProtocolError(error) => {
+                self.emit(ClientEvent::Warning(format!(
+                    "The server rejected something we sent: {}",
+                    error
+                )));
+            }
+            ClientBoundMessage::DirectRequest(client_description, address) => {
+                let peer = client_description.uuid;
+                if self.no_direct {
+                    return Ok(Action::Continue);
+                }
+                if !self.open_connections.lock().await.contains_key(&peer) {
+                    self.emit(ClientEvent::Warning(format!(
+                        "Ignored a direct connection offer from {}, you have no open connection to them.",
+                        client_description.display_name()
+                    )));
+                    return Ok(Action::Continue);
+                }
+                direct::dial(
+                    peer,
+                    address,
+                    self.wire_format,
+                    self.open_connections.clone(),
+                    self.private_key(),
+                    self.direct_events.clone(),
+                );
+            }
+            ClientBoundMessage::Message(client_description, payload) => {
+                let name = self
+                    .peer_list
+                    .lock()
+                    .await
+                    .iter()
+                    .find(|peer| peer.uuid == client_description.uuid)
+                    .map(|peer| peer.name.clone())
+                    .unwrap_or("Unknown".to_string());
+
+                let mut open_connections = self.open_connections.lock().await;
+                let Some(session) = open_connections.get_mut(&client_description.uuid) else {
+                    return Ok(Action::Continue);
+                };
+                let (message, verified) = match session.open(&payload) {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        self.emit(ClientEvent::Warning(format!(
+                            "Dropped a message from {}: {}",
+                            name, e
+                        )));
+                        return Ok(Action::Continue);
+                    }
+                };
+                let Ok(chunk) = framing::from_bincode::<MessageChunk>(&message) else {
+                    self.emit(ClientEvent::Warning(format!(
+                        "Received a malformed message from {}.",
+                        name
+                    )));
+                    return Ok(Action::Continue);
+                };
+                let message_id = chunk.message_id;
+                let expires_in = chunk.expires_in;
+                let Some(message) = session.reassembler.add(chunk) else {
+                    return Ok(Action::Continue);
+                };
+                session.last_received = Some(message_id);
+
+                drop(open_connections);
+                if *self.current_channel.lock().await != Some(client_description.uuid) {
+                    *self.unread.lock().await.entry(client_description.uuid).or_default() += 1;
+                }
+                let time = chrono::Local::now();
+                self.remember(Entry {
+                    peer_name: name.clone(),
+                    peer_uuid: client_description.uuid,
+                    outgoing: false,
+                    text: message.clone(),
+                    verified,
+                    time,
+                    message_id: Some(message_id),
+                    reactions: Vec::new(),
+                })
+                .await;
+                if let Some(expires_in) = expires_in {
+                    let expired = ClientEvent::MessageExpired {
+                        from: client_description.uuid,
+                        name: name.clone(),
+                    };
+                    self.forget_after(client_description.uuid, message_id, expires_in, Some(expired));
+                }
+                self.emit(ClientEvent::MessageReceived {
+                    from: client_description.clone(),
+                    name,
+                    text: message,
+                    verified,
+                    time,
+                });
+
+                if *self.receipts.lock().await {
+                    let receipt =
+                        ServerBoundMessage::ReadReceipt(client_description.uuid, message_id);
+                    self.send_message(receipt).await?;
+                }
+            }
+            ClientBoundMessage::FileOffer(client_description, payload) => {
+                if let Err(e) = self.receive_file_offer(client_description, payload).await {
+                    self.emit(ClientEvent::Warning(format!("Dropped a file offer: {}", e)));
+                }
+            }
+            ClientBoundMessage::FileResponse(client_description, payload) => {
+                if let Err(e) = self
+                    .receive_file_response(client_description, payload)
+                    .await
+                {
+                    self.emit(ClientEvent::Warning(format!("File transfer failed: {}", e)));
+                }
+            }
+            ClientBoundMessage::FileChunk(client_description, payload) => {
+                if let Err(e) = self.receive_file_chunk(client_description, payload).await {
+                    self.emit(ClientEvent::Warning(format!("Dropped part of a file: {}", e)));
+                }
+            }
+            ClientBoundMessage::FileAck(client_description, payload) => {
+                if let Err(e) = self.receive_file_ack(client_description, payload).await {
+                    self.emit(ClientEvent::Warning(format!("File transfer failed: {}", e)));
+                }
+            }
+            ClientBoundMessage::ReadReceipt(client_description, message_id) => {
+                let mut open_connections = self.open_connections.lock().await;
+                let Some(session) = open_connections.get_mut(&client_description.uuid) else {
+                    return Ok(Action::Continue);
+                };
+                if session.take_receipt(message_id) {
+                    self.emit(ClientEvent::MessageSeen(client_description));
+                }
+            }
+            ClientBoundMessage::Reaction(client_description, message_id, emoji) => {
+                let uuid = client_description.uuid;
+                if !self.open_connections.lock().await.contains_key(&uuid) {
+                    return Ok(Action::Continue);
+                }
+                // Direct links don't pass the server's check
+                if !is_valid_reaction(&emoji) {
+                    self.emit(ClientEvent::Warning(format!(
+                        "Dropped a malformed reaction from {}.",
+                        client_description.display_name()
+                    )));
+                    return Ok(Action::Continue);
+                }
+                let reaction = Reaction {
+                    outgoing: false,
+                    emoji: emoji.clone(),
+                };
+                let text = self.remember_reaction(uuid, message_id, reaction).await;
+                let name = self.peer_name(uuid).await;
+                self.emit(ClientEvent::ReactionReceived {
+                    from: client_description,
+                    name,
+                    emoji,
+                    text,
+                });
+            }
+        }
+        Ok(Action::Continue)
+    }
+}
+
+impl Client {
+    pub async fn run_ui(self: &Arc<Self>) -> Result<()> {
+        self.print_events();
+        let idle_watcher = self.idle_away_after.map(|idle_away_after| {
+            let client = self.clone();
+            tokio::spawn(async move { client.watch_idle(idle_away_after).await })
+        });
+        let result = match self.ask_for_name().await {
+            Ok(()) if self.simple_ui => self.prompt().await,
+            Ok(()) => self.run_tui().await,
+            Err(e) => Err(e),
+        };
+        if let Some(idle_watcher) = idle_watcher {
+            idle_watcher.abort();
+        }
+        result
+    }
+
+    /// Prints events from `handle` as they happen, until the client is gone
+    fn print_events(&self) {
+        let mut events = self.subscribe();
+        let output = self.output;
+        let quiet = self.quiet.clone();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) if event.is_notice() && *quiet.lock().await => {}
+                    Ok(event) => output.print_event(&event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Asks for a friendly name unless `--name` gave one that the server
+    /// took
+    async fn ask_for_name(&self) -> Result<()> {
+        let named = self.advertised_name.lock().await.clone();
+        if let Some(name) = named {
+            if !self.name_accepted(&name).await {
+                self.choose_name().await?;
+            }
+        } else if tui::suspended(|| Confirm::new("Set friendly name?")
+            .with_default(true)
+            .prompt())
+            .unwrap_or(false)
+        {
+            self.choose_name().await?;
+        } else {
+            say!(
+                "\n\n No friendly name set. Your uuid will not be displayed to other clients.\n"
+            );
+            say!("Anyone who wants to connect to you will need to know your uuid. Type 'uuid' to view it.\n");
+        }
+        Ok(())
+    }
+
+    /// Reads and runs actions at a plain prompt until `exit`
+    async fn prompt(&self) -> Result<()> {
+        loop {
+            // Taken while we were at the prompt, such as by a client that
+            // got it first while we were reconnecting
+            if std::mem::take(&mut *self.name_taken.lock().await) {
+                self.choose_name().await?;
+            }
+            say!();
+            let prompt = self.prompt_label().await;
+            let action = Text::new(&prompt)
+                .with_placeholder("Type 'exit' to exit or 'help' to view available actions")
+                .with_autocomplete(completion::ActionCompleter::new(
+                    self.history.clone(),
+                    self.peer_list.clone(),
+                ))
+                .prompt();
+            let action = match action {
+                Ok(action) => action,
+                // Escape throws away what was typed, Ctrl-C exits like `exit`
+                Err(InquireError::OperationCanceled) => continue,
+                Err(_) => return Ok(()),
+            };
+            self.note_activity().await;
+            self.record_history(&action).await;
+            match self.handle_action(&action).await {
+                Ok(Action::Exit) => return Ok(()),
+                Ok(Action::Continue) => {}
+                Err(e) => say!("\n\r\n Error: {}\n\r", e),
+            }
+        }
+    }
+
+    /// Asks for a friendly name and advertises it, asking again for as long
+    /// as the server says another client has it
+    async fn choose_name(&self) -> Result<()> {
+        loop {
+            let friendly_name = tui::suspended(|| Text::new("Friendly name")
+                .with_placeholder("Enter a name that other clients will see")
+                .with_default("Anonymous Turtle 🐢")
+                .prompt())
+                .unwrap_or("Anonymous Turtle 🐢".to_string());
+            self.advertise(friendly_name.clone()).await?;
+            if self.name_accepted(&friendly_name).await {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the server's answer to advertising `name`: listing us
+    /// under it, or `NameTaken`. Takes silence for a yes after
+    /// `NAME_CHECK_TIMEOUT`.
+    async fn name_accepted(&self, name: &str) -> bool {
+        // Subscribed before looking at the state, so an answer that lands in
+        // between still wakes us
+        let mut events = self.subscribe();
+        let give_up = tokio::time::Instant::now() + NAME_CHECK_TIMEOUT;
+        let listed_as = output::sanitize_name(name);
+        loop {
+            if std::mem::take(&mut *self.name_taken.lock().await) {
+                return false;
+            }
+            let uuid = self.uuid().await;
+            let listed = self
+                .peer_list
+                .lock()
+                .await
+                .iter()
+                .any(|peer| Some(peer.uuid) == uuid && peer.name == listed_as);
+            if listed {
+                return true;
+            }
+            match tokio::time::timeout_at(give_up, events.recv()).await {
+                Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => {}
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return true,
+            }
+        }
+    }
+
+    /// Runs one command typed at the action prompt, expanding an alias first
+    pub async fn handle_action(&self, typed: &str) -> Result<Action> {
+        let action = self.aliases.lock().unwrap().expand(typed)?;
+        let action = action.as_str();
+        if !self.is_connected() && needs_connection(action) {
+            say!("\n\r\n Not connected to the server.\n\r");
+            return Ok(Action::Continue);
+        }
+        match action {
+            "exit" => return Ok(Action::Exit),
+            "help" => Self::display_help().await?,
+            "uuid" => self.display_uuid().await?,
+            "list" => self.list_peers(None).await?,
+            "direct" => match *self.current_channel.lock().await {
+                Some(uuid) => self.ui_request_direct(uuid).await?,
+                None => say!("\n\r\n You are not connected to a channel.\n\r"),
+            },
+            "unread" => self.display_unread().await?,
+            "react" => say!("Usage: react <emoji>"),
+            "ping" => self.display_latency().await?,
+            "stats" => self.display_traffic().await,
+            "server" => self.send_message(ServerBoundMessage::ServerInfo).await?,
+            "alias" => self.display_aliases(),
+            "open" => self.open_connection(None).await?,
+            "accept" => self.accept_connection().await?,
+            "close" => self.close_connection(None).await?,
+            "cancel" => say!("Usage: cancel <uuid>"),
+            "clearhistory" => self.clear_history().await?,
+            "history" => self.show_history(None).await?,
+            "history stats" => self.show_history_stats().await,
+            "acceptfile" => self.accept_file().await?,
+            "verify" => match *self.current_channel.lock().await {
+                Some(uuid) => self.verify(uuid).await?,
+                None => say!("\n\r\n You are not connected to a channel.\n\r"),
+            },
+            "" => {}
+            _ => {
+                if let Some(target) = action.strip_prefix("open ") {
+                    let uuid = self.resolve_peer(target.trim()).await?;
+                    self.open_connection(Some(uuid)).await?
+                } else if let Some(target) = action.strip_prefix("accept ") {
+                    let uuid = self.resolve_peer(target.trim()).await?;
+                    self.accept(uuid).await?;
+                    say!("\n\r\n Accepted the connection from {}.\n\r", self.peer_name(uuid).await);
+                } else if action.starts_with("close") {
+                    match action.split_whitespace().nth(1).map(Uuid::parse_str) {
+                        Some(Ok(uuid)) => self.close_connection(Some(uuid)).await?,
+                        _ => return Err(Error::Protocol(format!("Invalid uuid: {}", action))),
+                    }
+                } else if let Some(target) = action.strip_prefix("history ") {
+                    self.show_history(Some(target.trim())).await?
+                } else if let Some(target) = action.strip_prefix("verify ") {
+                    let uuid = self.resolve_peer(target.trim()).await?;
+                    self.verify(uuid).await?
+                } else if let Some(target) = action.strip_prefix("direct ") {
+                    let uuid = self.resolve_peer(target.trim()).await?;
+                    self.ui_request_direct(uuid).await?
+                } else if let Some(argument) = action.strip_prefix("list ") {
+                    self.list_peers(Some(argument.trim())).await?
+                } else if action.starts_with("cancel") {
+                    match action.split_whitespace().nth(1).map(Uuid::parse_str) {
+                        Some(Ok(uuid)) => self.cancel_request(uuid).await?,
+                        _ => return Err(Error::Protocol(format!("Invalid uuid: {}", action))),
+                    }
+                } else if action.starts_with("status") {
+                    match action.split_whitespace().nth(1).map(str::parse::<Presence>) {
+                        Some(Ok(presence)) => self.set_status(presence).await?,
+                        _ => say!("Usage: status <online|away|busy|invisible>"),
+                    }
+                } else if let Some(argument) = action.strip_prefix("profile") {
+                    self.profile_action(argument).await?
+                } else if let Some(emoji) = action.strip_prefix("react ") {
+                    self.react(emoji.trim()).await?
+                } else if action.starts_with("receipts") {
+                    match action.split_whitespace().nth(1) {
+                        Some("on") => self.set_receipts(true).await?,
+                        Some("off") => self.set_receipts(false).await?,
+                        _ => say!("Usage: receipts <on|off>"),
+                    }
+                } else if action.starts_with("quiet") {
+                    match action.split_whitespace().nth(1) {
+                        Some("on") => self.set_quiet(true).await,
+                        Some("off") => self.set_quiet(false).await,
+                        _ => say!("Usage: quiet <on|off>"),
+                    }
+                } else if let Some(verb @ ("export" | "import")) = action.split_whitespace().next() {
+                    match action[verb.len()..].trim() {
+                        "" => say!("Usage: {} <path>", verb),
+                        path if verb == "export" => self.export_identity(PathBuf::from(path)).await?,
+                        path => self.import_identity(PathBuf::from(path)).await?,
+                    }
+                } else if action.starts_with("sendfile") {
+                    match action.split_once(' ') {
+                        Some((_, path)) if !path.trim().is_empty() => {
+                            self.send_file(PathBuf::from(path.trim())).await?
+                        }
+                        _ => say!("Usage: sendfile <path>"),
+                    }
+                } else if history::is_disappearing_send(action) {
+                    let (verb, message) = action.split_once(' ').unwrap_or((action, ""));
+                    match verb["send!".len()..].parse::<u64>() {
+                        Ok(secs) if secs > 0 => {
+                            let expires_in = Duration::from_secs(secs);
+                            self.ui_send_message(message.to_string(), Some(expires_in))
+                                .await?;
+                            self.forget_command_after(typed.to_string(), expires_in);
+                        }
+                        _ => say!("Usage: send!<secs> <message>"),
+                    }
+                } else if let Some(definition) = action.strip_prefix("alias ") {
+                    self.aliases.lock().unwrap().define(definition)?;
+                    say!("\n\r\n Defined for this session. Add it to ~/.ycnbts_aliases to keep it.\n\r");
+                } else if let Some(message) = action.strip_prefix("broadcast ") {
+                    match message.trim() {
+                        "" => say!("Usage: broadcast <message>"),
+                        message => self.broadcast(message).await?,
+                    }
+                } else if let Some(rest) = action.strip_prefix("msg ") {
+                    match rest.trim_start().split_once(' ') {
+                        Some((target, message)) if !message.trim().is_empty() => {
+                            self.msg(target, message).await?
+                        }
+                        _ => say!("Usage: msg <uuid|name> <message>"),
+                    }
+                } else if action.starts_with("send") {
+                    let message = action
+                        .split_once(' ')
+                        .map(|x| x.1)
+                        .unwrap_or("")
+                        .to_string();
+                    self.ui_send_message(message, None).await?
+                } else {
+                    say!("Unknown action: {}", action);
+                }
+            }
+        }
+        Ok(Action::Continue)
+    }
+
+    async fn set_status(&self, presence: Presence) -> Result<()> {
+        self.send_message(ServerBoundMessage::SetStatus(presence))
+            .await?;
+        *self.presence.lock().await = presence;
+        *self.idle_away.lock().await = false;
+        say!("Your status is now {}.", presence);
+        Ok(())
+    }
+
+    /// Shows our profile, or sets or clears one of its parts, as in
+    /// `profile emoji 🐢` or `profile status` (which clears it)
+    async fn profile_action(&self, argument: &str) -> Result<()> {
+        let argument = argument.trim();
+        let (field, value) = argument.split_once(' ').unwrap_or((argument, ""));
+        let value = value.trim();
+        let value = (!value.is_empty()).then(|| value.to_string());
+        let mut profile = self.profile.lock().await.clone();
+        match field {
+            "" => {
+                let name = self.advertised_name.lock().await.clone().unwrap_or_default();
+                say!("Your profile: {}", output::describe_profile(&name, &profile));
+                return Ok(());
+            }
+            "emoji" => profile.emoji = value,
+            "status" => profile.status_text = value,
+            _ => {
+                say!("Usage: profile (emoji|status) (value?)");
+                return Ok(());
+            }
+        }
+        if let Some(problem) = profile.problem() {
+            return Err(Error::Protocol(problem));
+        }
+        self.send_message(ServerBoundMessage::SetProfile(profile.clone()))
+            .await?;
+        let name = self.advertised_name.lock().await.clone().unwrap_or_default();
+        say!("Peers now see you as {}.", output::describe_profile(&name, &profile));
+        *self.profile.lock().await = profile;
+        Ok(())
+    }
+
+    /// Records that the user did something, bringing them back online if
+    /// they were automatically set Away
+    async fn note_activity(&self) {
+        *self.last_action.lock().unwrap() = Instant::now();
+        let mut idle_away = self.idle_away.lock().await;
+        if !*idle_away || !self.is_connected() {
+            return;
+        }
+        match self
+            .send_message(ServerBoundMessage::SetStatus(Presence::Online))
+            .await
+        {
+            Ok(()) => {
+                *idle_away = false;
+                *self.presence.lock().await = Presence::Online;
+                say!("\n\r\n Welcome back, your status is online again.\n\r");
+            }
+            Err(e) => say_error!("Failed to set your status back to online: {}", e),
+        }
+    }
+
+    /// Sets us Away once no command has been entered for `idle_away_after`.
+    /// A status chosen with `status` other than online is left alone.
+    async fn watch_idle(&self, idle_away_after: Duration) {
+        let mut ticks = tokio::time::interval(IDLE_CHECK_INTERVAL.min(idle_away_after));
+        loop {
+            ticks.tick().await;
+            let idle_for = self.last_action.lock().unwrap().elapsed();
+            if idle_for < idle_away_after || !self.is_connected() {
+                continue;
+            }
+            let mut presence = self.presence.lock().await;
+            if *presence != Presence::Online {
+                continue;
+            }
+            if self
+                .send_message(ServerBoundMessage::SetStatus(Presence::Away))
+                .await
+                .is_err()
+            {
+                continue;
+            }
+            *presence = Presence::Away;
+            *self.idle_away.lock().await = true;
+            if *self.quiet.lock().await {
+                continue;
+            }
+            let minutes = idle_away_after.as_secs() / 60;
+            say!(
+                "\n\r\n You've been idle for {} minute{}, your status is now away.\n\r",
+                minutes,
+                if minutes == 1 { "" } else { "s" }
+            );
+        }
+    }
+
+    async fn set_receipts(&self, enabled: bool) -> Result<()> {
+        *self.receipts.lock().await = enabled;
+        if enabled {
+            say!("Read receipts are on. Peers will see when you've read their messages.");
+        } else {
+            say!("Read receipts are off.");
+        }
+        Ok(())
+    }
+
+    async fn set_quiet(&self, enabled: bool) {
+        *self.quiet.lock().await = enabled;
+        if enabled {
+            say!("Quiet mode is on. Only messages, files and errors will be shown.");
+        } else {
+            say!("Quiet mode is off.");
+        }
+    }
+
+    /// Builds the action prompt, e.g. `Action [alice | 2 open, 1 pending]`
+    async fn prompt_label(&self) -> String {
+        let current_channel = *self.current_channel.lock().await;
+        let channel = match current_channel {
+            Some(uuid) => self
+                .peer_list
+                .lock()
+                .await
+                .iter()
+                .find(|peer| peer.uuid == uuid)
+                .map(|peer| peer.name.clone())
+                .unwrap_or(uuid.to_string()),
+            None => "no channel".to_string(),
+        };
+        let channel = match current_channel {
+            Some(uuid) if self.direct_links.lock().await.contains_key(&uuid) => {
+                format!("{} (direct)", channel)
+            }
+            _ => channel,
+        };
+        let open = self.open_connections.lock().await.len();
+        let pending = self.connection_requests.lock().await.len();
+        let mut status = if !self.is_connected() {
+            " | reconnecting".to_string()
+        } else {
+            match self.server_latency.lock().await.average() {
+                Some(rtt) => format!(" | {}", latency::describe(Some(rtt))),
+                None => String::new(),
+            }
+        };
+        // Requests we sent, cleared when they're answered or time out
+        let waiting_for = self.pending_handshakes.lock().await.keys().copied().collect::<Vec<_>>();
+        match waiting_for.as_slice() {
+            [] => {}
+            [uuid] => status += &format!(" | waiting for {}…", self.peer_name(*uuid).await),
+            _ => status += &format!(" | waiting for {} peers…", waiting_for.len()),
+        }
+        format!(
+            "Action [{} | {} open, {} pending{}]",
+            channel, open, pending, status
+        )
+    }
+
+    async fn display_help() -> Result<()> {
+        say!();
+        say!("Available actions:");
+        say!("exit: Exit the program");
+        say!("help: Display this help message");
+        say!("uuid: Display your uuid");
+        say!("list (page?): List available peers, {} per page", PEERS_PER_PAGE);
+        say!("list <text>: List peers whose name or uuid contains <text>");
+        say!("unread: Show how many messages are waiting from each peer");
+        say!("ping: Show the round-trip time to the server and to direct peers");
+        say!("server: Show the server's version, uptime and how many clients are connected");
+        say!("stats: Show how many bytes and frames have gone to and from each peer");
+        say!("open (uuid|name?): Open a connection to a peer");
+        say!("close (uuid?): Close a connection to a peer (defaults to current channel)");
+        say!("cancel <uuid>: Withdraw a connection request you sent");
+        say!("accept (uuid|name?): Accept a pending connection request, or pick one to accept");
+        say!("send <message>: Send a message to current channel");
+        say!("send!<secs> <message>: Send a message that disappears after <secs> seconds");
+        say!("broadcast <message>: Send a message to every peer you have an open connection to");
+        say!("msg <uuid|name> <message>: Send to an open connection without switching to it");
+        say!("react <emoji>: React to the last message the current channel sent you");
+        say!("direct (uuid|name?): Connect straight to a peer over your network, bypassing the server (reveals your address to them)");
+        say!("sendfile <path>: Offer a file to the current channel");
+        say!("acceptfile: View file offers and accept or decline them");
+        say!("verify (uuid|name?): Compare key fingerprints with a peer and mark its key verified");
+        say!("export <path>: Save your identity key to a passphrase-protected file, to use on another machine");
+        say!("import <path>: Switch to the identity key saved by export (needs no open connections)");
+        say!("history (uuid|name?): Show past messages with a peer (defaults to current channel)");
+        say!("history stats: Show how many messages the history keeps, and for how long");
+        say!("clearhistory: Forget previously entered commands");
+        say!("alias (name expansion?): List aliases, or define one for this session ($1-$9 and $* stand for its arguments)");
+        say!("status <online|away|busy|invisible>: Set the status peers see");
+        say!("profile (emoji|status (value?)?): Show your profile, or set or clear the emoji or status text shown beside your name");
+        say!("receipts <on|off>: Choose whether peers see when you've read their messages");
+        say!("quiet <on|off>: Choose whether notices about connections are shown");
+        Ok(())
+    }
+
+    async fn record_history(&self, action: &str) {
+        if action.is_empty() {
+            return;
+        }
+        let mut history = self.history.lock().await;
+        if history.last().map(|last| last.as_str()) != Some(action) {
+            history.push(action.to_string());
+        }
+        if history.len() > history::MAX_HISTORY {
+            let overflow = history.len() - history::MAX_HISTORY;
+            history.drain(..overflow);
+        }
+        history::save_history(&history, self.persist_send_history, &self.aliases.lock().unwrap());
+    }
+
+    /// Drops a disappearing message's command from the in-memory history once
+    /// it expires. It was never written to the history file.
+    fn forget_command_after(&self, action: String, expires_in: Duration) {
+        let history = self.history.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(expires_in).await;
+            history.lock().await.retain(|entry| *entry != action);
+        });
+    }
+
+    async fn clear_history(&self) -> Result<()> {
+        let mut history = self.history.lock().await;
+        history.clear();
+        history::save_history(&history, self.persist_send_history, &self.aliases.lock().unwrap());
+        say!();
+        say!("Command history cleared.");
+        Ok(())
+    }
+
+    async fn display_uuid(&self) -> Result<()> {
+        let uuid = self.uuid.lock().await;
+        say!();
+        match *uuid {
+            Some(uuid) => say!("Your uuid is: {}", uuid),
+            None => say!("The server hasn't assigned a uuid yet."),
+        }
+        Ok(())
+    }
+
+    /// Prints a page of peers, or every peer matching a filter. `argument` is
+    /// a page number if it parses as one.
+    async fn list_peers(&self, argument: Option<&str>) -> Result<()> {
+        let peer_list = self.peer_list.lock().await;
+        let unread = self.unread.lock().await;
+        let argument = argument.unwrap_or("1");
+        say!();
+
+        let Ok(page) = argument.parse::<usize>() else {
+            let matching = sorted_peers(&peer_list, argument);
+            if matching.is_empty() {
+                say!("No peers match {:?}.", argument);
+                return Ok(());
+            }
+            say!("Peers matching {:?}:", argument);
+            for peer in matching {
+                say!("{}", describe_peer(peer, &unread));
+            }
+            return Ok(());
+        };
+
+        let peers = sorted_peers(&peer_list, "");
+        let pages = peers.len().div_ceil(PEERS_PER_PAGE).max(1);
+        if page == 0 || page > pages {
+            say!("There is no page {}. Pages run from 1 to {}.", page, pages);
+            return Ok(());
+        }
+        say!("Available peers:");
+        for peer in peers.iter().skip((page - 1) * PEERS_PER_PAGE).take(PEERS_PER_PAGE) {
+            say!("{}", describe_peer(peer, &unread));
+        }
+        say!("page {} of {}", page, pages);
+        Ok(())
+    }
+
+    fn display_aliases(&self) {
+        let aliases = self.aliases.lock().unwrap();
+        say!();
+        let mut any = false;
+        for (name, expansion) in aliases.iter() {
+            say!("{} = {}", name, expansion);
+            any = true;
+        }
+        if !any {
+            say!("No aliases defined. Try `alias w msg $1 $*`.");
+        }
+    }
+
+    async fn display_latency(&self) -> Result<()> {
+        say!();
+        if !self.is_connected() {
+            say!("Server: disconnected");
+        } else {
+            let rtt = self.server_latency.lock().await.average();
+            say!("Server: {}", latency::describe(rtt));
+        }
+        let rtts = self
+            .direct_links
+            .lock()
+            .await
+            .iter()
+            .map(|(uuid, link)| (*uuid, link.latency.average()))
+            .collect::<Vec<_>>();
+        for (uuid, rtt) in rtts {
+            say!("{} (direct): {}", self.peer_name(uuid).await, latency::describe(rtt));
+        }
+        Ok(())
+    }
+
+    /// Prints how much has gone to and from each peer, through the server
+    /// or directly
+    async fn display_traffic(&self) {
+        let traffic: Vec<(Uuid, Traffic)> = self
+            .traffic
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(uuid, traffic)| (*uuid, *traffic))
+            .collect();
+        say!();
+        if traffic.is_empty() {
+            say!("Nothing sent to or received from peers yet.");
+            return;
+        }
+        for (uuid, traffic) in traffic {
+            say!(
+                "{}: sent {} bytes in {} frames, received {} bytes in {} frames",
+                self.peer_name(uuid).await,
+                traffic.bytes_sent,
+                traffic.frames_sent,
+                traffic.bytes_received,
+                traffic.frames_received
+            );
+        }
+    }
+
+    async fn display_unread(&self) -> Result<()> {
+        let peer_list = self.peer_list.lock().await;
+        let unread = self.unread.lock().await;
+        say!();
+        if unread.is_empty() {
+            say!("No unread messages.");
+            return Ok(());
+        }
+        let mut counts = unread
+            .iter()
+            .map(|(uuid, count)| {
+                let name = peer_list
+                    .iter()
+                    .find(|peer| peer.uuid == *uuid)
+                    .map(|peer| peer.name.as_str())
+                    .unwrap_or("Unknown");
+                (name, *uuid, *count)
+            })
+            .collect::<Vec<_>>();
+        counts.sort_by_cached_key(|(name, uuid, _)| (name.to_lowercase(), *uuid));
+        say!("Unread messages:");
+        for (name, uuid, count) in counts {
+            say!("{}: {} ({} unread)", uuid, name, count);
+        }
+        Ok(())
+    }
+
+    async fn open_connection(&self, uuid: Option<Uuid>) -> Result<()> {
+        let open_connections = self.open_connections.lock().await;
+        let mut current_channel = self.current_channel.lock().await;
+
+        if let Some(uuid) = uuid {
+            if open_connections.contains_key(&uuid) {
+                if *current_channel == Some(uuid) {
+                    say!("\n\r\n You are already connected to this channel.\n\r");
+                } else {
+                    say!("\n\r\n You are now connected to this channel.\n\r");
+                    *current_channel = Some(uuid);
+                }
+                self.unread.lock().await.remove(&uuid);
+                return Ok(());
+            }
+
+            // Accepting a request the peer already sent opens the session
+            drop(open_connections);
+            drop(current_channel);
+            return self.ui_request_connection(uuid).await;
+        }
+
+        let peer_list = self.peer_list.lock().await;
+        let options = sorted_peers(&peer_list, "")
+            .into_iter()
+            .map(|peer| {
+                if open_connections.contains_key(&peer.uuid) {
+                    format!("{}: {} (Connected)", peer.uuid, peer.name)
+                } else {
+                    format!("{}: {}", peer.uuid, peer.name)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let Ok(selection) = tui::suspended(|| Select::new("Select a peer", options)
+            .with_page_size(PEERS_PER_PAGE)
+            .with_help_message("type to filter, ↑↓ to move, enter to select")
+            .prompt())
+        else {
+            return Ok(());
+        };
+
+        let Some(selected_peer) = peer_list.iter().find(|peer| {
+            format!("{}: {}", peer.uuid, peer.name) == selection
+                || format!("{}: {} (Connected)", peer.uuid, peer.name) == selection
+        }) else {
+            return Ok(());
+        };
+
+        if open_connections.contains_key(&selected_peer.uuid) {
+            if *current_channel == Some(selected_peer.uuid) {
+                say!("\n\r\n You are already connected to this channel.\n\r");
+            } else {
+                say!("\n\r\n You are now connected to this channel.\n\r");
+                *current_channel = Some(selected_peer.uuid);
+            }
+            self.unread.lock().await.remove(&selected_peer.uuid);
+            return Ok(());
+        }
+
+        let uuid = selected_peer.uuid;
+        // `request_connection` looks the peer's name up in the list, and
+        // may open the session
+        drop(peer_list);
+        drop(open_connections);
+        drop(current_channel);
+        self.ui_request_connection(uuid).await
+    }
+
+    async fn ui_request_direct(&self, uuid: Uuid) -> Result<()> {
+        self.request_direct(uuid).await?;
+        say!(
+            "\n\r\n Asked {} to connect directly.\n\r",
+            self.peer_name(uuid).await
+        );
+        Ok(())
+    }
+
+    async fn ui_request_connection(&self, uuid: Uuid) -> Result<()> {
+        // Otherwise the peer had asked first, and accepting it was announced
+        if !self.request_connection(uuid).await? {
+            return Ok(());
+        }
+        say!(
+            "\n\r\n Sent a connection request to {}. Waiting up to {} seconds for them to accept…\n\r",
+            self.peer_name(uuid).await,
+            REQUEST_TIMEOUT.as_secs()
+        );
+        Ok(())
+    }
+
+    /// Asks `uuid` to open a session. The peer has `REQUEST_TIMEOUT` to accept.
+    /// If it already asked us, its request is accepted instead and `false`
+    /// returned, so crossed requests never open two sessions.
+    pub async fn request_connection(&self, uuid: Uuid) -> Result<bool> {
+        let asked = self
+            .connection_requests
+            .lock()
+            .await
+            .keys()
+            .find(|peer| peer.uuid == uuid)
+            .cloned();
+        if let Some(description) = asked {
+            self.accept(uuid).await?;
+            self.emit(ClientEvent::ConnectionAccepted(description));
+            return Ok(false);
+        }
+
+        let (secret, handshake) = crypto::new_handshake(&self.private_key(), suite::DEFAULT_SUITE)?;
+        self.pending_handshakes
+            .lock()
+            .await
+            .insert(uuid, (secret, handshake.clone()));
+
+        let ephemeral_key = handshake.ephemeral_key;
+        let message = ServerBoundMessage::ConnectionRequest(
+            ClientDescription::to(uuid),
+            (*self.public_key()).clone(),
+            handshake,
+        );
+        self.send_message(message).await?;
+
+        let name = self.peer_name(uuid).await;
+        let pending_handshakes = self.pending_handshakes.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(REQUEST_TIMEOUT).await;
+            let mut pending_handshakes = pending_handshakes.lock().await;
+            if forget_request(&mut pending_handshakes, &uuid, ephemeral_key) {
+                let _ = events.send(ClientEvent::RequestTimedOut { uuid, name });
+            }
+        });
+        Ok(true)
+    }
+
+    /// Forgets a received request once it's older than `REQUEST_TIMEOUT`,
+    /// since the sender will have stopped waiting for it
+    fn expire_request(&self, peer: ClientDescription, ephemeral_key: [u8; 32]) {
+        let connection_requests = self.connection_requests.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(REQUEST_TIMEOUT).await;
+            let mut connection_requests = connection_requests.lock().await;
+            if forget_request(&mut connection_requests, &peer, ephemeral_key) {
+                let _ = events.send(ClientEvent::RequestExpired(peer));
+            }
+        });
+    }
+
+    /// Withdraws a request we sent. The peer is told, so it can't accept a
+    /// request nobody is waiting on.
+    async fn cancel_request(&self, uuid: Uuid) -> Result<()> {
+        if self.pending_handshakes.lock().await.remove(&uuid).is_none() {
+            say!("\n\r\n You have no pending connection request to {}.\n\r", uuid);
+            return Ok(());
+        }
+        let message = ServerBoundMessage::CloseConnection(ClientDescription::to(uuid));
+        self.send_message(message).await?;
+        say!(
+            "\n\r\n Cancelled the connection request to {}.\n\r",
+            self.peer_name(uuid).await
+        );
+        Ok(())
+    }
+
+    /// A peer's name if it's in the peer list, otherwise its uuid
+    async fn peer_name(&self, uuid: Uuid) -> String {
+        self.peer_list
+            .lock()
+            .await
+            .iter()
+            .find(|peer| peer.uuid == uuid)
+            .map(ClientDescription::display_name)
+            .unwrap_or_else(|| uuid.to_string())
+    }
+
+    async fn accept_connection(&self) -> Result<()> {
+        let connection_requests = self.connection_requests.lock().await;
+        let options = connection_requests
+            .keys()
+            .map(|peer| format!("{}: {}", peer.uuid, peer.name))
+            .collect::<Vec<_>>();
+
+        let Ok(selection) = tui::suspended(|| Select::new("Select a peer", options).prompt()) else {
+            return Ok(());
+        };
+
+        let selected_peer = connection_requests
+            .iter()
+            .find(|(peer, _)| format!("{}: {}", peer.uuid, peer.name) == selection);
+
+        let Some(uuid) = selected_peer.map(|(description, _)| description.uuid) else {
+            say!("\n\r\n Invalid selection.\n\r");
+            return Ok(());
+        };
+        drop(connection_requests);
+        self.accept(uuid).await
+    }
+
+    /// Accepts a pending connection request from `uuid`, opening a session
+    /// with that peer
+    pub async fn accept(&self, uuid: Uuid) -> Result<()> {
+        let request = {
+            let mut connection_requests = self.connection_requests.lock().await;
+            let description = connection_requests.keys().find(|peer| peer.uuid == uuid).cloned();
+            description.and_then(|description| {
+                let request = connection_requests.remove(&description)?;
+                Some((description, request))
+            })
+        };
+        let Some((description, (public_key, remote_handshake))) = request else {
+            return Err(Error::Protocol(format!("no connection request from {}", uuid)));
+        };
+        self.answer(description, public_key, remote_handshake).await
+    }
+
+    /// Opens a session from a peer's connection request and sends the peer
+    /// our half of the handshake
+    async fn answer(
+        &self,
+        description: ClientDescription,
+        public_key: PublicIdentity,
+        remote_handshake: Handshake,
+    ) -> Result<()> {
+        // Answer in the suite the peer asked for
+        let (secret, handshake) = crypto::new_handshake(&self.private_key(), remote_handshake.suite)?;
+        let key = crypto::derive_session_key(&secret, &handshake, &remote_handshake)?;
+        let session = Session::new(public_key, key, secret, &handshake, &remote_handshake)?;
+        self.open_connections.lock().await.insert(description.uuid, session);
+
+        let message = ServerBoundMessage::ConnectionResponse(description, (*self.public_key()).clone(), handshake);
+        self.send_message(message).await
+    }
+
+    async fn close_connection(&self, uuid: Option<Uuid>) -> Result<()> {
+        let mut current_channel = self.current_channel.lock().await;
+        let Some(uuid) = uuid.or(*current_channel) else {
+            say!("\n\r\n You are not connected to a channel.\n\r");
+            return Ok(());
+        };
+
+        if self.open_connections.lock().await.remove(&uuid).is_none() {
+            say!("\n\r\n You have no open connection to {}.\n\r", uuid);
+            return Ok(());
+        }
+        if *current_channel == Some(uuid) {
+            *current_channel = None;
+        }
+        self.unread.lock().await.remove(&uuid);
+
+        let message = ServerBoundMessage::CloseConnection(ClientDescription::to(uuid));
+        let result = self.send_message(message).await;
+        self.drop_direct(uuid).await;
+        result?;
+        say!("\n\r\n Connection to {} closed.\n\r", uuid);
+        Ok(())
+    }
+
+    async fn ui_send_message(&self, message: String, expires_in: Option<Duration>) -> Result<()> {
+        let Some(current_channel) = *self.current_channel.lock().await else {
+            say!("\n\r\n You are not connected to a channel.\n\r");
+            return Ok(());
+        };
+        if !self.confirm_unverified(current_channel).await {
+            say!("\n\r\n Not sent.\n\r");
+            return Ok(());
+        }
+        self.send_to(current_channel, &message, expires_in).await
+    }
+
+    /// Asks before the first message to a peer whose key hasn't been
+    /// verified, remembering a yes for the rest of the session. Returns
+    /// whether to send. With `--strict-verify`, or nobody to ask, `send_to`
+    /// has the final say instead.
+    async fn confirm_unverified(&self, uuid: Uuid) -> bool {
+        if self.strict_verify || !self.interactive {
+            return true;
+        }
+        match self.open_connections.lock().await.get(&uuid) {
+            Some(session) if !session.key_verified && !session.send_unverified => {}
+            _ => return true,
+        }
+        // Name the peer, since `broadcast` may ask about several
+        let question = format!("{}'s key is unverified; send anyway?", self.peer_name(uuid).await);
+        let send = tui::suspended(|| Confirm::new(&question)
+            .with_default(false)
+            .with_help_message("Type 'verify' to compare fingerprints with the peer first")
+            .prompt())
+            .unwrap_or(false);
+        if send {
+            if let Some(session) = self.open_connections.lock().await.get_mut(&uuid) {
+                session.send_unverified = true;
+            }
+        }
+        send
+    }
+
+    fn private_key(&self) -> Arc<IdentityKey> {
+        self.private_key.lock().unwrap().clone()
+    }
+
+    fn public_key(&self) -> Arc<PublicIdentity> {
+        self.public_key.lock().unwrap().clone()
+    }
+
+    /// Asks for a passphrase for an identity file, twice if it's a new one
+    fn identity_passphrase(&self, confirm: bool) -> Result<String> {
+        if !self.interactive {
+            return Err(Error::Protocol(
+                "identity files need a passphrase typed at the prompt".to_string(),
+            ));
+        }
+        let prompt = Password::new("Identity file passphrase:");
+        let prompt = if confirm {
+            prompt.with_custom_confirmation_message("Choose it again to confirm:")
+        } else {
+            prompt.without_confirmation()
+        };
+        tui::suspended(|| prompt.prompt())
+            .map_err(|e| Error::Protocol(format!("no passphrase given: {}", e)))
+    }
+
+    /// Writes our identity key to `path`, encrypted with a passphrase, for
+    /// `import` on another machine
+    async fn export_identity(&self, path: PathBuf) -> Result<()> {
+        let passphrase = self.identity_passphrase(true)?;
+        let key = self.private_key();
+        let destination = path.clone();
+        // Key derivation is deliberately slow
+        let export = tokio::task::spawn_blocking(move || identity::export(&destination, &key, &passphrase));
+        output::spinner("Exporting identity", export)
+            .await
+            .map_err(|e| Error::Crypto(e.to_string()))??;
+        say!("\n\r\n Exported your identity to {}. Anyone with the file and passphrase can pose as you.\n\r", path.display());
+        Ok(())
+    }
+
+    /// Replaces our identity key with the one `export` wrote to `path`.
+    /// Peers we're connected to already hold the old key, so this waits
+    /// until there are none.
+    async fn import_identity(&self, path: PathBuf) -> Result<()> {
+        if !self.open_connections.lock().await.is_empty() {
+            return Err(Error::Protocol(
+                "close your open connections first, since those peers know your current key".to_string(),
+            ));
+        }
+        if !self.pending_handshakes.lock().await.is_empty() {
+            return Err(Error::Protocol(
+                "cancel your outgoing connection requests first, since they carry your current key".to_string(),
+            ));
+        }
+        let passphrase = self.identity_passphrase(false)?;
+        let source = path.clone();
+        let import = tokio::task::spawn_blocking(move || identity::import(&source, &passphrase));
+        let key = output::spinner("Importing identity", import)
+            .await
+            .map_err(|e| Error::Crypto(e.to_string()))??;
+        let public_key = key.public();
+        let fingerprint = public_key.fingerprint();
+        *self.private_key.lock().unwrap() = Arc::new(key);
+        *self.public_key.lock().unwrap() = Arc::new(public_key);
+        say!("\n\r\n Imported the identity from {}. Your key is now {}\n\r", path.display(), fingerprint);
+        Ok(())
+    }
+
+    /// Shows our key's fingerprint next to the one we hold for `uuid`, and
+    /// marks the peer's key verified if the user says they match what the
+    /// peer sees
+    async fn verify(&self, uuid: Uuid) -> Result<()> {
+        let name = self.peer_name(uuid).await;
+        let Some(theirs) = self
+            .open_connections
+            .lock()
+            .await
+            .get(&uuid)
+            .map(|session| session.public_key.fingerprint())
+        else {
+            return Err(Error::Protocol(format!("you have no open connection to {}", name)));
+        };
+        say!();
+        say!("Your key:  {}", self.public_key().fingerprint());
+        say!("Their key: {}", theirs);
+        say!("\nAsk {} to read out both fingerprints over a channel the server can't touch, like a call.", name);
+
+        let matches = tui::suspended(|| Confirm::new(&format!("Do they match what {} sees?", name))
+            .with_default(false)
+            .prompt())
+            .unwrap_or(false);
+        if !matches {
+            say!("\n\r\n {}'s key is still unverified.\n\r", name);
+            return Ok(());
+        }
+        match self.open_connections.lock().await.get_mut(&uuid) {
+            Some(session) => session.key_verified = true,
+            None => return Err(Error::Protocol(format!("the connection to {} has closed", name))),
+        }
+        say!("\n\r\n {}'s key is verified.\n\r", name);
+        Ok(())
+    }
+
+    /// Sends to the open connection with `target`, a uuid or peer name,
+    /// leaving the current channel as it is
+    async fn msg(&self, target: &str, message: &str) -> Result<()> {
+        let uuid = self.resolve_peer(target).await?;
+        if !self.open_connections.lock().await.contains_key(&uuid) {
+            return Err(Error::Protocol(format!(
+                "you have no open connection to {}",
+                self.peer_name(uuid).await
+            )));
+        }
+        if !self.confirm_unverified(uuid).await {
+            say!("\n\r\n Not sent.\n\r");
+            return Ok(());
+        }
+        self.send_to(uuid, message, None).await
+    }
+
+    /// Sends `message` to every peer with an open connection, each sealed
+    /// with its own session, then says who it reached. Failing to send to one
+    /// peer doesn't stop the others.
+    async fn broadcast(&self, message: &str) -> Result<()> {
+        let recipients = self.open_connections.lock().await.keys().copied().collect::<Vec<_>>();
+        if recipients.is_empty() {
+            say!("\n\r\n You have no open connections.\n\r");
+            return Ok(());
+        }
+
+        let mut sent = Vec::new();
+        let mut failed = Vec::new();
+        for uuid in recipients {
+            let name = self.peer_name(uuid).await;
+            if !self.confirm_unverified(uuid).await {
+                failed.push(format!("{} (not sent)", name));
+                continue;
+            }
+            match self.send_to(uuid, message, None).await {
+                Ok(()) => sent.push(name),
+                Err(e) => failed.push(format!("{} ({})", name, e)),
+            }
+        }
+
+        say!();
+        if !sent.is_empty() {
+            say!("Sent to {}.", sent.join(", "));
+        }
+        if !failed.is_empty() {
+            say!("Failed for {}.", failed.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Parses `target` as a uuid, or finds the one peer with that name
+    /// (ignoring case)
+    async fn resolve_peer(&self, target: &str) -> Result<Uuid> {
+        if let Ok(uuid) = Uuid::parse_str(target) {
+            return Ok(uuid);
+        }
+        let peer_list = self.peer_list.lock().await;
+        let mut matching = peer_list
+            .iter()
+            .filter(|peer| peer.name.to_lowercase() == target.to_lowercase());
+        match (matching.next(), matching.next()) {
+            (Some(peer), None) => Ok(peer.uuid),
+            (Some(_), Some(_)) => Err(Error::Protocol(format!(
+                "several peers are named {}, use a uuid instead",
+                target
+            ))),
+            (None, _) => Err(Error::Protocol(format!("no peer named {}", target))),
+        }
+    }
+
+    /// Sends a message over the open session with `uuid`. With `expires_in`
+    /// set, both sides drop it from their history after that long.
+    pub async fn send_to(&self, uuid: Uuid, message: &str, expires_in: Option<Duration>) -> Result<()> {
+        if message.len() > self.max_message_len {
+            return Err(Error::Protocol(format!(
+                "message is too long ({} bytes, the limit is {}), try splitting it up",
+                message.len(),
+                self.max_message_len
+            )));
+        }
+
+        let mut open_connections = self.open_connections.lock().await;
+        let Some(session) = open_connections.get_mut(&uuid) else {
+            return Err(Error::Protocol(format!("no open connection to {}", uuid)));
+        };
+        if self.strict_verify && !session.key_verified {
+            drop(open_connections);
+            return Err(Error::Protocol(format!(
+                "{}'s key is unverified, and --strict-verify refuses to send to it. Check it with `verify` first.",
+                self.peer_name(uuid).await
+            )));
+        }
+
+        let message_id = rand::random();
+        for chunk in chunks::split(message_id, message, expires_in) {
+            let payload = session.seal(&self.private_key(), &framing::to_bincode(&chunk)?)?;
+
+            let message = ServerBoundMessage::Message(ClientDescription::to(uuid), payload);
+            self.send_message(message).await?;
+        }
+        session.expect_receipt(message_id);
+        drop(open_connections);
+
+        let name = self.peer_name(uuid).await;
+        let time = chrono::Local::now();
+        self.remember(Entry {
+            peer_name: name.clone(),
+            peer_uuid: uuid,
+            outgoing: true,
+            text: message.to_string(),
+            verified: true,
+            time,
+            message_id: Some(message_id),
+            reactions: Vec::new(),
+        })
+        .await;
+        if let Some(expires_in) = expires_in {
+            self.forget_after(uuid, message_id, expires_in, None);
+        }
+        self.emit(ClientEvent::MessageSent {
+            to: uuid,
+            name,
+            text: message.to_string(),
+            time,
+        });
+        Ok(())
+    }
+
+    /// Reacts with `emoji` to the latest message the current channel sent us
+    async fn react(&self, emoji: &str) -> Result<()> {
+        if !is_valid_reaction(emoji) {
+            return Err(Error::Protocol(format!("{} isn't a single emoji", emoji)));
+        }
+        let Some(uuid) = *self.current_channel.lock().await else {
+            say!("\n\r\n You are not connected to a channel.\n\r");
+            return Ok(());
+        };
+        let last_received = self
+            .open_connections
+            .lock()
+            .await
+            .get(&uuid)
+            .and_then(|session| session.last_received);
+        let Some(message_id) = last_received else {
+            say!("\n\r\n {} hasn't sent you anything to react to.\n\r", self.peer_name(uuid).await);
+            return Ok(());
+        };
+
+        self.send_message(ServerBoundMessage::React(uuid, message_id, emoji.to_string()))
+            .await?;
+        let reaction = Reaction {
+            outgoing: true,
+            emoji: emoji.to_string(),
+        };
+        match self.remember_reaction(uuid, message_id, reaction).await {
+            Some(text) => say!("\n\r\n You reacted {} to \"{}\"\n\r", emoji, output::excerpt(&text)),
+            None => say!("\n\r\n You reacted {}.\n\r", emoji),
+        }
+        Ok(())
+    }
+
+    /// Puts a reaction on a message in the history, if it's being kept and
+    /// has the message, returning the message's text
+    async fn remember_reaction(&self, uuid: Uuid, message_id: u64, reaction: Reaction) -> Option<String> {
+        let transcript = self.transcript.as_ref()?;
+        match transcript.lock().await.react(uuid, message_id, reaction) {
+            Ok(entry) => entry.map(|entry| entry.text.clone()),
+            Err(e) => {
+                self.emit(ClientEvent::Warning(format!(
+                    "Failed to save message history: {}",
+                    e
+                )));
+                None
+            }
+        }
+    }
+
+    /// Adds a message to the history, if it's being kept
+    async fn remember(&self, entry: Entry) {
+        let Some(transcript) = &self.transcript else {
+            return;
+        };
+        if let Err(e) = transcript.lock().await.record(entry) {
+            self.emit(ClientEvent::Warning(format!(
+                "Failed to save message history: {}",
+                e
+            )));
+        }
+    }
+
+    /// Takes a disappearing message out of the history once `expires_in` is
+    /// up, then publishes `expired` if given. Runs whether or not anyone
+    /// looks at the history meanwhile.
+    fn forget_after(&self, uuid: Uuid, message_id: u64, expires_in: Duration, expired: Option<ClientEvent>) {
+        let transcript = self.transcript.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(expires_in).await;
+            if let Some(transcript) = transcript {
+                if let Err(e) = transcript.lock().await.forget(uuid, message_id) {
+                    let _ = events.send(ClientEvent::Warning(format!(
+                        "Failed to remove an expired message from the history: {}",
+                        e
+                    )));
+                }
+            }
+            if let Some(expired) = expired {
+                let _ = events.send(expired);
+            }
+        });
+    }
+
+    /// Prints how many messages the history keeps, in total and with each
+    /// peer, and how long they're kept for
+    async fn show_history_stats(&self) {
+        let Some(transcript) = &self.transcript else {
+            say!("\n\r\n Message history is turned off.\n\r");
+            return;
+        };
+        let transcript = transcript.lock().await;
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for entry in transcript.entries() {
+            *counts.entry(output::sanitize_name(&entry.peer_name)).or_default() += 1;
+        }
+        let retention = match self.history_ttl {
+            Some(ttl) => format!("for {} day(s)", ttl.as_secs() / (24 * 60 * 60)),
+            None => "until deleted".to_string(),
+        };
+        say!(
+            "\n\r\n {} message(s) kept {}:",
+            transcript.entries().len(),
+            retention
+        );
+        for (name, count) in counts {
+            say!(" {}: {}", name, count);
+        }
+        if let Some(oldest) = transcript.entries().iter().map(|entry| entry.time).min() {
+            say!(" Oldest from {}", oldest.format("%Y-%m-%d %H:%M"));
+        }
+        say!();
+    }
+
+    /// Prints the conversation with `target`, a uuid or peer name, or with
+    /// the current channel. Earlier sessions' messages are found by name.
+    async fn show_history(&self, target: Option<&str>) -> Result<()> {
+        let Some(transcript) = &self.transcript else {
+            say!("\n\r\n Message history is turned off.\n\r");
+            return Ok(());
+        };
+        let (uuid, name) = match target {
+            Some(target) => match self.resolve_peer(target).await {
+                Ok(uuid) => (Some(uuid), self.peer_name(uuid).await),
+                // Not connected now, but maybe in an earlier session
+                Err(_) => (None, target.to_string()),
+            },
+            None => match *self.current_channel.lock().await {
+                Some(uuid) => (Some(uuid), self.peer_name(uuid).await),
+                None => {
+                    say!("\n\r\n You are not connected to a channel.\n\r");
+                    return Ok(());
+                }
+            },
+        };
+        if let Some(uuid) = uuid {
+            self.unread.lock().await.remove(&uuid);
+        }
+
+        let entries = history_with(&*transcript.lock().await, uuid, &name);
+        if entries.is_empty() {
+            say!("\n\r\n No messages with {}.\n\r", name);
+            return Ok(());
+        }
+        say!("\n\r\n Messages with {}:", name);
+        for entry in &entries {
+            self.output.print_entry(entry);
+        }
+        Ok(())
+    }
+
+    /// Messages sent and received with `uuid` this session, or with a peer
+    /// named `name` in an earlier one, oldest first. `None` with history
+    /// turned off.
+    pub async fn history(&self, uuid: Option<Uuid>, name: &str) -> Option<Vec<Entry>> {
+        let transcript = self.transcript.as_ref()?;
+        Some(history_with(&*transcript.lock().await, uuid, name))
+    }
+
+    async fn send_file(&self, path: PathBuf) -> Result<()> {
+        let current_channel = self.current_channel.lock().await;
+        let mut open_connections = self.open_connections.lock().await;
+        let Some((current_channel, session)) = current_channel.and_then(|uuid| {
+            open_connections
+                .get_mut(&uuid)
+                .map(|session| (uuid, session))
+        }) else {
+            say!("\n\r\n You are not connected to a channel.\n\r");
+            return Ok(());
+        };
+
+        let data = files::read(&path).await?;
+        let offer = files::offer(rand::random(), &path, &data);
+        let payload = session.seal(&self.private_key(), &framing::to_bincode(&offer)?)?;
+        session.offered_files.insert(offer.id, path);
+
+        let message =
+            ServerBoundMessage::FileOffer(ClientDescription::to(current_channel), payload);
+        self.send_message(message).await?;
+        say!(
+            "\n\r\n Offered {} ({} bytes). It will be sent once the peer accepts.\n\r",
+            offer.name, offer.size
+        );
+        Ok(())
+    }
+
+    async fn accept_file(&self) -> Result<()> {
+        let mut offers = Vec::new();
+        for (uuid, session) in self.open_connections.lock().await.iter() {
+            for offer in session.file_offers.values() {
+                offers.push((*uuid, offer.clone()));
+            }
+        }
+        if offers.is_empty() {
+            say!("\n\r\n No pending file offers.\n\r");
+            return Ok(());
+        }
+
+        let peer_list = self.peer_list.lock().await.clone();
+        let options = offers
+            .iter()
+            .map(|(uuid, offer)| {
+                let peer = peer_list
+                    .iter()
+                    .find(|peer| peer.uuid == *uuid)
+                    .map(|peer| peer.display_name())
+                    .unwrap_or(uuid.to_string());
+                format!("{} ({} bytes) from {}", offer.name, offer.size, peer)
+            })
+            .collect::<Vec<_>>();
+        let Ok(selection) = tui::suspended(|| Select::new("Select a file", options).raw_prompt()) else {
+            return Ok(());
+        };
+        let (uuid, offer) = offers.swap_remove(selection.index);
+        let destination = files::destination(&self.download_dir, &offer)?;
+        let accepted = tui::suspended(|| Confirm::new(&format!("Save it to {}?", destination.display()))
+            .with_default(true)
+            .prompt())
+            .unwrap_or(false);
+        self.answer_file(uuid, offer.id, accepted).await
+    }
+
+    /// Accepts or declines the file with offer `id` from `uuid`, saving it
+    /// to the download directory if accepted. An offer that's already been
+    /// answered is ignored.
+    pub async fn answer_file(&self, uuid: Uuid, id: u64, accepted: bool) -> Result<()> {
+        let mut open_connections = self.open_connections.lock().await;
+        let Some(session) = open_connections.get_mut(&uuid) else {
+            say!("\n\r\n The connection to that peer has closed.\n\r");
+            return Ok(());
+        };
+        let Some(offer) = session.file_offers.remove(&id) else {
+            return Ok(());
+        };
+        let destination = files::destination(&self.download_dir, &offer)?;
+
+        let accepted = accepted && {
+            if destination.exists() {
+                say!(
+                    "\n\r\n {} already exists, declining.\n\r",
+                    destination.display()
+                );
+                false
+            } else {
+                match files::IncomingFile::create(offer, destination).await {
+                    Ok(incoming) => {
+                        session.incoming_files.insert(id, incoming);
+                        true
+                    }
+                    Err(e) => {
+                        say!("\n\r\n Couldn't create the file ({}), declining.\n\r", e);
+                        false
+                    }
+                }
+            }
+        };
+
+        let response = FileResponse { id, accepted };
+        let payload = session.seal(&self.private_key(), &framing::to_bincode(&response)?)?;
+        drop(open_connections);
+        let message = ServerBoundMessage::FileResponse(ClientDescription::to(uuid), payload);
+        self.send_message(message).await
+    }
+
+    async fn receive_file_offer(
+        &self,
+        from: ClientDescription,
+        payload: EncryptedPayload,
+    ) -> Result<()> {
+        let mut open_connections = self.open_connections.lock().await;
+        let Some(session) = open_connections.get_mut(&from.uuid) else {
+            return Ok(());
+        };
+        let offer: FileOffer = session.open_signed(&payload)?;
+
+        if offer.size > files::MAX_FILE_SIZE {
+            self.emit(ClientEvent::FileTooLarge {
+                from: from.clone(),
+                name: offer.name.clone(),
+                size: offer.size,
+            });
+            let response = FileResponse {
+                id: offer.id,
+                accepted: false,
+            };
+            let payload = session.seal(&self.private_key(), &framing::to_bincode(&response)?)?;
+            let message =
+                ServerBoundMessage::FileResponse(ClientDescription::to(from.uuid), payload);
+            return self.send_message(message).await;
+        }
+
+        self.emit(ClientEvent::FileOffered {
+            from,
+            id: offer.id,
+            name: offer.name.clone(),
+            size: offer.size,
+        });
+        session.file_offers.insert(offer.id, offer);
+        Ok(())
+    }
+
+    async fn receive_file_response(
+        &self,
+        from: ClientDescription,
+        payload: EncryptedPayload,
+    ) -> Result<()> {
+        let mut open_connections = self.open_connections.lock().await;
+        let Some(session) = open_connections.get_mut(&from.uuid) else {
+            return Ok(());
+        };
+        let response: FileResponse = session.open_signed(&payload)?;
+        let Some(path) = session.offered_files.remove(&response.id) else {
+            return Ok(());
+        };
+        drop(open_connections);
+        if !response.accepted {
+            self.emit(ClientEvent::FileDeclined { by: from, path });
+            return Ok(());
+        }
+
+        let data = files::read(&path).await?;
+        let mut open_connections = self.open_connections.lock().await;
+        let Some(session) = open_connections.get_mut(&from.uuid) else {
+            return Ok(());
+        };
+        let outgoing = files::OutgoingFile::new(response.id, path, &data);
+        session.outgoing_files.insert(response.id, outgoing);
+        drop(open_connections);
+        self.send_file_chunks(from.uuid, response.id).await
+    }
+
+    /// Sends as many chunks of the outgoing file `id` as its window allows.
+    /// The chunks are sealed under the sessions lock but sent after it's
+    /// released, so other sessions aren't held up while they're written.
+    async fn send_file_chunks(&self, uuid: Uuid, id: u64) -> Result<()> {
+        let mut open_connections = self.open_connections.lock().await;
+        let Some(session) = open_connections.get_mut(&uuid) else {
+            return Ok(());
+        };
+        let Some(outgoing) = session.outgoing_files.get_mut(&id) else {
+            return Ok(());
+        };
+        let chunks = outgoing.next_chunks();
+        let mut messages = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            let payload = session.seal(&self.private_key(), &framing::to_bincode(&chunk)?)?;
+            messages.push(ServerBoundMessage::FileChunk(ClientDescription::to(uuid), payload));
+        }
+        drop(open_connections);
+        for message in messages {
+            self.send_message(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive_file_ack(&self, from: ClientDescription, payload: EncryptedPayload) -> Result<()> {
+        let mut open_connections = self.open_connections.lock().await;
+        let Some(session) = open_connections.get_mut(&from.uuid) else {
+            return Ok(());
+        };
+        let ack: FileAck = session.open_signed(&payload)?;
+        let Some(outgoing) = session.outgoing_files.get_mut(&ack.id) else {
+            return Ok(());
+        };
+        outgoing.acked(ack.received);
+        if outgoing.is_done() {
+            let Some(outgoing) = session.outgoing_files.remove(&ack.id) else {
+                return Ok(());
+            };
+            drop(open_connections);
+            self.emit(ClientEvent::FileSent {
+                to: from,
+                path: outgoing.path,
+            });
+            return Ok(());
+        }
+        drop(open_connections);
+        self.send_file_chunks(from.uuid, ack.id).await
+    }
+
+    async fn receive_file_chunk(
+        &self,
+        from: ClientDescription,
+        payload: EncryptedPayload,
+    ) -> Result<()> {
+        let mut open_connections = self.open_connections.lock().await;
+        let Some(session) = open_connections.get_mut(&from.uuid) else {
+            return Ok(());
+        };
+        let chunk: FileChunk = session.open_signed(&payload)?;
+        let id = chunk.id;
+        let Some(incoming) = session.incoming_files.get_mut(&id) else {
+            return Ok(());
+        };
+
+        let progress = match incoming.write(chunk).await {
+            Ok(progress) => progress,
+            Err(e) => {
+                if let Some(incoming) = session.incoming_files.remove(&id) {
+                    let name = incoming.name().to_string();
+                    incoming.discard().await;
+                    self.emit(ClientEvent::FileFailed {
+                        name,
+                        error: e.to_string(),
+                    });
+                }
+                return Ok(());
+            }
+        };
+        let ack = match incoming.ack(&progress) {
+            Some(ack) => Some(session.seal(&self.private_key(), &framing::to_bincode(&ack)?)?),
+            None => None,
+        };
+        if let files::Progress::Done(path) = progress {
+            session.incoming_files.remove(&id);
+            self.emit(ClientEvent::FileSaved {
+                from: from.clone(),
+                path,
+            });
+        }
+        drop(open_connections);
+        if let Some(ack) = ack {
+            let message = ServerBoundMessage::FileAck(ClientDescription::to(from.uuid), ack);
+            self.send_message(message).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Server to connect to: a hostname, or an IPv4 or IPv6 address
+    #[arg(short, long, default_value = "127.0.0.1")]
+    pub address: String,
+
+    /// Port the server listens on
+    #[arg(short, long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Also write `send` commands (and their message text) to the history file
+    #[arg(long)]
+    pub persist_send_history: bool,
+
+    /// Keep message history in this file across sessions, encrypted with a
+    /// passphrase asked for at startup. Without it, history lasts only
+    /// until exit.
+    #[arg(long, conflicts_with = "no_history")]
+    pub history_file: Option<PathBuf>,
+
+    /// Don't keep message history at all, not even in memory
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// Forget messages from history once they're this many days old,
+    /// including ones in the history file. 0 keeps them until deleted.
+    #[arg(long, default_value_t = 0, conflicts_with = "no_history")]
+    pub history_ttl_days: u64,
+
+    /// Disable colored output
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Longest message, in bytes, that `send` accepts. Messages over 4 KiB
+    /// are sent in several chunks.
+    #[arg(long, default_value_t = 65536)]
+    pub max_message_len: usize,
+
+    /// Let peers see when you've read their messages (toggle with `receipts on|off`)
+    #[arg(long)]
+    pub receipts: bool,
+
+    /// Don't print notices about connections, such as new requests or
+    /// sessions opening and closing. Messages, files and errors are still
+    /// shown, and `accept` still lists requests. Toggle with `quiet on|off`.
+    #[arg(long)]
+    pub quiet: bool,
+
+    /// Print messages above a plain prompt instead of using the
+    /// full-screen UI, which keeps them in a pane of their own
+    #[arg(long)]
+    pub simple_ui: bool,
+
+    /// Directory accepted files are saved to
+    #[arg(long, default_value = ".")]
+    pub download_dir: PathBuf,
+
+    /// Algorithm of the identity key generated at startup. Only peers of
+    /// the same type can open a session with us.
+    #[arg(long, value_enum, default_value_t = KeyType::Rsa)]
+    pub key_type: KeyType,
+
+    /// Size of the identity key when it's RSA. Larger keys take noticeably
+    /// longer to generate and make every signature slower.
+    #[arg(long, default_value_t = 2048, value_parser = parse_key_bits)]
+    pub key_bits: usize,
+
+    /// Connect to a server listening on this Unix domain socket instead of
+    /// over TCP. --address and --port are then unused.
+    #[arg(long, conflicts_with = "proxy")]
+    pub socket: Option<PathBuf>,
+
+    /// Connect over TLS, to a server's --tls-address. Its certificate is
+    /// checked against the usual public roots, or --tls-ca.
+    #[cfg(feature = "tls")]
+    #[arg(long, conflicts_with = "socket")]
+    pub tls: bool,
+
+    /// Trust only the PEM certificates in this file for --tls, such as a
+    /// self-signed server's own
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Reach the server through a SOCKS5 proxy such as Tor, given as
+    /// socks5://[user:password@]host:port. The server address is resolved by
+    /// the proxy, so .onion addresses work.
+    #[arg(long)]
+    pub proxy: Option<Proxy>,
+
+    /// Idle seconds before TCP keepalive probes start, or 0 to disable them
+    #[arg(long, default_value_t = 60)]
+    pub tcp_keepalive_secs: u64,
+
+    /// Set your status to away after this many minutes without entering a
+    /// command, and back to online on the next one. 0 disables it.
+    #[arg(long, default_value_t = 15)]
+    pub idle_away_mins: u64,
+
+    /// Refuse direct connections from peers, so they never learn your
+    /// address. Messages always go through the server.
+    #[arg(long)]
+    pub no_direct: bool,
+
+    /// Refuse to send messages to a peer until its key has been checked with
+    /// `verify`, instead of asking before the first one
+    #[arg(long)]
+    pub strict_verify: bool,
+
+    /// Instead of the prompt, write events to stdout as JSON lines and read
+    /// JSON commands such as {"cmd":"send","to":"<uuid>","text":"hi"} from stdin
+    #[arg(long)]
+    pub json_events: bool,
+
+    /// Instead of the prompt, run the actions in this file one per line,
+    /// then exit. `sleep <ms>` lines pause, and lines starting with `#` are
+    /// skipped.
+    #[arg(long, conflicts_with_all = ["json_events", "history_file"])]
+    pub script: Option<PathBuf>,
+
+    /// Friendly name to advertise, instead of asking for one at startup
+    #[arg(long)]
+    pub name: Option<String>,
+}
+
+fn parse_key_bits(bits: &str) -> std::result::Result<usize, String> {
+    match bits.parse() {
+        Ok(bits @ (2048 | 3072 | 4096)) => Ok(bits),
+        _ => Err("must be 2048, 3072 or 4096".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::shared::messages::RatchetHeader;
+
+    fn peer(name: &str, uuid: Uuid) -> ClientDescription {
+        ClientDescription::new(name.to_string(), uuid)
+    }
+
+    #[test]
+    fn upsert_adds_a_new_peer() {
+        let mut peers = vec![peer("alice", Uuid::new_v4())];
+        let bob = Uuid::new_v4();
+        upsert_peer(&mut peers, peer("bob", bob));
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[1].uuid, bob);
+    }
+
+    #[test]
+    fn upsert_replaces_a_known_peer_in_place() {
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        let mut peers = vec![peer("alice", alice), peer("bob", bob)];
+        upsert_peer(&mut peers, peer("alicia", alice));
+        assert_eq!(peers.len(), 2);
+        assert_eq!((peers[0].uuid, peers[0].name.as_str()), (alice, "alicia"));
+        assert_eq!(peers[1].uuid, bob);
+    }
+
+    #[test]
+    fn names_from_the_server_are_sanitized_before_use() {
+        let uuid = Uuid::new_v4();
+        let mut message = ClientBoundMessage::NewClient(peer("mallory\n admin:", uuid));
+        sanitize_names(&mut message);
+        let ClientBoundMessage::NewClient(peer) = message else {
+            unreachable!();
+        };
+        assert_eq!(peer.name, "mallory admin:");
+
+        let mut message = ClientBoundMessage::ClientRenamed(uuid, "\r\nadmin: hi".to_string());
+        sanitize_names(&mut message);
+        assert!(matches!(message, ClientBoundMessage::ClientRenamed(_, name) if name == "admin: hi"));
+    }
+
+    #[test]
+    fn sorted_peers_filters_by_name_or_uuid_and_sorts_by_name() {
+        let uuids: Vec<Uuid> = (1..=4u128).map(Uuid::from_u128).collect();
+        let peers = vec![
+            peer("carol", uuids[0]),
+            peer("Alice", uuids[1]),
+            peer("bob", uuids[2]),
+            peer("alice", uuids[3]),
+        ];
+        let names_and_uuids = |filter: &str| -> Vec<(String, Uuid)> {
+            sorted_peers(&peers, filter)
+                .into_iter()
+                .map(|peer| (peer.name.clone(), peer.uuid))
+                .collect()
+        };
+
+        // Case is ignored, and equal names fall back to the uuid
+        let everyone = names_and_uuids("");
+        assert_eq!(
+            everyone,
+            [
+                ("Alice".to_string(), uuids[1]),
+                ("alice".to_string(), uuids[3]),
+                ("bob".to_string(), uuids[2]),
+                ("carol".to_string(), uuids[0]),
+            ]
+        );
+        assert_eq!(names_and_uuids("ALI").len(), 2);
+        assert_eq!(names_and_uuids(&uuids[2].to_string()), [("bob".to_string(), uuids[2])]);
+        assert!(names_and_uuids("dave").is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn the_runtime_keeps_running_during_keygen() {
+        let ticks = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    ticks.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        });
+        // Small enough to be quick in a test but still slow enough to notice.
+        // With one thread, keygen on the runtime would leave no tick.
+        generate_key(KeyType::Rsa, 1024).await.unwrap();
+        assert!(ticks.load(std::sync::atomic::Ordering::Relaxed) > 0);
+        ticker.abort();
+    }
+
+    fn handshake(ephemeral_key: [u8; 32]) -> Handshake {
+        Handshake {
+            suite: suite::DEFAULT_SUITE,
+            ephemeral_key,
+            signature: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn a_request_is_forgotten_only_by_its_own_timer() {
+        let peer = Uuid::new_v4();
+        let mut requests = HashMap::from([(peer, ((), handshake([1; 32])))]);
+
+        // The timer of an earlier request, since replaced, leaves it alone
+        assert!(!forget_request(&mut requests, &peer, [0; 32]));
+        assert!(requests.contains_key(&peer));
+
+        assert!(forget_request(&mut requests, &peer, [1; 32]));
+        assert!(requests.is_empty());
+        // Already answered or cancelled, so there's nothing to time out
+        assert!(!forget_request(&mut requests, &peer, [1; 32]));
+    }
+
+    /// Connects a client to the server at `address`, with its connection
+    /// handled in the background
+    async fn connect(address: std::net::SocketAddr, name: &str) -> Arc<Client> {
+        let client = unhandled(address, name).await;
+        tokio::spawn({
+            let client = client.clone();
+            async move { client.run_connection().await }
+        });
+        client
+    }
+
+    /// Like `connect`, leaving the connection for the test to handle
+    async fn unhandled(address: std::net::SocketAddr, name: &str) -> Arc<Client> {
+        let port = address.port().to_string();
+        let args = Args::parse_from([
+            "client",
+            "--address",
+            "127.0.0.1",
+            "--port",
+            &port,
+            "--key-type",
+            "ed25519",
+            "--simple-ui",
+            "--json-events",
+            "--name",
+            name,
+        ]);
+        Arc::new(Client::new(args).await.unwrap())
+    }
+
+    /// Waits for `peer` to be reported with `presence`
+    async fn wait_for_presence(
+        events: &mut broadcast::Receiver<ClientEvent>,
+        peer: Uuid,
+        presence: Presence,
+    ) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(ClientEvent::PresenceChanged(uuid, now)) = events.recv().await {
+                    if uuid == peer && now == presence {
+                        return;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for the presence change");
+    }
+
+    /// Starts a server on an ephemeral loopback port for the rest of the test
+    async fn start_server() -> std::net::SocketAddr {
+        let args = crate::server::Args::parse_from(["server", "--address", "127.0.0.1", "--port", "0"]);
+        let mut server = crate::server::Server::new(args).await.unwrap();
+        let address = server.local_addrs().unwrap()[0];
+        tokio::spawn(async move { server.run_until(std::future::pending::<()>()).await });
+        address
+    }
+
+    #[tokio::test]
+    async fn going_idle_sets_us_away_until_the_next_action() {
+        let address = start_server().await;
+        let alice = connect(address, "alice").await;
+        let bob = connect(address, "bob").await;
+        let alice_uuid = alice.uuid().await.unwrap();
+        let mut events = bob.subscribe();
+
+        let watcher = tokio::spawn({
+            let alice = alice.clone();
+            async move { alice.watch_idle(Duration::from_millis(50)).await }
+        });
+        wait_for_presence(&mut events, alice_uuid, Presence::Away).await;
+        assert_eq!(*alice.presence.lock().await, Presence::Away);
+        assert!(*alice.idle_away.lock().await);
+        // Stopped first, so it can't set us Away again before we look
+        watcher.abort();
+
+        alice.note_activity().await;
+        wait_for_presence(&mut events, alice_uuid, Presence::Online).await;
+        assert_eq!(*alice.presence.lock().await, Presence::Online);
+        assert!(!*alice.idle_away.lock().await);
+    }
+
+    #[tokio::test]
+    async fn crossed_requests_settle_on_one_session() {
+        let address = start_server().await;
+        let alice = unhandled(address, "alice").await;
+        let bob = unhandled(address, "bob").await;
+        let (alice_uuid, bob_uuid) = (alice.uuid().await.unwrap(), bob.uuid().await.unwrap());
+        let mut alice_events = alice.subscribe();
+        let mut bob_events = bob.subscribe();
+
+        // Both ask before either connection is read, so each request finds
+        // the other side's handshake pending
+        assert!(alice.request_connection(bob_uuid).await.unwrap());
+        assert!(bob.request_connection(alice_uuid).await.unwrap());
+        for client in [&alice, &bob] {
+            tokio::spawn({
+                let client = client.clone();
+                async move { client.run_connection().await }
+            });
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let settled = alice.open_connections.lock().await.contains_key(&bob_uuid)
+                && bob.open_connections.lock().await.contains_key(&alice_uuid)
+                && alice.pending_handshakes.lock().await.is_empty()
+                && bob.pending_handshakes.lock().await.is_empty();
+            if settled {
+                break;
+            }
+            assert!(Instant::now() < deadline, "the crossed requests never settled");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(alice.open_connections.lock().await.len(), 1);
+        assert_eq!(bob.open_connections.lock().await.len(), 1);
+        assert!(alice.connection_requests.lock().await.is_empty());
+        assert!(bob.connection_requests.lock().await.is_empty());
+
+        // The same session at both ends, so each can read the other
+        alice.send_to(bob_uuid, "hi bob", None).await.unwrap();
+        bob.send_to(alice_uuid, "hi alice", None).await.unwrap();
+        for (events, from, expected) in [
+            (&mut bob_events, alice_uuid, "hi bob"),
+            (&mut alice_events, bob_uuid, "hi alice"),
+        ] {
+            let text = tokio::time::timeout(Duration::from_secs(10), async {
+                loop {
+                    if let Ok(ClientEvent::MessageReceived { from: sender, text, .. }) =
+                        events.recv().await
+                    {
+                        if sender.uuid == from {
+                            return text;
+                        }
+                    }
+                }
+            })
+            .await
+            .expect("the message should be read on the one session");
+            assert_eq!(text, expected);
+        }
+    }
+
+    /// Stands in for the server on an ephemeral port: greets one client,
+    /// then hands over the connection
+    async fn fake_server() -> (std::net::SocketAddr, JoinHandle<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let greeted = tokio::spawn(async move { greet(&listener, Uuid::new_v4()).await });
+        (address, greeted)
+    }
+
+    /// Accepts a client on `listener` and greets it, assigning it `uuid`
+    async fn greet(listener: &TcpListener, uuid: Uuid) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let hello = ClientBoundMessage::ServerHello {
+            protocol_version: PROTOCOL_VERSION,
+            wire_format: WireFormat::Bincode,
+        };
+        let hello = framing::encode_hello_frame(&hello).unwrap();
+        framing::write_encoded(&mut stream, &hello).await.unwrap();
+        framing::read_frame(&mut stream).await.unwrap().unwrap();
+        let uuid = ClientBoundMessage::SetUuid(uuid, [0; 32]);
+        framing::write_frame(&mut stream, WireFormat::Bincode, &uuid)
+            .await
+            .unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn the_pruning_task_forgets_only_messages_past_the_ttl() {
+        let day = chrono::Duration::days(1);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut transcript = Transcript::in_memory();
+        let backdated = [(alice, "old", day * 8), (alice, "recent", day), (bob, "older", day * 30)];
+        for (peer_uuid, text, age) in backdated {
+            let entry = Entry {
+                peer_name: String::new(),
+                peer_uuid,
+                outgoing: false,
+                text: text.to_string(),
+                verified: true,
+                time: chrono::Local::now() - age,
+                message_id: None,
+                reactions: Vec::new(),
+            };
+            transcript.record(entry).unwrap();
+        }
+        let transcript = Arc::new(Mutex::new(transcript));
+        let events = broadcast::channel(SUBSCRIBER_BUFFER).0;
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        let pruning = tokio::spawn(prune_history(transcript.clone(), week, events));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while transcript.lock().await.entries().len() == 3 {
+            assert!(Instant::now() < deadline, "nothing was pruned");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        pruning.abort();
+        let transcript = transcript.lock().await;
+        let texts: Vec<_> = transcript.entries().iter().map(|entry| entry.text.as_str()).collect();
+        assert_eq!(texts, ["recent"]);
+    }
+
+    /// A client of the server at `address` with `options`, without the
+    /// connection being handled
+    async fn fake_client(address: std::net::SocketAddr, options: &[&str]) -> Client {
+        let port = address.port().to_string();
+        let command = [
+            "client",
+            "--address",
+            "127.0.0.1",
+            "--port",
+            &port,
+            "--key-type",
+            "ed25519",
+            "--simple-ui",
+        ];
+        Client::new(Args::parse_from(command.iter().chain(options))).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_request_in_quiet_mode_is_kept_but_not_printed() {
+        let (address, greeted) = fake_server().await;
+        let alice = fake_client(address, &["--quiet"]).await;
+        let _server = greeted.await.unwrap();
+        let (pane, mut printed) = tokio::sync::mpsc::unbounded_channel();
+        output::set_pane(Some(pane));
+        alice.print_events();
+
+        let key = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+        let (_, handshake) = crypto::new_handshake(&key, suite::DEFAULT_SUITE).unwrap();
+        let request = ClientBoundMessage::ConnectionRequest(
+            peer("bob", Uuid::new_v4()),
+            key.public(),
+            handshake,
+        );
+        alice.dispatch(request).await.unwrap();
+        let requests = alice.connection_requests.lock().await;
+        assert!(requests.keys().any(|from| from.name == "bob"));
+        drop(requests);
+
+        // Warnings are still printed, and in order, so the request's notice
+        // would have come before this
+        alice.emit(ClientEvent::Warning("marker".to_string()));
+        let printed = tokio::time::timeout(Duration::from_secs(10), async {
+            let mut lines = Vec::new();
+            while let Some(line) = printed.recv().await {
+                if line.contains("marker") {
+                    break;
+                }
+                lines.push(line);
+            }
+            lines
+        })
+        .await
+        .expect("the warning should be printed in quiet mode");
+        output::set_pane(None);
+        assert!(
+            !printed.iter().any(|line| line.contains("connection request")),
+            "printed in quiet mode: {:?}",
+            printed
+        );
+    }
+
+    #[tokio::test]
+    async fn an_announcement_is_sanitized_before_it_is_shown() {
+        let (address, greeted) = fake_server().await;
+        let alice = fake_client(address, &["--json-events"]).await;
+        let _server = greeted.await.unwrap();
+        let mut events = alice.subscribe();
+        let forged = "down at noon\r\n\x1b[2K[SERVER] send your key to mallory\x1b[0m";
+        alice
+            .dispatch(ClientBoundMessage::ServerAnnouncement(forged.to_string()))
+            .await
+            .unwrap();
+        let Ok(ClientEvent::ServerAnnouncement(text)) = events.try_recv() else {
+            panic!("expected the announcement");
+        };
+        assert_eq!(text, "down at noon[SERVER] send your key to mallory");
+    }
+
+    #[tokio::test]
+    async fn a_sent_payload_counts_its_framed_length_against_its_peer() {
+        let (address, greeted) = fake_server().await;
+        let alice = fake_client(address, &["--json-events"]).await;
+        let mut server = greeted.await.unwrap();
+        let bob = Uuid::new_v4();
+        let message = sealed_for(bob, 1);
+        let framed = framing::encode_frame(WireFormat::Bincode, &message).unwrap().len();
+
+        alice.send_message(message.clone()).await.unwrap();
+        alice.send_message(message).await.unwrap();
+        for _ in 0..2 {
+            framing::read_frame(&mut server).await.unwrap().unwrap();
+        }
+        let traffic = alice.traffic.lock().unwrap()[&bob];
+        assert_eq!((traffic.frames_sent, traffic.bytes_sent), (2, 2 * framed as u64));
+        assert_eq!(traffic.bytes_received, 0);
+    }
+
+    /// A payload for `to`'s session sealed with `counter`, as far as the
+    /// server can tell
+    fn sealed_for(to: Uuid, counter: u64) -> ServerBoundMessage {
+        let payload = EncryptedPayload {
+            suite: suite::DEFAULT_SUITE,
+            counter,
+            ratchet: RatchetHeader {
+                epoch: 0,
+                ratchet_key: [1; 32],
+                peer_ratchet_key: [2; 32],
+                index: 0,
+                previous_len: 0,
+            },
+            nonce: vec![0; 12],
+            ciphertext: vec![0; 200],
+            signature: vec![0; 64],
+        };
+        ServerBoundMessage::Message(ClientDescription::to(to), payload)
+    }
+
+    #[tokio::test]
+    async fn resuming_asks_after_unacknowledged_messages_and_reports_the_lost_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let uuid = Uuid::new_v4();
+        let (alice, mut server) = tokio::join!(fake_client(address, &["--json-events"]), async {
+            greet(&listener, uuid).await
+        });
+        let alice = Arc::new(alice);
+        let mut events = alice.subscribe();
+        let connection = tokio::spawn({
+            let alice = alice.clone();
+            async move { alice.run_connection().await }
+        });
+
+        // The server relays the first, then the connection drops before it
+        // can acknowledge either
+        let bob = Uuid::new_v4();
+        alice.send_message(sealed_for(bob, 1)).await.unwrap();
+        alice.send_message(sealed_for(bob, 2)).await.unwrap();
+        framing::read_frame(&mut server).await.unwrap().unwrap();
+        drop(server);
+        assert_eq!(alice.in_flight.lock().await.len(), 2);
+
+        let mut server = greet(&listener, uuid).await;
+        let frame = framing::read_frame(&mut server).await.unwrap().unwrap();
+        let ServerBoundMessage::QueryAcks(queried) = WireFormat::Bincode.decode(&frame).unwrap()
+        else {
+            panic!("expected the resumed client to ask after its acks");
+        };
+        assert_eq!(queried, [(bob, 1), (bob, 2)]);
+        let acked = ClientBoundMessage::Acked(vec![(bob, 1)]);
+        framing::write_frame(&mut server, WireFormat::Bincode, &acked)
+            .await
+            .unwrap();
+
+        let lost = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Ok(ClientEvent::MessageLost { uuid, counter, .. }) = events.recv().await {
+                    return (uuid, counter);
+                }
+            }
+        })
+        .await
+        .expect("the unrelayed message should be reported lost");
+        assert_eq!(lost, (bob, 2));
+        assert!(alice.in_flight.lock().await.is_empty());
+        assert!(alice.queried_acks.lock().await.is_empty());
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn a_delivered_message_is_no_longer_in_flight() {
+        let (address, greeted) = fake_server().await;
+        let alice = fake_client(address, &["--json-events"]).await;
+        let _server = greeted.await.unwrap();
+        let bob = Uuid::new_v4();
+        alice.send_message(sealed_for(bob, 1)).await.unwrap();
+        alice.send_message(sealed_for(bob, 2)).await.unwrap();
+        alice.dispatch(ClientBoundMessage::Delivered(bob, 2)).await.unwrap();
+        assert_eq!(Vec::from(alice.in_flight.lock().await.clone()), [(bob, 1)]);
+    }
+
+    #[tokio::test]
+    async fn a_shutdown_notice_ends_the_connection_without_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (alice, mut server) = tokio::join!(fake_client(address, &["--json-events"]), async {
+            greet(&listener, Uuid::new_v4()).await
+        });
+        framing::write_frame(&mut server, WireFormat::Bincode, &ClientBoundMessage::ServerShutdown)
+            .await
+            .unwrap();
+        drop(server);
+
+        let finished = tokio::time::timeout(Duration::from_secs(10), alice.run_connection())
+            .await
+            .expect("the client should stop once the server shuts down");
+        assert!(finished.is_ok());
+        assert!(!alice.is_connected());
+    }
+
+    #[tokio::test]
+    async fn a_clean_close_is_a_drop_that_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let uuid = Uuid::new_v4();
+        let (alice, server) = tokio::join!(fake_client(address, &["--json-events"]), async {
+            greet(&listener, uuid).await
+        });
+        let alice = Arc::new(alice);
+        drop(server);
+        assert_eq!(alice.handle().await.unwrap(), Closed::Dropped);
+
+        let connection = tokio::spawn({
+            let alice = alice.clone();
+            async move { alice.run_connection().await }
+        });
+        tokio::time::timeout(Duration::from_secs(10), greet(&listener, uuid))
+            .await
+            .expect("the client should reconnect after a clean close");
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn a_handshake_cut_short_while_reconnecting_is_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let uuid = Uuid::new_v4();
+        let (alice, server) = tokio::join!(fake_client(address, &["--json-events"]), async {
+            greet(&listener, uuid).await
+        });
+        drop(server);
+
+        let connection = tokio::spawn(async move { alice.run_connection().await });
+        // Accepted and closed before the hello, as by a server going down
+        let (cut_short, _) = listener.accept().await.unwrap();
+        drop(cut_short);
+        tokio::time::timeout(Duration::from_secs(10), greet(&listener, uuid))
+            .await
+            .expect("the client should keep trying after a failed handshake");
+        assert!(!connection.is_finished());
+        connection.abort();
+    }
+
+    #[tokio::test]
+    async fn a_server_on_another_protocol_version_stops_the_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (alice, server) = tokio::join!(fake_client(address, &["--json-events"]), async {
+            greet(&listener, Uuid::new_v4()).await
+        });
+        drop(server);
+
+        let connection = tokio::spawn(async move { alice.run_connection().await });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let hello = ClientBoundMessage::ServerHello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            wire_format: WireFormat::Bincode,
+        };
+        let hello = framing::encode_hello_frame(&hello).unwrap();
+        framing::write_encoded(&mut stream, &hello).await.unwrap();
+
+        let finished = tokio::time::timeout(Duration::from_secs(10), connection)
+            .await
+            .expect("the client should give up on an incompatible server")
+            .unwrap();
+        assert!(matches!(finished, Err(Error::Rejected(_))), "{:?}", finished);
+    }
+
+    #[tokio::test]
+    async fn a_frame_cut_off_partway_fails_the_connection() {
+        let (address, greeted) = fake_server().await;
+        let alice = fake_client(address, &["--json-events"]).await;
+        let mut server = greeted.await.unwrap();
+        let pong = ClientBoundMessage::Pong(1);
+        let pong = framing::encode_frame(WireFormat::Bincode, &pong).unwrap();
+        server.write_all(&pong[..pong.len() - 1]).await.unwrap();
+        drop(server);
+
+        let error = alice.handle().await.unwrap_err();
+        assert!(
+            matches!(&error, Error::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof),
+            "{:?}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn a_frame_failing_its_checksum_is_skipped() {
+        let (address, greeted) = fake_server().await;
+        let alice = fake_client(address, &["--json-events"]).await;
+        let mut server = greeted.await.unwrap();
+        let mut events = alice.subscribe();
+        let announcement = ClientBoundMessage::ServerAnnouncement("hello".to_string());
+        let mut damaged = framing::encode_frame(WireFormat::Bincode, &announcement).unwrap();
+        *damaged.last_mut().unwrap() ^= 0x20;
+        server.write_all(&damaged).await.unwrap();
+        for message in [announcement, ClientBoundMessage::ServerShutdown] {
+            framing::write_frame(&mut server, WireFormat::Bincode, &message)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(alice.handle().await.unwrap(), Closed::Shutdown);
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            seen.push(event);
+        }
+        assert!(
+            matches!(&seen[..], [
+                ClientEvent::Warning(warning),
+                ClientEvent::ServerAnnouncement(text),
+                ClientEvent::ServerShutdown,
+            ] if warning.contains("checksum") && text == "hello"),
+            "{:?}",
+            seen
+        );
+    }
+
+    #[tokio::test]
+    async fn a_list_sent_in_parts_is_only_used_once_complete() {
+        let (address, greeted) = fake_server().await;
+        let alice = fake_client(address, &["--json-events"]).await;
+        let _server = greeted.await.unwrap();
+
+        let everyone: Vec<_> = (0..5000u128)
+            .map(|i| peer(&format!("peer{}", i), Uuid::from_u128(i + 1)))
+            .collect();
+        let mut chunks = everyone.chunks(500).peekable();
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_some() {
+                alice.dispatch(ClientBoundMessage::ClientListPart(chunk.to_vec())).await.unwrap();
+                assert!(alice.peers().await.is_empty(), "a partial list was used");
+            } else {
+                alice.dispatch(ClientBoundMessage::ClientList(chunk.to_vec())).await.unwrap();
+            }
+        }
+        assert_eq!(alice.peers().await, everyone);
+    }
+
+    #[tokio::test]
+    async fn a_broadcast_is_sealed_separately_for_each_peer() {
+        let (address, greeted) = fake_server().await;
+        let alice = fake_client(address, &["--json-events"]).await;
+        let mut server = greeted.await.unwrap();
+
+        // Open sessions with three peers, keeping their ends
+        let mut peers = HashMap::new();
+        for _ in 0..3 {
+            let uuid = Uuid::new_v4();
+            let key = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+            let (secret, local) =
+                crypto::new_handshake(&alice.private_key(), suite::DEFAULT_SUITE).unwrap();
+            let (peer_secret, remote) = crypto::new_handshake(&key, suite::DEFAULT_SUITE).unwrap();
+            let session_key = crypto::derive_session_key(&secret, &local, &remote).unwrap();
+            let ours = Session::new(key.public(), session_key, secret, &local, &remote).unwrap();
+            let session_key = crypto::derive_session_key(&peer_secret, &remote, &local).unwrap();
+            let alice_public = alice.public_key().as_ref().clone();
+            let theirs = Session::new(alice_public, session_key, peer_secret, &remote, &local).unwrap();
+            alice.open_connections.lock().await.insert(uuid, ours);
+            peers.insert(uuid, theirs);
+        }
+
+        alice.broadcast("hello everyone").await.unwrap();
+        let mut payloads = HashMap::new();
+        while payloads.len() < peers.len() {
+            let frame = framing::read_frame(&mut server).await.unwrap().unwrap();
+            let message = WireFormat::Bincode.decode(&frame).unwrap();
+            if let ServerBoundMessage::Message(to, payload) = message {
+                assert!(payloads.insert(to.uuid, payload).is_none(), "sent twice to one peer");
+            }
+        }
+
+        let ciphertexts: HashSet<_> = payloads.values().map(|payload| &payload.ciphertext).collect();
+        let nonces: HashSet<_> = payloads.values().map(|payload| &payload.nonce).collect();
+        assert_eq!((ciphertexts.len(), nonces.len()), (3, 3));
+        for (uuid, payload) in &payloads {
+            // Only the peer it was sealed for can open it
+            for (peer, session) in peers.iter_mut() {
+                assert_eq!(session.open(payload).is_ok(), peer == uuid);
+            }
+        }
+    }
 }
@@ -0,0 +1,511 @@
+use std::{
+    future::Future,
+    io::{IsTerminal, Write},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use super::{events::ClientEvent, transcript::Entry};
+use crate::shared::messages::Profile;
+
+/// Foreground colors used for sender names, picked by uuid
+const NAME_COLORS: &[u8] = &[31, 32, 33, 34, 35, 36, 91, 92, 93, 94, 95, 96];
+
+/// Frames of the spinner shown during slow startup work
+const SPINNER_FRAMES: &[char] = &['|', '/', '-', '\\'];
+
+/// Width used when the terminal size can't be determined
+const DEFAULT_WIDTH: usize = 80;
+
+/// Longest peer name shown, in characters. Longer ones end in `…`.
+const MAX_NAME_WIDTH: usize = 32;
+
+/// Longest part of a message quoted by a notice about it, in characters
+const MAX_EXCERPT_WIDTH: usize = 40;
+
+/// Takes what `say!` prints while the full-screen UI is up
+static PANE: Mutex<Option<mpsc::UnboundedSender<String>>> = Mutex::new(None);
+
+/// `println!`, except that while the full-screen UI is up the line goes to
+/// its message pane
+macro_rules! say {
+    () => {
+        $crate::client::output::write_line(String::new(), false)
+    };
+    ($($arg:tt)*) => {
+        $crate::client::output::write_line(format!($($arg)*), false)
+    };
+}
+pub(crate) use say;
+
+/// `eprintln!`, except that while the full-screen UI is up the line goes to
+/// its message pane
+macro_rules! say_error {
+    ($($arg:tt)*) => {
+        $crate::client::output::write_line(format!($($arg)*), true)
+    };
+}
+pub(crate) use say_error;
+
+/// Prints `line` to stdout, or stderr if it's an `error`, unless the
+/// full-screen UI has its pane open
+pub fn write_line(line: String, error: bool) {
+    if let Some(pane) = PANE.lock().unwrap().as_ref() {
+        let _ = pane.send(line);
+    } else if error {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Sends what `say!` prints to `pane` from now on, or with `None` back to
+/// the terminal
+pub fn set_pane(pane: Option<mpsc::UnboundedSender<String>>) {
+    *PANE.lock().unwrap() = pane;
+}
+
+/// Renders everything the client prints about conversations, so live
+/// messages and replayed history look the same
+#[derive(Clone, Copy, Debug)]
+pub struct Output {
+    color: bool,
+}
+
+impl Output {
+    /// Color is used only when it isn't disabled and stdout is a terminal
+    pub fn new(no_color: bool) -> Self {
+        Output {
+            color: !no_color && std::io::stdout().is_terminal(),
+        }
+    }
+
+    /// Formats a message as `[time] name: text`, wrapped to `width` columns
+    /// with continuation lines aligned under the text. Messages whose
+    /// signature didn't verify are prefixed with `UNVERIFIED`.
+    pub fn render_message(
+        &self,
+        sender: &str,
+        sender_uuid: Uuid,
+        time: DateTime<Local>,
+        text: &str,
+        verified: bool,
+        width: usize,
+    ) -> Vec<String> {
+        let timestamp = format!("[{}]", time.format("%H:%M:%S"));
+        let warning = if verified { "" } else { "UNVERIFIED " };
+        let prefix_width = timestamp.chars().count() + warning.len() + sender.chars().count() + 3;
+        let prefix = if self.color {
+            let warning = if verified {
+                String::new()
+            } else {
+                format!("\x1b[1;31m{}\x1b[0m", warning)
+            };
+            format!(
+                "\x1b[2m{}\x1b[0m {}\x1b[1;{}m{}\x1b[0m: ",
+                timestamp,
+                warning,
+                name_color(sender_uuid),
+                sender
+            )
+        } else {
+            format!("{} {}{}: ", timestamp, warning, sender)
+        };
+
+        let text_width = width.saturating_sub(prefix_width).max(20);
+        let indent = " ".repeat(prefix_width);
+        wrap(text, text_width)
+            .into_iter()
+            .enumerate()
+            .map(|(i, line)| {
+                if i == 0 {
+                    format!("{}{}", prefix, line)
+                } else {
+                    format!("{}{}", indent, line)
+                }
+            })
+            .collect()
+    }
+
+    /// Prints a message above the prompt
+    pub fn print_message(
+        &self,
+        sender: &str,
+        sender_uuid: Uuid,
+        time: DateTime<Local>,
+        text: &str,
+        verified: bool,
+    ) {
+        let lines =
+            self.render_message(sender, sender_uuid, time, text, verified, terminal_width());
+        // The prompt may have the terminal in raw mode, so return the carriage explicitly
+        say!("\r\n{}\r", lines.join("\r\n"));
+    }
+
+    /// Prints a message from the history the way it looked when it arrived
+    pub fn print_entry(&self, entry: &Entry) {
+        let peer_name = sanitize_name(&entry.peer_name);
+        let mut text = entry.text.clone();
+        if !entry.reactions.is_empty() {
+            let reactions: Vec<String> = entry
+                .reactions
+                .iter()
+                .map(|reaction| {
+                    let by = if reaction.outgoing { "you" } else { &peer_name };
+                    format!("{} {}", reaction.emoji, by)
+                })
+                .collect();
+            text.push_str(&format!(" [{}]", reactions.join(", ")));
+        }
+        if entry.outgoing {
+            self.print_sent(&peer_name, entry.time, &text);
+        } else {
+            self.print_message(&peer_name, entry.peer_uuid, entry.time, &text, entry.verified);
+        }
+    }
+
+    fn print_sent(&self, recipient: &str, time: DateTime<Local>, text: &str) {
+        // Our own messages all share one color, that of the nil uuid
+        let sender = format!("You → {}", recipient);
+        self.print_message(&sender, Uuid::nil(), time, text, true)
+    }
+
+    /// Prints an event from the connection above the prompt. Peer list
+    /// changes are only visible through `list`, so they print nothing.
+    pub fn print_event(&self, event: &ClientEvent) {
+        match event {
+            ClientEvent::PeerJoined(_)
+            | ClientEvent::PeerLeft(_)
+            | ClientEvent::PeerRenamed(..)
+            | ClientEvent::PresenceChanged(..)
+            | ClientEvent::ProfileChanged(..) => {}
+            ClientEvent::ConnectionRequested { pending, .. } => {
+                say!("\n\r\n {}\n\r", describe_pending(*pending))
+            }
+            ClientEvent::ConnectionAccepted(_) => {
+                say!("\n\r\n Connection accepted.Type 'open' again to choose channel.\n\r")
+            }
+            ClientEvent::ChannelClosed { name, .. } => {
+                say!("\n\r\n {} left the conversation.\n\r", name)
+            }
+            ClientEvent::DirectConnected { name, .. } => say!(
+                "\n\r\n Connected directly to {}, messages no longer pass through the server.\n\r",
+                name
+            ),
+            ClientEvent::DirectFailed { name, .. } => say!(
+                "\n\r\n Couldn't connect directly to {}, messages still go through the server.\n\r",
+                name
+            ),
+            ClientEvent::DirectClosed { name, .. } => say!(
+                "\n\r\n Direct connection to {} closed, messages go through the server again.\n\r",
+                name
+            ),
+            ClientEvent::RequestTimedOut { name, .. } => say!(
+                "\n\r\n No response from {} after {}s.\n\r",
+                name,
+                super::REQUEST_TIMEOUT.as_secs()
+            ),
+            ClientEvent::RecipientUnavailable { name, .. } => say!(
+                "\n\r\n {} is offline, so your message was not delivered.\n\r",
+                name
+            ),
+            ClientEvent::MessageLost { name, .. } => say!(
+                "\n\r\n Your message to {} was lost when the connection dropped. Send it again if it matters.\n\r",
+                name
+            ),
+            ClientEvent::RequestRejected { name, .. } => say!(
+                "\n\r\n {} turned your connection request away. They may have too many waiting, or you asked again too soon.\n\r",
+                name
+            ),
+            ClientEvent::RequestExpired(from) => say!(
+                "\n\r\n The connection request from {} has expired.\n\r",
+                from.display_name()
+            ),
+            ClientEvent::RequestWithdrawn(from) => say!(
+                "\n\r\n {} cancelled their connection request.\n\r",
+                from.display_name()
+            ),
+            ClientEvent::MessageReceived {
+                from,
+                name,
+                text,
+                verified,
+                time,
+            } => self.print_message(name, from.uuid, *time, text, *verified),
+            ClientEvent::MessageSent {
+                name, text, time, ..
+            } => self.print_sent(name, *time, text),
+            ClientEvent::MessageExpired { name, .. } => {
+                say!("\n\r\n {}: (message expired)\n\r", name)
+            }
+            ClientEvent::MessageSeen(by) => {
+                say!("\n\r\n {} has seen your message.\n\r", by.display_name())
+            }
+            ClientEvent::ReactionReceived {
+                name, emoji, text, ..
+            } => match text {
+                Some(text) => say!(
+                    "\n\r\n {} reacted {} to \"{}\"\n\r",
+                    name,
+                    emoji,
+                    excerpt(text)
+                ),
+                None => say!("\n\r\n {} reacted {} to a message.\n\r", name, emoji),
+            },
+            ClientEvent::FileOffered { from, name, size, .. } => say!(
+                "\n\r\n {} wants to send you {} ({} bytes). Type 'acceptfile' to accept or decline it.\n\r",
+                from.display_name(),
+                name,
+                size
+            ),
+            ClientEvent::FileTooLarge { from, name, size } => say!(
+                "\n\r\n Declined {} from {}: it's {} bytes, the limit is {}.\n\r",
+                name,
+                from.display_name(),
+                size,
+                super::files::MAX_FILE_SIZE
+            ),
+            ClientEvent::FileDeclined { by, path } => say!(
+                "\n\r\n {} declined {}.\n\r",
+                by.display_name(),
+                path.display()
+            ),
+            ClientEvent::FileSent { to, path } => say!(
+                "\n\r\n Sent {} to {}.\n\r",
+                path.display(),
+                to.display_name()
+            ),
+            ClientEvent::FileSaved { from, path } => say!(
+                "\n\r\n Saved {} from {}.\n\r",
+                path.display(),
+                from.display_name()
+            ),
+            ClientEvent::FileFailed { name, error } => say!(
+                "\n\r\n Receiving {} failed ({}), the partial file was deleted.\n\r",
+                name, error
+            ),
+            ClientEvent::Warning(warning) => say_error!("\n\r\n {}\n\r", warning),
+            ClientEvent::NameTaken(name) => {
+                say_error!("\n\r\n The name {} is already taken on this server.\n\r", name)
+            }
+            ClientEvent::ServerShutdown => say!("\n\r\n Server is shutting down.\n\r"),
+            ClientEvent::ServerAnnouncement(text) if self.color => {
+                say!("\n\r\n \x1b[1m[SERVER] {}\x1b[0m\n\r", text)
+            }
+            ClientEvent::ServerAnnouncement(text) => say!("\n\r\n [SERVER] {}\n\r", text),
+            ClientEvent::ServerInfo {
+                version,
+                uptime_secs,
+                connected_clients,
+            } => say!(
+                "\n\r\n Server version {} (this client is {}), up for {}, {} client{} connected\n\r",
+                version,
+                env!("CARGO_PKG_VERSION"),
+                describe_uptime(*uptime_secs),
+                connected_clients,
+                if *connected_clients == 1 { "" } else { "s" }
+            ),
+            ClientEvent::Disconnected => {
+                say!("\n\r\n The server closed the connection.\n\r")
+            }
+            ClientEvent::Reconnecting => say!("\n\r\n Disconnected, reconnecting...\n\r"),
+            ClientEvent::Reconnected { resumed: true } => {
+                say!("\n\r\n Reconnected to the server.\n\r")
+            }
+            ClientEvent::Reconnected { resumed: false } => say!(
+                "\n\r\n Reconnected to the server. Open conversations were closed, so reopen them with 'open'.\n\r"
+            ),
+        }
+    }
+}
+
+/// The start of a message, cut to `MAX_EXCERPT_WIDTH` on one line, for
+/// notices that refer back to it
+pub fn excerpt(text: &str) -> String {
+    let line = text.split('\n').next().unwrap_or_default();
+    if line.chars().count() > MAX_EXCERPT_WIDTH || line.len() < text.len() {
+        let mut cut: String = line.chars().take(MAX_EXCERPT_WIDTH - 1).collect();
+        cut.push('…');
+        cut
+    } else {
+        line.to_string()
+    }
+}
+
+/// Formats a number of seconds as the two largest units, e.g. `3d 4h`,
+/// `12m 5s`
+/// The notice for a new connection request, counting every one waiting
+fn describe_pending(pending: usize) -> String {
+    format!(
+        "You have {} pending connection request{}. Type 'accept' to view and accept {}.",
+        pending,
+        if pending == 1 { "" } else { "s" },
+        if pending == 1 { "it" } else { "them" }
+    )
+}
+
+fn describe_uptime(secs: u64) -> String {
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = units.iter().position(|(n, _)| *n > 0).unwrap_or(units.len() - 1);
+    units[first..]
+        .iter()
+        .take(2)
+        .map(|(n, unit)| format!("{}{}", n, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Awaits `future`, animating `label` on stderr meanwhile if it's a terminal
+pub async fn spinner<F: Future>(label: &str, future: F) -> F::Output {
+    if !std::io::stderr().is_terminal() {
+        return future.await;
+    }
+
+    tokio::pin!(future);
+    let mut ticks = tokio::time::interval(Duration::from_millis(100));
+    let mut frames = SPINNER_FRAMES.iter().cycle();
+    let output = loop {
+        tokio::select! {
+            output = &mut future => break output,
+            _ = ticks.tick() => {
+                eprint!("\r{} {}...", frames.next().unwrap(), label);
+                let _ = std::io::stderr().flush();
+            }
+        }
+    };
+    // Clear the spinner line
+    eprint!("\r{}\r", " ".repeat(label.chars().count() + 5));
+    output
+}
+
+/// A name with the profile around it, as in "🐢 alice — brb"
+pub fn describe_profile(name: &str, profile: &Profile) -> String {
+    let mut described = String::new();
+    if let Some(emoji) = &profile.emoji {
+        described.push_str(emoji);
+        described.push(' ');
+    }
+    described.push_str(name);
+    if let Some(text) = &profile.status_text {
+        described.push_str(" — ");
+        described.push_str(text);
+    }
+    described
+}
+
+/// Makes a name another client chose safe to print: escape sequences and
+/// control characters are removed so it can't break the line or recolor the
+/// terminal, and it's cut to `MAX_NAME_WIDTH`
+pub fn sanitize_name(name: &str) -> String {
+    let clean = sanitize_text(name);
+    let clean = clean.trim();
+    if clean.chars().count() > MAX_NAME_WIDTH {
+        let mut cut: String = clean.chars().take(MAX_NAME_WIDTH - 1).collect();
+        cut.push('…');
+        cut
+    } else {
+        clean.to_string()
+    }
+}
+
+/// Removes escape sequences and control characters from text someone else
+/// wrote, so printing it can't break the line or recolor the terminal
+pub fn sanitize_text(text: &str) -> String {
+    let mut clean = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.next_if_eq(&'[').is_some() {
+            // A CSI sequence runs up to its final byte
+            while chars.next().is_some_and(|c| !('@'..='~').contains(&c)) {}
+        } else if !c.is_control() && !is_bidi_control(c) {
+            clean.push(c);
+        }
+    }
+    clean
+}
+
+/// Characters that reorder the text around them, which could make a name
+/// read as something else
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+fn name_color(uuid: Uuid) -> u8 {
+    NAME_COLORS[(uuid.as_u128() % NAME_COLORS.len() as u128) as usize]
+}
+
+fn terminal_width() -> usize {
+    crossterm::terminal::size()
+        .map(|(columns, _)| columns as usize)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// Greedy word wrap; words longer than `width` are split
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut line_len = 0;
+        for word in paragraph.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            while word.len() > width {
+                if line_len > 0 {
+                    lines.push(std::mem::take(&mut line));
+                    line_len = 0;
+                }
+                lines.push(word.drain(..width).collect());
+            }
+            if line_len > 0 && line_len + 1 + word.len() > width {
+                lines.push(std::mem::take(&mut line));
+                line_len = 0;
+            }
+            if line_len > 0 {
+                line.push(' ');
+                line_len += 1;
+            }
+            line_len += word.len();
+            line.extend(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_request_notice_counts_what_is_pending() {
+        assert_eq!(
+            describe_pending(1),
+            "You have 1 pending connection request. Type 'accept' to view and accept it."
+        );
+        assert_eq!(
+            describe_pending(3),
+            "You have 3 pending connection requests. Type 'accept' to view and accept them."
+        );
+    }
+
+    #[test]
+    fn a_name_cant_forge_a_line_of_its_own() {
+        let name = sanitize_name("mallory\n admin: the server is moving, send your key");
+        assert!(!name.contains(['\n', '\r']));
+        assert!(name.starts_with("mallory admin:"), "{}", name);
+        // Cut short, so it can't pass for a whole message either
+        assert_eq!(name.chars().count(), MAX_NAME_WIDTH);
+        assert!(name.ends_with('…'));
+
+        assert_eq!(sanitize_name("\x1b[2K\radmin\x1b[31m"), "admin");
+        assert_eq!(sanitize_name("eve\u{202e}nimda"), "evenimda");
+    }
+}
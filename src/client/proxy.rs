@@ -0,0 +1,98 @@
+use std::{fmt, str::FromStr};
+
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+
+use crate::shared::{Error, Result};
+
+/// A SOCKS5 proxy given as `socks5://[user:password@]host:port`.
+///
+/// The server's hostname is passed to the proxy unresolved, so it's looked
+/// up by the proxy rather than locally. That's what lets Tor reach `.onion`
+/// addresses, and keeps the lookup from leaking outside the proxy.
+#[derive(Clone)]
+pub struct Proxy {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Opens a connection to `host:port` through the proxy
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let proxy = (self.host.as_str(), self.port);
+        let stream = match &self.credentials {
+            Some((username, password)) => {
+                Socks5Stream::connect_with_password(proxy, (host, port), username, password).await
+            }
+            None => Socks5Stream::connect(proxy, (host, port)).await,
+        };
+        stream.map(Socks5Stream::into_inner).map_err(|e| match e {
+            // Leave I/O failures as I/O errors so reconnecting retries them
+            tokio_socks::Error::Io(e) => Error::Io(e),
+            tokio_socks::Error::ProxyServerUnreachable
+            | tokio_socks::Error::GeneralSocksServerFailure
+            | tokio_socks::Error::NetworkUnreachable
+            | tokio_socks::Error::HostUnreachable
+            | tokio_socks::Error::ConnectionRefused
+            | tokio_socks::Error::TtlExpired => Error::Io(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("SOCKS5 proxy {}: {}", self, e),
+            )),
+            e => Error::Protocol(format!("SOCKS5 proxy {}: {}", self, e)),
+        })
+    }
+}
+
+/// Shows the proxy without its credentials
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Proxy({})", self)
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(url: &str) -> std::result::Result<Self, Self::Err> {
+        let Some(rest) = url.strip_prefix("socks5://") else {
+            return Err("expected a socks5://host:port URL".to_string());
+        };
+        let (credentials, address) = match rest.rsplit_once('@') {
+            Some((credentials, address)) => {
+                let Some((username, password)) = credentials.split_once(':') else {
+                    return Err("credentials must be given as user:password".to_string());
+                };
+                (Some((username.to_string(), password.to_string())), address)
+            }
+            None => (None, rest),
+        };
+        let address = address.trim_end_matches('/');
+        let Some((host, port)) = address.rsplit_once(':') else {
+            return Err("the proxy address needs a port".to_string());
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err("the proxy address needs a host".to_string());
+        }
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid proxy port: {}", port))?;
+
+        Ok(Proxy {
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+}
@@ -0,0 +1,61 @@
+use std::{path::Path, time::Duration};
+
+use super::{Action, Client};
+use crate::shared::{Error, Result};
+
+/// Actions that ask which peer or file they're for when given no argument,
+/// which a script can't answer
+const PROMPTING: &[&str] = &["open", "accept", "acceptfile"];
+
+impl Client {
+    /// Runs the actions in the file at `path` as if typed at the prompt, one
+    /// per line, printing events as `run_ui` would. Stops at the end of the
+    /// file or at `exit`.
+    ///
+    /// `sleep <ms>` waits, giving peers time to answer, and lines starting
+    /// with `#` are comments. The first action to fail stops the script,
+    /// naming its line.
+    pub async fn run_script(&self, path: &Path) -> Result<()> {
+        let script = tokio::fs::read_to_string(path).await.map_err(|e| {
+            Error::Protocol(format!("couldn't read the script {}: {}", path.display(), e))
+        })?;
+        self.print_events();
+
+        for (number, line) in script.lines().enumerate() {
+            let action = line.trim();
+            if action.is_empty() || action.starts_with('#') {
+                continue;
+            }
+            match self.run_script_line(action).await {
+                Ok(Action::Exit) => break,
+                Ok(Action::Continue) => {}
+                Err(e) => {
+                    eprintln!("Script stopped at line {}: {}", number + 1, action);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one line of a script that isn't blank or a comment
+    async fn run_script_line(&self, action: &str) -> Result<Action> {
+        if let Some(ms) = action.strip_prefix("sleep ") {
+            let ms = ms
+                .trim()
+                .parse()
+                .map_err(|_| Error::Protocol(format!("invalid duration: {}", ms)))?;
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            return Ok(Action::Continue);
+        }
+        if PROMPTING.contains(&action) {
+            return Err(Error::Protocol(format!(
+                "`{}` would ask which one, which a script can't answer",
+                action
+            )));
+        }
+
+        println!("> {}", action);
+        self.handle_action(action).await
+    }
+}
@@ -0,0 +1,442 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use serde::de::DeserializeOwned;
+use x25519_dalek::ReusableSecret;
+
+use crate::shared::{
+    crypto::{self, IdentityKey, PublicIdentity, SessionKey},
+    framing,
+    messages::{EncryptedPayload, FileOffer, Handshake, RatchetHeader},
+    suite::SuiteId,
+    Error, Result,
+};
+
+use super::{
+    chunks::Reassembler,
+    files::{IncomingFile, OutgoingFile},
+};
+
+/// How many sent message ids to remember while waiting for read receipts
+const MAX_AWAITING_RECEIPT: usize = 64;
+
+/// Payloads sealed in one ratchet epoch before we rotate
+const ROTATE_AFTER_MESSAGES: u32 = 1000;
+
+/// Longest an epoch lasts before we rotate
+const ROTATE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Furthest a payload's index may be ahead of the next one we expect. Each
+/// step is two HKDFs and leaves a key behind, so this bounds the work and
+/// memory a bogus index can cause.
+const MAX_SKIP: u32 = 256;
+
+/// Keys kept for payloads that were skipped over, in case they turn up late
+/// after ones sent after them, as when a direct link opens mid-conversation
+const MAX_SKIPPED_KEYS: usize = 256;
+
+/// Ratchet keys of ours kept after we rotate, since the peer keeps answering
+/// to an older one until our new one reaches it
+const OWN_RATCHET_KEYS_KEPT: usize = 4;
+
+/// An open connection to a peer
+pub struct Session {
+    /// The peer's identity key, used to verify its message signatures
+    pub public_key: PublicIdentity,
+    /// Whether the user compared the key's fingerprint with the peer's
+    /// using `verify`. Until then the server could have substituted its own.
+    pub key_verified: bool,
+    /// Whether the user chose to send to the key before verifying it
+    pub send_unverified: bool,
+    /// Cipher suite agreed in the handshake. Payloads naming another are
+    /// refused, so nobody can switch a session to a weaker one.
+    suite: SuiteId,
+    /// Root and chain keys of what we send. Every payload takes the next key
+    /// from the chain. Every `ROTATE_AFTER_MESSAGES` or `ROTATE_AFTER`,
+    /// whichever comes first, a Diffie-Hellman step with a fresh ratchet key
+    /// of ours starts a new epoch with a new chain.
+    send_root: SessionKey,
+    send_chain: SessionKey,
+    send_epoch: u32,
+    send_index: u32,
+    /// How many payloads the epoch before this one sealed
+    send_previous_len: u32,
+    send_epoch_started: Instant,
+    /// Our ratchet keys, newest first. The newest is the one we send with.
+    own_ratchet_keys: VecDeque<(ReusableSecret, [u8; 32])>,
+    /// Newest ratchet key the peer sent with, used for our next rotation
+    peer_ratchet_key: [u8; 32],
+    /// The ratchet key of ours that `peer_ratchet_key` was combined with
+    send_peer_ratchet_key: [u8; 32],
+    /// Root and chain keys of what the peer sends, and where in its ratchet
+    /// the next payload is expected
+    recv_root: SessionKey,
+    recv_chain: SessionKey,
+    recv_epoch: u32,
+    recv_index: u32,
+    /// Keys for payloads skipped over, by epoch and index, oldest first.
+    /// Each is removed when used, so a replay finds nothing to open with.
+    skipped: VecDeque<((u32, u32), SessionKey)>,
+    /// Counter of the last message we sent
+    send_counter: u64,
+    /// Chunks of long messages that haven't fully arrived yet
+    pub reassembler: Reassembler,
+    /// Ids of recently sent messages the peer hasn't acknowledged reading
+    awaiting_receipt: VecDeque<u64>,
+    /// Id of the peer's latest message, which `react` reacts to
+    pub last_received: Option<u64>,
+    /// Files we offered, by offer id, waiting for the peer to answer
+    pub offered_files: HashMap<u64, PathBuf>,
+    /// Files the peer accepted that are still being sent
+    pub outgoing_files: HashMap<u64, OutgoingFile>,
+    /// Files the peer offered that we haven't answered yet
+    pub file_offers: HashMap<u64, FileOffer>,
+    /// Accepted files still arriving
+    pub incoming_files: HashMap<u64, IncomingFile>,
+}
+
+impl Session {
+    /// Starts the ratchets from the key agreed in the handshake. Our
+    /// handshake secret becomes our first ratchet key.
+    pub fn new(
+        public_key: PublicIdentity,
+        key: SessionKey,
+        secret: ReusableSecret,
+        local: &Handshake,
+        remote: &Handshake,
+    ) -> Result<Self> {
+        let lower = local.ephemeral_key < remote.ephemeral_key;
+        let (send_root, send_chain) = crypto::first_chain(&key, lower)?;
+        let (recv_root, recv_chain) = crypto::first_chain(&key, !lower)?;
+        Ok(Session {
+            public_key,
+            key_verified: false,
+            send_unverified: false,
+            suite: local.suite,
+            send_root,
+            send_chain,
+            send_epoch: 0,
+            send_index: 0,
+            send_previous_len: 0,
+            send_epoch_started: Instant::now(),
+            own_ratchet_keys: VecDeque::from([(secret, local.ephemeral_key)]),
+            peer_ratchet_key: remote.ephemeral_key,
+            send_peer_ratchet_key: remote.ephemeral_key,
+            recv_root,
+            recv_chain,
+            recv_epoch: 0,
+            recv_index: 0,
+            skipped: VecDeque::new(),
+            send_counter: 0,
+            reassembler: Reassembler::default(),
+            awaiting_receipt: VecDeque::new(),
+            last_received: None,
+            offered_files: HashMap::new(),
+            outgoing_files: HashMap::new(),
+            file_offers: HashMap::new(),
+            incoming_files: HashMap::new(),
+        })
+    }
+
+    /// Encrypts and signs the next payload to the peer
+    pub fn seal(
+        &mut self,
+        private_key: &IdentityKey,
+        plaintext: &[u8],
+    ) -> Result<EncryptedPayload> {
+        if self.send_index >= ROTATE_AFTER_MESSAGES
+            || self.send_epoch_started.elapsed() >= ROTATE_AFTER
+        {
+            self.rotate()?;
+        }
+        let (next_chain, message_key) = crypto::chain_step(&self.send_chain)?;
+        let ratchet = RatchetHeader {
+            epoch: self.send_epoch,
+            ratchet_key: self.own_ratchet_keys[0].1,
+            peer_ratchet_key: self.send_peer_ratchet_key,
+            index: self.send_index,
+            previous_len: self.send_previous_len,
+        };
+        self.send_chain = next_chain;
+        self.send_index += 1;
+        self.send_counter += 1;
+        crypto::encrypt(
+            &message_key,
+            private_key,
+            self.suite,
+            self.send_counter,
+            ratchet,
+            plaintext,
+        )
+    }
+
+    /// Starts a new send epoch from a fresh ratchet key and the peer's newest
+    fn rotate(&mut self) -> Result<()> {
+        let (secret, ratchet_key) = crypto::new_ratchet_key(self.suite)?;
+        let shared = crypto::agree(self.suite, &secret, &self.peer_ratchet_key)?;
+        (self.send_root, self.send_chain) = crypto::ratchet_step(&self.send_root, &shared)?;
+        self.send_epoch += 1;
+        self.send_previous_len = self.send_index;
+        self.send_index = 0;
+        self.send_epoch_started = Instant::now();
+        self.send_peer_ratchet_key = self.peer_ratchet_key;
+        self.own_ratchet_keys.push_front((secret, ratchet_key));
+        self.own_ratchet_keys.truncate(OWN_RATCHET_KEYS_KEPT);
+        Ok(())
+    }
+
+    /// Decrypts a payload from the peer and rejects replays. Payloads may
+    /// arrive out of order within `MAX_SKIP` of each other. Also returns
+    /// whether the signature verified.
+    pub fn open(&mut self, payload: &EncryptedPayload) -> Result<(Vec<u8>, bool)> {
+        if payload.suite != self.suite {
+            return Err(Error::Crypto(format!(
+                "it used cipher suite {}, not the session's {}",
+                payload.suite, self.suite
+            )));
+        }
+        let header = payload.ratchet;
+        let position = (header.epoch, header.index);
+        if header.epoch < self.recv_epoch
+            || (header.epoch == self.recv_epoch && header.index < self.recv_index)
+        {
+            let Some(found) = self.skipped.iter().position(|(at, _)| *at == position) else {
+                return Err(Error::Crypto(
+                    "it was a replay, or its key has been discarded".to_string(),
+                ));
+            };
+            let plaintext = crypto::decrypt(&self.skipped[found].1, payload)?;
+            self.skipped.remove(found);
+            return Ok((plaintext, crypto::verify_payload(&self.public_key, payload)));
+        }
+
+        // Work on copies, and only keep them once the payload has decrypted
+        let mut root = self.recv_root.clone();
+        let mut chain = self.recv_chain.clone();
+        let mut index = self.recv_index;
+        let mut skipped = Vec::new();
+        if header.epoch == self.recv_epoch + 1 {
+            skip_to(self.recv_epoch, &mut chain, &mut index, header.previous_len, &mut skipped)?;
+            let Some((secret, _)) = self
+                .own_ratchet_keys
+                .iter()
+                .find(|(_, public)| *public == header.peer_ratchet_key)
+            else {
+                return Err(Error::Crypto(
+                    "it answered a ratchet key of ours that has been discarded".to_string(),
+                ));
+            };
+            let shared = crypto::agree(self.suite, secret, &header.ratchet_key)?;
+            (root, chain) = crypto::ratchet_step(&root, &shared)?;
+            index = 0;
+        } else if header.epoch != self.recv_epoch {
+            return Err(Error::Crypto(format!(
+                "its ratchet epoch {} is too far ahead of {}",
+                header.epoch, self.recv_epoch
+            )));
+        }
+        skip_to(header.epoch, &mut chain, &mut index, header.index, &mut skipped)?;
+        let (next_chain, message_key) = crypto::chain_step(&chain)?;
+
+        let plaintext = crypto::decrypt(&message_key, payload)?;
+        self.recv_root = root;
+        self.recv_chain = next_chain;
+        self.recv_epoch = header.epoch;
+        self.recv_index = index + 1;
+        self.peer_ratchet_key = header.ratchet_key;
+        self.skipped.extend(skipped);
+        while self.skipped.len() > MAX_SKIPPED_KEYS {
+            self.skipped.pop_front();
+        }
+        Ok((plaintext, crypto::verify_payload(&self.public_key, payload)))
+    }
+
+    /// Like `open`, but also requires a valid signature and decodes the
+    /// plaintext. Used for file transfers, where unverified data is dropped
+    /// rather than shown with a warning.
+    pub fn open_signed<T: DeserializeOwned>(&mut self, payload: &EncryptedPayload) -> Result<T> {
+        let (plaintext, verified) = self.open(payload)?;
+        if !verified {
+            return Err(Error::Crypto("signature didn't verify".to_string()));
+        }
+        framing::from_bincode(&plaintext)
+    }
+
+    /// Remembers a sent message so a later read receipt for it is recognized
+    pub fn expect_receipt(&mut self, message_id: u64) {
+        if self.awaiting_receipt.len() == MAX_AWAITING_RECEIPT {
+            self.awaiting_receipt.pop_front();
+        }
+        self.awaiting_receipt.push_back(message_id);
+    }
+
+    /// Returns whether `message_id` was waiting for a receipt, forgetting it
+    pub fn take_receipt(&mut self, message_id: u64) -> bool {
+        let Some(index) = self
+            .awaiting_receipt
+            .iter()
+            .position(|id| *id == message_id)
+        else {
+            return false;
+        };
+        self.awaiting_receipt.remove(index);
+        true
+    }
+}
+
+/// Steps `chain` from `index` up to `target` in `epoch`, keeping the key of
+/// each payload passed over in `skipped`
+fn skip_to(
+    epoch: u32,
+    chain: &mut SessionKey,
+    index: &mut u32,
+    target: u32,
+    skipped: &mut Vec<((u32, u32), SessionKey)>,
+) -> Result<()> {
+    if target.saturating_sub(*index) > MAX_SKIP {
+        return Err(Error::Crypto(format!(
+            "its index {} is too far ahead of {}",
+            target, index
+        )));
+    }
+    while *index < target {
+        let (next_chain, message_key) = crypto::chain_step(chain)?;
+        skipped.push(((epoch, *index), message_key));
+        *chain = next_chain;
+        *index += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shared::{crypto::KeyType, suite::DEFAULT_SUITE};
+
+    use super::*;
+
+    /// Two ends of a session, as after a handshake, with the identity keys
+    /// they seal with
+    fn pair() -> ((Session, IdentityKey), (Session, IdentityKey)) {
+        let alice = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+        let bob = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+        let (alice_secret, alice_handshake) = crypto::new_handshake(&alice, DEFAULT_SUITE).unwrap();
+        let (bob_secret, bob_handshake) = crypto::new_handshake(&bob, DEFAULT_SUITE).unwrap();
+        let alice_key =
+            crypto::derive_session_key(&alice_secret, &alice_handshake, &bob_handshake).unwrap();
+        let bob_key = crypto::derive_session_key(&bob_secret, &bob_handshake, &alice_handshake).unwrap();
+        let alice_session = Session::new(
+            bob.public(),
+            alice_key,
+            alice_secret,
+            &alice_handshake,
+            &bob_handshake,
+        )
+        .unwrap();
+        let bob_session =
+            Session::new(alice.public(), bob_key, bob_secret, &bob_handshake, &alice_handshake).unwrap();
+        ((alice_session, alice), (bob_session, bob))
+    }
+
+    #[test]
+    fn a_replayed_payload_is_refused_the_second_time() {
+        let ((mut alice, alice_key), (mut bob, _)) = pair();
+        let payload = alice.seal(&alice_key, b"hello").unwrap();
+        assert_eq!(bob.open(&payload).unwrap(), (b"hello".to_vec(), true));
+        assert!(bob.open(&payload).is_err());
+
+        // The session carries on after the replay
+        let next = alice.seal(&alice_key, b"again").unwrap();
+        assert_eq!(bob.open(&next).unwrap().0, b"again");
+        assert!(bob.open(&payload).is_err());
+    }
+
+    #[test]
+    fn every_payload_takes_a_new_key_and_both_chains_advance() {
+        let ((mut alice, alice_key), (mut bob, _)) = pair();
+        let mut chains = vec![alice.send_chain.clone()];
+        let mut payloads = Vec::new();
+        for _ in 0..3 {
+            payloads.push(alice.seal(&alice_key, b"same").unwrap());
+            chains.push(alice.send_chain.clone());
+        }
+        let distinct: std::collections::HashSet<_> = chains.iter().map(|chain| **chain).collect();
+        assert_eq!(distinct.len(), chains.len(), "the send chain stood still");
+        assert_ne!(payloads[0].ciphertext, payloads[1].ciphertext);
+
+        for (index, payload) in payloads.iter().enumerate() {
+            assert_eq!(payload.ratchet.index, index as u32);
+            bob.open(payload).unwrap();
+            assert_eq!(bob.recv_chain, chains[index + 1]);
+        }
+    }
+
+    #[test]
+    fn payloads_out_of_order_within_the_window_still_open() {
+        let ((mut alice, alice_key), (mut bob, _)) = pair();
+        let payloads: Vec<_> = (0..5)
+            .map(|i| alice.seal(&alice_key, format!("message {}", i).as_bytes()).unwrap())
+            .collect();
+        for i in [3, 0, 4, 2, 1] {
+            let (plaintext, verified) = bob.open(&payloads[i]).unwrap();
+            assert_eq!((plaintext, verified), (format!("message {}", i).into_bytes(), true));
+        }
+        assert!(bob.skipped.is_empty(), "every skipped key should have been used");
+
+        // Too far ahead to step to
+        alice.send_index += MAX_SKIP + 1;
+        let far_ahead = alice.seal(&alice_key, b"far").unwrap();
+        assert!(bob.open(&far_ahead).is_err());
+    }
+
+    #[test]
+    fn only_the_expected_receipt_id_matches() {
+        let ((mut alice, _), _) = pair();
+        alice.expect_receipt(5);
+        alice.expect_receipt(6);
+        assert!(!alice.take_receipt(7));
+        assert!(alice.take_receipt(6));
+        assert!(!alice.take_receipt(6));
+        assert!(alice.take_receipt(5));
+    }
+
+    #[test]
+    fn an_epoch_is_reused_up_to_its_limit_then_rotated() {
+        let ((mut alice, alice_key), (mut bob, _)) = pair();
+        // As if most of the epoch had gone already. The index only labels
+        // where each end has got to in the chain.
+        alice.send_index = ROTATE_AFTER_MESSAGES - 2;
+        bob.recv_index = ROTATE_AFTER_MESSAGES - 2;
+        let reused = alice.seal(&alice_key, b"reused").unwrap();
+        let last = alice.seal(&alice_key, b"last").unwrap();
+        assert_eq!((last.ratchet.epoch, last.ratchet.index), (0, ROTATE_AFTER_MESSAGES - 1));
+        assert_eq!(last.ratchet.ratchet_key, reused.ratchet.ratchet_key);
+
+        let rotated = alice.seal(&alice_key, b"rotated").unwrap();
+        assert_eq!((rotated.ratchet.epoch, rotated.ratchet.index), (1, 0));
+        assert_ne!(rotated.ratchet.ratchet_key, reused.ratchet.ratchet_key);
+        assert_eq!(rotated.ratchet.previous_len, ROTATE_AFTER_MESSAGES);
+
+        for (payload, text) in [(reused, "reused"), (last, "last"), (rotated, "rotated")] {
+            assert_eq!(bob.open(&payload).unwrap().0, text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn an_epoch_rotates_once_it_is_old_enough() {
+        let ((mut alice, alice_key), (mut bob, _)) = pair();
+        let fresh = alice.seal(&alice_key, b"fresh").unwrap();
+        alice.send_epoch_started -= ROTATE_AFTER - Duration::from_secs(1);
+        let still = alice.seal(&alice_key, b"still fresh").unwrap();
+        assert_eq!(still.ratchet.epoch, 0);
+
+        alice.send_epoch_started -= Duration::from_secs(1);
+        let rotated = alice.seal(&alice_key, b"rotated").unwrap();
+        assert_eq!((rotated.ratchet.epoch, rotated.ratchet.index), (1, 0));
+        for payload in [fresh, still, rotated] {
+            bob.open(&payload).unwrap();
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::shared::{
+    crypto::{self, SessionKey},
+    framing, Error, Result,
+};
+
+/// Start of every history file, followed by the passphrase salt
+const MAGIC: &[u8; 8] = b"YCNBHIS2";
+
+/// Start of history files from before reactions, whose records are bare
+/// `LegacyEntry`s. They're rewritten in the current format when opened.
+const LEGACY_MAGIC: &[u8; 8] = b"YCNBHIS1";
+
+const SALT_LEN: usize = 16;
+
+/// Sealed as the first record, so a wrong passphrase is caught on opening
+/// even when no messages have been saved yet
+const CHECK: &[u8] = b"ycnbts-history-v1";
+
+/// One message sent or received in a conversation
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// The peer's name at the time. Uuids change with every connection, so
+    /// this is what finds a conversation again in later sessions.
+    pub peer_name: String,
+    pub peer_uuid: Uuid,
+    /// Whether we sent it rather than received it
+    pub outgoing: bool,
+    pub text: String,
+    pub verified: bool,
+    pub time: DateTime<Local>,
+    /// Id the message was sent with, which reactions refer to. `None` for
+    /// messages kept before reactions existed.
+    pub message_id: Option<u64>,
+    /// At most one from each side, the latest
+    pub reactions: Vec<Reaction>,
+}
+
+/// An emoji one side of a conversation put on a message
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reaction {
+    /// Whether we reacted rather than the peer
+    pub outgoing: bool,
+    pub emoji: String,
+}
+
+/// An entry as kept before reactions existed
+#[derive(Deserialize)]
+struct LegacyEntry {
+    peer_name: String,
+    peer_uuid: Uuid,
+    outgoing: bool,
+    text: String,
+    verified: bool,
+    time: DateTime<Local>,
+}
+
+impl From<LegacyEntry> for Entry {
+    fn from(legacy: LegacyEntry) -> Self {
+        Entry {
+            peer_name: legacy.peer_name,
+            peer_uuid: legacy.peer_uuid,
+            outgoing: legacy.outgoing,
+            text: legacy.text,
+            verified: legacy.verified,
+            time: legacy.time,
+            message_id: None,
+            reactions: Vec::new(),
+        }
+    }
+}
+
+/// What each record in a history file holds. Reactions arrive after their
+/// messages are written, so they're records of their own.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Message(Entry),
+    Reaction {
+        peer_uuid: Uuid,
+        message_id: u64,
+        reaction: Reaction,
+    },
+}
+
+impl Entry {
+    /// Whether this belongs to the conversation with `uuid`, or with a peer
+    /// named `name` in an earlier session
+    pub fn is_with(&self, uuid: Option<Uuid>, name: &str) -> bool {
+        Some(self.peer_uuid) == uuid || (!name.is_empty() && self.peer_name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Messages from every conversation this session, and from earlier ones
+/// when kept in an encrypted history file
+pub struct Transcript {
+    entries: Vec<Entry>,
+    file: Option<HistoryFile>,
+}
+
+/// An open history file, and what's needed to seal records for it or
+/// write it out again
+struct HistoryFile {
+    file: File,
+    key: SessionKey,
+    salt: [u8; SALT_LEN],
+    path: PathBuf,
+}
+
+impl Transcript {
+    /// Keeps history in memory only, for this session
+    pub fn in_memory() -> Self {
+        Transcript {
+            entries: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Opens the history file at `path`, creating it if it doesn't exist,
+    /// and decrypts everything in it. Fails without changing anything if
+    /// `passphrase` isn't the one the file was created with.
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self> {
+        let mut contents = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut contents)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => return Self::create(path, passphrase),
+            Err(e) => return Err(e.into()),
+        }
+
+        let (legacy, rest) = if let Some(rest) = contents.strip_prefix(MAGIC.as_slice()) {
+            (false, rest)
+        } else if let Some(rest) = contents.strip_prefix(LEGACY_MAGIC.as_slice()) {
+            (true, rest)
+        } else {
+            return Err(Error::Protocol(format!(
+                "{} isn't a history file",
+                path.display()
+            )));
+        };
+        if rest.len() < SALT_LEN {
+            return Err(Error::Protocol(format!("{} is truncated", path.display())));
+        }
+        let (salt, mut records) = rest.split_at(SALT_LEN);
+        let salt: [u8; SALT_LEN] = salt.try_into().unwrap();
+        let key = crypto::passphrase_key(passphrase, &salt)?;
+
+        let check = next_record(&mut records)
+            .ok_or_else(|| Error::Protocol(format!("{} is truncated", path.display())))?;
+        if crypto::open_at_rest(&key, check).ok().as_deref() != Some(CHECK) {
+            return Err(Error::Crypto("wrong passphrase for the history file".to_string()));
+        }
+
+        let mut entries = Vec::new();
+        // A record cut short by a crash while appending is left out
+        while let Some(record) = next_record(&mut records) {
+            let plaintext = crypto::open_at_rest(&key, record).map_err(|_| {
+                Error::Crypto(format!("{} has been corrupted or tampered with", path.display()))
+            })?;
+            if legacy {
+                entries.push(framing::from_bincode::<LegacyEntry>(&plaintext)?.into());
+                continue;
+            }
+            match framing::from_bincode(&plaintext)? {
+                Record::Message(entry) => entries.push(entry),
+                Record::Reaction {
+                    peer_uuid,
+                    message_id,
+                    reaction,
+                } => {
+                    attach(&mut entries, peer_uuid, message_id, reaction);
+                }
+            }
+        }
+        if legacy {
+            return Self::upgrade(path, passphrase, entries);
+        }
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        if !records.is_empty() {
+            // Drop the partial record so new ones don't land after it
+            file.set_len((contents.len() - records.len()) as u64)?;
+        }
+        Ok(Transcript {
+            entries,
+            file: Some(HistoryFile {
+                file,
+                key,
+                salt,
+                path: path.to_path_buf(),
+            }),
+        })
+    }
+
+    fn create(path: &Path, passphrase: &str) -> Result<Self> {
+        let salt: [u8; SALT_LEN] = rand::random();
+        let key = crypto::passphrase_key(passphrase, &salt)?;
+        let file = write_file(path, &salt, &key, &[])?;
+        Ok(Transcript {
+            entries: Vec::new(),
+            file: Some(HistoryFile {
+                file,
+                key,
+                salt,
+                path: path.to_path_buf(),
+            }),
+        })
+    }
+
+    /// Rewrites a history file from before reactions in the current format
+    fn upgrade(path: &Path, passphrase: &str, entries: Vec<Entry>) -> Result<Self> {
+        let salt: [u8; SALT_LEN] = rand::random();
+        let key = crypto::passphrase_key(passphrase, &salt)?;
+        let file = replace_file(path, &salt, &key, &entries)?;
+        Ok(Transcript {
+            entries,
+            file: Some(HistoryFile {
+                file,
+                key,
+                salt,
+                path: path.to_path_buf(),
+            }),
+        })
+    }
+
+    /// Forgets messages from before `cutoff`, and rewrites the history file
+    /// without them. Returns how many were forgotten.
+    pub fn prune(&mut self, cutoff: DateTime<Local>) -> Result<usize> {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.time >= cutoff);
+        let pruned = before - self.entries.len();
+        if pruned > 0 {
+            if let Some(history_file) = &mut self.file {
+                history_file.file = replace_file(
+                    &history_file.path,
+                    &history_file.salt,
+                    &history_file.key,
+                    &self.entries,
+                )?;
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Forgets the message with `message_id` in the conversation with
+    /// `peer_uuid`, and rewrites the history file without it. Returns whether
+    /// it was there.
+    pub fn forget(&mut self, peer_uuid: Uuid, message_id: u64) -> Result<bool> {
+        let before = self.entries.len();
+        self.entries
+            .retain(|entry| !(entry.peer_uuid == peer_uuid && entry.message_id == Some(message_id)));
+        if self.entries.len() == before {
+            return Ok(false);
+        }
+        if let Some(history_file) = &mut self.file {
+            history_file.file = replace_file(
+                &history_file.path,
+                &history_file.salt,
+                &history_file.key,
+                &self.entries,
+            )?;
+        }
+        Ok(true)
+    }
+
+    /// Adds a message, appending it to the history file if there is one.
+    /// It's kept in memory even if writing fails.
+    pub fn record(&mut self, entry: Entry) -> Result<()> {
+        let result = self.append(&Record::Message(entry.clone()));
+        self.entries.push(entry);
+        result
+    }
+
+    /// Puts `reaction` on the message with `message_id` in the conversation
+    /// with `peer_uuid`, replacing that side's earlier one. Returns the
+    /// message, or `None` if it isn't in the history.
+    pub fn react(
+        &mut self,
+        peer_uuid: Uuid,
+        message_id: u64,
+        reaction: Reaction,
+    ) -> Result<Option<&Entry>> {
+        let Some(index) = attach(&mut self.entries, peer_uuid, message_id, reaction.clone()) else {
+            return Ok(None);
+        };
+        self.append(&Record::Reaction {
+            peer_uuid,
+            message_id,
+            reaction,
+        })?;
+        Ok(Some(&self.entries[index]))
+    }
+
+    /// Writes a record to the history file, if there is one
+    fn append(&mut self, record: &Record) -> Result<()> {
+        match &mut self.file {
+            Some(history_file) => {
+                let sealed = seal_record(&history_file.key, record)?;
+                Ok(history_file.file.write_all(&sealed)?)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+}
+
+/// Seals `record` and prefixes it with its length, ready to append
+fn seal_record(key: &SessionKey, record: &Record) -> Result<Vec<u8>> {
+    let plaintext = framing::to_bincode(record)?;
+    Ok(frame_record(&crypto::seal_at_rest(key, &plaintext)?))
+}
+
+/// Writes a new history file holding `entries`, and returns it open for
+/// appending
+fn write_file(path: &Path, salt: &[u8; SALT_LEN], key: &SessionKey, entries: &[Entry]) -> Result<File> {
+    let mut contents = [MAGIC.as_slice(), salt].concat();
+    contents.extend(frame_record(&crypto::seal_at_rest(key, CHECK)?));
+    for entry in entries {
+        contents.extend(seal_record(key, &Record::Message(entry.clone()))?);
+    }
+    let mut file = private_file(path)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+/// Like `write_file`, but replaces the file at `path`. The new file is
+/// written beside it and renamed over it, so an interruption leaves the old
+/// one as it was.
+fn replace_file(path: &Path, salt: &[u8; SALT_LEN], key: &SessionKey, entries: &[Entry]) -> Result<File> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".rewriting");
+    let rewriting = path.with_file_name(name);
+    let _ = std::fs::remove_file(&rewriting);
+
+    let written = write_file(&rewriting, salt, key, entries)
+        .and_then(|file| Ok(std::fs::rename(&rewriting, path).map(|_| file)?));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&rewriting);
+    }
+    written
+}
+
+/// Puts `reaction` on the latest entry with `peer_uuid` and `message_id`,
+/// returning its index
+fn attach(entries: &mut [Entry], peer_uuid: Uuid, message_id: u64, reaction: Reaction) -> Option<usize> {
+    let index = entries
+        .iter()
+        .rposition(|entry| entry.peer_uuid == peer_uuid && entry.message_id == Some(message_id))?;
+    let reactions = &mut entries[index].reactions;
+    reactions.retain(|earlier| earlier.outgoing != reaction.outgoing);
+    reactions.push(reaction);
+    Some(index)
+}
+
+/// Creates a file only its owner can read
+fn private_file(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create_new(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Prefixes a sealed record with its length
+fn frame_record(sealed: &[u8]) -> Vec<u8> {
+    [&(sealed.len() as u32).to_le_bytes(), sealed].concat()
+}
+
+/// Splits the next length-prefixed record off `records`, or returns `None`
+/// if what's left is too short to hold one
+fn next_record<'a>(records: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_le_bytes(records.get(..4)?.try_into().ok()?) as usize;
+    let record = records.get(4..4 + len)?;
+    *records = &records[4 + len..];
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(peer_uuid: Uuid, message_id: u64, text: &str) -> Entry {
+        Entry {
+            peer_name: "alice".to_string(),
+            peer_uuid,
+            outgoing: false,
+            text: text.to_string(),
+            verified: true,
+            time: Local::now(),
+            message_id: Some(message_id),
+            reactions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn the_right_passphrase_reads_back_what_was_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let peer = Uuid::new_v4();
+        let mut transcript = Transcript::open(&path, "hunter2").unwrap();
+        transcript.record(entry(peer, 1, "first secret")).unwrap();
+        transcript.record(entry(peer, 2, "second secret")).unwrap();
+        drop(transcript);
+
+        let contents = std::fs::read(&path).unwrap();
+        assert!(!contents.windows(6).any(|window| window == b"secret"));
+        let transcript = Transcript::open(&path, "hunter2").unwrap();
+        let texts: Vec<_> = transcript.entries().iter().map(|entry| entry.text.as_str()).collect();
+        assert_eq!(texts, ["first secret", "second secret"]);
+        assert_eq!(transcript.entries()[1].peer_uuid, peer);
+    }
+
+    #[test]
+    fn a_wrong_passphrase_fails_without_touching_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut transcript = Transcript::open(&path, "hunter2").unwrap();
+        transcript.record(entry(Uuid::new_v4(), 1, "secret")).unwrap();
+        drop(transcript);
+        let before = std::fs::read(&path).unwrap();
+
+        assert!(matches!(Transcript::open(&path, "hunter3"), Err(Error::Crypto(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert_eq!(Transcript::open(&path, "hunter2").unwrap().entries().len(), 1);
+    }
+
+    #[test]
+    fn a_forgotten_message_is_gone_from_the_file_too() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let peer = Uuid::new_v4();
+        let mut transcript = Transcript::open(&path, "hunter2").unwrap();
+        transcript.record(entry(peer, 1, "kept")).unwrap();
+        transcript.record(entry(peer, 2, "disappearing")).unwrap();
+
+        assert!(transcript.forget(peer, 2).unwrap());
+        assert!(!transcript.forget(peer, 2).unwrap());
+        let texts = |transcript: &Transcript| -> Vec<String> {
+            transcript.entries().iter().map(|entry| entry.text.clone()).collect()
+        };
+        assert_eq!(texts(&transcript), ["kept"]);
+        drop(transcript);
+        assert_eq!(texts(&Transcript::open(&path, "hunter2").unwrap()), ["kept"]);
+    }
+
+    #[test]
+    fn a_reaction_lands_on_its_message_and_is_kept_in_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut transcript = Transcript::open(&path, "hunter2").unwrap();
+        transcript.record(entry(alice, 1, "first")).unwrap();
+        transcript.record(entry(alice, 2, "second")).unwrap();
+        transcript.record(entry(bob, 2, "bob's second")).unwrap();
+
+        let reaction = |outgoing, emoji: &str| Reaction {
+            outgoing,
+            emoji: emoji.to_string(),
+        };
+        let reacted = transcript.react(alice, 2, reaction(false, "👍")).unwrap().unwrap();
+        assert_eq!(reacted.text, "second");
+        // Our own reaction sits beside the peer's, and replaces our earlier one
+        transcript.react(alice, 2, reaction(true, "🎉")).unwrap();
+        transcript.react(alice, 2, reaction(true, "❤")).unwrap();
+        assert!(transcript.react(alice, 3, reaction(true, "👍")).unwrap().is_none());
+
+        let emoji = |transcript: &Transcript| -> Vec<Vec<(bool, String)>> {
+            transcript
+                .entries()
+                .iter()
+                .map(|entry| {
+                    entry.reactions.iter().map(|r| (r.outgoing, r.emoji.clone())).collect()
+                })
+                .collect()
+        };
+        let expected = vec![
+            vec![],
+            vec![(false, "👍".to_string()), (true, "❤".to_string())],
+            vec![],
+        ];
+        assert_eq!(emoji(&transcript), expected);
+        drop(transcript);
+        assert_eq!(emoji(&Transcript::open(&path, "hunter2").unwrap()), expected);
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crossterm::{
+    cursor::MoveTo,
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use tokio::sync::mpsc;
+
+use super::{completion, output, Action, Client};
+use crate::shared::Result;
+
+/// Most lines kept in the message pane. Older ones scroll away for good.
+const MAX_SCROLLBACK: usize = 1000;
+
+/// How often the key reader looks up from waiting for a key, to notice
+/// that the UI was suspended or has closed
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the full-screen UI is up
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the full-screen UI has handed the terminal to a prompt (see
+/// `suspended`), so keys belong to the prompt
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// What a key did to the input line
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    /// Nothing that needs acting on, though the screen may have changed
+    Edited,
+    /// Enter was pressed on this line
+    Submit(String),
+    /// Ctrl-C or Ctrl-D, which exit like `exit`
+    Quit,
+}
+
+/// What the full-screen UI shows: a scrolling pane of everything printed,
+/// and an input line under a status bar. Kept apart from the terminal so
+/// drawing is just a function of this state.
+#[derive(Debug, Default)]
+pub struct Screen {
+    lines: VecDeque<String>,
+    input: Vec<char>,
+    /// Index into `input` the cursor is before
+    cursor: usize,
+    /// Lines the pane is scrolled up from the bottom
+    scroll: usize,
+    /// The action prompt's label, e.g. the current channel
+    pub status: String,
+    /// Entry of `history` shown by the up arrow, counted from the newest
+    recalled: Option<usize>,
+}
+
+impl Screen {
+    /// Adds printed text to the pane. The blank lines the plain prompt
+    /// pads notices with are trimmed, and runs of blank lines collapsed.
+    pub fn push(&mut self, text: &str) {
+        let text = text.replace('\r', "");
+        let text = text.trim_matches('\n');
+        for line in text.split('\n') {
+            let blank = line.trim().is_empty();
+            if blank && self.lines.back().is_none_or(|last| last.trim().is_empty()) {
+                continue;
+            }
+            self.lines.push_back(line.to_string());
+            // Keep what the user scrolled back to in view
+            if self.scroll > 0 {
+                self.scroll += 1;
+            }
+        }
+        while self.lines.len() > MAX_SCROLLBACK {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn input(&self) -> String {
+        self.input.iter().collect()
+    }
+
+    /// Applies a key to the input line or the pane. `history` is typed
+    /// actions, oldest first, recalled with the up and down arrows, and
+    /// `complete` gives what tab may complete the input to.
+    pub fn key(
+        &mut self,
+        key: KeyEvent,
+        history: &[String],
+        complete: impl Fn(&str) -> Vec<String>,
+    ) -> Input {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') if ctrl => return Input::Quit,
+            KeyCode::Char('u') if ctrl => self.set_input(""),
+            KeyCode::Char(c) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up => {
+                let recalled = self.recalled.map_or(0, |recalled| recalled + 1);
+                if let Some(action) = history.iter().rev().nth(recalled) {
+                    self.set_input(action);
+                    self.recalled = Some(recalled);
+                }
+            }
+            KeyCode::Down => match self.recalled {
+                Some(0) | None => self.set_input(""),
+                Some(recalled) => {
+                    if let Some(action) = history.iter().rev().nth(recalled - 1) {
+                        self.set_input(action);
+                    }
+                    self.recalled = Some(recalled - 1);
+                }
+            },
+            // Like the plain prompt, tab completes when only one candidate fits
+            KeyCode::Tab => {
+                if let [only] = complete(&self.input()).as_slice() {
+                    self.set_input(only);
+                }
+            }
+            KeyCode::PageUp => self.scroll = (self.scroll + 10).min(self.lines.len()),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Esc => self.set_input(""),
+            KeyCode::Enter => {
+                let action = self.input();
+                self.set_input("");
+                self.scroll = 0;
+                return Input::Submit(action);
+            }
+            _ => {}
+        }
+        Input::Edited
+    }
+
+    fn set_input(&mut self, input: &str) {
+        self.input = input.chars().collect();
+        self.cursor = self.input.len();
+        self.recalled = None;
+    }
+
+    /// The pane's rows for a terminal `width` columns wide with `rows` of
+    /// them free, long lines wrapped, ending `scroll` rows up from the end
+    pub fn visible(&self, width: usize, rows: usize) -> Vec<String> {
+        let wrapped: Vec<String> = self
+            .lines
+            .iter()
+            .flat_map(|line| wrap(line, width))
+            .collect();
+        let end = wrapped.len().saturating_sub(self.scroll);
+        let start = end.saturating_sub(rows);
+        wrapped[start..end].to_vec()
+    }
+
+    /// Draws the whole screen
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let rows = height.saturating_sub(2) as usize;
+        let visible = self.visible(width as usize, rows);
+        // Bottom-aligned, so new lines appear just above the status bar
+        let top = rows - visible.len();
+        for row in 0..rows {
+            queue!(out, MoveTo(0, row as u16))?;
+            if let Some(line) = row.checked_sub(top).and_then(|i| visible.get(i)) {
+                queue!(out, Print(line), SetAttribute(Attribute::Reset))?;
+            }
+            queue!(out, terminal::Clear(ClearType::UntilNewLine))?;
+        }
+
+        let status: String = self.status.chars().take(width as usize).collect();
+        let scrolled = if self.scroll > 0 {
+            " [scrolled, PgDn to return]"
+        } else {
+            ""
+        };
+        queue!(
+            out,
+            MoveTo(0, height.saturating_sub(2)),
+            SetAttribute(Attribute::Reverse),
+            Print(format!(
+                "{:width$}",
+                format!("{}{}", status, scrolled),
+                width = width as usize
+            )),
+            SetAttribute(Attribute::Reset),
+        )?;
+
+        // Scrolled sideways so the cursor stays on screen
+        let room = (width as usize).saturating_sub(3).max(1);
+        let skip = (self.cursor + 1).saturating_sub(room);
+        let shown: String = self.input.iter().skip(skip).take(room).collect();
+        queue!(
+            out,
+            MoveTo(0, height.saturating_sub(1)),
+            Print("> "),
+            Print(shown),
+            terminal::Clear(ClearType::UntilNewLine),
+            MoveTo((2 + self.cursor - skip) as u16, height.saturating_sub(1)),
+        )?;
+        out.flush()
+    }
+}
+
+/// Splits `line` into rows at most `width` columns wide. Escape sequences
+/// take no room.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    let mut columns = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            row.push(c);
+            // CSI sequences end with a byte in @..~
+            for c in chars.by_ref() {
+                row.push(c);
+                if c != '[' && ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+            continue;
+        }
+        if columns == width {
+            rows.push(std::mem::take(&mut row));
+            columns = 0;
+        }
+        row.push(c);
+        columns += 1;
+    }
+    rows.push(row);
+    rows
+}
+
+/// Gives the terminal back to an ordinary prompt while `prompt` runs, then
+/// takes it again, if the full-screen UI is up. Every inquire prompt goes
+/// through this, since inquire draws in place and reads keys itself.
+pub fn suspended<T>(prompt: impl FnOnce() -> T) -> T {
+    if !ACTIVE.load(Ordering::SeqCst) {
+        return prompt();
+    }
+    SUSPENDED.store(true, Ordering::SeqCst);
+    let _ = crossterm::execute!(io::stdout(), LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    let result = prompt();
+    let _ = terminal::enable_raw_mode();
+    let _ = crossterm::execute!(io::stdout(), EnterAlternateScreen);
+    SUSPENDED.store(false, Ordering::SeqCst);
+    result
+}
+
+/// The terminal in raw mode on the alternate screen, put back on drop
+struct Terminal;
+
+impl Terminal {
+    fn take() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        crossterm::execute!(io::stdout(), EnterAlternateScreen)?;
+        ACTIVE.store(true, Ordering::SeqCst);
+        Ok(Terminal)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        ACTIVE.store(false, Ordering::SeqCst);
+        output::set_pane(None);
+        let _ = crossterm::execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Reads terminal events on a plain thread, since crossterm's reads block,
+/// and stops once the UI closes
+fn read_events() -> mpsc::UnboundedReceiver<Event> {
+    let (sender, events) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while ACTIVE.load(Ordering::SeqCst) {
+            if SUSPENDED.load(Ordering::SeqCst) {
+                std::thread::sleep(KEY_POLL_INTERVAL);
+                continue;
+            }
+            match event::poll(KEY_POLL_INTERVAL) {
+                Ok(true) => match event::read() {
+                    Ok(event) => {
+                        if sender.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                },
+                Ok(false) => {}
+                Err(_) => break,
+            }
+        }
+    });
+    events
+}
+
+impl Client {
+    /// Reads and runs actions in the full-screen UI until `exit`. Everything
+    /// the client prints goes to the message pane instead of the terminal.
+    pub(super) async fn run_tui(&self) -> Result<()> {
+        let (pane, mut printed) = mpsc::unbounded_channel();
+        output::set_pane(Some(pane));
+        let terminal = Terminal::take()?;
+        let mut events = read_events();
+        let mut screen = Screen::default();
+        let mut stdout = io::stdout();
+
+        loop {
+            // Taken while we were at the prompt, such as by a client that
+            // got it first while we were reconnecting
+            if std::mem::take(&mut *self.name_taken.lock().await) {
+                self.choose_name().await?;
+            }
+            screen.status = self.prompt_label().await;
+            screen.draw(&mut stdout)?;
+
+            let input = tokio::select! {
+                Some(text) = printed.recv() => {
+                    screen.push(&text);
+                    continue;
+                }
+                event = events.recv() => match event {
+                    Some(Event::Key(key)) => {
+                        let history = self.history.lock().await.clone();
+                        let peers = self.peer_list.lock().await.clone();
+                        screen.key(key, &history, |input| completion::complete(input, &history, &peers))
+                    }
+                    Some(_) => continue,
+                    None => Input::Quit,
+                },
+            };
+            let action = match input {
+                Input::Edited => continue,
+                Input::Quit => break,
+                Input::Submit(action) => action,
+            };
+
+            screen.push(&format!("> {}", action));
+            self.note_activity().await;
+            self.record_history(&action).await;
+            // Keep showing what's printed while a slow action runs
+            let handled = self.handle_action(&action);
+            tokio::pin!(handled);
+            let handled = loop {
+                tokio::select! {
+                    handled = &mut handled => break handled,
+                    Some(text) = printed.recv() => {
+                        screen.push(&text);
+                        screen.draw(&mut stdout)?;
+                    }
+                }
+            };
+            match handled {
+                Ok(Action::Exit) => break,
+                Ok(Action::Continue) => {}
+                Err(e) => screen.push(&format!(" Error: {}", e)),
+            }
+            // Drawn from scratch, as a prompt may have had the terminal
+            while let Ok(text) = printed.try_recv() {
+                screen.push(&text);
+            }
+        }
+        drop(terminal);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(screen: &mut Screen, code: KeyCode, history: &[String]) -> Input {
+        screen.key(KeyEvent::new(code, KeyModifiers::NONE), history, |_| Vec::new())
+    }
+
+    fn type_in(screen: &mut Screen, text: &str) {
+        for c in text.chars() {
+            press(screen, KeyCode::Char(c), &[]);
+        }
+    }
+
+    #[test]
+    fn printed_lines_fill_the_pane_from_the_bottom() {
+        let mut screen = Screen::default();
+        screen.push("\n\r\n alice: hi\n\n\n");
+        screen.push("\n bob: hello\r\n");
+        screen.push("\x1b[1mcarol\x1b[0m: a line long enough to wrap");
+        assert_eq!(
+            screen.visible(80, 10),
+            [" alice: hi", " bob: hello", "\x1b[1mcarol\x1b[0m: a line long enough to wrap"]
+        );
+        // Only the newest rows fit, and the escapes take no columns
+        assert_eq!(
+            screen.visible(20, 3),
+            [" bob: hello", "\x1b[1mcarol\x1b[0m: a line long e", "nough to wrap"]
+        );
+    }
+
+    #[test]
+    fn scrolling_up_holds_the_view_as_messages_arrive() {
+        let mut screen = Screen::default();
+        for i in 0..30 {
+            screen.push(&format!("line {}", i));
+        }
+        press(&mut screen, KeyCode::PageUp, &[]);
+        assert_eq!(screen.visible(80, 2), ["line 18", "line 19"]);
+        screen.push("line 30");
+        assert_eq!(screen.visible(80, 2), ["line 18", "line 19"]);
+        // Sending something returns to the newest lines
+        type_in(&mut screen, "hi");
+        assert_eq!(press(&mut screen, KeyCode::Enter, &[]), Input::Submit("hi".to_string()));
+        assert_eq!(screen.visible(80, 2), ["line 29", "line 30"]);
+    }
+
+    #[test]
+    fn the_pane_keeps_only_the_newest_lines() {
+        let mut screen = Screen::default();
+        for i in 0..MAX_SCROLLBACK + 5 {
+            screen.push(&format!("line {}", i));
+        }
+        let visible = screen.visible(80, MAX_SCROLLBACK + 5);
+        assert_eq!(visible.len(), MAX_SCROLLBACK);
+        assert_eq!(visible[0], "line 5");
+    }
+
+    #[test]
+    fn the_input_line_edits_recalls_and_submits() {
+        let history = vec!["connect alice".to_string(), "list".to_string()];
+        let mut screen = Screen::default();
+        type_in(&mut screen, "helo");
+        press(&mut screen, KeyCode::Left, &history);
+        type_in(&mut screen, "l");
+        assert_eq!(screen.input(), "hello");
+        press(&mut screen, KeyCode::Up, &history);
+        assert_eq!(screen.input(), "list");
+        press(&mut screen, KeyCode::Up, &history);
+        assert_eq!(screen.input(), "connect alice");
+        press(&mut screen, KeyCode::Down, &history);
+        assert_eq!(screen.input(), "list");
+        press(&mut screen, KeyCode::Backspace, &history);
+        assert_eq!(press(&mut screen, KeyCode::Enter, &history), Input::Submit("lis".to_string()));
+        assert_eq!(screen.input(), "");
+
+        let completed = screen.key(KeyEvent::new(KeyCode::Tab, KeyModifiers::NONE), &history, |_| {
+            vec!["help".to_string()]
+        });
+        assert_eq!(completed, Input::Edited);
+        assert_eq!(screen.input(), "help");
+        let quit = screen.key(
+            KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            &history,
+            |_| Vec::new(),
+        );
+        assert_eq!(quit, Input::Quit);
+    }
+}
@@ -0,0 +1,9 @@
+//! Encrypted chat between clients, relayed by a server that can't read it.
+//!
+//! The `ycnbts` binary is a thin wrapper around [`client::Client`] and
+//! [`server::Server`], which can also be embedded to build bots or other
+//! frontends.
+
+pub mod client;
+pub mod server;
+pub mod shared;
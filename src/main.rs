@@ -1,10 +1,7 @@
-use std::sync::Arc;
+use std::{io::IsTerminal, sync::Arc};
 
 use clap::Parser;
-
-mod client;
-mod server;
-pub mod shared;
+use ycnbts::{client, server, shared};
 
 #[derive(Parser, Debug)]
 #[command(version, long_about = None)]
@@ -26,18 +23,50 @@ enum SubCommand {
 async fn main() {
     let args = Args::parse();
 
+    if let Err(e) = run(args).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> shared::Result<()> {
     match args.subcmd {
         SubCommand::Server(args) => {
-            let mut server = server::Server::new(args.address, args.port).await;
+            let mut server = server::Server::new(args).await?;
+            for address in server.local_addrs()? {
+                println!("Listening on {}", address);
+            }
+            if let Some(path) = server.socket_path() {
+                println!("Listening on {}", path.display());
+            }
             server.run().await;
         }
         SubCommand::Client(args) => {
-            let client = Arc::new(client::Client::new(args.address, args.port).await);
-            let cloned_client = client.clone();
-            tokio::spawn(async move {
-                cloned_client.handle().await;
-            });
-            client.run_ui().await;
+            let json_events = args.json_events;
+            let script = args.script.clone();
+            // Every prompt would fail at once, which looks like exiting normally
+            if script.is_none() && !json_events && !std::io::stdin().is_terminal() {
+                return Err(shared::Error::Protocol(
+                    "the interactive client needs a terminal on stdin; use --script <file> or --json-events to run without one".to_string(),
+                ));
+            }
+            let client = Arc::new(client::Client::new(args).await?);
+            let run = async {
+                if let Some(script) = script {
+                    client.run_script(&script).await
+                } else if json_events {
+                    client.run_json().await
+                } else {
+                    client.run_ui().await
+                }
+            };
+            // The action prompt reads Ctrl-C as a key and exits by itself.
+            // Anywhere else, such as in a script, it arrives as SIGINT.
+            let interrupted = async {
+                let _ = tokio::signal::ctrl_c().await;
+            };
+            client.run_until(run, interrupted).await?;
         }
     }
+    Ok(())
 }
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+
+use crate::shared::messages::ClientBoundMessage;
+
+use super::{audit::AuditLog, client::Client, metrics::Metrics, names::Names};
+
+/// Least time between two announcements, so a stuck key or a script gone
+/// wrong can't flood every client
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest announcement, in characters
+const MAX_ANNOUNCEMENT_LEN: usize = 1000;
+
+/// Reads operator commands from stdin until it is closed
+pub async fn run(
+    clients: Arc<Mutex<HashMap<Uuid, Client>>>,
+    metrics: Arc<Metrics>,
+    audit: AuditLog,
+    names: Arc<Names>,
+) {
+    // tokio's stdin blocks runtime shutdown until the pending read returns, so
+    // read on a plain thread that won't keep the process alive after `main`
+    let (sender, mut lines) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut last_announcement: Option<Instant> = None;
+    while let Some(line) = lines.recv().await {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("list"), None) => list(&clients).await,
+            (Some("announce"), Some(_)) => {
+                let text = line.trim_start()["announce".len()..].trim();
+                announce(&clients, &mut last_announcement, text).await
+            }
+            (Some("kick"), Some(uuid)) => match Uuid::parse_str(uuid) {
+                Ok(uuid) => kick(&clients, &metrics, &audit, &names, uuid).await,
+                Err(_) => println!("Invalid uuid: {}", uuid),
+            },
+            (Some("help"), None) => {
+                println!("Admin commands:");
+                println!("list: List connected clients and how much each has sent and received");
+                println!("kick <uuid>: Disconnect a client");
+                println!("announce <text>: Show a notice to every connected client");
+            }
+            (None, _) => {}
+            _ => println!("Unknown command: {}", line),
+        }
+    }
+}
+
+async fn list(clients: &Mutex<HashMap<Uuid, Client>>) {
+    let clients = clients.lock().await;
+    println!("{} connected client(s):", clients.len());
+    for client in clients.values() {
+        let traffic = *client.traffic.lock().unwrap();
+        println!(
+            "{} {} {} since {}, sent {} bytes in {} frames, received {} bytes in {} frames",
+            client.uuid,
+            client.address,
+            client
+                .friendly_name
+                .load()
+                .as_deref()
+                .map_or("(no name)", String::as_str),
+            client.connected_since.format("%Y-%m-%d %H:%M:%S"),
+            traffic.bytes_sent,
+            traffic.frames_sent,
+            traffic.bytes_received,
+            traffic.frames_received,
+        );
+    }
+}
+
+async fn announce(
+    clients: &Mutex<HashMap<Uuid, Client>>,
+    last_announcement: &mut Option<Instant>,
+    text: &str,
+) {
+    if text.chars().count() > MAX_ANNOUNCEMENT_LEN {
+        println!(
+            "Announcements can be at most {} characters long",
+            MAX_ANNOUNCEMENT_LEN
+        );
+        return;
+    }
+    if let Some(wait) = last_announcement
+        .and_then(|last| MIN_ANNOUNCE_INTERVAL.checked_sub(last.elapsed()))
+    {
+        println!(
+            "Wait {}s before announcing again",
+            wait.as_secs_f32().ceil()
+        );
+        return;
+    }
+    *last_announcement = Some(Instant::now());
+    let clients = clients.lock().await;
+    super::broadcast(
+        clients.values(),
+        &ClientBoundMessage::ServerAnnouncement(text.to_string()),
+    );
+    println!("Announced to {} client(s)", clients.len());
+}
+
+pub async fn kick(
+    clients: &Mutex<HashMap<Uuid, Client>>,
+    metrics: &Metrics,
+    audit: &AuditLog,
+    names: &Names,
+    uuid: Uuid,
+) {
+    let Some(client) = clients.lock().await.get(&uuid).cloned() else {
+        println!("No client with uuid {}", uuid);
+        return;
+    };
+
+    if let Some(reader_task) = client.reader_task.get() {
+        reader_task.abort();
+    }
+    client.close().await;
+    super::remove_client(clients, metrics, audit, names, uuid).await;
+    println!("Kicked client: {} ({})", uuid, client.address);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::{client::tests::connected, store::MemoryStore},
+        shared::framing::{self, WireFormat},
+    };
+
+    #[tokio::test]
+    async fn kick_removes_the_client() {
+        let (kicked, _kicked_end) = connected("127.0.0.1:4000".parse().unwrap());
+        let (kept, _kept_end) = connected("127.0.0.1:4001".parse().unwrap());
+        let clients = Mutex::new(HashMap::from([
+            (kicked.uuid, kicked.clone()),
+            (kept.uuid, kept.clone()),
+        ]));
+        let names = Names::new(false, Arc::new(MemoryStore::default()));
+
+        kick(&clients, &Metrics::default(), &AuditLog::disabled(), &names, kicked.uuid).await;
+
+        let clients = clients.lock().await;
+        assert!(!clients.contains_key(&kicked.uuid));
+        assert!(clients.contains_key(&kept.uuid));
+        assert!(kicked.is_closing());
+        assert!(!kept.is_closing());
+    }
+
+    #[tokio::test]
+    async fn an_announcement_reaches_every_client_but_not_twice_in_a_row() {
+        let connections: Vec<_> = (0..3)
+            .map(|i| connected(format!("127.0.0.1:{}", 4000 + i).parse().unwrap()))
+            .collect();
+        let clients = Mutex::new(
+            connections
+                .iter()
+                .map(|(client, _)| (client.uuid, client.clone()))
+                .collect::<HashMap<_, _>>(),
+        );
+        let mut last_announcement = None;
+        announce(&clients, &mut last_announcement, "maintenance at noon").await;
+        // Too soon after the first, so nobody gets it
+        announce(&clients, &mut last_announcement, "again").await;
+        for client in clients.lock().await.values() {
+            client.send_message(ClientBoundMessage::Ping(1)).unwrap();
+        }
+
+        for (_, mut far) in connections {
+            let frame = framing::read_frame(&mut far).await.unwrap().unwrap();
+            assert!(matches!(
+                WireFormat::Bincode.decode(&frame).unwrap(),
+                ClientBoundMessage::ServerAnnouncement(text) if text == "maintenance at noon"
+            ));
+            let frame = framing::read_frame(&mut far).await.unwrap().unwrap();
+            assert!(matches!(
+                WireFormat::Bincode.decode(&frame).unwrap(),
+                ClientBoundMessage::Ping(1)
+            ));
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, error::TrySendError},
+};
+use uuid::Uuid;
+
+use super::webhook::Webhook;
+use crate::shared::Result;
+
+/// Records that can wait for the writer before new ones are dropped
+const AUDIT_QUEUE_LEN: usize = 4096;
+
+/// Something worth an audit record. Only metadata: message contents are
+/// end-to-end encrypted and never seen by the server anyway.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Connected {
+        uuid: Uuid,
+        address: SocketAddr,
+        /// Whether this is a dropped client reconnecting with its resume token
+        resumed: bool,
+    },
+    Disconnected {
+        uuid: Uuid,
+    },
+    /// A connection from a banned address was refused
+    Rejected {
+        address: SocketAddr,
+    },
+    /// A peer asked another to open an encrypted session
+    SessionRequested {
+        from_uuid: Uuid,
+        to_uuid: Uuid,
+    },
+    /// A peer accepted a session request
+    SessionAccepted {
+        from_uuid: Uuid,
+        to_uuid: Uuid,
+    },
+    /// A peer offered another an address to connect to directly. Messages
+    /// on the resulting link aren't relayed, so they go unrecorded.
+    DirectRequested {
+        from_uuid: Uuid,
+        to_uuid: Uuid,
+    },
+    /// A message was relayed. The message id is inside the encrypted payload,
+    /// so the session counter stands in for it.
+    Message {
+        from_uuid: Uuid,
+        to_uuid: Uuid,
+        bytes: usize,
+        counter: u64,
+    },
+}
+
+/// One line of the audit file. `prev` is the SHA-256 of the line before it
+/// (carried across rotations), so editing or removing a line breaks the chain.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: chrono::DateTime<chrono::Local>,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+    prev: &'a str,
+}
+
+/// Append-only JSONL audit log, written by a background task so a slow or
+/// failing disk never holds up relaying. Disabled unless `--audit-log` is set.
+/// Events are also passed on to the `--webhook`, if there is one.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: Option<mpsc::Sender<AuditEvent>>,
+    webhook: Option<Webhook>,
+}
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        AuditLog {
+            sender: None,
+            webhook: None,
+        }
+    }
+
+    pub fn with_webhook(self, webhook: Webhook) -> Self {
+        AuditLog {
+            webhook: Some(webhook),
+            ..self
+        }
+    }
+
+    /// Opens (or creates) the file at `path` and starts the writer. Once the
+    /// file reaches `max_bytes` it's renamed to `<path>.1`, replacing any
+    /// older copy, and a new one is started.
+    pub async fn open(path: PathBuf, max_bytes: u64) -> Result<Self> {
+        let prev = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents.lines().last().map(hash).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let (file, size) = open_file(&path).await?;
+
+        let (sender, queue) = mpsc::channel(AUDIT_QUEUE_LEN);
+        let writer = Writer {
+            path,
+            max_bytes,
+            file,
+            size,
+            prev,
+        };
+        tokio::spawn(write_loop(writer, queue));
+        Ok(AuditLog {
+            sender: Some(sender),
+            webhook: None,
+        })
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(&event);
+        }
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Err(TrySendError::Full(event)) = sender.try_send(event) {
+            eprintln!("Audit log is falling behind, dropped {:?}", event);
+        }
+    }
+}
+
+async fn open_file(path: &Path) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path).await?;
+    let size = file.metadata().await?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+fn hash(line: &str) -> String {
+    Sha256::digest(line.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Owns the open audit file
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    file: BufWriter<File>,
+    size: u64,
+    /// Hash of the last line written
+    prev: String,
+}
+
+impl Writer {
+    /// Appends one record, rotating the file if it's grown too large.
+    /// Failures are reported and the record skipped; they never stop the
+    /// server.
+    async fn write(&mut self, event: &AuditEvent) {
+        let record = AuditRecord {
+            timestamp: chrono::Local::now(),
+            event,
+            prev: &self.prev,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Failed to encode an audit record: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.file.write_all(format!("{}\n", line).as_bytes()).await {
+            eprintln!("Failed to write the audit log: {}", e);
+            return;
+        }
+        self.size += line.len() as u64 + 1;
+        self.prev = hash(&line);
+
+        if self.size >= self.max_bytes {
+            match self.rotate().await {
+                Ok(()) => self.size = 0,
+                Err(e) => eprintln!("Failed to rotate the audit log: {}", e),
+            }
+        }
+    }
+
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(".1");
+        tokio::fs::rename(&self.path, rotated).await?;
+        (self.file, _) = open_file(&self.path).await?;
+        Ok(())
+    }
+}
+
+/// Writes queued records, flushing whenever the queue runs dry
+async fn write_loop(mut writer: Writer, mut queue: mpsc::Receiver<AuditEvent>) {
+    while let Some(event) = queue.recv().await {
+        writer.write(&event).await;
+        while let Ok(event) = queue.try_recv() {
+            writer.write(&event).await;
+        }
+        if let Err(e) = writer.file.flush().await {
+            eprintln!("Failed to flush the audit log: {}", e);
+        }
+    }
+}
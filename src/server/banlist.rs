@@ -0,0 +1,129 @@
+use std::{net::IpAddr, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// A banned address or CIDR range
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanEntry {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for BanEntry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network = IpAddr::from_str(address)
+            .map_err(|_| format!("invalid address: {}", address))?
+            .to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or(format!("invalid prefix length: {}", prefix_len))?,
+            None => max_len,
+        };
+
+        Ok(BanEntry {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+/// Reads a newline-delimited list of addresses and CIDR ranges. Blank lines
+/// and `#` comments are skipped; invalid lines are reported and ignored.
+pub fn load(path: &Path) -> std::io::Result<Vec<BanEntry>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut banlist = Vec::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(entry) => banlist.push(entry),
+            Err(e) => eprintln!("Ignoring banlist entry '{}': {}", line, e),
+        }
+    }
+    Ok(banlist)
+}
+
+pub fn ban_matches(addr: IpAddr, banlist: &[BanEntry]) -> bool {
+    let addr = addr.to_canonical();
+    banlist.iter().any(|entry| match (addr, entry.network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => prefix_matches(
+            u32::from(addr).into(),
+            u32::from(network).into(),
+            32,
+            entry.prefix_len,
+        ),
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            prefix_matches(addr.into(), network.into(), 128, entry.prefix_len)
+        }
+        _ => false,
+    })
+}
+
+fn prefix_matches(addr: u128, network: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    addr >> shift == network >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn banlist(entries: &[&str]) -> Vec<BanEntry> {
+        entries.iter().map(|entry| entry.parse().unwrap()).collect()
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_exact_addresses() {
+        let bans = banlist(&["192.0.2.7", "2001:db8::1"]);
+        assert!(ban_matches(ip("192.0.2.7"), &bans));
+        assert!(!ban_matches(ip("192.0.2.8"), &bans));
+        assert!(ban_matches(ip("2001:db8::1"), &bans));
+        assert!(!ban_matches(ip("2001:db8::2"), &bans));
+        // An IPv4 client reaching a dual-stack listener shows up mapped
+        assert!(ban_matches(ip("::ffff:192.0.2.7"), &bans));
+    }
+
+    #[test]
+    fn matches_cidr_ranges() {
+        let bans = banlist(&["10.1.0.0/16", "2001:db8:ab00::/40"]);
+        assert!(ban_matches(ip("10.1.0.0"), &bans));
+        assert!(ban_matches(ip("10.1.255.255"), &bans));
+        assert!(!ban_matches(ip("10.2.0.0"), &bans));
+        assert!(ban_matches(ip("2001:db8:abff::1"), &bans));
+        assert!(!ban_matches(ip("2001:db8:ac00::1"), &bans));
+        assert!(!ban_matches(ip("10.1.0.1"), &banlist(&["2001:db8:ab00::/40"])));
+    }
+
+    #[test]
+    fn a_zero_prefix_matches_its_whole_family() {
+        let bans = banlist(&["0.0.0.0/0"]);
+        assert!(ban_matches(ip("203.0.113.9"), &bans));
+        assert!(!ban_matches(ip("2001:db8::1"), &bans));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        assert!("10.0.0.0/33".parse::<BanEntry>().is_err());
+        assert!("::/129".parse::<BanEntry>().is_err());
+        assert!("not-an-address".parse::<BanEntry>().is_err());
+    }
+}
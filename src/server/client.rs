@@ -1,33 +1,510 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
+use arc_swap::{ArcSwap, ArcSwapOption};
 use tokio::{
-    io::AsyncWriteExt,
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-    sync::Mutex,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch, Mutex,
+    },
+    task::{AbortHandle, JoinHandle},
+};
+
+use super::metrics::Metrics;
+use crate::shared::{
+    framing::{self, Traffic, WireFormat},
+    messages::{ClientBoundMessage, ClientDescription, Presence, Profile, ResumeToken},
+    transport::{ReadHalf, WriteHalf},
+    Error, Result,
 };
 
+/// Frames that can wait for a client before it counts as too slow and is
+/// disconnected
+const SEND_QUEUE_LEN: usize = 256;
+
+/// How long a closing connection gets to write out what's still queued,
+/// unless it's drained for longer at shutdown
+const CLOSE_GRACE: Duration = Duration::from_secs(2);
+
+/// Longest one batch of frames may take to write. A client whose socket
+/// accepts nothing for this long has stopped reading and is disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most queued frames written before a flush
+const MAX_BATCH: usize = 64;
+
+/// Relayed or dropped messages remembered per client for `QueryAcks`. A
+/// resumed client only asks about what it sent just before its connection
+/// dropped.
+pub const RELAYED_KEPT: usize = 256;
+
+/// An encoded frame, shared between every client it's broadcast to
+pub type Frame = Arc<[u8]>;
+
 #[derive(Clone)]
 pub struct Client {
-    pub readonly_half: Arc<Mutex<OwnedReadHalf>>,
-    pub writeable_half: Arc<Mutex<OwnedWriteHalf>>,
-    pub friendly_name: Arc<std::sync::Mutex<Option<String>>>,
+    pub readonly_half: Arc<Mutex<ReadHalf>>,
+    /// Frames waiting for the writer task, which owns the write half
+    outgoing: mpsc::Sender<Frame>,
+    /// Set to true to make the reader and writer tasks stop
+    closing: Arc<watch::Sender<bool>>,
+    /// How long the writer task keeps writing once `closing` is set
+    grace: Arc<std::sync::Mutex<Duration>>,
+    writer_task: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
+    /// Set by `Advertise`. Read on every relayed frame and written rarely, so
+    /// reads don't take a lock.
+    pub friendly_name: Arc<ArcSwapOption<String>>,
+    /// Read whenever the client is described, which may be under the
+    /// clients lock, so like the name it isn't behind a lock of its own
+    pub presence: Arc<ArcSwap<Presence>>,
+    pub profile: Arc<ArcSwap<Profile>>,
+    /// What went to and from this client since it was greeted
+    pub traffic: Arc<std::sync::Mutex<Traffic>>,
     pub uuid: uuid::Uuid,
+    /// Remote address of the connection, as returned by `accept`. Loopback
+    /// with port 0 for connections over a Unix socket.
+    pub address: SocketAddr,
+    pub connected_since: chrono::DateTime<chrono::Local>,
+    /// Task reading this client's frames, aborted when the client is kicked
+    pub reader_task: Arc<OnceLock<AbortHandle>>,
+    pub wire_format: WireFormat,
+    /// Protocol version the client announced in its hello
+    pub protocol_version: u32,
+    /// Lets this client take its uuid back after a dropped connection
+    pub resume_token: ResumeToken,
+    /// Recipient and counter of the latest `RELAYED_KEPT` messages relayed
+    /// from this client, or dropped by the relay hook, oldest first. Handed
+    /// over when it resumes.
+    pub relayed: Arc<std::sync::Mutex<VecDeque<(uuid::Uuid, u64)>>>,
 }
 
 impl Client {
-    pub async fn send_message(&self, message: crate::shared::messages::ClientBoundMessage) {
-        let mut buffer = Vec::new();
-        bincode::serialize_into(&mut buffer, &message).unwrap();
+    /// Starts the writer task for a connection that has exchanged hellos
+    pub fn new(
+        readable_half: ReadHalf,
+        writeable_half: WriteHalf,
+        uuid: uuid::Uuid,
+        address: SocketAddr,
+        wire_format: WireFormat,
+        protocol_version: u32,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let (outgoing, queue) = mpsc::channel(SEND_QUEUE_LEN);
+        let closing = Arc::new(watch::channel(false).0);
+        let grace = Arc::new(std::sync::Mutex::new(CLOSE_GRACE));
+        let traffic = Arc::new(std::sync::Mutex::new(Traffic::default()));
+        let writer_task = tokio::spawn(write_loop(
+            writeable_half,
+            queue,
+            closing.clone(),
+            grace.clone(),
+            uuid,
+            Counters {
+                traffic: traffic.clone(),
+                metrics,
+            },
+        ));
+
+        Client {
+            readonly_half: Arc::new(Mutex::new(readable_half)),
+            outgoing,
+            closing,
+            grace,
+            writer_task: Arc::new(std::sync::Mutex::new(Some(writer_task))),
+            friendly_name: Arc::new(ArcSwapOption::empty()),
+            presence: Arc::new(ArcSwap::from_pointee(Presence::default())),
+            profile: Arc::new(ArcSwap::from_pointee(Profile::default())),
+            traffic,
+            uuid,
+            address,
+            connected_since: chrono::Local::now(),
+            reader_task: Arc::new(OnceLock::new()),
+            wire_format,
+            protocol_version,
+            resume_token: rand::random(),
+            relayed: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Queues a message for the writer task without waiting. Fails if the
+    /// client's queue is full or its connection is closing.
+    pub fn send_message(&self, message: ClientBoundMessage) -> Result<()> {
+        let frame = framing::encode_frame(self.wire_format, &message)?;
+        self.outgoing.try_send(frame.into()).map_err(|e| match e {
+            TrySendError::Full(_) => Error::Protocol("send queue is full".to_string()),
+            TrySendError::Closed(_) => Error::Protocol("connection is closed".to_string()),
+        })
+    }
+
+    /// Like `send_message`, for frames relayed or broadcast from other
+    /// clients. A client whose queue is full is too slow to keep up and gets
+    /// disconnected, so it can't hold up everyone else.
+    pub fn relay(&self, message: ClientBoundMessage) {
+        match framing::encode_frame(self.wire_format, &message) {
+            Ok(frame) => self.relay_frame(frame.into()),
+            Err(e) => eprintln!("Failed to encode a frame for {}: {}", self.uuid, e),
+        }
+    }
+
+    /// Like `relay`, for a frame already encoded in this client's wire format
+    pub fn relay_frame(&self, frame: Frame) {
+        if let Err(TrySendError::Full(_)) = self.outgoing.try_send(frame) {
+            if !self.closing.send_replace(true) {
+                eprintln!("Send queue for {} is full, disconnecting it", self.uuid);
+            }
+        }
+    }
+
+    /// Remembers that our message with `counter` went on to `recipient`, and
+    /// tells the client with a `Delivered`
+    pub fn acknowledge(&self, recipient: uuid::Uuid, counter: u64) {
+        self.settle(recipient, counter);
+        let _ = self.send_message(ClientBoundMessage::Delivered(recipient, counter));
+    }
+
+    /// Remembers that the server is done with our message with `counter` to
+    /// `recipient`, without telling the client. A message the relay hook
+    /// drops is settled this way, so a resumed client doesn't think it lost.
+    pub fn settle(&self, recipient: uuid::Uuid, counter: u64) {
+        let mut relayed = self.relayed.lock().unwrap();
+        if relayed.len() == RELAYED_KEPT {
+            relayed.pop_front();
+        }
+        relayed.push_back((recipient, counter));
+    }
+
+    /// Which of `queried` were relayed or dropped by the relay hook, as the
+    /// answer to `QueryAcks`
+    pub fn acked(&self, queried: &[(uuid::Uuid, u64)]) -> Vec<(uuid::Uuid, u64)> {
+        let relayed = self.relayed.lock().unwrap();
+        queried
+            .iter()
+            .filter(|message| relayed.contains(message))
+            .copied()
+            .collect()
+    }
+
+    /// Whether `disconnect` has been called
+    pub fn is_closing(&self) -> bool {
+        *self.closing.borrow()
+    }
+
+    /// Resolves once `disconnect` has been called
+    pub async fn closed(&self) {
+        let _ = self.closing.subscribe().wait_for(|closing| *closing).await;
+    }
 
-        let mut buffer_with_length = Vec::new();
-        bincode::serialize_into(&mut buffer_with_length, &(buffer.len() as u64)).unwrap();
-        buffer_with_length.extend(buffer);
+    /// How other clients see this one
+    pub fn description(&self) -> ClientDescription {
+        let mut description = ClientDescription::new(
+            self.friendly_name
+                .load()
+                .as_deref()
+                .cloned()
+                .unwrap_or_default(),
+            self.uuid,
+        );
+        description.presence = **self.presence.load();
+        description.profile = Profile::clone(&self.profile.load());
+        description
+    }
+
+    /// Whether this client should appear in other clients' peer lists
+    pub fn is_listed(&self) -> bool {
+        self.friendly_name.load().is_some() && **self.presence.load() != Presence::Invisible
+    }
+
+    /// Stops reading from the client and closes the connection once queued
+    /// frames are written (or `CLOSE_GRACE` runs out)
+    pub fn disconnect(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// Like `disconnect`, giving queued frames `grace` rather than
+    /// `CLOSE_GRACE` to be written
+    pub fn drain(&self, grace: Duration) {
+        *self.grace.lock().unwrap() = grace;
+        self.disconnect();
+    }
+
+    /// Reads and discards whatever the client still sends until it closes
+    /// its end or `deadline` passes. Closing the socket with data unread
+    /// would reset the connection, and the client could lose the frames we
+    /// wrote before it read them.
+    pub async fn await_hangup(&self, deadline: tokio::time::Instant) {
+        let mut readonly_half = self.readonly_half.lock().await;
+        let mut discarded = [0; 4096];
+        let _ = tokio::time::timeout_at(deadline, async {
+            while let Ok(1..) = readonly_half.read(&mut discarded).await {}
+        })
+        .await;
+    }
 
-        self.writeable_half
-            .lock()
+    /// Disconnects and waits for the writer task to finish
+    pub async fn close(&self) {
+        self.disconnect();
+        let writer_task = self.writer_task.lock().unwrap().take();
+        if let Some(writer_task) = writer_task {
+            let _ = writer_task.await;
+        }
+    }
+}
+
+/// Where the writer task counts the frames it writes
+struct Counters {
+    traffic: Arc<std::sync::Mutex<Traffic>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Counters {
+    fn sent(&self, len: usize) {
+        self.traffic.lock().unwrap().sent(len);
+        self.metrics.frame_sent(len);
+    }
+}
+
+/// Owns the write half, writing queued frames in order. Frames already
+/// queued behind the one being written join it before the flush, so with a
+/// buffered write half a burst takes a few syscalls rather than one per
+/// frame, and a lone frame still goes out at once. Once the client is
+/// disconnected, whatever is already queued gets its grace to be written
+/// before the socket is shut down, even if a write is stuck on a client that
+/// stopped reading. A write stuck for `WRITE_TIMEOUT` disconnects the client.
+async fn write_loop(
+    mut writeable_half: WriteHalf,
+    mut queue: mpsc::Receiver<Frame>,
+    disconnect: Arc<watch::Sender<bool>>,
+    grace: Arc<std::sync::Mutex<Duration>>,
+    uuid: uuid::Uuid,
+    counters: Counters,
+) {
+    let mut closed = disconnect.subscribe();
+    let write = async {
+        loop {
+            let frame = tokio::select! {
+                frame = queue.recv() => frame,
+                _ = closed.wait_for(|closing| *closing) => None,
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+            let mut batch = vec![frame];
+            while batch.len() < MAX_BATCH {
+                let Ok(frame) = queue.try_recv() else {
+                    break;
+                };
+                batch.push(frame);
+            }
+            let written = async {
+                for frame in &batch {
+                    framing::write_encoded_unflushed(&mut writeable_half, frame).await?;
+                }
+                writeable_half.flush().await?;
+                Ok::<_, Error>(())
+            };
+            match tokio::time::timeout(WRITE_TIMEOUT, written).await {
+                Ok(Ok(())) => {
+                    for frame in &batch {
+                        counters.sent(frame.len());
+                    }
+                }
+                Ok(Err(e)) => {
+                    eprintln!("Failed to write to {}: {}", uuid, e);
+                    disconnect.send_replace(true);
+                    return;
+                }
+                Err(_) => {
+                    eprintln!(
+                        "Writing to {} stalled for {}s, disconnecting it",
+                        uuid,
+                        WRITE_TIMEOUT.as_secs()
+                    );
+                    disconnect.send_replace(true);
+                    return;
+                }
+            }
+        }
+        while let Ok(frame) = queue.try_recv() {
+            if framing::write_encoded_unflushed(&mut writeable_half, &frame)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            counters.sent(frame.len());
+        }
+        let _ = writeable_half.shutdown().await;
+    };
+
+    let mut closing = disconnect.subscribe();
+    let deadline = async {
+        let _ = closing.wait_for(|closing| *closing).await;
+        let grace = *grace.lock().unwrap();
+        tokio::time::sleep(grace).await;
+    };
+
+    // Shutting down flushes, so it's part of what the deadline cuts short.
+    // Past it, the write half is dropped with anything still unwritten.
+    tokio::select! {
+        _ = write => {}
+        _ = deadline => {}
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    /// A client connected to the returned stream, as the far end of the
+    /// socket would be
+    pub fn connected(address: SocketAddr) -> (Client, DuplexStream) {
+        connected_with_buffer(address, 64 * 1024)
+    }
+
+    /// Like `connected`, with `buffer` bytes in flight before writes block
+    /// until the far end reads
+    pub fn connected_with_buffer(address: SocketAddr, buffer: usize) -> (Client, DuplexStream) {
+        let (near, far) = tokio::io::duplex(buffer);
+        let (readable_half, writeable_half) = tokio::io::split(near);
+        let client = Client::new(
+            Box::new(readable_half),
+            Box::new(writeable_half),
+            uuid::Uuid::new_v4(),
+            address,
+            WireFormat::Bincode,
+            crate::shared::messages::PROTOCOL_VERSION,
+            Arc::new(Metrics::default()),
+        );
+        (client, far)
+    }
+
+    #[tokio::test]
+    async fn keeps_the_address_it_was_accepted_from() {
+        let address: SocketAddr = "[2001:db8::7]:51234".parse().unwrap();
+        let (client, _far) = connected(address);
+        assert_eq!(client.address, address);
+        assert_eq!(client.clone().address, address);
+    }
+
+    fn address() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_client_that_stops_reading_is_disconnected_not_buffered() {
+        let (client, _never_read) = connected_with_buffer(address(), 64);
+        let message = ClientBoundMessage::ServerAnnouncement("x".repeat(100));
+        for _ in 0..SEND_QUEUE_LEN + 2 {
+            client.relay(message.clone());
+        }
+        assert!(client.is_closing());
+        // The stuck write is only given the grace period
+        tokio::time::timeout(CLOSE_GRACE * 2, client.close())
             .await
-            .write_all(&buffer_with_length)
+            .expect("closing shouldn't wait on the stalled reader");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_write_stalled_past_the_timeout_disconnects_only_that_client() {
+        let (stalled, _never_read) = connected_with_buffer(address(), 64);
+        let (healthy, mut far) = connected(address());
+        // One frame is enough to fill the stalled client's socket
+        stalled.relay(ClientBoundMessage::ServerAnnouncement("x".repeat(100)));
+        tokio::time::sleep(WRITE_TIMEOUT - Duration::from_secs(1)).await;
+        assert!(!stalled.is_closing());
+
+        healthy.relay(ClientBoundMessage::Ping(1));
+        let frame = framing::read_frame(&mut far).await.unwrap().unwrap();
+        assert!(matches!(
+            WireFormat::Bincode.decode(&frame).unwrap(),
+            ClientBoundMessage::Ping(1)
+        ));
+        tokio::time::timeout(Duration::from_secs(2), stalled.closed())
             .await
-            .unwrap();
+            .expect("the stalled write should time out");
+        assert!(!healthy.is_closing());
+    }
+
+    #[tokio::test]
+    async fn a_failed_write_disconnects() {
+        let (client, far) = connected(address());
+        drop(far);
+        client.send_message(ClientBoundMessage::Ping(1)).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), client.closed())
+            .await
+            .expect("the write error should disconnect the client");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_sends_arrive_whole_and_in_order() {
+        const TASKS: u64 = 8;
+        const EACH: u64 = 25;
+        let (client, mut far) = connected(address());
+        let senders: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    for i in 0..EACH {
+                        client.send_message(ClientBoundMessage::Ping(task * 1000 + i)).unwrap();
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+
+        let mut next = [0; TASKS as usize];
+        for _ in 0..TASKS * EACH {
+            let frame = framing::read_frame(&mut far).await.unwrap().unwrap();
+            let ClientBoundMessage::Ping(id) = WireFormat::Bincode.decode(&frame).unwrap() else {
+                panic!("expected a ping");
+            };
+            let (task, i) = ((id / 1000) as usize, id % 1000);
+            assert_eq!(i, next[task], "task {}'s pings arrived out of order", task);
+            next[task] += 1;
+        }
+        assert!(next.iter().all(|sent| *sent == EACH));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn names_read_during_renames_are_always_whole() {
+        const RENAMES: usize = 2000;
+        let (client, _far) = connected(address());
+        let names = ["a".repeat(100), "b".repeat(7)];
+        client.friendly_name.store(Some(Arc::new(names[0].clone())));
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let (client, names, done) = (client.clone(), names.clone(), done.clone());
+                tokio::spawn(async move {
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) || reads == 0 {
+                        let name = client.description().name;
+                        assert!(names.contains(&name), "read a torn name: {:?}", name);
+                        reads += 1;
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for i in 0..RENAMES {
+            client.friendly_name.store(Some(Arc::new(names[i % 2].clone())));
+            tokio::task::yield_now().await;
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.await.unwrap();
+        }
     }
 }
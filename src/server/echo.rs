@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use uuid::Uuid;
+
+use super::client::Client;
+use crate::{
+    client::session::Session,
+    shared::{
+        crypto::{self, IdentityKey, KeyType, PublicIdentity},
+        messages::{ClientBoundMessage, ClientDescription, EncryptedPayload, Handshake, ServerBoundMessage},
+        Result,
+    },
+};
+
+/// Name the mirror advertises
+const MIRROR_NAME: &str = "echo";
+
+/// Size of the key the mirror answers RSA clients with
+const RSA_BITS: usize = 2048;
+
+/// The fake peer behind `--echo`, which sends every message back to whoever
+/// sent it. It's a real end of each session, answering connection requests
+/// with its own key and sealing each message again after opening it, so a
+/// lone client can be tested end to end. That means the server reads
+/// everything sent to it: this is for testing only.
+pub struct Mirror {
+    uuid: Uuid,
+    /// Signs sessions with Ed25519 clients
+    key: IdentityKey,
+    public_key: PublicIdentity,
+    /// Signs sessions with RSA clients, since both sides of a session need
+    /// the same key type. Generated when the first one asks, as it's slow.
+    rsa_key: OnceLock<(IdentityKey, PublicIdentity)>,
+    /// One session per client, by the client's uuid. Kept across a resume,
+    /// and replaced if the client asks again.
+    sessions: Mutex<HashMap<Uuid, Session>>,
+}
+
+impl Mirror {
+    pub fn new() -> Result<Self> {
+        let key = IdentityKey::generate(KeyType::Ed25519, 0)?;
+        Ok(Mirror {
+            uuid: Uuid::new_v4(),
+            public_key: key.public(),
+            key,
+            rsa_key: OnceLock::new(),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Our key of `key_type`, with its public half
+    fn key(&self, key_type: KeyType) -> Result<(&IdentityKey, &PublicIdentity)> {
+        if key_type == KeyType::Ed25519 {
+            return Ok((&self.key, &self.public_key));
+        }
+        if self.rsa_key.get().is_none() {
+            let key = IdentityKey::generate(KeyType::Rsa, RSA_BITS)?;
+            let public_key = key.public();
+            // Another client may have got there first, and either key will do
+            let _ = self.rsa_key.set((key, public_key));
+        }
+        let (key, public_key) = self.rsa_key.get().unwrap();
+        Ok((key, public_key))
+    }
+
+    pub fn description(&self) -> ClientDescription {
+        ClientDescription::new(MIRROR_NAME.to_string(), self.uuid)
+    }
+
+    /// Whether `message` is meant for the mirror rather than a real client
+    pub fn is_addressed(&self, message: &ServerBoundMessage) -> bool {
+        let to = match message {
+            ServerBoundMessage::ConnectionRequest(to, ..)
+            | ServerBoundMessage::ConnectionResponse(to, ..)
+            | ServerBoundMessage::Message(to, _)
+            | ServerBoundMessage::CloseConnection(to)
+            | ServerBoundMessage::FileOffer(to, _)
+            | ServerBoundMessage::FileResponse(to, _)
+            | ServerBoundMessage::FileChunk(to, _)
+            | ServerBoundMessage::FileAck(to, _)
+            | ServerBoundMessage::RequestDirect(to, _)
+            | ServerBoundMessage::RejectRequest(to) => to.uuid,
+            ServerBoundMessage::ReadReceipt(to, _) | ServerBoundMessage::React(to, ..) => *to,
+            _ => return false,
+        };
+        to == self.uuid
+    }
+
+    /// Acts on a message `client` sent the mirror. Connection requests are
+    /// accepted and messages come straight back; anything else, such as a
+    /// file offer, is ignored.
+    pub fn answer(&self, client: &Client, message: ServerBoundMessage) {
+        let result = match message {
+            ServerBoundMessage::ConnectionRequest(_, public_key, handshake) => {
+                self.accept(client, public_key, handshake)
+            }
+            ServerBoundMessage::Message(_, payload) => self.echo(client, &payload),
+            ServerBoundMessage::CloseConnection(_) => {
+                self.sessions.lock().unwrap().remove(&client.uuid);
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Echo failed to answer {}: {}", client.uuid, e);
+        }
+    }
+
+    fn accept(&self, client: &Client, public_key: PublicIdentity, remote: Handshake) -> Result<()> {
+        if !crypto::verify_handshake(&public_key, &remote) {
+            return Ok(());
+        }
+        let (identity, our_public_key) = self.key(public_key.key_type())?;
+        let (secret, handshake) = crypto::new_handshake(identity, remote.suite)?;
+        let key = crypto::derive_session_key(&secret, &handshake, &remote)?;
+        let session = Session::new(public_key, key, secret, &handshake, &remote)?;
+        self.sessions.lock().unwrap().insert(client.uuid, session);
+        client.send_message(ClientBoundMessage::ConnectionResponse(
+            self.description(),
+            our_public_key.clone(),
+            handshake,
+        ))
+    }
+
+    fn echo(&self, client: &Client, payload: &EncryptedPayload) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(&client.uuid) else {
+            return Ok(());
+        };
+        let (plaintext, _) = session.open(payload)?;
+        let (identity, _) = self.key(session.public_key.key_type())?;
+        let payload = session.seal(identity, &plaintext)?;
+        drop(sessions);
+        client.send_message(ClientBoundMessage::Message(self.description(), payload))
+    }
+}
@@ -0,0 +1,67 @@
+use std::{collections::HashSet, time::Duration};
+
+use uuid::Uuid;
+
+/// What to do with a message about to be relayed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookDecision {
+    Allow,
+    /// Discard it without telling either side
+    Drop,
+    /// Relay it after waiting this long. Nothing else from the sender is
+    /// read meanwhile, so this slows a flood down to the hook's pace.
+    RateLimit(Duration),
+}
+
+/// Moderation policy applied to every relayed message. Contents are end-to-end
+/// encrypted, so a hook only sees who is talking to whom and how much.
+///
+/// The default methods allow everything. Embedders install their own with
+/// [`Server::with_hook`](super::Server::with_hook).
+pub trait RelayHook: Send + Sync {
+    /// Called for each `Message` frame before it's relayed. `size` is the
+    /// length of the encrypted payload.
+    fn on_message(&self, from: Uuid, to: Uuid, size: usize) -> HookDecision {
+        let _ = (from, to, size);
+        HookDecision::Allow
+    }
+}
+
+/// Relays everything, the default hook
+pub struct AllowAll;
+
+impl RelayHook for AllowAll {}
+
+/// Drops every message addressed to one of `recipients`, for
+/// `--drop-messages-to`
+pub struct Blocklist {
+    pub recipients: HashSet<Uuid>,
+}
+
+impl RelayHook for Blocklist {
+    fn on_message(&self, _from: Uuid, to: Uuid, _size: usize) -> HookDecision {
+        if self.recipients.contains(&to) {
+            HookDecision::Drop
+        } else {
+            HookDecision::Allow
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_blocklist_drops_only_messages_to_its_recipients() {
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let hook = Blocklist {
+            recipients: HashSet::from([bob]),
+        };
+        assert_eq!(hook.on_message(alice, bob, 10), HookDecision::Drop);
+        assert_eq!(hook.on_message(alice, carol, 10), HookDecision::Allow);
+        // Only the recipient counts, so bob can still send
+        assert_eq!(hook.on_message(bob, alice, 10), HookDecision::Allow);
+        assert_eq!(AllowAll.on_message(alice, bob, 10), HookDecision::Allow);
+    }
+}
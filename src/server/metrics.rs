@@ -0,0 +1,170 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Counters exposed in Prometheus text format on `--metrics-port`
+#[derive(Default)]
+pub struct Metrics {
+    pub clients_connected: AtomicU64,
+    pub messages_relayed: AtomicU64,
+    pub frames_dropped: AtomicU64,
+    /// Connections dropped before or instead of a proper hello: banned
+    /// addresses, failed or timed out greetings, and hellos sent late
+    pub auth_failures: AtomicU64,
+    /// Frames and bytes to and from clients, headers included. The hello
+    /// and anything before it aren't counted.
+    pub frames_sent: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub frames_received: AtomicU64,
+    pub bytes_received: AtomicU64,
+}
+
+impl Metrics {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrement(counter: &AtomicU64) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, amount: u64) {
+        counter.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Counts a frame of `len` bytes written to a client
+    pub fn frame_sent(&self, len: usize) {
+        Metrics::increment(&self.frames_sent);
+        Metrics::add(&self.bytes_sent, len as u64);
+    }
+
+    /// Counts a frame of `len` bytes read from a client
+    pub fn frame_received(&self, len: usize) {
+        Metrics::increment(&self.frames_received);
+        Metrics::add(&self.bytes_received, len as u64);
+    }
+
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "ycnbts_clients_connected",
+                "gauge",
+                "Number of currently connected clients",
+                &self.clients_connected,
+            ),
+            (
+                "ycnbts_messages_relayed_total",
+                "counter",
+                "Messages relayed between clients",
+                &self.messages_relayed,
+            ),
+            (
+                "ycnbts_frames_dropped_total",
+                "counter",
+                "Frames that were malformed or had no recipient",
+                &self.frames_dropped,
+            ),
+            (
+                "ycnbts_auth_failures_total",
+                "counter",
+                "Clients that failed authentication",
+                &self.auth_failures,
+            ),
+            (
+                "ycnbts_frames_sent_total",
+                "counter",
+                "Frames written to clients",
+                &self.frames_sent,
+            ),
+            (
+                "ycnbts_bytes_sent_total",
+                "counter",
+                "Bytes written to clients, frame headers included",
+                &self.bytes_sent,
+            ),
+            (
+                "ycnbts_frames_received_total",
+                "counter",
+                "Frames read from clients",
+                &self.frames_received,
+            ),
+            (
+                "ycnbts_bytes_received_total",
+                "counter",
+                "Bytes read from clients, frame headers included",
+                &self.bytes_received,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            output.push_str(&format!("# HELP {} {}\n", name, help));
+            output.push_str(&format!("# TYPE {} {}\n", name, kind));
+            output.push_str(&format!("{} {}\n", name, value.load(Ordering::Relaxed)));
+        }
+        output
+    }
+}
+
+/// Answers every HTTP request on the listener with the current metrics
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // The request itself doesn't matter, every path returns the metrics
+            let mut request = [0u8; 1024];
+            if stream.read(&mut request).await.is_err() {
+                return;
+            }
+
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_counters_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        Metrics::increment(&metrics.auth_failures);
+        Metrics::increment(&metrics.auth_failures);
+        tokio::spawn(serve(listener, metrics));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let value: u64 = response
+            .lines()
+            .find_map(|line| line.strip_prefix("ycnbts_auth_failures_total "))
+            .expect("the counter should be listed")
+            .parse()
+            .unwrap();
+        assert_eq!(value, 2);
+    }
+}
@@ -1,195 +1,2284 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{hash_map, HashMap, HashSet, VecDeque},
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
 
 use clap::Parser;
-use client::Client;
-use tokio::{io::AsyncReadExt, net::TcpListener, sync::Mutex};
+use client::{Client, Frame};
+use socket2::{Domain, Socket, Type};
+#[cfg(feature = "tls")]
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, BufReader, BufWriter},
+    net::TcpListener,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+};
 
-use crate::shared::messages::{ClientBoundMessage, ClientDescription, ServerBoundMessage};
+use crate::shared::{
+    framing::{self, WireFormat},
+    messages::{
+        is_valid_reaction, ClientBoundMessage, ClientDescription, Presence, Profile, ResumeToken,
+        ServerBoundMessage, PROTOCOL_VERSION,
+    },
+    transport::{ClientTransport, ReadHalf, WriteHalf},
+    Error, Result,
+};
+#[cfg(feature = "tls")]
+use crate::shared::tls::{self, TlsAcceptor};
+use audit::{AuditEvent, AuditLog};
+use echo::Mirror;
+pub use hook::{HookDecision, RelayHook};
+use metrics::Metrics;
+use names::Names;
+use store::{MemoryStore, ServerStore};
+use webhook::Webhook;
 
+mod admin;
+mod audit;
+mod banlist;
 mod client;
+mod echo;
+mod hook;
+mod metrics;
+mod names;
+mod store;
+mod webhook;
+
+/// A repeated connection request from the same sender to the same target
+/// within this long is dropped rather than relayed
+const REQUEST_DEDUPE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Most clients described in one frame of the list sent on connecting
+const CLIENT_LIST_CHUNK: usize = 500;
+
+/// How long a dropped client's uuid and name are held for it to resume.
+/// Peers aren't told it left until this runs out.
+const RESUME_GRACE: Duration = Duration::from_secs(60);
+
+/// Undecodable frames in a row a client may send before it's disconnected
+const MAX_PROTOCOL_ERRORS: u32 = 5;
+
+/// Most connections greeted at once. Further accepts wait for a slot.
+const MAX_CONCURRENT_HANDSHAKES: usize = 64;
 
 pub struct Server {
     clients: Arc<Mutex<HashMap<uuid::Uuid, Client>>>,
-    listener: TcpListener,
+    /// One per `--address` and `--tls-address`, all feeding the same
+    /// clients, or just the `--socket` one
+    listeners: Vec<Listener>,
+    /// Path of the Unix socket listened on, removed on shutdown
+    socket_path: Option<PathBuf>,
+    metrics: Arc<Metrics>,
+    /// The banlist, and the name index behind `names`
+    store: Arc<dyn ServerStore>,
+    wire_format: WireFormat,
+    handshakes: Arc<Semaphore>,
+    tcp_keepalive_secs: u64,
+    audit: AuditLog,
+    /// Dropped clients that can still resume, by resume token
+    departed: Arc<Mutex<HashMap<ResumeToken, Departed>>>,
+    hook: Arc<dyn RelayHook>,
+    names: Arc<Names>,
+    /// When the server started, for the uptime in `ServerInfo`
+    started: Instant,
+    /// How long a new connection has to send its hello
+    handshake_timeout: Duration,
+    /// The fake peer that answers every client, with `--echo`
+    echo: Option<Arc<Mirror>>,
+    /// Bytes of each client's write buffer, or 0 for none
+    write_buffer: usize,
+    /// How long clients' queued frames get to be written at shutdown
+    drain: Duration,
+    /// Set once shutdown starts, so clients still greeting aren't registered
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl Server {
-    pub async fn new(address: String, port: u16) -> Self {
-        let listener = TcpListener::bind(format!("{}:{}", address, port))
-            .await
-            .unwrap();
+    pub async fn new(args: Args) -> Result<Self> {
+        let listeners = match &args.socket {
+            Some(path) => vec![bind_unix(path)?],
+            None => args
+                .address
+                .iter()
+                .map(|address| Ok(Listener::Tcp(bind(address.socket_addr(args.port), args.dual_stack)?)))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        #[cfg(feature = "tls")]
+        let listeners: Vec<_> = listeners.into_iter().chain(bind_tls(&args)?).collect();
         let clients = Arc::new(Mutex::new(HashMap::new()));
+        let metrics = Arc::new(Metrics::default());
+
+        if let Some(metrics_port) = args.metrics_port {
+            // Served on the first address's IP
+            let ip = args.address.first().map_or(ListenAddress::DEFAULT_IP, |a| a.ip);
+            let metrics_listener = bind(SocketAddr::new(ip, metrics_port), args.dual_stack)?;
+            tokio::spawn(metrics::serve(metrics_listener, metrics.clone()));
+        }
+
+        let store = open_store(&args)?;
+        // The file replaces whatever banlist the store kept from last time
+        if let Some(path) = args.banlist {
+            store.set_bans(&banlist::load(&path)?)?;
+            tokio::spawn(reload_banlist_on_hangup(path, store.clone()));
+        }
+
+        let mut audit = match args.audit_log {
+            Some(path) => AuditLog::open(path, args.audit_log_max_bytes).await?,
+            None => AuditLog::disabled(),
+        };
+        if let Some(url) = &args.webhook {
+            audit = audit.with_webhook(Webhook::start(url)?);
+        }
 
-        Server { clients, listener }
+        let hook: Arc<dyn RelayHook> = if args.drop_messages_to.is_empty() {
+            Arc::new(hook::AllowAll)
+        } else {
+            Arc::new(hook::Blocklist {
+                recipients: args.drop_messages_to.into_iter().collect(),
+            })
+        };
+
+        Ok(Server {
+            clients,
+            listeners,
+            socket_path: args.socket,
+            metrics,
+            names: Arc::new(Names::new(args.unique_names, store.clone())),
+            store,
+            wire_format: args.wire_format,
+            handshakes: Arc::new(Semaphore::new(MAX_CONCURRENT_HANDSHAKES)),
+            tcp_keepalive_secs: args.tcp_keepalive_secs,
+            audit,
+            departed: Arc::new(Mutex::new(HashMap::new())),
+            hook,
+            started: Instant::now(),
+            handshake_timeout: Duration::from_secs(args.handshake_timeout),
+            echo: args.echo.then(Mirror::new).transpose()?.map(Arc::new),
+            write_buffer: args.write_buffer,
+            drain: Duration::from_secs(args.drain_secs),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Replaces the hook consulted before relaying each message, including
+    /// the one `--drop-messages-to` sets up
+    pub fn with_hook(mut self, hook: Arc<dyn RelayHook>) -> Self {
+        self.hook = hook;
+        self
+    }
+
+    /// Addresses the TCP listeners actually bound, in `--address` order and
+    /// then `--tls-address` order. These differ from the requested ones
+    /// where port 0 was asked for.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self
+            .listeners
+            .iter()
+            .filter_map(|listener| match listener {
+                Listener::Tcp(listener) => Some(listener.local_addr()),
+                #[cfg(feature = "tls")]
+                Listener::Tls(listener, _) => Some(listener.local_addr()),
+                #[cfg(unix)]
+                Listener::Unix(_) => None,
+            })
+            .collect::<std::io::Result<_>>()?)
+    }
+
+    /// The Unix socket listened on instead of TCP, if any
+    pub fn socket_path(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
     }
 
+    /// Serves clients and reads admin commands from stdin until Ctrl-C
     pub async fn run(&mut self) {
-        loop {
-            let (stream, _) = self.listener.accept().await.unwrap();
+        tokio::spawn(admin::run(
+            self.clients.clone(),
+            self.metrics.clone(),
+            self.audit.clone(),
+            self.names.clone(),
+        ));
+        self.run_until(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+    }
 
-            let uuid = uuid::Uuid::new_v4();
+    /// Serves clients until `stop` resolves, then shuts down as on Ctrl-C.
+    /// Unlike `run`, reads no admin commands, so several servers can run in
+    /// one process.
+    pub async fn run_until(&mut self, stop: impl Future<Output = ()>) {
+        tokio::pin!(stop);
+        loop {
+            let accepted = tokio::select! {
+                accepted = accept_any(&self.listeners) => accepted,
+                _ = &mut stop => {
+                    self.shutdown().await;
+                    return;
+                }
+            };
+            let (incoming, address) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
 
-            let (readable_half, writeable_half) = stream.into_split();
+            let bans = self.store.bans().unwrap_or_else(|e| {
+                eprintln!("Failed to read the banlist, letting {} in: {}", address, e);
+                Vec::new()
+            });
+            if banlist::ban_matches(address.ip(), &bans) {
+                println!("Rejected connection from banned address {}", address);
+                Metrics::increment(&self.metrics.auth_failures);
+                self.audit.record(AuditEvent::Rejected { address });
+                continue;
+            }
+            if let Err(e) = incoming.configure(self.tcp_keepalive_secs) {
+                eprintln!("Failed to configure the socket for {}: {}", address, e);
+            }
 
-            let client = Client {
-                readonly_half: Arc::new(Mutex::new(readable_half)),
-                writeable_half: Arc::new(Mutex::new(writeable_half)),
-                friendly_name: Arc::new(std::sync::Mutex::new(None)),
-                uuid,
+            // Greeting the client waits on its network round trip, so it runs
+            // in its own task and the loop goes straight back to accepting.
+            // Holding a permit until then bounds how many run at once.
+            let permit = match self.handshakes.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => continue,
             };
-            self.clients.lock().await.insert(uuid, client.clone());
-
-            let client_clone = client.clone();
-            let clients_clone = self.clients.clone();
-            tokio::spawn(async move {
-                let client_clone = client_clone.clone();
-                loop {
-                    let mut length_buf = [0u8; 8];
-                    if client_clone
-                        .readonly_half
-                        .lock()
-                        .await
-                        .read_exact(&mut length_buf)
-                        .await
-                        .is_err()
-                    {
-                        break;
-                    }
+            let context = ConnectionContext {
+                clients: self.clients.clone(),
+                metrics: self.metrics.clone(),
+                audit: self.audit.clone(),
+                departed: self.departed.clone(),
+                wire_format: self.wire_format,
+                hook: self.hook.clone(),
+                names: self.names.clone(),
+                started: self.started,
+                handshake_timeout: self.handshake_timeout,
+                echo: self.echo.clone(),
+                write_buffer: self.write_buffer,
+                shutting_down: self.shutting_down.clone(),
+            };
+            tokio::spawn(set_up_client(incoming, address, context, permit));
+        }
+    }
 
-                    let message_len: u64 = match bincode::deserialize_from(&length_buf[..]) {
-                        Ok(len) => len,
-                        Err(_) => break,
-                    };
+    /// Stops accepting, tells every client the server is going away and
+    /// closes their connections once what's queued for them is written and
+    /// they've hung up, or `--drain-secs` runs out
+    async fn shutdown(&mut self) {
+        println!("Shutting down");
+        self.listeners.clear();
+        let deadline = tokio::time::Instant::now() + self.drain;
+        // Handshakes still running see this once they have the map, so
+        // every client is either in the snapshot or turned away
+        self.shutting_down.store(true, Ordering::SeqCst);
+        // Taken out of the map, so it isn't locked while they drain, and
+        // those hanging up meanwhile don't announce it to the rest
+        let clients: Vec<Client> = std::mem::take(&mut *self.clients.lock().await)
+            .into_values()
+            .collect();
+        broadcast(clients.iter(), &ClientBoundMessage::ServerShutdown);
+        for client in &clients {
+            client.drain(self.drain);
+        }
+        for client in &clients {
+            client.close().await;
+        }
+        // Clients hang up once they've read the notice, which ends this early
+        for client in &clients {
+            client.await_hangup(deadline).await;
+        }
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A newly accepted connection and the address it came from
+type Accepted = (Incoming, SocketAddr);
+
+/// Where the server accepts connections
+enum Listener {
+    Tcp(TcpListener),
+    /// TCP with a TLS handshake before the hello
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Connections over a Unix socket have no address of their own, so
+    /// they're given loopback with port 0
+    fn poll_accept(&self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<Accepted>> {
+        match self {
+            Listener::Tcp(listener) => listener.poll_accept(cx).map_ok(|(stream, address)| {
+                (Incoming::Ready(Box::new(stream)), address)
+            }),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, acceptor) => {
+                listener.poll_accept(cx).map_ok(|(stream, address)| {
+                    (Incoming::Tls(stream, acceptor.clone()), address)
+                })
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.poll_accept(cx).map_ok(|(stream, _)| {
+                let address = SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0));
+                (Incoming::Ready(Box::new(stream)), address)
+            }),
+        }
+    }
+}
+
+/// An accepted connection. A TLS one is handshaken in its own task, where a
+/// slow client only holds up itself.
+enum Incoming {
+    Ready(Box<dyn ClientTransport>),
+    #[cfg(feature = "tls")]
+    Tls(TcpStream, TlsAcceptor),
+}
+
+impl Incoming {
+    fn configure(&self, keepalive_secs: u64) -> Result<()> {
+        match self {
+            Incoming::Ready(stream) => stream.configure(keepalive_secs),
+            #[cfg(feature = "tls")]
+            Incoming::Tls(stream, _) => stream.configure(keepalive_secs),
+        }
+    }
+
+    /// The connection hellos are exchanged over, once any TLS handshake is
+    /// done
+    async fn establish(self) -> Result<Box<dyn ClientTransport>> {
+        match self {
+            Incoming::Ready(stream) => Ok(stream),
+            #[cfg(feature = "tls")]
+            Incoming::Tls(stream, acceptor) => Ok(Box::new(acceptor.accept(stream).await?)),
+        }
+    }
+}
+
+/// Accepts the next connection on whichever listener has one first
+async fn accept_any(listeners: &[Listener]) -> std::io::Result<Accepted> {
+    std::future::poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready(accepted);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Binds a listener. IPv6 listeners accept IPv4 connections too (as mapped
+/// addresses) only if `dual_stack` is set, whatever the OS default is.
+fn bind(address: SocketAddr, dual_stack: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Binds a Unix socket at `path`. A socket file left behind by a server that
+/// didn't shut down cleanly is replaced, but not one that's still in use.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<Listener> {
+    match UnixListener::bind(path) {
+        Err(e)
+            if e.kind() == std::io::ErrorKind::AddrInUse
+                && std::os::unix::net::UnixStream::connect(path).is_err() =>
+        {
+            std::fs::remove_file(path)?;
+            Ok(Listener::Unix(UnixListener::bind(path)?))
+        }
+        result => Ok(Listener::Unix(result?)),
+    }
+}
+
+/// A listener for each `--tls-address`, all presenting `--tls-cert`
+#[cfg(feature = "tls")]
+fn bind_tls(args: &Args) -> Result<Vec<Listener>> {
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(Vec::new());
+    };
+    let acceptor = tls::acceptor(cert, key)?;
+    args.tls_address
+        .iter()
+        .map(|address| {
+            let listener = bind(address.socket_addr(args.port), args.dual_stack)?;
+            Ok(Listener::Tls(listener, acceptor.clone()))
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> Result<Listener> {
+    Err(Error::Protocol("Unix sockets aren't supported on this platform".to_string()))
+}
+
+/// Server state shared by every connection's tasks
+#[derive(Clone)]
+struct ConnectionContext {
+    clients: Arc<Mutex<HashMap<uuid::Uuid, Client>>>,
+    metrics: Arc<Metrics>,
+    audit: AuditLog,
+    departed: Arc<Mutex<HashMap<ResumeToken, Departed>>>,
+    wire_format: WireFormat,
+    hook: Arc<dyn RelayHook>,
+    names: Arc<Names>,
+    started: Instant,
+    handshake_timeout: Duration,
+    echo: Option<Arc<Mirror>>,
+    write_buffer: usize,
+    shutting_down: Arc<AtomicBool>,
+}
+
+/// What's kept of a dropped client while it might still resume
+struct Departed {
+    uuid: uuid::Uuid,
+    friendly_name: Option<Arc<String>>,
+    presence: Presence,
+    profile: Profile,
+    relayed: VecDeque<(uuid::Uuid, u64)>,
+}
+
+/// Greets a newly accepted client, registers it and sends it its uuid and
+/// the client list
+async fn set_up_client(
+    incoming: Incoming,
+    address: SocketAddr,
+    context: ConnectionContext,
+    permit: OwnedSemaphorePermit,
+) {
+    // Bounded so a connection that never says hello can't hold a
+    // handshake slot, and the task behind it, for ever
+    let greeting = tokio::time::timeout(context.handshake_timeout, async {
+        let (readable_half, mut writeable_half) = incoming.establish().await?.into_split();
+        // Buffered so whatever comes after the hello can be seen without
+        // being consumed
+        let mut readable_half = BufReader::new(readable_half);
+        let hello = greet(&mut readable_half, &mut writeable_half, context.wire_format).await?;
+        Ok::<_, Error>((readable_half, writeable_half, hello))
+    });
+    let greeted = match greeting.await {
+        Ok(Ok(greeted)) => greeted,
+        Ok(Err(e)) => {
+            eprintln!("Failed to greet {}: {}", address, e);
+            Metrics::increment(&context.metrics.auth_failures);
+            return;
+        }
+        Err(_) => {
+            Metrics::increment(&context.metrics.auth_failures);
+            eprintln!(
+                "Dropping {}: no hello within {} seconds",
+                address,
+                context.handshake_timeout.as_secs()
+            );
+            return;
+        }
+    };
+    drop(permit);
+    let (mut readable_half, mut writeable_half, (protocol_version, resume_token)) = greeted;
+
+    // Authenticating until the uuid is queued
+    let resumed = match resume_token {
+        Some(resume_token) => claim_identity(&context, resume_token).await,
+        None => None,
+    };
+    let departed_uuids: HashSet<uuid::Uuid> =
+        context.departed.lock().await.values().map(|departed| departed.uuid).collect();
+    // Held until the client is registered and its list queued, so clients
+    // connecting in parallel list each other one way or the other
+    let mut clients = context.clients.lock().await;
+    if context.shutting_down.load(Ordering::SeqCst) {
+        drop(clients);
+        let _ = framing::write_frame(
+            &mut writeable_half,
+            context.wire_format,
+            &ClientBoundMessage::ServerShutdown,
+        )
+        .await;
+        println!("Turned {} away: the server is shutting down", address);
+        return;
+    }
+    let resumed = resumed.filter(|departed| {
+        let free = !clients.contains_key(&departed.uuid);
+        if !free {
+            eprintln!(
+                "Can't resume {} for {}: another client has that uuid",
+                departed.uuid, address
+            );
+        }
+        free
+    });
+    if sent_early(&mut readable_half) {
+        drop(clients);
+        Metrics::increment(&context.metrics.auth_failures);
+        // An identity presented this way is gone for good, as it would be
+        // for a client disconnected for breaking the protocol
+        if let Some(departed) = &resumed {
+            let name = departed.friendly_name.as_deref().map(String::as_str);
+            announce_departure(&context, departed.uuid, name).await;
+        }
+        let early = framing::read_frame(&mut readable_half);
+        let kind = match tokio::time::timeout(context.handshake_timeout, early).await {
+            Ok(Ok(Some(frame))) => context.wire_format.decode::<ServerBoundMessage>(&frame).ok(),
+            _ => None,
+        }
+        .map_or("a frame", |message| message.kind());
+        let feedback = ClientBoundMessage::ProtocolError(format!(
+            "expected nothing before the uuid, got {}",
+            kind
+        ));
+        let _ = framing::write_frame(&mut writeable_half, context.wire_format, &feedback).await;
+        eprintln!("Dropping {}: sent {} while {}", address, kind, Stage::Authenticating);
+        return;
+    }
+    let uuid = match &resumed {
+        Some(departed) => departed.uuid,
+        None => unused_uuid(uuid::Uuid::new_v4, |uuid| {
+            clients.contains_key(uuid) || departed_uuids.contains(uuid)
+        }),
+    };
+    // Lets the writer send a burst of queued frames in a few syscalls
+    let writeable_half: WriteHalf = match context.write_buffer {
+        0 => writeable_half,
+        capacity => Box::new(BufWriter::with_capacity(capacity, writeable_half)),
+    };
+    let client = Client::new(
+        Box::new(readable_half),
+        writeable_half,
+        uuid,
+        address,
+        context.wire_format,
+        protocol_version,
+        context.metrics.clone(),
+    );
+    if let Some(departed) = &resumed {
+        client.friendly_name.store(departed.friendly_name.clone());
+        client.presence.store(Arc::new(departed.presence));
+        client.profile.store(Arc::new(departed.profile.clone()));
+        *client.relayed.lock().unwrap() = departed.relayed.clone();
+    }
+    if resumed.is_some() {
+        println!("Client resumed: {} ({})", uuid, address);
+    } else {
+        println!("New client connected: {} ({})", uuid, address);
+    }
+
+    // The uuid is queued before the client is in the map, so it's the first
+    // frame whatever is broadcast meanwhile. The list is queued before the
+    // map is released, so a peer listed later comes after it rather than
+    // being overwritten by it.
+    let uuid_message = ClientBoundMessage::SetUuid(uuid, client.resume_token);
+    if let Err(e) = client.send_message(uuid_message) {
+        eprintln!("Failed to send to {}: {}", uuid, e);
+    }
+    clients.insert(uuid, client.clone());
+    let client_descriptions: Vec<ClientDescription> = clients
+        .values()
+        .filter(|c| c.is_listed())
+        .map(|c| c.description())
+        .collect();
+    println!("Describing {} clients to {}", client_descriptions.len(), uuid);
+    send_client_list(&client, &client_descriptions);
+    drop(clients);
+    Metrics::increment(&context.metrics.clients_connected);
+    context.audit.record(AuditEvent::Connected {
+        uuid,
+        address,
+        resumed: resumed.is_some(),
+    });
+
+    let client_clone = client.clone();
+    let context_clone = context.clone();
+    let reader_task = tokio::spawn(async move {
+        let context = context_clone;
+        let result = handle_client(&client_clone, &context).await;
+        match result {
+            Ok(()) => println!(
+                "Client disconnected: {} ({})",
+                client_clone.uuid, client_clone.address
+            ),
+            Err(e) => println!(
+                "Client disconnected: {} ({}): {}",
+                client_clone.uuid, client_clone.address, e
+            ),
+        }
+        // A client that left, or that the server closed, is gone for good.
+        // Anything else may just be a network blip.
+        if client_clone.is_closing() {
+            let ConnectionContext {
+                clients,
+                metrics,
+                audit,
+                names,
+                ..
+            } = &context;
+            remove_client(clients, metrics, audit, names, client_clone.uuid).await;
+        } else {
+            park_client(&context, &client_clone).await;
+        }
+    });
+    let _ = client.reader_task.set(reader_task.abort_handle());
+}
+
+/// Sends `client` the peer list. Nearly always a single frame. A huge
+/// server sends parts first so no frame nears MAX_FRAME_LEN.
+fn send_client_list(client: &Client, client_descriptions: &[ClientDescription]) {
+    let mut chunks = client_descriptions.chunks(CLIENT_LIST_CHUNK).peekable();
+    while let Some(chunk) = chunks.next() {
+        let message = if chunks.peek().is_some() {
+            ClientBoundMessage::ClientListPart(chunk.to_vec())
+        } else {
+            ClientBoundMessage::ClientList(chunk.to_vec())
+        };
+        if let Err(e) = client.send_message(message) {
+            eprintln!("Failed to send to {}: {}", client.uuid, e);
+            return;
+        }
+    }
+    if client_descriptions.is_empty() {
+        if let Err(e) = client.send_message(ClientBoundMessage::ClientList(Vec::new())) {
+            eprintln!("Failed to send to {}: {}", client.uuid, e);
+        }
+    }
+}
+
+/// How far a connection has got. Frames are only dispatched once it's
+/// `Ready`, and one that's out of order for the stage ends the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    /// Only a `ClientHello` may come next. Read by `greet`.
+    AwaitingHello,
+    /// Said hello and is being given its identity, resumed or new. The
+    /// client waits for its uuid, so nothing may come. Checked by
+    /// `sent_early` before the uuid is sent.
+    Authenticating,
+    /// Registered with a uuid. Anything but another hello is dispatched by
+    /// `handle_client`.
+    Ready,
+}
+
+impl Stage {
+    fn admits(self, message: &ServerBoundMessage) -> bool {
+        let hello = matches!(message, ServerBoundMessage::ClientHello { .. });
+        match self {
+            Stage::AwaitingHello => hello,
+            Stage::Authenticating => false,
+            Stage::Ready => !hello,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::AwaitingHello => write!(f, "awaiting its hello"),
+            Stage::Authenticating => write!(f, "authenticating"),
+            Stage::Ready => write!(f, "already greeted"),
+        }
+    }
+}
 
-                    let mut buffer = vec![0u8; message_len as usize];
-                    if client_clone
-                        .readonly_half
+/// Whether a client that's `Authenticating` has sent anything since its
+/// hello. Its uuid hasn't been sent, so nothing already here can be a reply
+/// to it. Only looks at what has arrived, without waiting.
+fn sent_early(readable_half: &mut BufReader<ReadHalf>) -> bool {
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    matches!(
+        std::pin::Pin::new(readable_half).poll_fill_buf(&mut cx),
+        Poll::Ready(Ok(buffered)) if !buffered.is_empty()
+    )
+}
+
+/// The error that ends a connection for sending `message` while in
+/// `stage`, which the disconnect is logged with
+fn out_of_order(stage: Stage, message: &ServerBoundMessage) -> Error {
+    Error::Protocol(format!("sent {} while {}", message.kind(), stage))
+}
+
+/// Sends the server's hello and reads the client's, returning the resume
+/// token it presented, if any
+async fn greet(
+    readable_half: &mut (impl AsyncRead + Unpin),
+    writeable_half: &mut WriteHalf,
+    wire_format: WireFormat,
+) -> Result<(u32, Option<ResumeToken>)> {
+    let hello = framing::encode_hello_frame(&ClientBoundMessage::ServerHello {
+        protocol_version: PROTOCOL_VERSION,
+        wire_format,
+    })?;
+    framing::write_encoded(writeable_half, &hello).await?;
+
+    let Some(frame) = framing::read_frame(readable_half).await? else {
+        return Err(Error::Protocol(
+            "closed the connection before its hello".to_string(),
+        ));
+    };
+    let message = wire_format.decode(&frame)?;
+    if !Stage::AwaitingHello.admits(&message) {
+        let error = out_of_order(Stage::AwaitingHello, &message);
+        // The client knows the wire format from our hello, so it can read why
+        let feedback = ClientBoundMessage::ProtocolError(format!("expected a hello, got {}", message.kind()));
+        let _ = framing::write_frame(writeable_half, wire_format, &feedback).await;
+        return Err(error);
+    }
+    match message {
+        ServerBoundMessage::ClientHello {
+            protocol_version,
+            resume_token,
+        } => Ok((protocol_version, resume_token)),
+        _ => unreachable!("admitted while awaiting the hello"),
+    }
+}
+
+/// The first uuid from `generate` for which `taken` is false. A v4 collision
+/// is astronomically unlikely, but one would silently replace a client in the
+/// map and orphan its task.
+fn unused_uuid(
+    mut generate: impl FnMut() -> uuid::Uuid,
+    taken: impl Fn(&uuid::Uuid) -> bool,
+) -> uuid::Uuid {
+    loop {
+        let uuid = generate();
+        if !taken(&uuid) {
+            return uuid;
+        }
+        eprintln!("Generated uuid {} is already in use, generating another", uuid);
+    }
+}
+
+/// Hands over the identity behind `resume_token`: that of a dropped client
+/// still in its grace period, or of a live one whose connection the server
+/// hasn't noticed is dead yet, which is closed in favour of the new one
+async fn claim_identity(
+    context: &ConnectionContext,
+    resume_token: ResumeToken,
+) -> Option<Departed> {
+    if let Some(departed) = context.departed.lock().await.remove(&resume_token) {
+        return Some(departed);
+    }
+
+    let mut clients = context.clients.lock().await;
+    let uuid = clients
+        .values()
+        .find(|client| client.resume_token == resume_token)?
+        .uuid;
+    let stale = clients.remove(&uuid)?;
+    drop(clients);
+    if let Some(reader_task) = stale.reader_task.get() {
+        reader_task.abort();
+    }
+    stale.disconnect();
+    Metrics::decrement(&context.metrics.clients_connected);
+    let presence = **stale.presence.load();
+    let profile = Profile::clone(&stale.profile.load());
+    let relayed = stale.relayed.lock().unwrap().clone();
+    Some(Departed {
+        uuid,
+        friendly_name: stale.friendly_name.load_full(),
+        presence,
+        profile,
+        relayed,
+    })
+}
+
+/// Takes a dropped client out of the map without telling anyone, then
+/// waits `RESUME_GRACE` for it to come back with its resume token. Only if
+/// it doesn't are its peers told it left, so a brief drop causes no churn.
+async fn park_client(context: &ConnectionContext, client: &Client) {
+    if context.clients.lock().await.remove(&client.uuid).is_none() {
+        return;
+    }
+    Metrics::decrement(&context.metrics.clients_connected);
+    let parked = match context.departed.lock().await.entry(client.resume_token) {
+        hash_map::Entry::Vacant(entry) => {
+            entry.insert(Departed {
+                uuid: client.uuid,
+                friendly_name: client.friendly_name.load_full(),
+                presence: **client.presence.load(),
+                profile: Profile::clone(&client.profile.load()),
+                relayed: client.relayed.lock().unwrap().clone(),
+            });
+            true
+        }
+        // Tokens are random, so this is as unlikely as a uuid collision.
+        // Replacing the other entry would hand its identity to this client.
+        hash_map::Entry::Occupied(_) => {
+            eprintln!(
+                "Resume token of {} is held by another dropped client, so it can't resume",
+                client.uuid
+            );
+            false
+        }
+    };
+
+    if parked {
+        tokio::time::sleep(RESUME_GRACE).await;
+        if context
+            .departed
+            .lock()
+            .await
+            .remove(&client.resume_token)
+            .is_none()
+        {
+            // Resumed in the meantime
+            return;
+        }
+    }
+    let name = client.friendly_name.load_full();
+    announce_departure(context, client.uuid, name.as_deref().map(String::as_str)).await;
+}
+
+/// Frees the name of a client that won't resume and tells everyone it left
+async fn announce_departure(context: &ConnectionContext, uuid: uuid::Uuid, name: Option<&str>) {
+    if let Some(name) = name {
+        context.names.release(uuid, name);
+    }
+    context.audit.record(AuditEvent::Disconnected { uuid });
+    broadcast(
+        context.clients.lock().await.values(),
+        &ClientBoundMessage::ClientDisconnected(uuid),
+    );
+}
+
+/// When one client last had a connection request forwarded to each target.
+/// A repeat within `REQUEST_DEDUPE_WINDOW` of that is dropped, without
+/// pushing the window back, so a client retrying now and then still gets
+/// through.
+#[derive(Default)]
+struct RecentRequests(HashMap<uuid::Uuid, Instant>);
+
+impl RecentRequests {
+    /// Whether a request to `target` at `now` repeats one still in the window
+    fn is_repeat(&mut self, target: uuid::Uuid, now: Instant) -> bool {
+        self.0.retain(|_, forwarded| now - *forwarded < REQUEST_DEDUPE_WINDOW);
+        self.0.contains_key(&target)
+    }
+
+    fn forwarded(&mut self, target: uuid::Uuid, now: Instant) {
+        self.0.insert(target, now);
+    }
+
+    /// Forgets the request to `target` once it's cancelled, so a new one
+    /// goes straight through
+    fn withdraw(&mut self, target: uuid::Uuid) {
+        self.0.remove(&target);
+    }
+}
+
+/// Reads and dispatches frames from one client. Returns `Ok` when the client
+/// closes the connection cleanly or the server disconnects it.
+async fn handle_client(client: &Client, context: &ConnectionContext) -> Result<()> {
+    let ConnectionContext {
+        clients,
+        metrics,
+        audit,
+        hook,
+        names,
+        started,
+        echo,
+        ..
+    } = context;
+    let mut recent_requests = RecentRequests::default();
+    let mut protocol_errors = 0;
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(client, metrics) => frame,
+            _ = client.closed() => return Ok(()),
+        };
+        let message = match frame {
+            Ok(Some(frame)) => client.wire_format.decode::<ServerBoundMessage>(&frame),
+            Ok(None) => return Ok(()),
+            // Read whole, so the next frame can still be found
+            Err(e @ Error::Corrupt(_)) => Err(e),
+            Err(e) => {
+                // Tell the client why before dropping it, if the socket still works
+                if let Error::Desync(_) = e {
+                    let _ = client.send_message(ClientBoundMessage::ProtocolError(e.to_string()));
+                }
+                return Err(e);
+            }
+        };
+        if message.is_ok() {
+            protocol_errors = 0;
+        }
+        if let Ok(message) = &message {
+            if !Stage::Ready.admits(message) {
+                let error = out_of_order(Stage::Ready, message);
+                let _ = client.send_message(ClientBoundMessage::ProtocolError(format!(
+                    "{} is only valid as the first frame",
+                    message.kind()
+                )));
+                Metrics::increment(&metrics.auth_failures);
+                // Gone for good, not held open for a resume
+                client.disconnect();
+                return Err(error);
+            }
+        }
+        match message {
+            Ok(message) if echo.as_ref().is_some_and(|mirror| mirror.is_addressed(&message)) => {
+                if let ServerBoundMessage::Message(to, payload) = &message {
+                    client.acknowledge(to.uuid, payload.counter);
+                }
+                if let Some(mirror) = echo {
+                    mirror.answer(client, message);
+                }
+            }
+            Ok(message) => match message {
+                ServerBoundMessage::Advertise(name) => {
+                    let previous = client.friendly_name.load_full();
+                    if !names.claim(client.uuid, previous.as_deref().map(String::as_str), &name) {
+                        let _ = client.send_message(ClientBoundMessage::NameTaken(name));
+                        continue;
+                    }
+                    let previous = client.friendly_name.swap(Some(Arc::new(name.clone())));
+                    if client.is_listed() {
+                        // A second Advertise is a rename, so peers update the
+                        // existing entry rather than listing the client twice
+                        let message = match previous {
+                            Some(_) => ClientBoundMessage::ClientRenamed(client.uuid, name),
+                            None => ClientBoundMessage::NewClient(client.description()),
+                        };
+                        broadcast(clients.lock().await.values(), &message);
+                    }
+                    // Named clients see the mirror as a peer, whether or not
+                    // anyone else is around
+                    if let Some(mirror) = echo {
+                        let _ = client.send_message(ClientBoundMessage::NewClient(mirror.description()));
+                    }
+                }
+                ServerBoundMessage::ConnectionRequest(
+                    client_description,
+                    public_key,
+                    handshake,
+                ) => {
+                    let now = Instant::now();
+                    if recent_requests.is_repeat(client_description.uuid, now) {
+                        Metrics::increment(&metrics.frames_dropped);
+                        continue;
+                    }
+                    let clients_lock = clients.lock().await;
+                    if let Some(target_client) = clients_lock.get(&client_description.uuid) {
+                        let message = ClientBoundMessage::ConnectionRequest(
+                            client.description(),
+                            public_key,
+                            handshake,
+                        );
+                        target_client.relay(message);
+                        recent_requests.forwarded(client_description.uuid, now);
+                        audit.record(AuditEvent::SessionRequested {
+                            from_uuid: client.uuid,
+                            to_uuid: client_description.uuid,
+                        });
+                    }
+                }
+                ServerBoundMessage::ConnectionResponse(client_description, response, handshake) => {
+                    let clients_lock = clients.lock().await;
+                    if let Some(target_client) = clients_lock.get(&client_description.uuid) {
+                        let message = ClientBoundMessage::ConnectionResponse(
+                            client.description(),
+                            response,
+                            handshake,
+                        );
+                        target_client.relay(message);
+                        audit.record(AuditEvent::SessionAccepted {
+                            from_uuid: client.uuid,
+                            to_uuid: client_description.uuid,
+                        });
+                    }
+                }
+                ServerBoundMessage::Message(client_description, message) => {
+                    let size = message.ciphertext.len();
+                    match hook.on_message(client.uuid, client_description.uuid, size) {
+                        HookDecision::Allow => {}
+                        HookDecision::Drop => {
+                            Metrics::increment(&metrics.frames_dropped);
+                            client.settle(client_description.uuid, message.counter);
+                            continue;
+                        }
+                        HookDecision::RateLimit(delay) => tokio::time::sleep(delay).await,
+                    }
+                    let clients_lock = clients.lock().await;
+                    if let Some(target_client) = clients_lock.get(&client_description.uuid) {
+                        audit.record(AuditEvent::Message {
+                            from_uuid: client.uuid,
+                            to_uuid: client_description.uuid,
+                            bytes: size,
+                            counter: message.counter,
+                        });
+                        let counter = message.counter;
+                        let message = ClientBoundMessage::Message(client.description(), message);
+                        target_client.relay(message);
+                        drop(clients_lock);
+                        client.acknowledge(client_description.uuid, counter);
+                        Metrics::increment(&metrics.messages_relayed);
+                    } else {
+                        drop(clients_lock);
+                        Metrics::increment(&metrics.frames_dropped);
+                        let _ = client.send_message(ClientBoundMessage::RecipientUnavailable(
+                            client_description.uuid,
+                            message.counter,
+                        ));
+                    }
+                }
+                ServerBoundMessage::FileOffer(client_description, payload) => {
+                    let message = ClientBoundMessage::FileOffer(client.description(), payload);
+                    forward(clients, metrics, client_description.uuid, message).await;
+                }
+                ServerBoundMessage::FileResponse(client_description, payload) => {
+                    let message = ClientBoundMessage::FileResponse(client.description(), payload);
+                    forward(clients, metrics, client_description.uuid, message).await;
+                }
+                ServerBoundMessage::FileChunk(client_description, payload) => {
+                    let message = ClientBoundMessage::FileChunk(client.description(), payload);
+                    forward(clients, metrics, client_description.uuid, message).await;
+                }
+                ServerBoundMessage::FileAck(client_description, payload) => {
+                    let message = ClientBoundMessage::FileAck(client.description(), payload);
+                    forward(clients, metrics, client_description.uuid, message).await;
+                }
+                ServerBoundMessage::RequestDirect(client_description, address) => {
+                    audit.record(AuditEvent::DirectRequested {
+                        from_uuid: client.uuid,
+                        to_uuid: client_description.uuid,
+                    });
+                    let message = ClientBoundMessage::DirectRequest(client.description(), address);
+                    forward(clients, metrics, client_description.uuid, message).await;
+                }
+                ServerBoundMessage::ClientHello { .. } => unreachable!("refused once greeted"),
+                ServerBoundMessage::RejectRequest(client_description) => {
+                    let message = ClientBoundMessage::RequestRejected(client.description());
+                    forward(clients, metrics, client_description.uuid, message).await;
+                }
+                ServerBoundMessage::Ping(id) => {
+                    let _ = client.send_message(ClientBoundMessage::Pong(id));
+                }
+                ServerBoundMessage::Leave => {
+                    client.disconnect();
+                    return Ok(());
+                }
+                ServerBoundMessage::CloseConnection(client_description) => {
+                    // A cancelled request, or a session that's over
+                    recent_requests.withdraw(client_description.uuid);
+                    let clients_lock = clients.lock().await;
+                    if let Some(target_client) = clients_lock.get(&client_description.uuid) {
+                        let message = ClientBoundMessage::ChannelClosed(client.uuid);
+                        target_client.relay(message);
+                    }
+                }
+                ServerBoundMessage::SetStatus(presence) => {
+                    let previous = *client.presence.swap(Arc::new(presence));
+                    if previous == presence || client.friendly_name.load().is_none() {
+                        continue;
+                    }
+                    // Going invisible looks like leaving, and coming back like joining
+                    let message = if presence == Presence::Invisible {
+                        ClientBoundMessage::ClientDisconnected(client.uuid)
+                    } else if previous == Presence::Invisible {
+                        ClientBoundMessage::NewClient(client.description())
+                    } else {
+                        ClientBoundMessage::PresenceChanged(client.uuid, presence)
+                    };
+                    broadcast(clients.lock().await.values(), &message);
+                }
+                ServerBoundMessage::ReadReceipt(uuid, message_id) => {
+                    let clients_lock = clients.lock().await;
+                    if let Some(target_client) = clients_lock.get(&uuid) {
+                        let message =
+                            ClientBoundMessage::ReadReceipt(client.description(), message_id);
+                        target_client.relay(message);
+                    }
+                }
+                ServerBoundMessage::React(uuid, message_id, emoji) => {
+                    if !is_valid_reaction(&emoji) {
+                        let feedback = "a reaction must be a single emoji".to_string();
+                        let _ = client.send_message(ClientBoundMessage::ProtocolError(feedback));
+                        continue;
+                    }
+                    let clients_lock = clients.lock().await;
+                    if let Some(target_client) = clients_lock.get(&uuid) {
+                        let message =
+                            ClientBoundMessage::Reaction(client.description(), message_id, emoji);
+                        target_client.relay(message);
+                    }
+                }
+                ServerBoundMessage::QueryAcks(queried) => {
+                    let _ = client.send_message(ClientBoundMessage::Acked(client.acked(&queried)));
+                }
+                ServerBoundMessage::ServerInfo => {
+                    let connected_clients = clients
                         .lock()
                         .await
-                        .read_exact(&mut buffer)
-                        .await
-                        .is_err()
-                    {
-                        break;
+                        .values()
+                        .filter(|c| **c.presence.load() != Presence::Invisible)
+                        .count();
+                    let _ = client.send_message(ClientBoundMessage::ServerInfo {
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        uptime_secs: started.elapsed().as_secs(),
+                        connected_clients: connected_clients as u64,
+                    });
+                }
+                ServerBoundMessage::SetProfile(profile) => {
+                    if let Some(feedback) = profile.problem() {
+                        let _ = client.send_message(ClientBoundMessage::ProtocolError(feedback));
+                        continue;
+                    }
+                    client.profile.store(Arc::new(profile.clone()));
+                    // Unlisted clients' peers get it with the description
+                    // once they're listed
+                    if client.is_listed() {
+                        let message = ClientBoundMessage::ProfileChanged(client.uuid, profile);
+                        broadcast(clients.lock().await.values(), &message);
                     }
+                }
+            },
+            Err(e) => {
+                let feedback = decode_failure(client, &e);
+                eprintln!("Skipping a malformed frame from {}: {}", client.uuid, feedback);
+                Metrics::increment(&metrics.frames_dropped);
+                let _ = client.send_message(ClientBoundMessage::ProtocolError(feedback));
 
-                    match bincode::deserialize_from::<
-                        &[u8],
-                        crate::shared::messages::ServerBoundMessage,
-                    >(&buffer[..])
-                    {
-                        Ok(message) => match message {
-                            ServerBoundMessage::Advertise(name) => {
-                                *client_clone.friendly_name.lock().unwrap() = Some(name.clone());
-                                let message =
-                                    ClientBoundMessage::NewClient((name, client_clone.uuid));
-                                for client in clients_clone.lock().await.values() {
-                                    client.send_message(message.clone()).await;
-                                }
-                            }
-                            ServerBoundMessage::ConnectionRequest(client_description, public_key) => {
-                                let clients_lock = clients_clone.lock().await;
-                                let target_client = clients_lock.get(&client_description.1);
-                                if let Some(target_client) = target_client {
-                                    let message = ClientBoundMessage::ConnectionRequest((
-                                        client_clone
-                                            .friendly_name
-                                            .lock()
-                                            .unwrap()
-                                            .clone()
-                                            .unwrap_or("".to_string()),
-                                        client_clone.uuid,
-                                    ), public_key);
-                                    target_client.send_message(message).await;
-                                }
-                            }
-                            ServerBoundMessage::ConnectionResponse(
-                                client_description,
-                                response,
-                            ) => {
-                                let clients_lock = clients_clone.lock().await;
-                                let target_client = clients_lock.get(&client_description.1);
-                                if let Some(target_client) = target_client {
-                                    let message = ClientBoundMessage::ConnectionResponse(
-                                        (
-                                            client_clone
-                                                .friendly_name
-                                                .lock()
-                                                .unwrap()
-                                                .clone()
-                                                .unwrap_or("".to_string()),
-                                            client_clone.uuid,
-                                        ),
-                                        response,
-                                    );
-                                    target_client.send_message(message).await;
-                                }
-                            }
-                            ServerBoundMessage::Message(client_description, message) => {
-                                let clients_lock = clients_clone.lock().await;
-                                let target_client = clients_lock.get(&client_description.1);
-                                if let Some(target_client) = target_client {
-                                    let message = ClientBoundMessage::Message(
-                                        (
-                                            client_clone
-                                                .friendly_name
-                                                .lock()
-                                                .unwrap()
-                                                .clone()
-                                                .unwrap_or("".to_string()),
-                                            client_clone.uuid,
-                                        ),
-                                        message,
-                                    );
-                                    target_client.send_message(message).await;
-                                }
-                            }
-                        },
-                        Err(e) => {
-                            eprintln!("Failed to deserialize message: {}", e);
-                        }
-                    };
+                protocol_errors += 1;
+                if protocol_errors >= MAX_PROTOCOL_ERRORS {
+                    // Gone for good, not held open for a resume
+                    client.disconnect();
+                    return Err(Error::Protocol(format!(
+                        "{} malformed frames in a row",
+                        protocol_errors
+                    )));
+                }
+            }
+        };
+    }
+}
+
+/// Why a frame from `client` couldn't be decoded. A message the server
+/// doesn't know usually means the two were built from different code, so
+/// both protocol versions are given to tell which is behind.
+fn decode_failure(client: &Client, error: &Error) -> String {
+    if error.is_unknown_variant() {
+        format!(
+            "couldn't decode a frame: it holds a message this server doesn't know \
+             (server protocol version {}, client announced {}): {}",
+            PROTOCOL_VERSION, client.protocol_version, error
+        )
+    } else {
+        format!("couldn't decode a frame: {}", error)
+    }
+}
+
+async fn read_frame(client: &Client, metrics: &Metrics) -> Result<Option<Vec<u8>>> {
+    let frame = framing::read_frame(&mut *client.readonly_half.lock().await).await?;
+    if let Some(body) = &frame {
+        let len = framing::frame_len(body.len());
+        client.traffic.lock().unwrap().received(len);
+        metrics.frame_received(len);
+    }
+    Ok(frame)
+}
+
+/// Sends an encrypted payload on to its recipient, counting it as dropped if
+/// the recipient isn't connected
+async fn forward(
+    clients: &Mutex<HashMap<uuid::Uuid, Client>>,
+    metrics: &Metrics,
+    target: uuid::Uuid,
+    message: ClientBoundMessage,
+) {
+    match clients.lock().await.get(&target) {
+        Some(target_client) => {
+            target_client.relay(message);
+            Metrics::increment(&metrics.messages_relayed);
+        }
+        None => Metrics::increment(&metrics.frames_dropped),
+    }
+}
+
+/// Re-reads the banlist file whenever the process receives SIGHUP
+#[cfg(unix)]
+async fn reload_banlist_on_hangup(path: PathBuf, store: Arc<dyn ServerStore>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!(
+                "Failed to listen for SIGHUP, banlist reload disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let reloaded = banlist::load(&path)
+            .map_err(Error::from)
+            .and_then(|entries| store.set_bans(&entries).map(|_| entries.len()));
+        match reloaded {
+            Ok(len) => println!("Reloaded banlist ({} entries)", len),
+            Err(e) => eprintln!("Failed to reload banlist: {}", e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_banlist_on_hangup(_path: PathBuf, _store: Arc<dyn ServerStore>) {}
+
+/// The store `--store` names, or one in memory without it
+#[cfg(feature = "sled-store")]
+fn open_store(args: &Args) -> Result<Arc<dyn ServerStore>> {
+    Ok(match &args.store {
+        Some(path) => Arc::new(store::SledStore::open(path)?),
+        None => Arc::new(MemoryStore::default()),
+    })
+}
+
+#[cfg(not(feature = "sled-store"))]
+fn open_store(_args: &Args) -> Result<Arc<dyn ServerStore>> {
+    Ok(Arc::new(MemoryStore::default()))
+}
+
+/// Removes a client from the map, frees its name and tells everyone else
+/// it left. Does nothing if it was already gone.
+async fn remove_client(
+    clients: &Mutex<HashMap<uuid::Uuid, Client>>,
+    metrics: &Metrics,
+    audit: &AuditLog,
+    names: &Names,
+    uuid: uuid::Uuid,
+) {
+    let mut clients = clients.lock().await;
+    let Some(client) = clients.remove(&uuid) else {
+        return;
+    };
+    if let Some(name) = client.friendly_name.load().as_deref() {
+        names.release(uuid, name);
+    }
+    Metrics::decrement(&metrics.clients_connected);
+    audit.record(AuditEvent::Disconnected { uuid });
+
+    broadcast(clients.values(), &ClientBoundMessage::ClientDisconnected(uuid));
+}
+
+/// Relays `message` to every recipient, encoding it once per wire format
+/// rather than once per client
+fn broadcast<'a>(recipients: impl IntoIterator<Item = &'a Client>, message: &ClientBoundMessage) {
+    let mut encoded: Vec<(WireFormat, Frame)> = Vec::new();
+    for client in recipients {
+        let frame = match encoded.iter().find(|(format, _)| *format == client.wire_format) {
+            Some((_, frame)) => frame.clone(),
+            None => match framing::encode_frame(client.wire_format, message) {
+                Ok(frame) => {
+                    let frame: Frame = frame.into();
+                    encoded.push((client.wire_format, frame.clone()));
+                    frame
                 }
-                println!("Client disconnected: {}", client_clone.uuid);
-                clients_clone.lock().await.remove(&client_clone.uuid);
-                let message = ClientBoundMessage::ClientDisconnected(client_clone.uuid);
-                for client in clients_clone.lock().await.values() {
-                    client.send_message(message.clone()).await;
+                Err(e) => {
+                    eprintln!("Failed to encode a broadcast frame: {}", e);
+                    return;
                 }
-            });
+            },
+        };
+        client.relay_frame(frame);
+    }
+}
 
-            println!("New client connected: {}", uuid);
+/// An `--address` value: an IP, optionally with a port
+#[derive(Clone, Copy, Debug)]
+pub struct ListenAddress {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
 
-            let uuid_message = ClientBoundMessage::SetUuid(uuid);
-            client.send_message(uuid_message).await;
+impl ListenAddress {
+    const DEFAULT_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
 
-            let client_descriptions: Vec<ClientDescription> = self
-                .clients
-                .lock()
-                .await
-                .iter()
-                .filter(|(_, c)| c.friendly_name.lock().unwrap().is_some())
-                .map(|(uuid, client)| {
-                    (client.friendly_name.lock().unwrap().clone().unwrap(), *uuid)
-                })
-                .collect();
+    fn socket_addr(&self, default_port: u16) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port.unwrap_or(default_port))
+    }
+}
 
-            println!("Describing clients: {:?}", client_descriptions);
+impl FromStr for ListenAddress {
+    type Err = String;
 
-            let message = ClientBoundMessage::ClientList(client_descriptions);
-            client.send_message(message).await;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(address) = SocketAddr::from_str(s) {
+            return Ok(ListenAddress {
+                ip: address.ip(),
+                port: Some(address.port()),
+            });
         }
+        let ip = s
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .map_err(|_| format!("expected an IP or IP:port, got {}", s))?;
+        Ok(ListenAddress { ip, port: None })
     }
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-pub(crate) struct Args {
-    /// Address to bind to
+pub struct Args {
+    /// Address to listen on, e.g. 0.0.0.0, or :: for IPv6. Give it a port
+    /// (127.0.0.1:9000, [::1]:9000) to override --port. Repeat to listen on
+    /// several addresses at once.
     #[arg(short, long, default_value = "0.0.0.0")]
-    pub address: String,
+    pub address: Vec<ListenAddress>,
 
-    /// Port to bind to
+    /// Port to listen on for addresses given without one, or 0 to let the OS
+    /// pick one
     #[arg(short, long, default_value_t = 8080)]
     pub port: u16,
+
+    /// When binding an IPv6 address such as ::, accept IPv4 connections too
+    #[arg(long)]
+    pub dual_stack: bool,
+
+    /// Serve Prometheus metrics over HTTP on this port
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+
+    /// File of banned IP addresses and CIDR ranges, one per line (reloaded on SIGHUP)
+    #[arg(long)]
+    pub banlist: Option<PathBuf>,
+
+    /// Encoding used for frame bodies; json is handy for debugging
+    #[arg(long, value_enum, default_value_t = WireFormat::Bincode)]
+    pub wire_format: WireFormat,
+
+    /// Idle seconds before TCP keepalive probes start, or 0 to disable them
+    #[arg(long, default_value_t = 60)]
+    pub tcp_keepalive_secs: u64,
+
+    /// Append a JSON line to this file for every connection, session request
+    /// and relayed message (metadata only, never contents)
+    #[arg(long)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Size at which the audit log is moved to <path>.1 and a new one started
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    pub audit_log_max_bytes: u64,
+
+    /// POST a JSON event to this http:// URL for every connect, disconnect
+    /// and relayed message (metadata only). Retried with backoff, then
+    /// dropped; a slow webhook never holds up relaying.
+    #[arg(long, value_name = "URL")]
+    pub webhook: Option<String>,
+
+    /// Silently drop messages addressed to this client uuid. Repeat to block
+    /// several.
+    #[arg(long, value_name = "UUID")]
+    pub drop_messages_to: Vec<uuid::Uuid>,
+
+    /// Keep the banlist and name index in a database at this path, so the
+    /// banlist survives a restart. Without --banlist, the one stored last
+    /// time applies.
+    #[cfg(feature = "sled-store")]
+    #[arg(long)]
+    pub store: Option<PathBuf>,
+
+    /// Seconds a new connection has to send its hello before it's dropped
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    pub handshake_timeout: u64,
+
+    /// Turn away a friendly name another connected client already has,
+    /// ignoring case. The client keeps its old name and is asked for another.
+    #[arg(long)]
+    pub unique_names: bool,
+
+    /// Bytes buffered per client so frames queued together are written
+    /// together, or 0 to write each frame on its own
+    #[arg(long, value_name = "BYTES", default_value_t = 16 * 1024)]
+    pub write_buffer: usize,
+
+    /// Seconds given at shutdown for frames still queued to each client,
+    /// including the shutdown notice, to be written before connections close
+    #[arg(long, value_name = "SECS", default_value_t = 2)]
+    pub drain_secs: u64,
+
+    /// Test mode: every client that sets a name sees a fake peer called
+    /// "echo" that accepts connection requests and sends each message back.
+    /// The server holds the echo peer's session keys, so it can read what's
+    /// sent to it.
+    #[arg(long)]
+    pub echo: bool,
+
+    /// Listen on a Unix domain socket at this path instead of TCP, for
+    /// clients on the same host. --address and --port are then unused.
+    #[arg(long, conflicts_with_all = ["address", "dual_stack"])]
+    pub socket: Option<PathBuf>,
+
+    /// Also listen for TLS connections on this address, in the same forms
+    /// as --address. Repeat for several.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "ADDRESS", requires = "tls_cert", conflicts_with = "socket")]
+    pub tls_address: Vec<ListenAddress>,
+
+    /// PEM certificate chain the --tls-address listeners present
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use tokio::{io::AsyncWriteExt, net::TcpStream, sync::oneshot};
+
+    use super::*;
+    use crate::shared::{
+        crypto::{IdentityKey, KeyType},
+        messages::{EncryptedPayload, Handshake, RatchetHeader},
+    };
+
+    #[tokio::test]
+    async fn a_long_client_list_arrives_complete_in_chunks() {
+        let (client, mut far) = client::tests::connected("127.0.0.1:4000".parse().unwrap());
+        let everyone: Vec<_> = (0..5000u128)
+            .map(|i| ClientDescription::new(format!("peer{}", i), uuid::Uuid::from_u128(i + 1)))
+            .collect();
+        // Queued without waiting, so the list can be read back afterwards
+        send_client_list(&client, &everyone);
+
+        let (mut received, mut parts) = (Vec::new(), 0);
+        loop {
+            let frame = framing::read_frame(&mut far).await.unwrap().unwrap();
+            match WireFormat::Bincode.decode(&frame).unwrap() {
+                ClientBoundMessage::ClientListPart(chunk) => {
+                    assert!(chunk.len() <= CLIENT_LIST_CHUNK);
+                    received.extend(chunk);
+                    parts += 1;
+                }
+                ClientBoundMessage::ClientList(chunk) => {
+                    received.extend(chunk);
+                    break;
+                }
+                other => panic!("expected part of the list, got {:?}", other),
+            }
+        }
+        assert_eq!(parts, 5000 / CLIENT_LIST_CHUNK - 1);
+        assert_eq!(received, everyone);
+    }
+
+    /// A server on an ephemeral loopback port, with its counters and a
+    /// sender that stops it
+    async fn start(options: &[&str]) -> (SocketAddr, Arc<Metrics>, oneshot::Sender<()>) {
+        let command = ["server", "--address", "127.0.0.1", "--port", "0"];
+        let mut server = Server::new(Args::parse_from(command.iter().chain(options)))
+            .await
+            .unwrap();
+        let address = server.local_addrs().unwrap()[0];
+        let metrics = server.metrics.clone();
+        let (stop, stopped) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            server
+                .run_until(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        (address, metrics, stop)
+    }
+
+    /// A connection driven frame by frame, to send what a real client never
+    /// would
+    struct RawClient {
+        stream: TcpStream,
+        uuid: uuid::Uuid,
+        resume_token: ResumeToken,
+    }
+
+    impl RawClient {
+        /// Connects and exchanges hellos, reading the uuid and client list
+        async fn connect(address: SocketAddr) -> Self {
+            Self::resume(address, None).await
+        }
+
+        /// Like `connect`, asking to resume the identity behind
+        /// `resume_token` if given
+        async fn resume(address: SocketAddr, resume_token: Option<ResumeToken>) -> Self {
+            Self::greet(TcpStream::connect(address).await.unwrap(), resume_token).await
+        }
+
+        /// Exchanges hellos on an already connected `stream`
+        async fn greet(mut stream: TcpStream, resume_token: Option<ResumeToken>) -> Self {
+            framing::read_hello_frame(&mut stream).await.unwrap().unwrap();
+            let hello = ServerBoundMessage::ClientHello {
+                protocol_version: PROTOCOL_VERSION,
+                resume_token,
+            };
+            framing::write_frame(&mut stream, WireFormat::Bincode, &hello)
+                .await
+                .unwrap();
+            let mut raw = RawClient {
+                stream,
+                uuid: uuid::Uuid::nil(),
+                resume_token: [0; 32],
+            };
+            let ClientBoundMessage::SetUuid(uuid, resume_token) = raw.next().await.unwrap() else {
+                panic!("expected a uuid first");
+            };
+            raw.uuid = uuid;
+            raw.resume_token = resume_token;
+            assert!(matches!(raw.next().await, Some(ClientBoundMessage::ClientList(_))));
+            raw
+        }
+
+        async fn send(&mut self, message: &ServerBoundMessage) {
+            framing::write_frame(&mut self.stream, WireFormat::Bincode, message)
+                .await
+                .unwrap();
+        }
+
+        /// The next message from the server, or `None` once it has closed
+        /// the connection
+        async fn next(&mut self) -> Option<ClientBoundMessage> {
+            let frame = tokio::time::timeout(
+                Duration::from_secs(10),
+                framing::read_frame(&mut self.stream),
+            )
+            .await
+            .expect("timed out waiting for a frame");
+            match frame {
+                Ok(Some(frame)) => Some(WireFormat::Bincode.decode(&frame).unwrap()),
+                Ok(None) | Err(_) => None,
+            }
+        }
+    }
+
+    async fn wait_for_count(counter: &AtomicU64, count: u64) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while counter.load(Ordering::Relaxed) != count {
+            assert!(
+                Instant::now() < deadline,
+                "the counter stayed at {}",
+                counter.load(Ordering::Relaxed)
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_connect_is_posted_to_the_webhook() {
+        let (url, mut received) = webhook::tests::mock_webhook(Vec::new()).await;
+        let (address, _metrics, _stop) = start(&["--webhook", &url]).await;
+        let raw = RawClient::connect(address).await;
+
+        let body = tokio::time::timeout(Duration::from_secs(10), received.recv())
+            .await
+            .expect("the connect should be posted")
+            .unwrap();
+        assert_eq!(body["type"], "connected");
+        assert_eq!(body["from"], raw.uuid.to_string());
+        assert!(body["timestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn failed_greetings_count_as_auth_failures() {
+        let (address, metrics, _stop) = start(&["--handshake-timeout", "1"]).await;
+
+        // Never says hello
+        let _silent = TcpStream::connect(address).await.unwrap();
+        wait_for_count(&metrics.auth_failures, 1).await;
+
+        // Says something else first
+        let mut rude = TcpStream::connect(address).await.unwrap();
+        framing::read_hello_frame(&mut rude).await.unwrap().unwrap();
+        let advertise = ServerBoundMessage::Advertise("rude".to_string());
+        framing::write_frame(&mut rude, WireFormat::Bincode, &advertise)
+            .await
+            .unwrap();
+        wait_for_count(&metrics.auth_failures, 2).await;
+        assert_eq!(metrics.clients_connected.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn a_connection_that_stays_silent_is_dropped_after_the_timeout() {
+        let (address, metrics, _stop) = start(&["--handshake-timeout", "1"]).await;
+        let connected = Instant::now();
+        let mut silent = TcpStream::connect(address).await.unwrap();
+        framing::read_hello_frame(&mut silent).await.unwrap().unwrap();
+
+        let dropped = tokio::time::timeout(Duration::from_secs(5), framing::read_frame(&mut silent))
+            .await
+            .expect("the silent connection should be dropped");
+        assert!(matches!(dropped, Ok(None) | Err(_)));
+        assert!(connected.elapsed() >= Duration::from_millis(900));
+        assert_eq!(metrics.clients_connected.load(Ordering::Relaxed), 0);
+    }
+
+    /// Sends `first` in place of the hello, checking the server explains
+    /// and hangs up without registering a client
+    async fn refused_before_the_hello(first: ServerBoundMessage) {
+        let (address, metrics, _stop) = start(&[]).await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        framing::read_hello_frame(&mut stream).await.unwrap().unwrap();
+        let kind = first.kind();
+        framing::write_frame(&mut stream, WireFormat::Bincode, &first)
+            .await
+            .unwrap();
+
+        let frame = framing::read_frame(&mut stream).await.unwrap().unwrap();
+        let feedback = WireFormat::Bincode.decode(&frame).unwrap();
+        let ClientBoundMessage::ProtocolError(feedback) = feedback else {
+            panic!("expected feedback on the {}", kind);
+        };
+        assert_eq!(feedback, format!("expected a hello, got {}", kind));
+        assert!(matches!(framing::read_frame(&mut stream).await, Ok(None) | Err(_)));
+        wait_for_count(&metrics.auth_failures, 1).await;
+        assert_eq!(metrics.clients_connected.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn an_advertise_before_the_hello_disconnects() {
+        refused_before_the_hello(ServerBoundMessage::Advertise("early".to_string())).await;
+    }
+
+    #[tokio::test]
+    async fn a_message_before_the_hello_disconnects() {
+        let to = ClientDescription::to(uuid::Uuid::new_v4());
+        refused_before_the_hello(ServerBoundMessage::Message(to, payload(1, 16))).await;
+    }
+
+    #[tokio::test]
+    async fn a_connection_request_before_the_hello_disconnects() {
+        let identity = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+        let handshake = Handshake {
+            suite: 1,
+            ephemeral_key: [8; 32],
+            signature: vec![9; 64],
+        };
+        let to = ClientDescription::to(uuid::Uuid::new_v4());
+        let request = ServerBoundMessage::ConnectionRequest(to, identity.public(), handshake);
+        refused_before_the_hello(request).await;
+    }
+
+    /// Sends `early` straight after the hello, before the uuid could have
+    /// arrived, checking the server explains and hangs up without
+    /// registering a client. Returns the stream to check anything else on.
+    async fn refused_while_authenticating(
+        address: SocketAddr,
+        resume_token: Option<ResumeToken>,
+        early: ServerBoundMessage,
+    ) -> TcpStream {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        framing::read_hello_frame(&mut stream).await.unwrap().unwrap();
+        let hello = ServerBoundMessage::ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            resume_token,
+        };
+        // In one write, so both are there by the time the hello is read
+        let mut frames = framing::encode_frame(WireFormat::Bincode, &hello).unwrap();
+        frames.extend(framing::encode_frame(WireFormat::Bincode, &early).unwrap());
+        framing::write_encoded(&mut stream, &frames).await.unwrap();
+
+        let frame = framing::read_frame(&mut stream).await.unwrap().unwrap();
+        let feedback = WireFormat::Bincode.decode(&frame).unwrap();
+        let ClientBoundMessage::ProtocolError(feedback) = feedback else {
+            panic!("expected feedback on the {}, not a uuid", early.kind());
+        };
+        assert_eq!(feedback, format!("expected nothing before the uuid, got {}", early.kind()));
+        assert!(matches!(framing::read_frame(&mut stream).await, Ok(None) | Err(_)));
+        stream
+    }
+
+    #[tokio::test]
+    async fn an_advertise_while_authenticating_disconnects() {
+        let (address, metrics, _stop) = start(&[]).await;
+        let early = ServerBoundMessage::Advertise("eager".to_string());
+        refused_while_authenticating(address, None, early).await;
+        wait_for_count(&metrics.auth_failures, 1).await;
+        assert_eq!(metrics.clients_connected.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn a_message_while_authenticating_disconnects() {
+        let (address, metrics, _stop) = start(&[]).await;
+        let to = ClientDescription::to(uuid::Uuid::new_v4());
+        let early = ServerBoundMessage::Message(to, payload(1, 16));
+        refused_while_authenticating(address, None, early).await;
+        wait_for_count(&metrics.auth_failures, 1).await;
+        assert_eq!(metrics.clients_connected.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn resuming_with_an_early_frame_gives_up_the_identity() {
+        let (address, _metrics, _stop) = start(&[]).await;
+        let alice = RawClient::connect(address).await;
+        let mut bob = RawClient::connect(address).await;
+        let (uuid, resume_token) = (alice.uuid, alice.resume_token);
+        drop(alice);
+
+        let early = ServerBoundMessage::Ping(1);
+        refused_while_authenticating(address, Some(resume_token), early).await;
+        // Told at once, rather than after the grace period
+        loop {
+            match bob.next().await {
+                Some(ClientBoundMessage::ClientDisconnected(gone)) => break assert_eq!(gone, uuid),
+                Some(_) => {}
+                None => panic!("the server closed the connection"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_second_hello_disconnects() {
+        let (address, metrics, _stop) = start(&[]).await;
+        let mut raw = RawClient::connect(address).await;
+        raw.send(&ServerBoundMessage::ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            resume_token: None,
+        })
+        .await;
+        let Some(ClientBoundMessage::ProtocolError(feedback)) = raw.next().await else {
+            panic!("expected feedback on the second hello");
+        };
+        assert_eq!(feedback, "ClientHello is only valid as the first frame");
+        assert!(raw.next().await.is_none());
+        wait_for_count(&metrics.clients_connected, 0).await;
+        assert_eq!(metrics.auth_failures.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn a_bad_frame_is_skipped_but_eof_disconnects() {
+        let (address, metrics, _stop) = start(&[]).await;
+        let mut raw = RawClient::connect(address).await;
+        wait_for_count(&metrics.clients_connected, 1).await;
+
+        // A whole frame whose body isn't a message
+        let garbage = framing::encode_frame(WireFormat::Bincode, &[0xffu8; 4]).unwrap();
+        framing::write_encoded(&mut raw.stream, &garbage).await.unwrap();
+        assert!(matches!(raw.next().await, Some(ClientBoundMessage::ProtocolError(_))));
+        raw.send(&ServerBoundMessage::Ping(7)).await;
+        assert!(matches!(raw.next().await, Some(ClientBoundMessage::Pong(7))));
+        assert_eq!(metrics.frames_dropped.load(Ordering::Relaxed), 1);
+
+        raw.stream.shutdown().await.unwrap();
+        wait_for_count(&metrics.clients_connected, 0).await;
+    }
+
+    #[tokio::test]
+    async fn too_many_bad_frames_in_a_row_disconnect() {
+        let (address, metrics, _stop) = start(&[]).await;
+        let mut raw = RawClient::connect(address).await;
+        let garbage = framing::encode_frame(WireFormat::Bincode, &[0xffu8; 4]).unwrap();
+
+        // A good frame in between starts the count over
+        for _ in 1..MAX_PROTOCOL_ERRORS {
+            framing::write_encoded(&mut raw.stream, &garbage).await.unwrap();
+            let Some(ClientBoundMessage::ProtocolError(feedback)) = raw.next().await else {
+                panic!("expected feedback on the bad frame");
+            };
+            assert!(feedback.starts_with("couldn't decode a frame"), "{}", feedback);
+        }
+        raw.send(&ServerBoundMessage::Ping(1)).await;
+        assert!(matches!(raw.next().await, Some(ClientBoundMessage::Pong(1))));
+
+        for _ in 0..MAX_PROTOCOL_ERRORS {
+            framing::write_encoded(&mut raw.stream, &garbage).await.unwrap();
+            assert!(matches!(raw.next().await, Some(ClientBoundMessage::ProtocolError(_))));
+        }
+        assert!(raw.next().await.is_none());
+        wait_for_count(&metrics.clients_connected, 0).await;
+    }
+
+    #[tokio::test]
+    async fn an_unknown_message_is_reported_with_both_versions_and_counted() {
+        let (address, _metrics, _stop) = start(&[]).await;
+        let mut raw = RawClient::connect(address).await;
+        // A variant index past the last message the server knows
+        let unknown = framing::encode_frame(WireFormat::Bincode, &200u32).unwrap();
+
+        framing::write_encoded(&mut raw.stream, &unknown).await.unwrap();
+        let Some(ClientBoundMessage::ProtocolError(feedback)) = raw.next().await else {
+            panic!("expected feedback on the unknown message");
+        };
+        let versions = format!(
+            "(server protocol version {}, client announced {})",
+            PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        assert!(feedback.starts_with("couldn't decode a frame: it holds a message"), "{}", feedback);
+        assert!(feedback.contains(&versions), "{}", feedback);
+
+        for _ in 1..MAX_PROTOCOL_ERRORS {
+            framing::write_encoded(&mut raw.stream, &unknown).await.unwrap();
+            assert!(matches!(raw.next().await, Some(ClientBoundMessage::ProtocolError(_))));
+        }
+        assert!(raw.next().await.is_none());
+    }
+
+    #[test]
+    fn a_uuid_already_in_use_is_generated_again() {
+        use rand::{Rng, SeedableRng};
+
+        let seeded = || {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+            move || uuid::Builder::from_random_bytes(rng.gen()).into_uuid()
+        };
+        // The same seed gives the same uuids, so the first is made to collide
+        let mut expected = seeded();
+        let (taken, next) = (expected(), expected());
+        let clients = HashSet::from([taken]);
+
+        let mut generated = 0;
+        let mut generate = seeded();
+        let uuid = unused_uuid(
+            || {
+                generated += 1;
+                generate()
+            },
+            |uuid| clients.contains(uuid),
+        );
+        assert_eq!((uuid, generated), (next, 2));
+        assert_eq!(unused_uuid(seeded(), |_| false), taken);
+    }
+
+    #[tokio::test]
+    async fn a_direct_request_reaches_the_peer_with_the_offered_address() {
+        let (address, _metrics, _stop) = start(&[]).await;
+        let mut alice = RawClient::connect(address).await;
+        let mut bob = RawClient::connect(address).await;
+        let offered: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        alice
+            .send(&ServerBoundMessage::RequestDirect(ClientDescription::to(bob.uuid), offered))
+            .await;
+
+        let Some(ClientBoundMessage::DirectRequest(from, address)) = bob.next().await else {
+            panic!("expected the direct request");
+        };
+        // Named by the server, not by what alice claims
+        assert_eq!((from.uuid, address), (alice.uuid, offered));
+    }
+
+    fn payload(counter: u64, len: usize) -> EncryptedPayload {
+        EncryptedPayload {
+            suite: 1,
+            counter,
+            ratchet: RatchetHeader {
+                epoch: 0,
+                ratchet_key: [1; 32],
+                peer_ratchet_key: [2; 32],
+                index: 0,
+                previous_len: 0,
+            },
+            nonce: vec![0; 12],
+            ciphertext: vec![0; len],
+            signature: Vec::new(),
+        }
+    }
+
+    /// Drops payloads longer than 16 bytes
+    struct DropLarge;
+
+    impl RelayHook for DropLarge {
+        fn on_message(&self, _from: uuid::Uuid, _to: uuid::Uuid, size: usize) -> HookDecision {
+            if size > 16 {
+                HookDecision::Drop
+            } else {
+                HookDecision::Allow
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_message_the_hook_drops_is_not_relayed() {
+        let command = ["server", "--address", "127.0.0.1", "--port", "0"];
+        let mut server = Server::new(Args::parse_from(command))
+            .await
+            .unwrap()
+            .with_hook(Arc::new(DropLarge));
+        let address = server.local_addrs().unwrap()[0];
+        let metrics = server.metrics.clone();
+        tokio::spawn(async move { server.run_until(std::future::pending::<()>()).await });
+        let mut alice = RawClient::connect(address).await;
+        let mut bob = RawClient::connect(address).await;
+
+        let to_bob = ClientDescription::to(bob.uuid);
+        alice
+            .send(&ServerBoundMessage::Message(to_bob.clone(), payload(1, 17)))
+            .await;
+        alice
+            .send(&ServerBoundMessage::Message(to_bob, payload(2, 16)))
+            .await;
+
+        let Some(ClientBoundMessage::Message(from, relayed)) = bob.next().await else {
+            panic!("expected a message");
+        };
+        assert_eq!((from.uuid, relayed.counter), (alice.uuid, 2));
+        assert_eq!(metrics.frames_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn a_message_the_hook_dropped_is_acked_after_a_resume() {
+        let command = ["server", "--address", "127.0.0.1", "--port", "0"];
+        let mut server = Server::new(Args::parse_from(command))
+            .await
+            .unwrap()
+            .with_hook(Arc::new(DropLarge));
+        let address = server.local_addrs().unwrap()[0];
+        tokio::spawn(async move { server.run_until(std::future::pending::<()>()).await });
+        let mut alice = RawClient::connect(address).await;
+        let mut bob = RawClient::connect(address).await;
+
+        let to_bob = ClientDescription::to(bob.uuid);
+        alice
+            .send(&ServerBoundMessage::Message(to_bob.clone(), payload(1, 17)))
+            .await;
+        alice
+            .send(&ServerBoundMessage::Message(to_bob, payload(2, 16)))
+            .await;
+        // Only the relayed one is acknowledged, and only after the drop
+        let Some(ClientBoundMessage::Delivered(_, 2)) = alice.next().await else {
+            panic!("expected the relayed message to be acknowledged");
+        };
+        assert!(matches!(bob.next().await, Some(ClientBoundMessage::Message(..))));
+
+        let resume_token = alice.resume_token;
+        drop(alice);
+        let mut alice = RawClient::resume(address, Some(resume_token)).await;
+        let queried = vec![(bob.uuid, 1), (bob.uuid, 2), (bob.uuid, 3)];
+        alice.send(&ServerBoundMessage::QueryAcks(queried)).await;
+        let Some(ClientBoundMessage::Acked(acked)) = alice.next().await else {
+            panic!("expected an answer to the query");
+        };
+        assert_eq!(acked, [(bob.uuid, 1), (bob.uuid, 2)]);
+    }
+
+    #[tokio::test]
+    async fn a_relayed_message_counts_its_framed_length_both_ways() {
+        let (address, metrics, _stop) = start(&[]).await;
+        let mut alice = RawClient::connect(address).await;
+        let mut bob = RawClient::connect(address).await;
+        // Each one's uuid and list. Nobody has a name, so neither hears of
+        // the other joining.
+        wait_for_count(&metrics.frames_sent, 4).await;
+        let received = metrics.bytes_received.load(Ordering::Relaxed);
+        let sent = metrics.bytes_sent.load(Ordering::Relaxed);
+
+        let message = ServerBoundMessage::Message(ClientDescription::to(bob.uuid), payload(1, 300));
+        let framed = framing::encode_frame(WireFormat::Bincode, &message).unwrap();
+        framing::write_encoded(&mut alice.stream, &framed).await.unwrap();
+        let relayed = framing::read_frame(&mut bob.stream).await.unwrap().unwrap();
+        let delivered = framing::read_frame(&mut alice.stream).await.unwrap().unwrap();
+        wait_for_count(&metrics.bytes_received, received + framed.len() as u64).await;
+        let relayed_len = framing::frame_len(relayed.len());
+        let delivered_len = framing::frame_len(delivered.len());
+        wait_for_count(&metrics.bytes_sent, sent + (relayed_len + delivered_len) as u64).await;
+        assert!(relayed_len > 300);
+    }
+
+    #[tokio::test]
+    async fn messages_queued_at_shutdown_are_delivered_within_the_drain() {
+        let (address, _metrics, stop) = start(&["--drain-secs", "10"]).await;
+        let mut alice = RawClient::connect(address).await;
+        // A small fixed buffer, so the kernel can't take bob's backlog off
+        // the server's hands
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(8 * 1024).unwrap();
+        let mut bob = RawClient::greet(socket.connect(address).await.unwrap(), None).await;
+
+        // More than the sockets hold, so most are still queued for bob when
+        // the server is stopped. Each acknowledgement means one was queued.
+        let sent = 200;
+        for counter in 0..sent {
+            let message = ServerBoundMessage::Message(
+                ClientDescription::to(bob.uuid),
+                payload(counter, 16 * 1024),
+            );
+            alice.send(&message).await;
+        }
+        for _ in 0..sent {
+            assert!(matches!(alice.next().await, Some(ClientBoundMessage::Delivered(..))));
+        }
+        stop.send(()).unwrap();
+        let stopped = Instant::now();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let mut counters = Vec::new();
+        loop {
+            match bob.next().await {
+                Some(ClientBoundMessage::Message(_, payload)) => counters.push(payload.counter),
+                Some(ClientBoundMessage::ServerShutdown) => break,
+                other => panic!("expected the queued messages, got {:?}", other),
+            }
+        }
+        assert_eq!(counters, (0..sent).collect::<Vec<_>>());
+
+        // Hanging up ends the drain without waiting it out
+        bob.stream.shutdown().await.unwrap();
+        assert!(bob.next().await.is_none());
+        assert!(stopped.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn a_client_greeting_during_the_drain_is_turned_away() {
+        let (address, _metrics, stop) = start(&["--drain-secs", "10"]).await;
+        // Never hangs up, so the drain runs its full length
+        let mut alice = RawClient::connect(address).await;
+        let mut carol = TcpStream::connect(address).await.unwrap();
+        framing::read_hello_frame(&mut carol).await.unwrap().unwrap();
+        stop.send(()).unwrap();
+        assert!(matches!(alice.next().await, Some(ClientBoundMessage::ServerShutdown)));
+
+        let stopped = Instant::now();
+        let hello = ServerBoundMessage::ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            resume_token: None,
+        };
+        framing::write_frame(&mut carol, WireFormat::Bincode, &hello).await.unwrap();
+        let frame = framing::read_frame(&mut carol).await.unwrap().unwrap();
+        assert!(matches!(
+            WireFormat::Bincode.decode(&frame).unwrap(),
+            ClientBoundMessage::ServerShutdown
+        ));
+        assert!(matches!(framing::read_frame(&mut carol).await, Ok(None) | Err(_)));
+        // Rather than once alice's drain is over
+        assert!(stopped.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn server_info_counts_the_connected_clients_that_are_visible() {
+        let (address, _metrics, _stop) = start(&[]).await;
+        let _alice = RawClient::connect(address).await;
+        let _bob = RawClient::connect(address).await;
+        let mut carol = RawClient::connect(address).await;
+        // Anything sent before the answer is passed over
+        async fn server_info(raw: &mut RawClient) -> (String, u64) {
+            raw.send(&ServerBoundMessage::ServerInfo).await;
+            loop {
+                let message = raw.next().await.expect("the server closed the connection");
+                if let ClientBoundMessage::ServerInfo {
+                    version,
+                    connected_clients,
+                    ..
+                } = message
+                {
+                    return (version, connected_clients);
+                }
+            }
+        }
+        assert_eq!(server_info(&mut carol).await, (env!("CARGO_PKG_VERSION").to_string(), 3));
+
+        carol.send(&ServerBoundMessage::SetStatus(Presence::Invisible)).await;
+        assert_eq!(server_info(&mut carol).await.1, 2);
+    }
+
+    #[tokio::test]
+    async fn a_resumed_client_learns_which_of_its_messages_were_relayed() {
+        let (address, _metrics, _stop) = start(&[]).await;
+        let mut alice = RawClient::connect(address).await;
+        let mut bob = RawClient::connect(address).await;
+        let to_bob = ClientDescription::to(bob.uuid);
+        alice
+            .send(&ServerBoundMessage::Message(to_bob, payload(5, 16)))
+            .await;
+        assert!(matches!(bob.next().await, Some(ClientBoundMessage::Message(..))));
+        let Some(ClientBoundMessage::Delivered(to, 5)) = alice.next().await else {
+            panic!("expected the message to be acknowledged");
+        };
+        assert_eq!(to, bob.uuid);
+
+        let (uuid, resume_token) = (alice.uuid, alice.resume_token);
+        drop(alice);
+        let mut alice = RawClient::resume(address, Some(resume_token)).await;
+        assert_eq!(alice.uuid, uuid);
+        alice
+            .send(&ServerBoundMessage::QueryAcks(vec![(bob.uuid, 5), (bob.uuid, 6)]))
+            .await;
+        let Some(ClientBoundMessage::Acked(acked)) = alice.next().await else {
+            panic!("expected an answer to the query");
+        };
+        assert_eq!(acked, [(bob.uuid, 5)]);
+    }
+
+    #[tokio::test]
+    async fn a_message_to_an_unknown_uuid_is_reported_unavailable() {
+        let (address, _metrics, _stop) = start(&[]).await;
+        let mut alice = RawClient::connect(address).await;
+        let nobody = uuid::Uuid::new_v4();
+        alice
+            .send(&ServerBoundMessage::Message(ClientDescription::to(nobody), payload(7, 16)))
+            .await;
+
+        let Some(ClientBoundMessage::RecipientUnavailable(uuid, counter)) = alice.next().await
+        else {
+            panic!("expected the message to be reported undelivered");
+        };
+        assert_eq!((uuid, counter), (nobody, 7));
+    }
+
+    #[tokio::test]
+    async fn a_repeated_connection_request_is_relayed_once() {
+        let (address, metrics, _stop) = start(&[]).await;
+        let mut alice = RawClient::connect(address).await;
+        let mut bob = RawClient::connect(address).await;
+        let identity = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+        let handshake = Handshake {
+            suite: 1,
+            ephemeral_key: [8; 32],
+            signature: vec![9; 64],
+        };
+        let request = ServerBoundMessage::ConnectionRequest(
+            ClientDescription::to(bob.uuid),
+            identity.public(),
+            handshake,
+        );
+        alice.send(&request).await;
+        alice.send(&request).await;
+        // Relayed after the requests, so bob sees it next if the second was dropped
+        alice
+            .send(&ServerBoundMessage::CloseConnection(ClientDescription::to(bob.uuid)))
+            .await;
+
+        let Some(ClientBoundMessage::ConnectionRequest(from, ..)) = bob.next().await else {
+            panic!("expected the request");
+        };
+        assert_eq!(from.uuid, alice.uuid);
+        let next = bob.next().await;
+        assert!(matches!(next, Some(ClientBoundMessage::ChannelClosed(uuid)) if uuid == alice.uuid));
+        assert_eq!(metrics.frames_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn a_request_sent_again_after_cancelling_is_relayed() {
+        let (address, _metrics, _stop) = start(&[]).await;
+        let mut alice = RawClient::connect(address).await;
+        let mut bob = RawClient::connect(address).await;
+        let identity = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+        let handshake = Handshake {
+            suite: 1,
+            ephemeral_key: [8; 32],
+            signature: vec![9; 64],
+        };
+        let request = ServerBoundMessage::ConnectionRequest(
+            ClientDescription::to(bob.uuid),
+            identity.public(),
+            handshake,
+        );
+        alice.send(&request).await;
+        alice
+            .send(&ServerBoundMessage::CloseConnection(ClientDescription::to(bob.uuid)))
+            .await;
+        alice.send(&request).await;
+
+        assert!(matches!(bob.next().await, Some(ClientBoundMessage::ConnectionRequest(..))));
+        assert!(matches!(bob.next().await, Some(ClientBoundMessage::ChannelClosed(_))));
+        let Some(ClientBoundMessage::ConnectionRequest(from, ..)) = bob.next().await else {
+            panic!("expected the request again");
+        };
+        assert_eq!(from.uuid, alice.uuid);
+    }
+
+    #[test]
+    fn retrying_a_request_does_not_push_the_dedupe_window_back() {
+        let (bob, start) = (uuid::Uuid::new_v4(), Instant::now());
+        let mut recent = RecentRequests::default();
+        assert!(!recent.is_repeat(bob, start));
+        recent.forwarded(bob, start);
+        // Dropped, but the window still runs from the forwarded one
+        assert!(recent.is_repeat(bob, start + Duration::from_secs(3)));
+        assert!(!recent.is_repeat(bob, start + REQUEST_DEDUPE_WINDOW));
+    }
+
+    #[test]
+    fn a_withdrawn_request_is_forgotten() {
+        let (bob, carol, start) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4(), Instant::now());
+        let mut recent = RecentRequests::default();
+        recent.forwarded(bob, start);
+        recent.forwarded(carol, start);
+        recent.withdraw(bob);
+        assert!(!recent.is_repeat(bob, start));
+        assert!(recent.is_repeat(carol, start));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn simultaneous_connects_all_complete_their_handshake() {
+        const CLIENTS: usize = 50;
+        let (address, metrics, _stop) = start(&[]).await;
+        // Setup happens off the accept loop, so one that never says hello
+        // holds nobody else up
+        let _silent = TcpStream::connect(address).await.unwrap();
+
+        let mut connecting = tokio::task::JoinSet::new();
+        for _ in 0..CLIENTS {
+            connecting.spawn(RawClient::connect(address));
+        }
+        let mut connected = Vec::new();
+        while let Some(raw) = connecting.join_next().await {
+            connected.push(raw.unwrap());
+        }
+        let uuids: HashSet<_> = connected.iter().map(|raw| raw.uuid).collect();
+        assert_eq!(uuids.len(), CLIENTS);
+        wait_for_count(&metrics.clients_connected, CLIENTS as u64).await;
+    }
+
+    #[test]
+    fn parses_listen_addresses_with_and_without_ports() {
+        let parse = |s: &str| s.parse::<ListenAddress>().unwrap().socket_addr(8080);
+        assert_eq!(parse("::"), "[::]:8080".parse().unwrap());
+        assert_eq!(parse("[::1]"), "[::1]:8080".parse().unwrap());
+        assert_eq!(parse("[::1]:9000"), "[::1]:9000".parse().unwrap());
+        assert_eq!(parse("127.0.0.1:9000"), "127.0.0.1:9000".parse().unwrap());
+        assert!("::1:9000:".parse::<ListenAddress>().is_err());
+        assert!("localhost".parse::<ListenAddress>().is_err());
+    }
+
+    #[tokio::test]
+    async fn resuming_within_the_grace_period_keeps_the_uuid() {
+        let (address, _metrics, _stop) = start(&[]).await;
+        let mut alice = RawClient::connect(address).await;
+        alice
+            .send(&ServerBoundMessage::Advertise("alice".to_string()))
+            .await;
+        let mut bob = RawClient::connect(address).await;
+        let (uuid, resume_token) = (alice.uuid, alice.resume_token);
+
+        drop(alice);
+        let alice = RawClient::resume(address, Some(resume_token)).await;
+        assert_eq!(alice.uuid, uuid);
+        assert_ne!(alice.resume_token, resume_token, "a token should be used only once");
+
+        // Peers never see the drop
+        let quiet = tokio::time::timeout(Duration::from_millis(300), async {
+            loop {
+                if let Some(ClientBoundMessage::ClientDisconnected(gone)) = bob.next().await {
+                    if gone == uuid {
+                        break;
+                    }
+                }
+            }
+        });
+        assert!(quiet.await.is_err(), "bob was told alice left");
+
+        // A token nobody was issued gets a fresh identity
+        let stranger = RawClient::resume(address, Some([7; 32])).await;
+        assert_ne!(stranger.uuid, uuid);
+    }
 }
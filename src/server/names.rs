@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+
+use uuid::Uuid;
+
+use super::store::ServerStore;
+
+/// Which client holds each friendly name, for `--unique-names`. Names are
+/// compared ignoring case and surrounding whitespace, so "Alice " can't
+/// pass for "alice".
+///
+/// A dropped client keeps its name while it may still resume.
+pub struct Names {
+    /// Without `--unique-names` every claim succeeds and nothing is kept
+    enforced: bool,
+    store: Arc<dyn ServerStore>,
+    /// Held from looking a name up to recording it, so two clients can't
+    /// both find it free
+    claiming: Mutex<()>,
+}
+
+impl Names {
+    /// Forgets names held when `store` was last used, since their holders
+    /// are gone
+    pub fn new(enforced: bool, store: Arc<dyn ServerStore>) -> Self {
+        if enforced {
+            if let Err(e) = store.clear_names() {
+                eprintln!("Failed to clear names left in the store: {}", e);
+            }
+        }
+        Names {
+            enforced,
+            store,
+            claiming: Mutex::new(()),
+        }
+    }
+
+    /// Gives `name` to `uuid` in place of `previous`, the name it held
+    /// before. Fails, changing nothing, if another client holds `name`. If
+    /// the store can't be read the name is let through, as if unchecked.
+    pub fn claim(&self, uuid: Uuid, previous: Option<&str>, name: &str) -> bool {
+        if !self.enforced {
+            return true;
+        }
+        let key = key(name);
+        let _claiming = self.claiming.lock().unwrap();
+        match self.store.name_holder(&key) {
+            Ok(Some(holder)) if holder != uuid => return false,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to look up who holds {}, allowing it: {}", name, e);
+                return true;
+            }
+        }
+        if let Some(previous) = previous {
+            self.release_locked(uuid, previous);
+        }
+        // An empty name means no name, which any number of clients may have
+        if !key.is_empty() {
+            if let Err(e) = self.store.set_name_holder(&key, Some(uuid)) {
+                eprintln!("Failed to record {} as holding {}: {}", uuid, name, e);
+            }
+        }
+        true
+    }
+
+    /// Frees `name` for others, if `uuid` holds it
+    pub fn release(&self, uuid: Uuid, name: &str) {
+        if self.enforced {
+            let _claiming = self.claiming.lock().unwrap();
+            self.release_locked(uuid, name);
+        }
+    }
+
+    fn release_locked(&self, uuid: Uuid, name: &str) {
+        let key = key(name);
+        let freed = match self.store.name_holder(&key) {
+            Ok(Some(holder)) if holder == uuid => self.store.set_name_holder(&key, None),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = freed {
+            eprintln!("Failed to free the name {}: {}", name, e);
+        }
+    }
+}
+
+fn key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
@@ -0,0 +1,170 @@
+use std::{collections::HashMap, sync::Mutex};
+
+#[cfg(feature = "sled-store")]
+use std::path::Path;
+
+use uuid::Uuid;
+
+use crate::shared::Result;
+
+use super::banlist::BanEntry;
+
+/// Server state that can outlive the process: who holds each friendly name
+/// (see `Names`) and the banlist. `MemoryStore` is the default. Built with
+/// the `sled-store` feature, `--store` keeps it on disk in a `SledStore`.
+pub trait ServerStore: Send + Sync {
+    /// The client holding `name`, keyed as `Names` normalizes it
+    fn name_holder(&self, name: &str) -> Result<Option<Uuid>>;
+    /// Records `uuid` as holding `name`, or frees `name` with `None`
+    fn set_name_holder(&self, name: &str, uuid: Option<Uuid>) -> Result<()>;
+    /// Frees every name, since a server starting up has nobody connected to
+    /// hold one
+    fn clear_names(&self) -> Result<()>;
+    fn bans(&self) -> Result<Vec<BanEntry>>;
+    fn set_bans(&self, bans: &[BanEntry]) -> Result<()>;
+}
+
+/// Keeps everything in memory, so nothing survives a restart
+#[derive(Default)]
+pub struct MemoryStore {
+    names: Mutex<HashMap<String, Uuid>>,
+    bans: Mutex<Vec<BanEntry>>,
+}
+
+impl ServerStore for MemoryStore {
+    fn name_holder(&self, name: &str) -> Result<Option<Uuid>> {
+        Ok(self.names.lock().unwrap().get(name).copied())
+    }
+
+    fn set_name_holder(&self, name: &str, uuid: Option<Uuid>) -> Result<()> {
+        let mut names = self.names.lock().unwrap();
+        match uuid {
+            Some(uuid) => names.insert(name.to_string(), uuid),
+            None => names.remove(name),
+        };
+        Ok(())
+    }
+
+    fn clear_names(&self) -> Result<()> {
+        self.names.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn bans(&self) -> Result<Vec<BanEntry>> {
+        Ok(self.bans.lock().unwrap().clone())
+    }
+
+    fn set_bans(&self, bans: &[BanEntry]) -> Result<()> {
+        *self.bans.lock().unwrap() = bans.to_vec();
+        Ok(())
+    }
+}
+
+/// Key in the default tree under which `SledStore` keeps the banlist
+#[cfg(feature = "sled-store")]
+const BANS_KEY: &[u8] = b"bans";
+
+/// Keeps everything in a sled database, so the banlist survives a restart
+/// and several servers could share it
+#[cfg(feature = "sled-store")]
+pub struct SledStore {
+    db: sled::Db,
+    /// Name to the holder's uuid bytes
+    names: sled::Tree,
+}
+
+#[cfg(feature = "sled-store")]
+impl SledStore {
+    /// Opens the database at `path`, creating it if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path).map_err(std::io::Error::from)?;
+        let names = db.open_tree("names").map_err(std::io::Error::from)?;
+        Ok(SledStore { db, names })
+    }
+}
+
+#[cfg(feature = "sled-store")]
+impl ServerStore for SledStore {
+    fn name_holder(&self, name: &str) -> Result<Option<Uuid>> {
+        let holder = self.names.get(name).map_err(std::io::Error::from)?;
+        Ok(holder.and_then(|uuid| Uuid::from_slice(&uuid).ok()))
+    }
+
+    fn set_name_holder(&self, name: &str, uuid: Option<Uuid>) -> Result<()> {
+        match uuid {
+            Some(uuid) => self.names.insert(name, uuid.as_bytes()).map(|_| ()),
+            None => self.names.remove(name).map(|_| ()),
+        }
+        .map_err(std::io::Error::from)?;
+        Ok(())
+    }
+
+    fn clear_names(&self) -> Result<()> {
+        self.names.clear().map_err(std::io::Error::from)?;
+        Ok(())
+    }
+
+    fn bans(&self) -> Result<Vec<BanEntry>> {
+        match self.db.get(BANS_KEY).map_err(std::io::Error::from)? {
+            Some(bans) => crate::shared::framing::from_bincode(&bans),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_bans(&self, bans: &[BanEntry]) -> Result<()> {
+        let bans = crate::shared::framing::to_bincode(&bans)?;
+        self.db.insert(BANS_KEY, bans).map_err(std::io::Error::from)?;
+        self.db.flush().map_err(std::io::Error::from)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `store` through everything the server asks of one
+    fn exercise(store: &dyn ServerStore) {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(store.name_holder("alice").unwrap(), None);
+        store.set_name_holder("alice", Some(alice)).unwrap();
+        store.set_name_holder("bob", Some(bob)).unwrap();
+        assert_eq!(store.name_holder("alice").unwrap(), Some(alice));
+        store.set_name_holder("alice", None).unwrap();
+        assert_eq!(store.name_holder("alice").unwrap(), None);
+        assert_eq!(store.name_holder("bob").unwrap(), Some(bob));
+        store.clear_names().unwrap();
+        assert_eq!(store.name_holder("bob").unwrap(), None);
+
+        assert!(store.bans().unwrap().is_empty());
+        let bans: Vec<BanEntry> = ["10.0.0.0/8", "192.0.2.7"]
+            .iter()
+            .map(|ban| ban.parse().unwrap())
+            .collect();
+        store.set_bans(&bans).unwrap();
+        assert_eq!(store.bans().unwrap(), bans);
+        store.set_bans(&bans[1..]).unwrap();
+        assert_eq!(store.bans().unwrap(), &bans[1..]);
+    }
+
+    #[test]
+    fn the_memory_store_keeps_names_and_bans() {
+        exercise(&MemoryStore::default());
+    }
+
+    #[cfg(feature = "sled-store")]
+    #[test]
+    fn the_sled_store_keeps_names_and_bans_across_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store");
+        let holder = Uuid::new_v4();
+        {
+            let store = SledStore::open(&path).unwrap();
+            exercise(&store);
+            store.set_name_holder("carol", Some(holder)).unwrap();
+        }
+        let store = SledStore::open(&path).unwrap();
+        assert_eq!(store.bans().unwrap(), ["192.0.2.7".parse::<BanEntry>().unwrap()]);
+        assert_eq!(store.name_holder("carol").unwrap(), Some(holder));
+    }
+}
@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc::{self, error::TrySendError},
+};
+use uuid::Uuid;
+
+use super::audit::AuditEvent;
+use crate::shared::{Error, Result};
+
+/// Events that can wait for delivery before new ones are dropped
+const WEBHOOK_QUEUE_LEN: usize = 1024;
+
+/// Tries at delivering one event before it's given up on
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry, doubled after each one
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How long one POST may take, connecting included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The JSON body POSTed for an event. Metadata only, like the audit log.
+#[derive(Debug, Serialize)]
+struct WebhookEvent {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    from: Uuid,
+    to: Option<Uuid>,
+    timestamp: chrono::DateTime<chrono::Local>,
+}
+
+impl WebhookEvent {
+    /// The webhook's view of `event`, if it's one the webhook is told about
+    fn from_audit(event: &AuditEvent) -> Option<Self> {
+        let (kind, from, to) = match event {
+            AuditEvent::Connected { uuid, .. } => ("connected", *uuid, None),
+            AuditEvent::Disconnected { uuid } => ("disconnected", *uuid, None),
+            AuditEvent::Message {
+                from_uuid, to_uuid, ..
+            } => ("message", *from_uuid, Some(*to_uuid)),
+            _ => return None,
+        };
+        Some(WebhookEvent {
+            kind,
+            from,
+            to,
+            timestamp: chrono::Local::now(),
+        })
+    }
+}
+
+/// Where events are POSTed. Only plain `http://` URLs are supported.
+struct Endpoint {
+    /// `host[:port]`, as given, for the Host header
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(Error::Protocol(format!(
+                "webhook {} isn't an http:// URL",
+                url
+            )));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| Error::Protocol(format!("webhook {} has a bad port", url)))?;
+                (host, port)
+            }
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(Error::Protocol(format!("webhook {} has no host", url)));
+        }
+        Ok(Endpoint {
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// POSTs `body`, failing unless the response is a 2xx
+    async fn post(&self, body: &str) -> Result<()> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.authority,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line).await?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some(status) => Err(Error::Protocol(format!("the webhook answered {}", status))),
+            None => Err(Error::Protocol("the webhook didn't answer".to_string())),
+        }
+    }
+}
+
+/// Tells an external URL about connects, disconnects and relayed messages.
+/// Events are delivered by a background task in order, so a slow or failing
+/// webhook never holds up relaying; if it falls too far behind, new events
+/// are dropped.
+#[derive(Clone)]
+pub struct Webhook {
+    sender: mpsc::Sender<WebhookEvent>,
+}
+
+impl Webhook {
+    /// Starts delivering to `url`, which must be `http://host[:port][/path]`
+    pub fn start(url: &str) -> Result<Self> {
+        let endpoint = Endpoint::parse(url)?;
+        let (sender, queue) = mpsc::channel(WEBHOOK_QUEUE_LEN);
+        tokio::spawn(deliver_loop(endpoint, queue));
+        Ok(Webhook { sender })
+    }
+
+    pub fn notify(&self, event: &AuditEvent) {
+        let Some(event) = WebhookEvent::from_audit(event) else {
+            return;
+        };
+        if let Err(TrySendError::Full(event)) = self.sender.try_send(event) {
+            eprintln!("Webhook is falling behind, dropped {:?}", event);
+        }
+    }
+}
+
+/// POSTs queued events one at a time, backing off between retries. An
+/// event that still hasn't gone through after `MAX_ATTEMPTS` is dropped.
+async fn deliver_loop(endpoint: Endpoint, mut queue: mpsc::Receiver<WebhookEvent>) {
+    while let Some(event) = queue.recv().await {
+        let body = match serde_json::to_string(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Failed to encode a webhook event: {}", e);
+                continue;
+            }
+        };
+
+        let mut backoff = FIRST_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let error = match tokio::time::timeout(REQUEST_TIMEOUT, endpoint.post(&body)).await {
+                Ok(Ok(())) => break,
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "timed out".to_string(),
+            };
+            if attempt == MAX_ATTEMPTS {
+                eprintln!(
+                    "Dropped a webhook event after {} attempts ({}): {}",
+                    MAX_ATTEMPTS, error, body
+                );
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+
+    /// An HTTP endpoint on an ephemeral loopback port, returned as its URL.
+    /// Each request is answered with the next of `statuses`, then 200 once
+    /// they run out, and its JSON body passed on to the receiver.
+    pub async fn mock_webhook(
+        statuses: Vec<u16>,
+    ) -> (String, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let mut statuses = statuses.into_iter();
+        let (bodies, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = header.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+                let status = statuses.next().unwrap_or(200);
+                let response = format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n", status);
+                stream.get_mut().write_all(response.as_bytes()).await.unwrap();
+                let _ = bodies.send(serde_json::from_slice(&body).unwrap());
+            }
+        });
+        (url, received)
+    }
+
+    #[test]
+    fn only_plain_http_urls_are_accepted() {
+        let endpoint = Endpoint::parse("http://example.com:8080/chat/events").unwrap();
+        assert_eq!(
+            (endpoint.authority.as_str(), endpoint.host.as_str(), endpoint.port),
+            ("example.com:8080", "example.com", 8080)
+        );
+        assert_eq!(endpoint.path, "/chat/events");
+        let endpoint = Endpoint::parse("http://[::1]").unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("::1", 80));
+        assert_eq!(endpoint.path, "/");
+        assert!(Endpoint::parse("https://example.com/").is_err());
+        assert!(Endpoint::parse("http://example.com:http/").is_err());
+        assert!(Endpoint::parse("http:///hook").is_err());
+    }
+
+    #[tokio::test]
+    async fn a_failed_post_is_retried_until_it_goes_through() {
+        let (url, mut received) = mock_webhook(vec![500, 503]).await;
+        let webhook = Webhook::start(&url).unwrap();
+        let from = Uuid::new_v4();
+        webhook.notify(&AuditEvent::Disconnected { uuid: from });
+
+        // Every attempt carries the event, and the third is answered 200
+        for _ in 0..3 {
+            let body = tokio::time::timeout(Duration::from_secs(10), received.recv())
+                .await
+                .expect("the event should be posted again")
+                .unwrap();
+            assert_eq!(body["type"], "disconnected");
+            assert_eq!(body["from"], from.to_string());
+            assert_eq!(body["to"], serde_json::Value::Null);
+        }
+        let retried = tokio::time::timeout(Duration::from_secs(2), received.recv()).await;
+        assert!(retried.is_err(), "a delivered event shouldn't be posted again");
+    }
+}
@@ -0,0 +1,597 @@
+use aes_gcm::{
+    aead::{Aead, Payload},
+    AeadCore, Aes256Gcm, Key, KeyInit,
+};
+use argon2::Argon2;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rsa::{
+    pkcs1v15::{Signature, SigningKey, VerifyingKey},
+    signature::{SignatureEncoding, Signer, Verifier},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, ReusableSecret};
+use zeroize::Zeroizing;
+
+use super::{
+    framing,
+    messages::{EncryptedPayload, Handshake, RatchetHeader},
+    suite::{self, Kem, SuiteId},
+    Error, Result,
+};
+
+/// Prefix for the signed handshake data, so the signature can't be reused elsewhere
+const HANDSHAKE_CONTEXT: &[u8] = b"ycnbts-handshake-v1";
+
+/// Prefix for the signed message data
+const PAYLOAD_CONTEXT: &[u8] = b"ycnbts-payload-v1";
+
+/// HKDF info for the session key
+const SESSION_KEY_INFO: &[u8] = b"ycnbts-session-key-v1";
+
+/// HKDF info for the root key of what the side with the lower ephemeral key
+/// sends, and of what the other side sends
+const LOWER_ROOT_INFO: &[u8] = b"ycnbts-root-lower-v1";
+const HIGHER_ROOT_INFO: &[u8] = b"ycnbts-root-higher-v1";
+
+/// HKDF info for the chain a direction starts with, before any ratchet step
+const FIRST_CHAIN_INFO: &[u8] = b"ycnbts-first-chain-v1";
+
+/// HKDF info for the new root and chain keys of a ratchet step
+const RATCHET_INFO: &[u8] = b"ycnbts-ratchet-v1";
+
+/// HKDF info for the next chain key, and for the message key taken from it
+const CHAIN_INFO: &[u8] = b"ycnbts-chain-v1";
+const MESSAGE_KEY_INFO: &[u8] = b"ycnbts-message-key-v1";
+
+pub type SessionKey = Zeroizing<[u8; 32]>;
+
+/// Algorithm of a client's long-term identity key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum KeyType {
+    #[default]
+    Rsa,
+    /// Much smaller keys and signatures than RSA, and generated instantly
+    Ed25519,
+}
+
+impl std::fmt::Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            KeyType::Rsa => "RSA",
+            KeyType::Ed25519 => "Ed25519",
+        })
+    }
+}
+
+/// The long-term key a client signs its handshakes and messages with. Key
+/// agreement uses ephemeral X25519 keys whatever the type, so this only
+/// vouches for them.
+pub enum IdentityKey {
+    Rsa(RsaPrivateKey),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl IdentityKey {
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            IdentityKey::Rsa(_) => KeyType::Rsa,
+            IdentityKey::Ed25519(_) => KeyType::Ed25519,
+        }
+    }
+
+    /// Generates a key of `key_type`. `rsa_bits` is ignored for Ed25519.
+    pub fn generate(key_type: KeyType, rsa_bits: usize) -> Result<Self> {
+        Ok(match key_type {
+            KeyType::Rsa => IdentityKey::Rsa(
+                RsaPrivateKey::new(&mut OsRng, rsa_bits)
+                    .map_err(|e| Error::Crypto(e.to_string()))?,
+            ),
+            KeyType::Ed25519 => IdentityKey::Ed25519(ed25519_dalek::SigningKey::generate(&mut OsRng)),
+        })
+    }
+
+    /// Serializes the key for an identity file. The bytes are secret.
+    pub fn to_bytes(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let stored = match self {
+            IdentityKey::Rsa(key) => StoredKey::Rsa(Box::new(key.clone())),
+            IdentityKey::Ed25519(key) => StoredKey::Ed25519(key.to_bytes()),
+        };
+        framing::to_bincode(&stored).map(Zeroizing::new)
+    }
+
+    /// Reverses `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(match framing::from_bincode(bytes)? {
+            StoredKey::Rsa(key) => {
+                key.validate().map_err(|e| Error::Crypto(e.to_string()))?;
+                IdentityKey::Rsa(*key)
+            }
+            StoredKey::Ed25519(key) => IdentityKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&key)),
+        })
+    }
+
+    pub fn public(&self) -> PublicIdentity {
+        match self {
+            IdentityKey::Rsa(key) => PublicIdentity::Rsa(RsaPublicKey::from(key)),
+            IdentityKey::Ed25519(key) => PublicIdentity::Ed25519(key.verifying_key().to_bytes()),
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            IdentityKey::Rsa(key) => SigningKey::<Sha256>::new(key.clone())
+                .try_sign(data)
+                .map(|signature| signature.to_vec())
+                .map_err(|e| Error::Crypto(e.to_string())),
+            IdentityKey::Ed25519(key) => Ok(key.sign(data).to_vec()),
+        }
+    }
+}
+
+/// How `IdentityKey::to_bytes` lays a key out
+#[derive(Serialize, Deserialize)]
+enum StoredKey {
+    Rsa(Box<RsaPrivateKey>),
+    Ed25519([u8; 32]),
+}
+
+/// The public half of an `IdentityKey`, sent to peers in the handshake.
+/// Both sides of a session must use the same key type, and a handshake
+/// between RSA and Ed25519 is refused (see `check_key_type`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PublicIdentity {
+    Rsa(RsaPublicKey),
+    Ed25519([u8; 32]),
+}
+
+impl PublicIdentity {
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            PublicIdentity::Rsa(_) => KeyType::Rsa,
+            PublicIdentity::Ed25519(_) => KeyType::Ed25519,
+        }
+    }
+
+    /// Short hash of the key for comparing out of band, as eight groups of
+    /// four hex digits
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        match self {
+            PublicIdentity::Rsa(key) => {
+                hasher.update(b"rsa");
+                hasher.update(key.n().to_bytes_be());
+                hasher.update(key.e().to_bytes_be());
+            }
+            PublicIdentity::Ed25519(key) => {
+                hasher.update(b"ed25519");
+                hasher.update(key);
+            }
+        }
+        let digest = hasher.finalize();
+        digest[..16]
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// False for a bad signature, including one made with the other algorithm
+    fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        match self {
+            PublicIdentity::Rsa(key) => {
+                let Ok(signature) = Signature::try_from(signature) else {
+                    return false;
+                };
+                VerifyingKey::<Sha256>::new(key.clone())
+                    .verify(data, &signature)
+                    .is_ok()
+            }
+            PublicIdentity::Ed25519(key) => {
+                let Ok(key) = ed25519_dalek::VerifyingKey::from_bytes(key) else {
+                    return false;
+                };
+                let Ok(signature) = ed25519_dalek::Signature::from_slice(signature) else {
+                    return false;
+                };
+                key.verify_strict(data, &signature).is_ok()
+            }
+        }
+    }
+}
+
+/// Refuses a peer whose identity key isn't of the type `ours` is
+pub fn check_key_type(ours: KeyType, theirs: &PublicIdentity) -> Result<()> {
+    if theirs.key_type() == ours {
+        return Ok(());
+    }
+    Err(Error::Crypto(format!(
+        "identity key type mismatch: the peer uses {} and we use {}, and both sides \
+         of a session need the same --key-type",
+        theirs.key_type(),
+        ours
+    )))
+}
+
+/// Derives the key for data kept on disk from a passphrase with Argon2id,
+/// so guessing passphrases is slow
+pub fn passphrase_key(passphrase: &str, salt: &[u8]) -> Result<SessionKey> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    Ok(key)
+}
+
+/// Encrypts data for storage as `nonce || ciphertext`
+pub fn seal_at_rest(key: &SessionKey, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref()));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Reverses `seal_at_rest`. Fails if the key is wrong or the data was altered.
+pub fn open_at_rest(key: &SessionKey, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < 12 {
+        return Err(Error::Crypto("sealed data is too short".to_string()));
+    }
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref()));
+    cipher
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|e| Error::Crypto(e.to_string()))
+}
+
+/// Generates an ephemeral keypair for `suite` and signs its public half,
+/// along with the suite, with our identity key. The secret half goes on to
+/// be the session's first ratchet key.
+pub fn new_handshake(
+    private_key: &IdentityKey,
+    suite: SuiteId,
+) -> Result<(ReusableSecret, Handshake)> {
+    let (secret, ephemeral_key) = new_ratchet_key(suite)?;
+
+    let signature = private_key.sign(&handshake_data(suite, &ephemeral_key))?;
+
+    Ok((
+        secret,
+        Handshake {
+            suite,
+            ephemeral_key,
+            signature,
+        },
+    ))
+}
+
+/// Checks that the peer's ephemeral key and suite were signed by the
+/// identity key it presented
+pub fn verify_handshake(public_key: &PublicIdentity, handshake: &Handshake) -> bool {
+    public_key.verify(
+        &handshake_data(handshake.suite, &handshake.ephemeral_key),
+        &handshake.signature,
+    )
+}
+
+/// Completes the exchange, returning the key the session's ratchets start
+/// from. Fails if the peer's ephemeral key is a low-order point.
+pub fn derive_session_key(
+    secret: &ReusableSecret,
+    local: &Handshake,
+    remote: &Handshake,
+) -> Result<SessionKey> {
+    if local.suite != remote.suite {
+        return Err(Error::Crypto(format!(
+            "peer answered in cipher suite {}, not {}",
+            remote.suite, local.suite
+        )));
+    }
+    let shared = agree(local.suite, secret, &remote.ephemeral_key)?;
+
+    // Both sides must feed the ephemeral keys in the same order
+    let (first, second) = if local.ephemeral_key < remote.ephemeral_key {
+        (&local.ephemeral_key, &remote.ephemeral_key)
+    } else {
+        (&remote.ephemeral_key, &local.ephemeral_key)
+    };
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(first);
+    salt[32..].copy_from_slice(second);
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_ref())
+        .expand(SESSION_KEY_INFO, key.as_mut())
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    Ok(key)
+}
+
+/// A fresh keypair for a ratchet step in `suite`, as the secret and the
+/// public half to send
+pub fn new_ratchet_key(suite: SuiteId) -> Result<(ReusableSecret, [u8; 32])> {
+    match suite::lookup(suite)?.kem {
+        Kem::X25519 => {
+            let secret = ReusableSecret::random_from_rng(OsRng);
+            let public = PublicKey::from(&secret).to_bytes();
+            Ok((secret, public))
+        }
+    }
+}
+
+/// Diffie-Hellman between our `secret` and the peer's `public` key. Fails if
+/// the peer's key is a low-order point.
+pub fn agree(suite: SuiteId, secret: &ReusableSecret, public: &[u8; 32]) -> Result<SessionKey> {
+    match suite::lookup(suite)?.kem {
+        Kem::X25519 => {
+            let shared = secret.diffie_hellman(&PublicKey::from(*public));
+            if !shared.was_contributory() {
+                return Err(Error::Crypto(
+                    "peer sent a low-order ephemeral key".to_string(),
+                ));
+            }
+            Ok(Zeroizing::new(shared.to_bytes()))
+        }
+    }
+}
+
+/// The root and first chain keys of what one side of a session sends.
+/// `lower` is whether that side's handshake key sorts below the other's.
+pub fn first_chain(session_key: &SessionKey, lower: bool) -> Result<(SessionKey, SessionKey)> {
+    let root = expand(
+        session_key,
+        if lower { LOWER_ROOT_INFO } else { HIGHER_ROOT_INFO },
+    )?;
+    let chain = expand(&root, FIRST_CHAIN_INFO)?;
+    Ok((root, chain))
+}
+
+/// Mixes a new Diffie-Hellman output into `root`, returning the next root
+/// key and the chain key the new epoch starts with. Someone who learns the
+/// old keys still can't follow without the new ratchet secret.
+pub fn ratchet_step(root: &SessionKey, shared: &SessionKey) -> Result<(SessionKey, SessionKey)> {
+    let mut okm = Zeroizing::new([0u8; 64]);
+    Hkdf::<Sha256>::new(Some(root.as_ref()), shared.as_ref())
+        .expand(RATCHET_INFO, okm.as_mut())
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    let mut next_root = Zeroizing::new([0u8; 32]);
+    let mut chain = Zeroizing::new([0u8; 32]);
+    next_root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    Ok((next_root, chain))
+}
+
+/// Advances a chain by one payload, returning the next chain key and the
+/// key to seal that payload with. Neither leads back to `chain`.
+pub fn chain_step(chain: &SessionKey) -> Result<(SessionKey, SessionKey)> {
+    Ok((expand(chain, CHAIN_INFO)?, expand(chain, MESSAGE_KEY_INFO)?))
+}
+
+fn expand(key: &SessionKey, info: &[u8]) -> Result<SessionKey> {
+    let mut next = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, key.as_ref())
+        .expand(info, next.as_mut())
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    Ok(next)
+}
+
+/// Encrypts with a message key in `suite`'s cipher and signs the result
+/// with our identity key. The suite, `counter` and ratchet header are
+/// authenticated as associated data, so none can be changed to make the
+/// recipient pick another key.
+pub fn encrypt(
+    key: &SessionKey,
+    private_key: &IdentityKey,
+    suite: SuiteId,
+    counter: u64,
+    ratchet: RatchetHeader,
+    plaintext: &[u8],
+) -> Result<EncryptedPayload> {
+    let aad = associated_data(suite, counter, &ratchet);
+    let (nonce, ciphertext) = match suite::lookup(suite)?.aead {
+        suite::Aead::Aes256Gcm => {
+            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref()));
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng).to_vec();
+            let ciphertext = cipher
+                .encrypt(
+                    nonce.as_slice().into(),
+                    Payload {
+                        msg: plaintext,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|e| Error::Crypto(e.to_string()))?;
+            (nonce, ciphertext)
+        }
+    };
+    let signature = private_key.sign(&payload_data(suite, counter, &nonce, &ciphertext))?;
+
+    Ok(EncryptedPayload {
+        suite,
+        counter,
+        ratchet,
+        nonce,
+        ciphertext,
+        signature,
+    })
+}
+
+/// Checks that the payload was signed by the peer's identity key
+pub fn verify_payload(public_key: &PublicIdentity, payload: &EncryptedPayload) -> bool {
+    public_key.verify(
+        &payload_data(payload.suite, payload.counter, &payload.nonce, &payload.ciphertext),
+        &payload.signature,
+    )
+}
+
+/// Decrypts with the cipher of the suite the payload names
+pub fn decrypt(key: &SessionKey, payload: &EncryptedPayload) -> Result<Vec<u8>> {
+    let aead = suite::lookup(payload.suite)?.aead;
+    if payload.nonce.len() != aead.nonce_len() {
+        return Err(Error::Crypto("invalid nonce length".to_string()));
+    }
+    let aad = associated_data(payload.suite, payload.counter, &payload.ratchet);
+    match aead {
+        suite::Aead::Aes256Gcm => Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref()))
+            .decrypt(
+                payload.nonce.as_slice().into(),
+                Payload {
+                    msg: &payload.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| Error::Crypto(e.to_string())),
+    }
+}
+
+fn associated_data(suite: SuiteId, counter: u64, ratchet: &RatchetHeader) -> Vec<u8> {
+    [
+        &[suite][..],
+        &counter.to_be_bytes(),
+        &ratchet.epoch.to_be_bytes(),
+        &ratchet.ratchet_key,
+        &ratchet.peer_ratchet_key,
+        &ratchet.index.to_be_bytes(),
+        &ratchet.previous_len.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn handshake_data(suite: SuiteId, ephemeral_key: &[u8; 32]) -> Vec<u8> {
+    [HANDSHAKE_CONTEXT, &[suite], ephemeral_key.as_slice()].concat()
+}
+
+fn payload_data(suite: SuiteId, counter: u64, nonce: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    [PAYLOAD_CONTEXT, &[suite], &counter.to_be_bytes(), nonce, ciphertext].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::suite::DEFAULT_SUITE;
+
+    fn identity() -> IdentityKey {
+        IdentityKey::generate(KeyType::Ed25519, 0).unwrap()
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_session_key() {
+        let (alice, bob) = (identity(), identity());
+        let (alice_secret, alice_handshake) = new_handshake(&alice, DEFAULT_SUITE).unwrap();
+        let (bob_secret, bob_handshake) = new_handshake(&bob, DEFAULT_SUITE).unwrap();
+
+        let alice_key = derive_session_key(&alice_secret, &alice_handshake, &bob_handshake).unwrap();
+        let bob_key = derive_session_key(&bob_secret, &bob_handshake, &alice_handshake).unwrap();
+        assert_eq!(*alice_key, *bob_key);
+
+        // A fresh exchange between the same identities gives a new key
+        let (again_secret, again_handshake) = new_handshake(&alice, DEFAULT_SUITE).unwrap();
+        let again_key = derive_session_key(&again_secret, &again_handshake, &bob_handshake).unwrap();
+        assert_ne!(*again_key, *alice_key);
+    }
+
+    #[test]
+    fn handshake_verifies_only_against_its_signer() {
+        let (alice, mallory) = (identity(), identity());
+        let (_, handshake) = new_handshake(&alice, DEFAULT_SUITE).unwrap();
+        assert!(verify_handshake(&alice.public(), &handshake));
+        assert!(!verify_handshake(&mallory.public(), &handshake));
+
+        // Swapping in another ephemeral key breaks the signature
+        let mut swapped = handshake.clone();
+        swapped.ephemeral_key = new_ratchet_key(DEFAULT_SUITE).unwrap().1;
+        assert!(!verify_handshake(&alice.public(), &swapped));
+    }
+
+    #[test]
+    fn low_order_ephemeral_keys_are_refused() {
+        let (secret, local) = new_handshake(&identity(), DEFAULT_SUITE).unwrap();
+        let remote = Handshake {
+            suite: DEFAULT_SUITE,
+            ephemeral_key: [0; 32],
+            signature: Vec::new(),
+        };
+        assert!(derive_session_key(&secret, &local, &remote).is_err());
+    }
+
+    #[test]
+    fn tampered_payloads_fail_verification() {
+        let (alice, mallory) = (identity(), identity());
+        let key = Zeroizing::new([7; 32]);
+        let ratchet = RatchetHeader {
+            epoch: 0,
+            ratchet_key: [1; 32],
+            peer_ratchet_key: [2; 32],
+            index: 0,
+            previous_len: 0,
+        };
+        let payload = encrypt(&key, &alice, DEFAULT_SUITE, 1, ratchet, b"hello").unwrap();
+        assert!(verify_payload(&alice.public(), &payload));
+        assert_eq!(decrypt(&key, &payload).unwrap(), b"hello");
+
+        let mut tampered = payload.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(!verify_payload(&alice.public(), &tampered));
+        assert!(decrypt(&key, &tampered).is_err());
+
+        // Knowing the session key isn't enough to pass as alice
+        let forged = encrypt(&key, &mallory, DEFAULT_SUITE, 1, ratchet, b"hello").unwrap();
+        assert!(!verify_payload(&alice.public(), &forged));
+    }
+
+    #[test]
+    fn ed25519_signatures_verify_only_unaltered_and_with_their_key() {
+        let (alice, mallory) = (identity(), identity());
+        let signature = alice.sign(b"data").unwrap();
+        assert_eq!(signature.len(), 64);
+        assert!(alice.public().verify(b"data", &signature));
+        assert!(!alice.public().verify(b"date", &signature));
+        assert!(!mallory.public().verify(b"data", &signature));
+
+        let mut altered = signature.clone();
+        altered[0] ^= 1;
+        assert!(!alice.public().verify(b"data", &altered));
+        assert!(!alice.public().verify(b"data", &signature[..63]));
+    }
+
+    #[test]
+    fn mismatched_key_types_are_refused() {
+        let ed25519 = identity();
+        let rsa = IdentityKey::generate(KeyType::Rsa, 1024).unwrap();
+        assert!(check_key_type(KeyType::Ed25519, &ed25519.public()).is_ok());
+        assert!(check_key_type(KeyType::Rsa, &rsa.public()).is_ok());
+
+        let Err(Error::Crypto(message)) = check_key_type(KeyType::Ed25519, &rsa.public()) else {
+            panic!("an RSA peer should be refused");
+        };
+        assert!(message.contains("the peer uses RSA and we use Ed25519"), "{}", message);
+        assert!(check_key_type(KeyType::Rsa, &ed25519.public()).is_err());
+
+        // Neither algorithm takes the other's signatures
+        let (_, handshake) = new_handshake(&rsa, DEFAULT_SUITE).unwrap();
+        assert!(!verify_handshake(&ed25519.public(), &handshake));
+        let (_, handshake) = new_handshake(&ed25519, DEFAULT_SUITE).unwrap();
+        assert!(!verify_handshake(&rsa.public(), &handshake));
+    }
+
+    #[test]
+    fn an_unknown_suite_is_refused_rather_than_used() {
+        assert!(new_handshake(&identity(), 99).is_err());
+
+        let key = Zeroizing::new([7; 32]);
+        let ratchet = RatchetHeader {
+            epoch: 0,
+            ratchet_key: [1; 32],
+            peer_ratchet_key: [2; 32],
+            index: 0,
+            previous_len: 0,
+        };
+        let mut payload = encrypt(&key, &identity(), DEFAULT_SUITE, 1, ratchet, b"hello").unwrap();
+        payload.suite = 99;
+        let error = decrypt(&key, &payload).unwrap_err();
+        assert!(error.to_string().contains("unknown cipher suite 99"), "{}", error);
+    }
+}
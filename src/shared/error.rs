@@ -0,0 +1,108 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to a socket or file failed
+    Io(std::io::Error),
+    /// A message couldn't be encoded or decoded
+    Serialize(bincode::Error),
+    /// A message couldn't be encoded or decoded as JSON
+    Json(serde_json::Error),
+    /// Key generation, signing, encryption or decryption failed
+    Crypto(String),
+    /// The other side sent something that doesn't make sense
+    Protocol(String),
+    /// The other side can't or won't talk to us, as when it speaks another
+    /// protocol version, so trying again won't help
+    Rejected(String),
+    /// A frame header didn't match, so the stream is corrupt and can't be
+    /// resynchronized
+    Desync(String),
+    /// A whole frame arrived but failed its checksum. The stream is still in
+    /// step, so only that frame is lost.
+    Corrupt(String),
+    /// A write failed after part of a frame had gone out. The peer now has a
+    /// truncated frame, so nothing more can be sent on the connection.
+    PartialWrite {
+        written: usize,
+        total: usize,
+        source: std::io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Serialize(e) => write!(f, "serialization error: {}", e),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+            Error::Crypto(e) => write!(f, "crypto error: {}", e),
+            Error::Protocol(e) => write!(f, "protocol error: {}", e),
+            Error::Rejected(e) => write!(f, "rejected: {}", e),
+            Error::Desync(e) => write!(f, "stream desync: {}", e),
+            Error::Corrupt(e) => write!(f, "corrupt frame: {}", e),
+            Error::PartialWrite {
+                written,
+                total,
+                source,
+            } => write!(
+                f,
+                "I/O error after writing {} of {} bytes of a frame: {}",
+                written, total, source
+            ),
+        }
+    }
+}
+
+impl Error {
+    /// Whether decoding failed on an enum variant this build doesn't have,
+    /// as when the other side was built from newer code
+    pub fn is_unknown_variant(&self) -> bool {
+        match self {
+            // serde reports an out-of-range discriminant as an invalid
+            // "variant index"
+            Error::Serialize(e) => matches!(
+                &**e,
+                bincode::ErrorKind::Custom(message) if message.contains("variant index")
+            ),
+            Error::Json(e) => e.is_data() && e.to_string().starts_with("unknown variant"),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Serialize(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::PartialWrite { source, .. } => Some(source),
+            Error::Crypto(_)
+            | Error::Protocol(_)
+            | Error::Rejected(_)
+            | Error::Desync(_)
+            | Error::Corrupt(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Serialize(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
@@ -0,0 +1,406 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{Error, Result};
+
+/// How frame bodies are encoded. The server picks one and announces it in a
+/// bincode `SetWireFormat` frame before anything else; every frame after that
+/// uses the chosen format in both directions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    /// Readable with `nc` or a packet capture, at the cost of much larger frames
+    Json,
+}
+
+impl WireFormat {
+    pub fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            WireFormat::Bincode => to_bincode(message)?,
+            WireFormat::Json => serde_json::to_vec(message)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T> {
+        Ok(match self {
+            WireFormat::Bincode => from_bincode(body)?,
+            WireFormat::Json => serde_json::from_slice(body)?,
+        })
+    }
+}
+
+/// Bincode settings for everything the protocol encodes, frame bodies and
+/// encrypted payloads alike. Integers are variable-length, so the lengths,
+/// counters and enum tags that fill most messages take a byte or two
+/// rather than four or eight.
+pub fn bincode_options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_varint_encoding()
+        .with_little_endian()
+        .allow_trailing_bytes()
+}
+
+pub fn to_bincode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(bincode_options().serialize(value)?)
+}
+
+pub fn from_bincode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(bincode_options().deserialize(bytes)?)
+}
+
+/// Sent after the length of every frame so a reader can tell it's still
+/// aligned with the stream
+pub const FRAME_MAGIC: [u8; 4] = *b"YCNB";
+
+/// Largest frame body either side will accept. A length above this almost
+/// certainly means the stream is out of sync rather than a real message.
+pub const MAX_FRAME_LEN: u64 = 16 * 1024 * 1024;
+
+/// Bytes in the length field of a frame header. The hello frame keeps the
+/// 8-byte length (and fixed-size bincode body) of protocol versions before
+/// 6, so a client of any version can read the server's protocol version and
+/// report a mismatch instead of a garbled stream.
+const LENGTH_LEN: usize = 4;
+const HELLO_LENGTH_LEN: usize = 8;
+
+/// Bytes a frame with a body of `body_len` takes on the wire
+pub fn frame_len(body_len: usize) -> usize {
+    LENGTH_LEN + 8 + body_len
+}
+
+/// Frames and bytes sent and received over a connection, counting each
+/// frame's header as well as its body
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Traffic {
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub frames_received: u64,
+    pub bytes_received: u64,
+}
+
+impl Traffic {
+    /// Counts a frame of `len` bytes, header included, as sent
+    pub fn sent(&mut self, len: usize) {
+        self.frames_sent += 1;
+        self.bytes_sent += len as u64;
+    }
+
+    /// Counts a frame of `len` bytes, header included, as received
+    pub fn received(&mut self, len: usize) {
+        self.frames_received += 1;
+        self.bytes_received += len as u64;
+    }
+}
+
+/// Encodes `message` as a frame: the body length (a little-endian u32),
+/// `FRAME_MAGIC`, a CRC32 of the body, then the body itself in `format`
+pub fn encode_frame<T: Serialize>(format: WireFormat, message: &T) -> Result<Vec<u8>> {
+    assemble(format.encode(message)?, LENGTH_LEN)
+}
+
+/// Encodes the server's hello in the layout every protocol version reads
+pub fn encode_hello_frame<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    assemble(bincode::serialize(message)?, HELLO_LENGTH_LEN)
+}
+
+/// Decodes the body of a frame read with `read_hello_frame`
+pub fn decode_hello<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(body)?)
+}
+
+fn assemble(body: Vec<u8>, length_len: usize) -> Result<Vec<u8>> {
+    if body.len() as u64 > MAX_FRAME_LEN {
+        return Err(Error::Protocol(format!(
+            "frame length {} exceeds the {} byte limit",
+            body.len(),
+            MAX_FRAME_LEN
+        )));
+    }
+    let mut frame = Vec::with_capacity(length_len + 8 + body.len());
+    frame.extend(&(body.len() as u64).to_le_bytes()[..length_len]);
+    frame.extend(FRAME_MAGIC);
+    frame.extend(crc32fast::hash(&body).to_le_bytes());
+    frame.extend(body);
+    Ok(frame)
+}
+
+/// Writes `message` as a frame (see `encode_frame` and `write_encoded`)
+pub async fn write_frame<W, T>(writer: &mut W, format: WireFormat, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let frame = encode_frame(format, message)?;
+    write_encoded(writer, &frame).await
+}
+
+/// Writes an already encoded frame in full, then flushes. A failure before
+/// any byte went out is a plain `Io` error and the stream is still aligned;
+/// one partway through is `PartialWrite`, and the caller must drop the
+/// connection rather than write another frame after the truncated one.
+pub async fn write_encoded<W>(writer: &mut W, frame: &[u8]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_encoded_unflushed(writer, frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Like `write_encoded`, but leaves flushing to the caller, so frames
+/// written to a buffered writer in a row can go out together
+pub async fn write_encoded_unflushed<W>(writer: &mut W, frame: &[u8]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut written = 0;
+    while written < frame.len() {
+        let error = match writer.write(&frame[written..]).await {
+            Ok(0) => std::io::ErrorKind::WriteZero.into(),
+            Ok(n) => {
+                written += n;
+                continue;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => e,
+        };
+        if written == 0 {
+            return Err(Error::Io(error));
+        }
+        return Err(Error::PartialWrite {
+            written,
+            total: frame.len(),
+            source: error,
+        });
+    }
+    Ok(())
+}
+
+/// Reads the next frame. There are three outcomes:
+///
+/// - `Ok(Some(body))`: a frame arrived whole.
+/// - `Ok(None)`: the peer closed the connection cleanly between frames.
+/// - `Err`: the connection dropped. The socket failed or closed partway
+///   through a frame, or the header doesn't match and there's no way to
+///   find the next frame. The caller should disconnect.
+///
+/// `Error::Corrupt` is the one error the connection survives. The frame
+/// failed its checksum but was read whole, so the caller can skip it like a
+/// body that fails to decode.
+pub async fn read_frame<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    read_body(reader, LENGTH_LEN).await
+}
+
+/// Like `read_frame`, for the hello frame (see `encode_hello_frame`)
+pub async fn read_hello_frame<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    read_body(reader, HELLO_LENGTH_LEN).await
+}
+
+async fn read_body<R>(reader: &mut R, length_len: usize) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; HELLO_LENGTH_LEN + 8];
+    let header = &mut header[..length_len + 8];
+    let mut filled = 0;
+    while filled < header.len() {
+        let read = reader.read(&mut header[filled..]).await?;
+        if read == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        filled += read;
+    }
+
+    let mut length = [0u8; 8];
+    length[..length_len].copy_from_slice(&header[..length_len]);
+    let length = u64::from_le_bytes(length);
+    let header = &header[length_len..];
+    if header[..4] != FRAME_MAGIC {
+        return Err(Error::Desync("bad frame magic".to_string()));
+    }
+    if length > MAX_FRAME_LEN {
+        return Err(Error::Desync(format!(
+            "frame length {} exceeds the {} byte limit",
+            length, MAX_FRAME_LEN
+        )));
+    }
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+    let mut body = vec![0u8; length as usize];
+    reader.read_exact(&mut body).await?;
+    if crc32fast::hash(&body) != checksum {
+        return Err(Error::Corrupt("checksum mismatch".to_string()));
+    }
+    Ok(Some(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::messages::ServerBoundMessage;
+
+    fn advertise() -> Vec<u8> {
+        let message = ServerBoundMessage::Advertise("alice".to_string());
+        encode_frame(WireFormat::Bincode, &message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_back_what_it_wrote() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, WireFormat::Bincode, &ServerBoundMessage::Ping(3))
+            .await
+            .unwrap();
+        stream.extend(advertise());
+
+        let mut reader = stream.as_slice();
+        let ping = read_frame(&mut reader).await.unwrap().unwrap();
+        assert!(matches!(
+            WireFormat::Bincode.decode(&ping).unwrap(),
+            ServerBoundMessage::Ping(3)
+        ));
+        let advertise = read_frame(&mut reader).await.unwrap().unwrap();
+        assert!(matches!(
+            WireFormat::Bincode.decode(&advertise).unwrap(),
+            ServerBoundMessage::Advertise(name) if name == "alice"
+        ));
+    }
+
+    /// Takes at most three bytes a write, and fails once `budget` bytes
+    /// have gone out
+    struct FailingWriter {
+        written: Vec<u8>,
+        budget: usize,
+    }
+
+    impl AsyncWrite for FailingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let n = buf.len().min(3).min(self.budget - self.written.len());
+            if n == 0 {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            self.written.extend(&buf[..n]);
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn a_write_failing_partway_reports_how_much_went_out() {
+        let frame = advertise();
+        let mut writer = FailingWriter {
+            written: Vec::new(),
+            budget: 10,
+        };
+        match write_encoded(&mut writer, &frame).await {
+            Err(Error::PartialWrite { written, total, source }) => {
+                assert_eq!((written, total), (10, frame.len()));
+                assert_eq!(source.kind(), std::io::ErrorKind::BrokenPipe);
+            }
+            other => panic!("expected a partial write, got {:?}", other),
+        }
+        assert_eq!(writer.written, frame[..10]);
+
+        // Nothing went out, so the stream is still aligned
+        let mut writer = FailingWriter {
+            written: Vec::new(),
+            budget: 0,
+        };
+        assert!(matches!(write_encoded(&mut writer, &frame).await, Err(Error::Io(_))));
+
+        let mut writer = FailingWriter {
+            written: Vec::new(),
+            budget: frame.len(),
+        };
+        write_encoded(&mut writer, &frame).await.unwrap();
+        assert_eq!(writer.written, frame);
+    }
+
+    #[test]
+    fn varint_bodies_round_trip_and_are_smaller() {
+        let message = ServerBoundMessage::Ping(3);
+        let body = to_bincode(&message).unwrap();
+        assert!(matches!(from_bincode(&body).unwrap(), ServerBoundMessage::Ping(3)));
+
+        // Fixed-size integers spend four bytes on the tag and eight on the
+        // counter, where varints take one each
+        let fixint = bincode::serialize(&message).unwrap();
+        assert_eq!((body.len(), fixint.len()), (2, 12));
+        assert_eq!(encode_frame(WireFormat::Bincode, &message).unwrap().len(), frame_len(2));
+        assert_eq!(encode_hello_frame(&message).unwrap().len(), HELLO_LENGTH_LEN + 8 + 12);
+    }
+
+    #[tokio::test]
+    async fn a_flipped_body_byte_fails_the_checksum() {
+        let mut frame = advertise();
+        *frame.last_mut().unwrap() ^= 0x20;
+        frame.extend(advertise());
+
+        let mut reader = frame.as_slice();
+        assert!(matches!(read_frame(&mut reader).await, Err(Error::Corrupt(_))));
+        // Read whole, so the next frame is still found
+        assert!(read_frame(&mut reader).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn a_damaged_header_is_a_desync() {
+        let mut frame = advertise();
+        frame[LENGTH_LEN] ^= 1;
+        assert!(matches!(read_frame(&mut frame.as_slice()).await, Err(Error::Desync(_))));
+
+        let mut frame = advertise();
+        frame[..LENGTH_LEN].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(read_frame(&mut frame.as_slice()).await, Err(Error::Desync(_))));
+    }
+
+    #[tokio::test]
+    async fn eof_between_frames_is_a_clean_close() {
+        let stream = advertise();
+        let mut reader = stream.as_slice();
+        assert!(read_frame(&mut reader).await.unwrap().is_some());
+        assert!(read_frame(&mut reader).await.unwrap().is_none());
+        assert!(read_frame(&mut [].as_slice()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn eof_inside_a_frame_is_an_error() {
+        let frame = advertise();
+        // Cut off in the header, then in the body
+        for len in [2, LENGTH_LEN + 8 + 1] {
+            let error = read_frame(&mut &frame[..len]).await.unwrap_err();
+            assert!(
+                matches!(&error, Error::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof),
+                "cut at {}: {:?}",
+                len,
+                error
+            );
+        }
+    }
+}
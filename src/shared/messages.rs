@@ -1,24 +1,524 @@
-use rsa::RsaPublicKey;
+use std::{fmt, net::SocketAddr, str::FromStr, time::Duration};
+
 use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
-pub type ClientDescription = (String, Uuid);
+use super::{crypto::PublicIdentity, framing::WireFormat, suite::SuiteId};
+
+/// Version of the frame and message layout. Bumped on any change that
+/// bincode can't decode across, such as adding a field to a message struct.
+pub const PROTOCOL_VERSION: u32 = 24;
+
+/// Secret the server hands a client so that, after a dropped connection, it
+/// can reconnect as the same uuid instead of a stranger
+pub type ResumeToken = [u8; 32];
+
+/// What one client knows about another
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ClientDescription {
+    /// Empty if the client never advertised a name
+    pub name: String,
+    pub uuid: Uuid,
+    pub presence: Presence,
+    /// Whether the peer's key has been checked. The server can't know, so it
+    /// always sends `false`.
+    pub verified: bool,
+    pub profile: Profile,
+}
+
+impl ClientDescription {
+    pub fn new(name: String, uuid: Uuid) -> Self {
+        ClientDescription {
+            name,
+            uuid,
+            presence: Presence::default(),
+            verified: false,
+            profile: Profile::default(),
+        }
+    }
+
+    /// Just a uuid, for messages where the server fills in the rest
+    pub fn to(uuid: Uuid) -> Self {
+        ClientDescription::new(String::new(), uuid)
+    }
+
+    /// The name, or the uuid for clients without one
+    pub fn display_name(&self) -> String {
+        if self.name.is_empty() {
+            self.uuid.to_string()
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+/// Longest status text, in characters
+pub const MAX_STATUS_TEXT_LEN: usize = 64;
+
+/// What a client shows beside its name, such as "🐢 alice — brb"
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Profile {
+    /// A single emoji, held to the same rules as a reaction
+    pub emoji: Option<String>,
+    pub status_text: Option<String>,
+}
+
+impl Profile {
+    /// Why the profile can't be set, if it can't: the emoji must pass
+    /// `is_valid_reaction`, and the status text must be one line of at
+    /// most `MAX_STATUS_TEXT_LEN` characters
+    pub fn problem(&self) -> Option<String> {
+        if let Some(emoji) = &self.emoji {
+            if !is_valid_reaction(emoji) {
+                return Some("the profile emoji must be a single emoji".to_string());
+            }
+        }
+        if let Some(text) = &self.status_text {
+            if text.chars().count() > MAX_STATUS_TEXT_LEN {
+                return Some(format!(
+                    "the status text can't be longer than {} characters",
+                    MAX_STATUS_TEXT_LEN
+                ));
+            }
+            if text.chars().any(char::is_control) {
+                return Some("the status text can't contain control characters".to_string());
+            }
+        }
+        None
+    }
+}
+
+/// Availability a client shows to its peers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Presence {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    /// Left out of everyone else's peer list, but still reachable by uuid
+    Invisible,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Presence::Online => "online",
+            Presence::Away => "away",
+            Presence::Busy => "busy",
+            Presence::Invisible => "invisible",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Presence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(Presence::Online),
+            "away" => Ok(Presence::Away),
+            "busy" => Ok(Presence::Busy),
+            "invisible" => Ok(Presence::Invisible),
+            _ => Err(format!("unknown status: {}", s)),
+        }
+    }
+}
+
+/// Ephemeral public key, signed with the sender's identity key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Handshake {
+    /// Cipher suite the session is to use. A response always repeats the
+    /// one in the request.
+    pub suite: SuiteId,
+    pub ephemeral_key: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+/// Message text encrypted with the session key and signed by the sender
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedPayload {
+    /// Cipher suite of the session it was sealed in
+    pub suite: SuiteId,
+    /// Per-session message number, increasing with each payload sent
+    pub counter: u64,
+    /// Which of the sender's ratchet keys sealed this
+    pub ratchet: RatchetHeader,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Where in the sender's ratchet a payload's key comes from. Each payload
+/// is sealed with a key of its own, taken from a hash chain. On rotation the
+/// sender starts a new chain from a Diffie-Hellman step between a fresh key
+/// of its own and the latest one the recipient sent.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    /// How many times the sender has rotated before sealing this
+    pub epoch: u32,
+    /// The sender's ratchet public key for this epoch
+    pub ratchet_key: [u8; 32],
+    /// The recipient's ratchet public key it was combined with
+    pub peer_ratchet_key: [u8; 32],
+    /// Position in the epoch's chain, counting from 0
+    pub index: u32,
+    /// How many payloads the sender sealed in the epoch before, so the
+    /// recipient can keep keys for any of them still on the way
+    pub previous_len: u32,
+}
+
+/// Plaintext inside an `EncryptedPayload`. Long messages are split into
+/// `total` chunks sharing a `message_id`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageChunk {
+    pub message_id: u64,
+    pub seq: u32,
+    pub total: u32,
+    pub data: Vec<u8>,
+    /// Set for a disappearing message: how long after it's shown the
+    /// recipient should treat it as gone
+    pub expires_in: Option<Duration>,
+}
+
+/// A file a peer wants to send. This and the other file types travel
+/// encrypted inside an `EncryptedPayload`, like message chunks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileOffer {
+    pub id: u64,
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileResponse {
+    pub id: u64,
+    pub accepted: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileChunk {
+    pub id: u64,
+    pub seq: u32,
+    pub data: Vec<u8>,
+}
+
+/// Tells the sender how many chunks of a file have been written, so it can
+/// send more
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileAck {
+    pub id: u64,
+    pub received: u32,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ClientBoundMessage {
-    SetUuid(Uuid),
+    /// The reply to `ClientHello`: the client's uuid, the same one as before
+    /// if it resumed, and the token to present next time
+    SetUuid(Uuid, ResumeToken),
+    /// Every listed client, or the last of them after `ClientListPart`s
     ClientList(Vec<ClientDescription>),
     NewClient(ClientDescription),
     ClientDisconnected(Uuid),
-    ConnectionRequest(ClientDescription, RsaPublicKey),
-    ConnectionResponse(ClientDescription, RsaPublicKey),
-    Message(ClientDescription, (Vec<u8>, Vec<u8>, Vec<u8>)),
+    ConnectionRequest(ClientDescription, PublicIdentity, Handshake),
+    ConnectionResponse(ClientDescription, PublicIdentity, Handshake),
+    Message(ClientDescription, EncryptedPayload),
+    /// The peer with this uuid closed its conversation with you. Other open
+    /// conversations, and the peer's connection to the server, are unaffected.
+    ChannelClosed(Uuid),
+    ServerShutdown,
+    /// Always sent first, and always as bincode
+    ServerHello {
+        protocol_version: u32,
+        wire_format: WireFormat,
+    },
+    /// A peer displayed the message with this id
+    ReadReceipt(ClientDescription, u64),
+    PresenceChanged(Uuid, Presence),
+    FileOffer(ClientDescription, EncryptedPayload),
+    FileResponse(ClientDescription, EncryptedPayload),
+    FileChunk(ClientDescription, EncryptedPayload),
+    /// A listed client advertised a new name
+    ClientRenamed(Uuid, String),
+    /// A peer is listening at this address for us to connect to it directly
+    DirectRequest(ClientDescription, SocketAddr),
+    /// The server couldn't make sense of a frame we sent, and why
+    ProtocolError(String),
+    /// Only sent over direct links, where each side pings the other.
+    /// Answered with a `Pong` carrying the same id.
+    Ping(u64),
+    /// The answer to a `Ping` with this id
+    Pong(u64),
+    /// A peer turned our connection request away without showing it,
+    /// because too many were waiting or we asked again too soon
+    RequestRejected(ClientDescription),
+    /// Some of the listed clients, when there are too many for one frame.
+    /// More parts or the closing `ClientList` follow.
+    ClientListPart(Vec<ClientDescription>),
+    /// Our message with this counter wasn't delivered, because no client
+    /// with this uuid is connected
+    RecipientUnavailable(Uuid, u64),
+    /// A notice from the server's operator to every client
+    ServerAnnouncement(String),
+    /// A peer reacted to the message with this id with this emoji
+    Reaction(ClientDescription, u64, String),
+    /// Another client has the name we advertised, on a server with
+    /// `--unique-names`. We keep whatever name we had before.
+    NameTaken(String),
+    /// The answer to `ServerInfo`. `version` is the server's crate version,
+    /// and invisible clients aren't counted.
+    ServerInfo {
+        version: String,
+        uptime_secs: u64,
+        connected_clients: u64,
+    },
+    /// A listed client changed its profile
+    ProfileChanged(Uuid, Profile),
+    FileAck(ClientDescription, EncryptedPayload),
+    /// Our message with this counter was relayed to the client with this
+    /// uuid. Not sent for messages over direct links.
+    Delivered(Uuid, u64),
+    /// The answer to `QueryAcks`: those of the messages asked about that
+    /// were relayed, or that the relay hook dropped. The others never
+    /// reached the server.
+    Acked(Vec<(Uuid, u64)>),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ServerBoundMessage {
     Advertise(String),
-    ConnectionRequest(ClientDescription, RsaPublicKey),
-    ConnectionResponse(ClientDescription, RsaPublicKey),
-    Message(ClientDescription, (Vec<u8>, Vec<u8>, Vec<u8>)),
+    ConnectionRequest(ClientDescription, PublicIdentity, Handshake),
+    ConnectionResponse(ClientDescription, PublicIdentity, Handshake),
+    Message(ClientDescription, EncryptedPayload),
+    CloseConnection(ClientDescription),
+    /// Tells the peer with this uuid that its message with this id was displayed
+    ReadReceipt(Uuid, u64),
+    SetStatus(Presence),
+    FileOffer(ClientDescription, EncryptedPayload),
+    FileResponse(ClientDescription, EncryptedPayload),
+    FileChunk(ClientDescription, EncryptedPayload),
+    /// Always sent first, in reply to the server's hello, with the protocol
+    /// version the client was built for. A token from an earlier connection
+    /// reclaims that connection's uuid and name if it's still within the
+    /// server's grace period.
+    ClientHello {
+        protocol_version: u32,
+        resume_token: Option<ResumeToken>,
+    },
+    /// Sent before closing on purpose, so the server forgets the client at
+    /// once rather than holding its identity open for a resume
+    Leave,
+    /// Tells the peer we're listening at this address, so it can connect to
+    /// us directly and stop relaying through the server
+    RequestDirect(ClientDescription, SocketAddr),
+    /// Heartbeat, answered at once with a `Pong` carrying the same id
+    Ping(u64),
+    /// Tells the peer its connection request was turned away
+    RejectRequest(ClientDescription),
+    /// Reacts to the peer's message with this id. Like read receipts, the
+    /// server sees the emoji, and it must pass `is_valid_reaction`.
+    React(Uuid, u64, String),
+    /// Asks for the server's version, uptime and client count
+    ServerInfo,
+    /// Replaces the profile peers see. It must pass `Profile::problem`.
+    SetProfile(Profile),
+    FileAck(ClientDescription, EncryptedPayload),
+    /// Asks which of our messages, by recipient and counter, were relayed.
+    /// Sent on resuming, about those whose `Delivered` hadn't arrived when
+    /// the old connection dropped. Counters are per session, so each comes
+    /// with its recipient.
+    QueryAcks(Vec<(Uuid, u64)>),
+}
+
+impl ServerBoundMessage {
+    /// The variant's name, for logs
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerBoundMessage::Advertise(_) => "Advertise",
+            ServerBoundMessage::ConnectionRequest(..) => "ConnectionRequest",
+            ServerBoundMessage::ConnectionResponse(..) => "ConnectionResponse",
+            ServerBoundMessage::Message(..) => "Message",
+            ServerBoundMessage::CloseConnection(_) => "CloseConnection",
+            ServerBoundMessage::ReadReceipt(..) => "ReadReceipt",
+            ServerBoundMessage::SetStatus(_) => "SetStatus",
+            ServerBoundMessage::FileOffer(..) => "FileOffer",
+            ServerBoundMessage::FileResponse(..) => "FileResponse",
+            ServerBoundMessage::FileChunk(..) => "FileChunk",
+            ServerBoundMessage::ClientHello { .. } => "ClientHello",
+            ServerBoundMessage::Leave => "Leave",
+            ServerBoundMessage::RequestDirect(..) => "RequestDirect",
+            ServerBoundMessage::Ping(_) => "Ping",
+            ServerBoundMessage::RejectRequest(_) => "RejectRequest",
+            ServerBoundMessage::React(..) => "React",
+            ServerBoundMessage::ServerInfo => "ServerInfo",
+            ServerBoundMessage::SetProfile(_) => "SetProfile",
+            ServerBoundMessage::FileAck(..) => "FileAck",
+            ServerBoundMessage::QueryAcks(_) => "QueryAcks",
+        }
+    }
+}
+
+/// Longest reaction, in bytes. Emoji joined into one with zero-width
+/// joiners, such as families, take up to about 25.
+const MAX_REACTION_LEN: usize = 32;
+
+/// Whether `emoji` is fit to send as a reaction: one grapheme, so a
+/// reaction can't carry a message of its own, and no control characters
+pub fn is_valid_reaction(emoji: &str) -> bool {
+    emoji.len() <= MAX_REACTION_LEN
+        && emoji.graphemes(true).count() == 1
+        && !emoji.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::crypto::{IdentityKey, KeyType};
+
+    fn payload() -> EncryptedPayload {
+        EncryptedPayload {
+            suite: 1,
+            counter: 42,
+            ratchet: RatchetHeader {
+                epoch: 1,
+                ratchet_key: [3; 32],
+                peer_ratchet_key: [4; 32],
+                index: 7,
+                previous_len: 9,
+            },
+            nonce: vec![5; 12],
+            ciphertext: vec![6; 40],
+            signature: vec![7; 64],
+        }
+    }
+
+    /// One of every variant, with both kinds of identity key
+    fn every_client_bound_message() -> Vec<ClientBoundMessage> {
+        let uuid = Uuid::new_v4();
+        let mut peer = ClientDescription::new("alice".to_string(), uuid);
+        peer.presence = Presence::Busy;
+        peer.profile = Profile {
+            emoji: Some("🦀".to_string()),
+            status_text: Some("rewriting it".to_string()),
+        };
+        let rsa = IdentityKey::generate(KeyType::Rsa, 1024).unwrap().public();
+        let ed25519 = IdentityKey::generate(KeyType::Ed25519, 0).unwrap().public();
+        let handshake = Handshake {
+            suite: 1,
+            ephemeral_key: [8; 32],
+            signature: vec![9; 64],
+        };
+        let address: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        let messages = vec![
+            ClientBoundMessage::SetUuid(uuid, [1; 32]),
+            ClientBoundMessage::ClientList(vec![peer.clone(), ClientDescription::to(uuid)]),
+            ClientBoundMessage::NewClient(peer.clone()),
+            ClientBoundMessage::ClientDisconnected(uuid),
+            ClientBoundMessage::ConnectionRequest(peer.clone(), rsa, handshake.clone()),
+            ClientBoundMessage::ConnectionResponse(peer.clone(), ed25519, handshake),
+            ClientBoundMessage::Message(peer.clone(), payload()),
+            ClientBoundMessage::ChannelClosed(uuid),
+            ClientBoundMessage::ServerShutdown,
+            ClientBoundMessage::ServerHello {
+                protocol_version: PROTOCOL_VERSION,
+                wire_format: WireFormat::Json,
+            },
+            ClientBoundMessage::ReadReceipt(peer.clone(), 11),
+            ClientBoundMessage::PresenceChanged(uuid, Presence::Away),
+            ClientBoundMessage::FileOffer(peer.clone(), payload()),
+            ClientBoundMessage::FileResponse(peer.clone(), payload()),
+            ClientBoundMessage::FileChunk(peer.clone(), payload()),
+            ClientBoundMessage::ClientRenamed(uuid, "bob".to_string()),
+            ClientBoundMessage::DirectRequest(peer.clone(), address),
+            ClientBoundMessage::ProtocolError("bad frame".to_string()),
+            ClientBoundMessage::Ping(12),
+            ClientBoundMessage::Pong(13),
+            ClientBoundMessage::RequestRejected(peer.clone()),
+            ClientBoundMessage::ClientListPart(vec![peer.clone()]),
+            ClientBoundMessage::RecipientUnavailable(uuid, 14),
+            ClientBoundMessage::ServerAnnouncement("maintenance at noon".to_string()),
+            ClientBoundMessage::Reaction(peer.clone(), 15, "👍".to_string()),
+            ClientBoundMessage::NameTaken("alice".to_string()),
+            ClientBoundMessage::ServerInfo {
+                version: "0.1.0".to_string(),
+                uptime_secs: 16,
+                connected_clients: 17,
+            },
+            ClientBoundMessage::ProfileChanged(uuid, peer.profile.clone()),
+            ClientBoundMessage::FileAck(peer.clone(), payload()),
+            ClientBoundMessage::Delivered(uuid, 18),
+            ClientBoundMessage::Acked(vec![(uuid, 19), (uuid, 20)]),
+        ];
+        // Fails to compile when a variant is added, as a reminder to list it
+        for message in &messages {
+            match message {
+                ClientBoundMessage::SetUuid(..)
+                | ClientBoundMessage::ClientList(_)
+                | ClientBoundMessage::NewClient(_)
+                | ClientBoundMessage::ClientDisconnected(_)
+                | ClientBoundMessage::ConnectionRequest(..)
+                | ClientBoundMessage::ConnectionResponse(..)
+                | ClientBoundMessage::Message(..)
+                | ClientBoundMessage::ChannelClosed(_)
+                | ClientBoundMessage::ServerShutdown
+                | ClientBoundMessage::ServerHello { .. }
+                | ClientBoundMessage::ReadReceipt(..)
+                | ClientBoundMessage::PresenceChanged(..)
+                | ClientBoundMessage::FileOffer(..)
+                | ClientBoundMessage::FileResponse(..)
+                | ClientBoundMessage::FileChunk(..)
+                | ClientBoundMessage::ClientRenamed(..)
+                | ClientBoundMessage::DirectRequest(..)
+                | ClientBoundMessage::ProtocolError(_)
+                | ClientBoundMessage::Ping(_)
+                | ClientBoundMessage::Pong(_)
+                | ClientBoundMessage::RequestRejected(_)
+                | ClientBoundMessage::ClientListPart(_)
+                | ClientBoundMessage::RecipientUnavailable(..)
+                | ClientBoundMessage::ServerAnnouncement(_)
+                | ClientBoundMessage::Reaction(..)
+                | ClientBoundMessage::NameTaken(_)
+                | ClientBoundMessage::ServerInfo { .. }
+                | ClientBoundMessage::ProfileChanged(..)
+                | ClientBoundMessage::FileAck(..)
+                | ClientBoundMessage::Delivered(..)
+                | ClientBoundMessage::Acked(_) => {}
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn every_client_bound_message_round_trips() {
+        for format in [WireFormat::Json, WireFormat::Bincode] {
+            for message in every_client_bound_message() {
+                let encoded = format.encode(&message).unwrap();
+                let decoded: ClientBoundMessage = format.decode(&encoded).unwrap_or_else(|e| {
+                    panic!("{:?} didn't decode from {:?}: {}", message, format, e)
+                });
+                // Nothing here implements PartialEq, but a lossless round trip
+                // encodes the same again
+                let reencoded = format.encode(&decoded).unwrap();
+                assert_eq!(reencoded, encoded, "{:?} in {:?}", message, format);
+            }
+        }
+    }
+
+    #[test]
+    fn a_profile_needs_one_emoji_and_a_short_line_of_status() {
+        let profile = |emoji: &str, status: &str| Profile {
+            emoji: Some(emoji.to_string()),
+            status_text: Some(status.to_string()),
+        };
+        assert_eq!(profile("🐢", "brb").problem(), None);
+        // Several chars, but one grapheme
+        assert_eq!(profile("👍🏽", "brb").problem(), None);
+        assert_eq!(Profile::default().problem(), None);
+        assert!(profile("ab", "brb").problem().is_some());
+        assert!(profile("🐢", "two\nlines").problem().is_some());
+        assert_eq!(profile("🐢", &"a".repeat(MAX_STATUS_TEXT_LEN)).problem(), None);
+        assert!(profile("🐢", &"a".repeat(MAX_STATUS_TEXT_LEN + 1)).problem().is_some());
+    }
 }
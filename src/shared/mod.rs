@@ -1 +1,11 @@
+pub mod crypto;
+pub mod error;
+pub mod framing;
 pub mod messages;
+pub mod socket;
+pub mod suite;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+
+pub use error::{Error, Result};
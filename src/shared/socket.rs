@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+use super::Result;
+
+/// Turns off Nagle's algorithm, since chat frames are small and latency
+/// matters more than packet count, and enables TCP keepalive probes after
+/// `keepalive_secs` idle seconds (0 leaves keepalive off). Keepalive catches
+/// connections whose peer vanished without closing them.
+pub fn configure(stream: &TcpStream, keepalive_secs: u64) -> Result<()> {
+    stream.set_nodelay(true)?;
+    if keepalive_secs == 0 {
+        return Ok(());
+    }
+
+    let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(keepalive_secs));
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", windows))]
+    let keepalive = keepalive.with_interval(Duration::from_secs(keepalive_secs));
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Both ends of an established loopback connection
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn turns_on_nodelay_and_keepalive() {
+        let (client, server) = connection().await;
+        for stream in [&client, &server] {
+            configure(stream, 30).unwrap();
+            assert!(stream.nodelay().unwrap());
+            let socket = SockRef::from(stream);
+            assert!(socket.keepalive().unwrap());
+            #[cfg(target_os = "linux")]
+            assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(30));
+        }
+    }
+
+    #[tokio::test]
+    async fn no_keepalive_seconds_leaves_keepalive_off() {
+        let (client, _server) = connection().await;
+        configure(&client, 0).unwrap();
+        assert!(client.nodelay().unwrap());
+        assert!(!SockRef::from(&client).keepalive().unwrap());
+    }
+}
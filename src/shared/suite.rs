@@ -0,0 +1,77 @@
+use super::{Error, Result};
+
+/// Identifies a cipher suite in handshakes and payloads
+pub type SuiteId = u8;
+
+/// How the ephemeral keys of a handshake become the shared secret the
+/// session key is derived from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kem {
+    X25519,
+}
+
+/// How payloads are encrypted with the session key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aead {
+    Aes256Gcm,
+}
+
+impl Aead {
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Aead::Aes256Gcm => 12,
+        }
+    }
+}
+
+/// A key exchange and a cipher that are used together
+#[derive(Debug)]
+pub struct Suite {
+    pub id: SuiteId,
+    pub name: &'static str,
+    pub kem: Kem,
+    pub aead: Aead,
+}
+
+/// X25519 key agreement with AES-256-GCM, the only suite so far
+pub const X25519_AES256GCM: SuiteId = 1;
+
+/// Suite new sessions are opened with. The peer answers in the same one.
+pub const DEFAULT_SUITE: SuiteId = X25519_AES256GCM;
+
+/// Every suite this build understands. Ids are never reused, so adding a
+/// suite means a new row here and new arms wherever `Kem` and `Aead` are
+/// matched, without touching the wire format.
+const SUITES: &[Suite] = &[Suite {
+    id: X25519_AES256GCM,
+    name: "x25519-aes256gcm",
+    kem: Kem::X25519,
+    aead: Aead::Aes256Gcm,
+}];
+
+/// The suite with `id`, or an error naming it if this build doesn't know it
+pub fn lookup(id: SuiteId) -> Result<&'static Suite> {
+    SUITES.iter().find(|suite| suite.id == id).ok_or_else(|| {
+        Error::Crypto(format!(
+            "unknown cipher suite {}, the peer may be running a newer version",
+            id
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_unknown_suite_is_an_error_naming_it() {
+        assert_eq!(lookup(DEFAULT_SUITE).unwrap().name, "x25519-aes256gcm");
+        match lookup(99) {
+            Err(Error::Crypto(message)) => assert_eq!(
+                message,
+                "unknown cipher suite 99, the peer may be running a newer version"
+            ),
+            other => panic!("expected an unknown suite error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! TLS between clients and the server, with the `tls` feature. Messages are
+//! end-to-end encrypted either way; TLS also hides from the network who is
+//! talking to whom, and the server's own frames.
+
+use std::{io, path::Path, sync::Arc};
+
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    pki_types::{
+        pem::{self, PemObject},
+        CertificateDer, PrivateKeyDer, ServerName,
+    },
+    ClientConfig, RootCertStore, ServerConfig,
+};
+pub use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use super::{Error, Result};
+
+/// What a TLS listener presents: the PEM certificate chain in `cert` and the
+/// PEM private key in `key`
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|chain| chain.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert, e))?;
+    let key_der = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(chain, key_der)
+        .map_err(|e| Error::Crypto(format!("TLS certificate {}: {}", cert.display(), e)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Trusts the usual public roots, or with `ca` only the PEM certificates in
+/// it, such as a self-signed server's own
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in CertificateDer::pem_file_iter(ca).map_err(|e| pem_error(ca, e))? {
+                roots
+                    .add(cert.map_err(|e| pem_error(ca, e))?)
+                    .map_err(|e| Error::Crypto(format!("TLS root {}: {}", ca.display(), e)))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Opens TLS over `stream`, checking that the server's certificate is for
+/// `host`, a name or an IP address
+pub async fn connect(
+    connector: &TlsConnector,
+    host: &str,
+    stream: TcpStream,
+) -> Result<client::TlsStream<TcpStream>> {
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|_| Error::Protocol(format!("{} can't be checked against a certificate", host)))?;
+    Ok(connector.connect(name, stream).await?)
+}
+
+/// A missing or unreadable file stays an I/O error, naming the file
+fn pem_error(path: &Path, e: pem::Error) -> Error {
+    match e {
+        pem::Error::Io(e) => {
+            Error::Io(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+        }
+        e => Error::Crypto(format!("{}: {}", path.display(), e)),
+    }
+}
@@ -0,0 +1,200 @@
+use std::{io, net::IpAddr};
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+#[cfg(feature = "tls")]
+use super::tls;
+use super::{socket, Result};
+
+/// Reading half of a connection between a client and the server
+pub type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Writing half of a connection between a client and the server
+pub type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// A connection between a client and the server, as either side holds it.
+/// Framing and encryption only ever see the split halves, so a new kind of
+/// connection just implements this.
+pub trait ClientTransport: Send + 'static {
+    /// Tunes the connection for chat traffic, with keepalive probes after
+    /// `keepalive_secs` idle seconds where it supports them. Does nothing
+    /// by default.
+    fn configure(&self, keepalive_secs: u64) -> Result<()> {
+        let _ = keepalive_secs;
+        Ok(())
+    }
+
+    /// Our end's IP address, which direct links are offered on
+    fn local_ip(&self) -> io::Result<IpAddr>;
+
+    fn into_split(self: Box<Self>) -> (ReadHalf, WriteHalf);
+}
+
+impl ClientTransport for TcpStream {
+    fn configure(&self, keepalive_secs: u64) -> Result<()> {
+        socket::configure(self, keepalive_secs)
+    }
+
+    fn local_ip(&self) -> io::Result<IpAddr> {
+        Ok(self.local_addr()?.ip())
+    }
+
+    fn into_split(self: Box<Self>) -> (ReadHalf, WriteHalf) {
+        let (readable_half, writeable_half) = TcpStream::into_split(*self);
+        (Box::new(readable_half), Box::new(writeable_half))
+    }
+}
+
+/// For a client on the same host as the server, without a port open
+#[cfg(unix)]
+impl ClientTransport for UnixStream {
+    /// A Unix socket only ever connects processes on this host
+    fn local_ip(&self) -> io::Result<IpAddr> {
+        Ok(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST))
+    }
+
+    fn into_split(self: Box<Self>) -> (ReadHalf, WriteHalf) {
+        let (readable_half, writeable_half) = UnixStream::into_split(*self);
+        (Box::new(readable_half), Box::new(writeable_half))
+    }
+}
+
+/// TLS over TCP as the client holds it. Tuning and addresses are the TCP
+/// connection's underneath.
+#[cfg(feature = "tls")]
+impl ClientTransport for tls::client::TlsStream<TcpStream> {
+    fn configure(&self, keepalive_secs: u64) -> Result<()> {
+        self.get_ref().0.configure(keepalive_secs)
+    }
+
+    fn local_ip(&self) -> io::Result<IpAddr> {
+        self.get_ref().0.local_ip()
+    }
+
+    /// The halves share the TLS session, which both reading and writing
+    /// advance, so they take turns with it
+    fn into_split(self: Box<Self>) -> (ReadHalf, WriteHalf) {
+        let (readable_half, writeable_half) = tokio::io::split(*self);
+        (Box::new(readable_half), Box::new(writeable_half))
+    }
+}
+
+/// TLS over TCP as the server holds it
+#[cfg(feature = "tls")]
+impl ClientTransport for tls::server::TlsStream<TcpStream> {
+    fn configure(&self, keepalive_secs: u64) -> Result<()> {
+        self.get_ref().0.configure(keepalive_secs)
+    }
+
+    fn local_ip(&self) -> io::Result<IpAddr> {
+        self.get_ref().0.local_ip()
+    }
+
+    fn into_split(self: Box<Self>) -> (ReadHalf, WriteHalf) {
+        let (readable_half, writeable_half) = tokio::io::split(*self);
+        (Box::new(readable_half), Box::new(writeable_half))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::shared::{
+        framing::{self, WireFormat},
+        messages::ServerBoundMessage,
+    };
+
+    /// Both ends of a loopback TCP connection, boxed as transports
+    async fn pair() -> (Box<dyn ClientTransport>, Box<dyn ClientTransport>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connecting = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connecting, listener.accept());
+        (Box::new(connected.unwrap()), Box::new(accepted.unwrap().0))
+    }
+
+    #[tokio::test]
+    async fn a_frame_round_trips_through_tcp_halves() {
+        let (client, server) = pair().await;
+        client.configure(30).unwrap();
+        assert_eq!(client.local_ip().unwrap(), IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+        let (mut client_reader, mut client_writer) = client.into_split();
+        let (mut server_reader, mut server_writer) = server.into_split();
+
+        let ping = ServerBoundMessage::Ping(42);
+        framing::write_frame(&mut client_writer, WireFormat::Bincode, &ping)
+            .await
+            .unwrap();
+        let frame = framing::read_frame(&mut server_reader).await.unwrap().unwrap();
+        assert!(matches!(
+            WireFormat::Bincode.decode(&frame).unwrap(),
+            ServerBoundMessage::Ping(42)
+        ));
+        framing::write_frame(&mut server_writer, WireFormat::Bincode, &ping).await.unwrap();
+        assert_eq!(framing::read_frame(&mut client_reader).await.unwrap(), Some(frame));
+
+        // Dropping a writer closes that direction cleanly
+        drop(client_writer);
+        assert!(framing::read_frame(&mut server_reader).await.unwrap().is_none());
+    }
+
+    /// Both ends of a loopback TLS connection. The server presents a
+    /// self-signed certificate for localhost, which the client trusts and
+    /// checks against `host`.
+    #[cfg(feature = "tls")]
+    async fn tls_pair(
+        host: &str,
+    ) -> (
+        Result<tls::client::TlsStream<TcpStream>>,
+        io::Result<tls::server::TlsStream<TcpStream>>,
+    ) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.signing_key.serialize_pem()).unwrap();
+        let acceptor = tls::acceptor(&cert, &key).unwrap();
+        let connector = tls::connector(Some(&cert)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connecting = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connecting, listener.accept());
+        tokio::join!(
+            tls::connect(&connector, host, connected.unwrap()),
+            acceptor.accept(accepted.unwrap().0)
+        )
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn a_frame_round_trips_through_tls_halves() {
+        let (client, server) = tls_pair("localhost").await;
+        let client: Box<dyn ClientTransport> = Box::new(client.unwrap());
+        let server: Box<dyn ClientTransport> = Box::new(server.unwrap());
+        client.configure(30).unwrap();
+        assert_eq!(client.local_ip().unwrap(), IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+        let (mut client_reader, mut client_writer) = client.into_split();
+        let (mut server_reader, mut server_writer) = server.into_split();
+
+        let ping = ServerBoundMessage::Ping(42);
+        framing::write_frame(&mut client_writer, WireFormat::Bincode, &ping)
+            .await
+            .unwrap();
+        let frame = framing::read_frame(&mut server_reader).await.unwrap().unwrap();
+        framing::write_frame(&mut server_writer, WireFormat::Bincode, &ping).await.unwrap();
+        assert_eq!(framing::read_frame(&mut client_reader).await.unwrap(), Some(frame));
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn tls_refuses_a_certificate_for_another_host() {
+        let (client, _) = tls_pair("example.com").await;
+        assert!(client.is_err());
+    }
+}
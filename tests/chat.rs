@@ -0,0 +1,843 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use clap::Parser;
+use common::{open_session, RawPeer, TestServer, TIMEOUT};
+use tokio::sync::oneshot;
+use ycnbts::{
+    client::{self, Client, ClientEvent},
+    shared::{
+        crypto::{self, IdentityKey, KeyType},
+        messages::{
+            ClientBoundMessage, ClientDescription, Presence, Profile, ServerBoundMessage,
+        },
+        suite::DEFAULT_SUITE,
+    },
+};
+
+#[tokio::test]
+async fn advertise_list_open_accept_send() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    bob.wait_for_peer(alice.uuid).await;
+    let listed = bob.client.peers().await;
+    let listed_alice = listed
+        .iter()
+        .find(|peer| peer.uuid == alice.uuid)
+        .expect("bob should list alice");
+    assert_eq!(listed_alice.name, "alice");
+
+    assert!(bob.client.request_connection(alice.uuid).await.unwrap());
+    let bob_uuid = bob.uuid;
+    let (name, pending) = alice
+        .wait_for(|event| match event {
+            ClientEvent::ConnectionRequested { from, pending } if from.uuid == bob_uuid => {
+                Some((from.name.clone(), *pending))
+            }
+            _ => None,
+        })
+        .await;
+    assert_eq!((name.as_str(), pending), ("bob", 1));
+
+    alice.client.accept(bob.uuid).await.unwrap();
+    let alice_uuid = alice.uuid;
+    bob.wait_for(|event| match event {
+        ClientEvent::ConnectionAccepted(peer) if peer.uuid == alice_uuid => Some(()),
+        _ => None,
+    })
+    .await;
+
+    bob.client.send_to(alice.uuid, "hello alice", None).await.unwrap();
+    assert_eq!(alice.wait_for_message(bob.uuid).await, "hello alice");
+    alice.client.send_to(bob.uuid, "hi bob", None).await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "hi bob");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn clients_see_the_server_shut_down() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    // Once bob lists alice, the server has read everything she sent
+    let mut bob = server.connect("bob").await;
+    bob.wait_for_peer(alice.uuid).await;
+    server.shut_down().await;
+    alice
+        .wait_for(|event| matches!(event, ClientEvent::ServerShutdown).then_some(()))
+        .await;
+    // Nothing to reconnect to, so the connection ends for good
+    alice.finished().await.unwrap();
+}
+
+#[tokio::test]
+async fn closing_a_session_tears_it_down_on_both_sides() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut bob, &mut alice).await;
+
+    alice
+        .client
+        .handle_action(&format!("close {}", bob.uuid))
+        .await
+        .unwrap();
+    let alice_uuid = alice.uuid;
+    bob.wait_for(|event| match event {
+        ClientEvent::ChannelClosed { uuid, .. } if *uuid == alice_uuid => Some(()),
+        _ => None,
+    })
+    .await;
+
+    assert!(alice.client.send_to(bob.uuid, "still there?", None).await.is_err());
+    assert!(bob.client.send_to(alice.uuid, "still there?", None).await.is_err());
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn oversized_messages_are_refused_before_sending() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect_with(&["--name", "alice", "--max-message-len", "100"]).await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+
+    let refused = alice.client.send_to(bob.uuid, &"x".repeat(101), None).await;
+    assert!(refused.unwrap_err().to_string().contains("too long"));
+    alice.client.send_to(bob.uuid, &"x".repeat(100), None).await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await.len(), 100);
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn long_messages_arrive_whole() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+
+    let text: String = (0..20_000).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+    alice.client.send_to(bob.uuid, &text, None).await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, text);
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn read_receipts_are_sent_only_when_enabled() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut reader = server.connect_with(&["--name", "reader", "--receipts"]).await;
+    let mut lurker = server.connect("lurker").await;
+    open_session(&mut alice, &mut reader).await;
+    open_session(&mut alice, &mut lurker).await;
+
+    alice.client.send_to(reader.uuid, "seen?", None).await.unwrap();
+    reader.wait_for_message(alice.uuid).await;
+    let reader_uuid = reader.uuid;
+    alice
+        .wait_for(|event| match event {
+            ClientEvent::MessageSeen(peer) if peer.uuid == reader_uuid => Some(()),
+            _ => None,
+        })
+        .await;
+
+    alice.client.send_to(lurker.uuid, "seen?", None).await.unwrap();
+    lurker.wait_for_message(alice.uuid).await;
+    alice
+        .expect_none(Duration::from_millis(500), |event| {
+            matches!(event, ClientEvent::MessageSeen(_))
+        })
+        .await;
+
+    alice.shut_down().await;
+    reader.shut_down().await;
+    lurker.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn status_changes_reach_connected_peers() {
+    let server = TestServer::start(&[]).await;
+    let alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    bob.wait_for_peer(alice.uuid).await;
+
+    alice.client.handle_action("status away").await.unwrap();
+    let alice_uuid = alice.uuid;
+    let presence = bob
+        .wait_for(|event| match event {
+            ClientEvent::PresenceChanged(uuid, presence) if *uuid == alice_uuid => Some(*presence),
+            _ => None,
+        })
+        .await;
+    assert_eq!(presence, Presence::Away);
+    let listed = bob.client.peers().await;
+    let listed_alice = listed.iter().find(|peer| peer.uuid == alice.uuid).unwrap();
+    assert_eq!(listed_alice.presence, Presence::Away);
+
+    // Invisible clients drop out of the list
+    alice.client.handle_action("status invisible").await.unwrap();
+    bob.wait_for(|event| match event {
+        ClientEvent::PeerLeft(uuid) if *uuid == alice_uuid => Some(()),
+        _ => None,
+    })
+    .await;
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn profile_changes_reach_connected_peers() {
+    let server = TestServer::start(&[]).await;
+    let alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    bob.wait_for_peer(alice.uuid).await;
+
+    alice.client.handle_action("profile emoji 🐢").await.unwrap();
+    alice.client.handle_action("profile status brb").await.unwrap();
+    let alice_uuid = alice.uuid;
+    let profile = bob
+        .wait_for(|event| match event {
+            ClientEvent::ProfileChanged(uuid, profile)
+                if *uuid == alice_uuid && profile.status_text.is_some() =>
+            {
+                Some(profile.clone())
+            }
+            _ => None,
+        })
+        .await;
+    let expected = Profile {
+        emoji: Some("🐢".to_string()),
+        status_text: Some("brb".to_string()),
+    };
+    assert_eq!(profile, expected);
+    let listed = bob.client.peers().await;
+    let listed_alice = listed.iter().find(|peer| peer.uuid == alice.uuid).unwrap();
+    assert_eq!(listed_alice.profile, expected);
+
+    // A client joining later gets it in its first list
+    let mut carol = server.connect("carol").await;
+    carol.wait_for_peer(alice.uuid).await;
+    let listed = carol.client.peers().await;
+    let listed_alice = listed.iter().find(|peer| peer.uuid == alice.uuid).unwrap();
+    assert_eq!(listed_alice.profile, expected);
+
+    // Refused before it's sent, so peers keep the last good one
+    assert!(alice.client.handle_action("profile emoji ab").await.is_err());
+    alice.client.handle_action("profile status").await.unwrap();
+    let profile = bob
+        .wait_for(|event| match event {
+            ClientEvent::ProfileChanged(uuid, profile) if *uuid == alice_uuid => {
+                Some(profile.clone())
+            }
+            _ => None,
+        })
+        .await;
+    assert_eq!(profile.emoji.as_deref(), Some("🐢"));
+    assert_eq!(profile.status_text, None);
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    carol.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn closing_one_session_leaves_the_peers_others_open() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    let mut carol = server.connect("carol").await;
+    open_session(&mut alice, &mut bob).await;
+    open_session(&mut carol, &mut bob).await;
+
+    alice
+        .client
+        .handle_action(&format!("close {}", bob.uuid))
+        .await
+        .unwrap();
+    let alice_uuid = alice.uuid;
+    bob.wait_for(|event| match event {
+        ClientEvent::ChannelClosed { uuid, .. } if *uuid == alice_uuid => Some(()),
+        _ => None,
+    })
+    .await;
+
+    bob.client.send_to(carol.uuid, "still here", None).await.unwrap();
+    assert_eq!(carol.wait_for_message(bob.uuid).await, "still here");
+    carol.client.send_to(bob.uuid, "me too", None).await.unwrap();
+    assert_eq!(bob.wait_for_message(carol.uuid).await, "me too");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    carol.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_cancelled_request_is_withdrawn_at_the_peer() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    bob.wait_for_peer(alice.uuid).await;
+
+    assert!(bob.client.request_connection(alice.uuid).await.unwrap());
+    let bob_uuid = bob.uuid;
+    alice
+        .wait_for(|event| match event {
+            ClientEvent::ConnectionRequested { from, .. } if from.uuid == bob_uuid => Some(()),
+            _ => None,
+        })
+        .await;
+
+    bob.client
+        .handle_action(&format!("cancel {}", alice.uuid))
+        .await
+        .unwrap();
+    alice
+        .wait_for(|event| match event {
+            ClientEvent::RequestWithdrawn(from) if from.uuid == bob_uuid => Some(()),
+            _ => None,
+        })
+        .await;
+    assert!(alice.client.accept(bob.uuid).await.is_err());
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn each_request_notice_counts_every_pending_request() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    let mut carol = server.connect("carol").await;
+    alice.wait_for_peer(bob.uuid).await;
+    alice.wait_for_peer(carol.uuid).await;
+
+    for (requester, count) in [(&mut bob, 1), (&mut carol, 2)] {
+        requester.wait_for_peer(alice.uuid).await;
+        assert!(requester.client.request_connection(alice.uuid).await.unwrap());
+        let uuid = requester.uuid;
+        let pending = alice
+            .wait_for(|event| match event {
+                ClientEvent::ConnectionRequested { from, pending } if from.uuid == uuid => {
+                    Some(*pending)
+                }
+                _ => None,
+            })
+            .await;
+        assert_eq!(pending, count);
+    }
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    carol.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_disappearing_message_expires_at_the_recipient() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+
+    alice
+        .client
+        .send_to(bob.uuid, "gone soon", Some(Duration::from_millis(200)))
+        .await
+        .unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "gone soon");
+    let alice_uuid = alice.uuid;
+    bob.wait_for(|event| match event {
+        ClientEvent::MessageExpired { from, .. } if *from == alice_uuid => Some(()),
+        _ => None,
+    })
+    .await;
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_relayed_message_is_published_to_subscribers() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+
+    // A frontend of its own, alongside the one the harness keeps
+    let mut events = bob.client.subscribe();
+    alice.client.send_to(bob.uuid, "over the relay", None).await.unwrap();
+    let received = tokio::time::timeout(common::TIMEOUT, async {
+        loop {
+            if let ClientEvent::MessageReceived { from, name, text, .. } = events.recv().await.unwrap() {
+                return (from.uuid, name, text);
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for the message");
+    assert_eq!(received, (alice.uuid, "alice".to_string(), "over the relay".to_string()));
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn sending_after_the_server_has_gone_reports_the_disconnection() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+    assert!(alice.client.is_connected());
+
+    server.shut_down().await;
+    alice.finished().await.unwrap();
+    assert!(!alice.client.is_connected());
+    let refused = alice.client.send_to(bob.uuid, "anyone?", None).await;
+    assert!(refused.unwrap_err().to_string().contains("not connected"));
+
+    bob.shut_down().await;
+}
+
+#[tokio::test]
+async fn msg_sends_to_its_target_without_switching_channels() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    let mut carol = server.connect("carol").await;
+    open_session(&mut alice, &mut bob).await;
+    open_session(&mut alice, &mut carol).await;
+    alice
+        .client
+        .handle_action(&format!("open {}", bob.uuid))
+        .await
+        .unwrap();
+    assert_eq!(alice.client.current_channel().await, Some(bob.uuid));
+
+    alice.client.handle_action("msg carol just for you").await.unwrap();
+    assert_eq!(carol.wait_for_message(alice.uuid).await, "just for you");
+    assert_eq!(alice.client.current_channel().await, Some(bob.uuid));
+    let alice_uuid = alice.uuid;
+    bob.expect_none(Duration::from_millis(300), |event| {
+        matches!(event, ClientEvent::MessageReceived { from, .. } if from.uuid == alice_uuid)
+    })
+    .await;
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    carol.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_sent_message_is_kept_in_the_history_of_its_channel() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    let mut carol = server.connect("carol").await;
+    open_session(&mut alice, &mut bob).await;
+    open_session(&mut alice, &mut carol).await;
+    alice
+        .client
+        .handle_action(&format!("open {}", bob.uuid))
+        .await
+        .unwrap();
+
+    alice.client.handle_action("send hello bob").await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "hello bob");
+    let with_bob = alice.client.history(Some(bob.uuid), "bob").await.unwrap();
+    assert_eq!(with_bob.len(), 1);
+    assert!(with_bob[0].outgoing);
+    assert_eq!(with_bob[0].text, "hello bob");
+    let with_carol = alice.client.history(Some(carol.uuid), "carol").await.unwrap();
+    assert!(with_carol.is_empty());
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    carol.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn messages_from_another_channel_count_as_unread_until_it_is_opened() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    let mut carol = server.connect("carol").await;
+    open_session(&mut alice, &mut bob).await;
+    open_session(&mut alice, &mut carol).await;
+    alice
+        .client
+        .handle_action(&format!("open {}", bob.uuid))
+        .await
+        .unwrap();
+
+    carol.client.send_to(alice.uuid, "psst", None).await.unwrap();
+    assert_eq!(alice.wait_for_message(carol.uuid).await, "psst");
+    bob.client.send_to(alice.uuid, "hi", None).await.unwrap();
+    assert_eq!(alice.wait_for_message(bob.uuid).await, "hi");
+    assert_eq!(alice.client.unread(carol.uuid).await, 1);
+    assert_eq!(alice.client.unread(bob.uuid).await, 0);
+
+    alice
+        .client
+        .handle_action(&format!("open {}", carol.uuid))
+        .await
+        .unwrap();
+    assert_eq!(alice.client.unread(carol.uuid).await, 0);
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    carol.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn both_sides_of_a_direct_link_connect_and_use_it() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+
+    alice.client.request_direct(bob.uuid).await.unwrap();
+    let (alice_uuid, bob_uuid) = (alice.uuid, bob.uuid);
+    alice
+        .wait_for(|event| match event {
+            ClientEvent::DirectConnected { uuid, .. } if *uuid == bob_uuid => Some(()),
+            _ => None,
+        })
+        .await;
+    bob.wait_for(|event| match event {
+        ClientEvent::DirectConnected { uuid, .. } if *uuid == alice_uuid => Some(()),
+        _ => None,
+    })
+    .await;
+    // Once linked, there's nothing left to offer or dial
+    assert!(alice.client.request_direct(bob.uuid).await.is_err());
+
+    alice.client.send_to(bob.uuid, "straight to you", None).await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "straight to you");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_request_with_another_key_type_is_rejected() {
+    let server = TestServer::start(&[]).await;
+    let mut bob = server.connect("bob").await;
+
+    // A bare connection stands in for an RSA client, which would be slow to
+    // start at a real key size
+    let mut rsa_peer = RawPeer::connect(server.address).await;
+    let rsa = IdentityKey::generate(KeyType::Rsa, 1024).unwrap();
+    let (_, handshake) = crypto::new_handshake(&rsa, DEFAULT_SUITE).unwrap();
+    let request = ServerBoundMessage::ConnectionRequest(
+        ClientDescription::to(bob.uuid),
+        rsa.public(),
+        handshake,
+    );
+    rsa_peer.send(&request).await;
+
+    let warning = bob
+        .wait_for(|event| match event {
+            ClientEvent::Warning(warning) => Some(warning.clone()),
+            _ => None,
+        })
+        .await;
+    assert!(warning.contains("identity key type mismatch"), "{}", warning);
+    // The requester hears back instead of waiting out its request
+    let rejected = rsa_peer
+        .wait_for(|message| match message {
+            ClientBoundMessage::RequestRejected(by) => Some(by.uuid),
+            _ => None,
+        })
+        .await;
+    assert_eq!(rejected, bob.uuid);
+
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn two_scripted_clients_open_accept_and_talk() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice.wait_for_peer(bob.uuid).await;
+    bob.wait_for_peer(alice.uuid).await;
+
+    let scripts = tempfile::tempdir().unwrap();
+    let alice_script = scripts.path().join("alice");
+    let bob_script = scripts.path().join("bob");
+    let alice_lines = [
+        "# ask bob, then talk once he's accepted",
+        "open bob",
+        "sleep 1500",
+        "open bob",
+        "send hello from a script",
+    ];
+    std::fs::write(&alice_script, alice_lines.join("\n")).unwrap();
+    let bob_lines = ["sleep 500", "accept alice", "open alice", "sleep 2000", "send hi back"];
+    std::fs::write(&bob_script, bob_lines.join("\n")).unwrap();
+
+    let alice_run = tokio::spawn({
+        let client = alice.client.clone();
+        async move { client.run_script(&alice_script).await }
+    });
+    let bob_run = tokio::spawn({
+        let client = bob.client.clone();
+        async move { client.run_script(&bob_script).await }
+    });
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "hello from a script");
+    assert_eq!(alice.wait_for_message(bob.uuid).await, "hi back");
+    alice_run.await.unwrap().expect("alice's script should run to the end");
+    bob_run.await.unwrap().expect("bob's script should run to the end");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn strict_verify_refuses_to_send_to_an_unverified_key() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect_with(&["--name", "alice", "--strict-verify"]).await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+
+    let error = alice.client.send_to(bob.uuid, "hello", None).await.unwrap_err();
+    assert!(error.to_string().contains("--strict-verify"), "{}", error);
+    let alice_uuid = alice.uuid;
+    bob.expect_none(Duration::from_millis(300), |event| {
+        matches!(event, ClientEvent::MessageReceived { from, .. } if from.uuid == alice_uuid)
+    })
+    .await;
+    // Only the strict side holds back
+    bob.client.send_to(alice.uuid, "hi", None).await.unwrap();
+    assert_eq!(alice.wait_for_message(bob.uuid).await, "hi");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_sent_request_is_awaited_until_answered() {
+    let server = TestServer::start(&[]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    alice.wait_for_peer(bob.uuid).await;
+    assert!(!alice.client.is_awaiting(bob.uuid).await);
+
+    assert!(alice.client.request_connection(bob.uuid).await.unwrap());
+    assert!(alice.client.is_awaiting(bob.uuid).await);
+    let alice_uuid = alice.uuid;
+    bob.wait_for(|event| match event {
+        ClientEvent::ConnectionRequested { from, .. } if from.uuid == alice_uuid => Some(()),
+        _ => None,
+    })
+    .await;
+    bob.client.accept(alice.uuid).await.unwrap();
+    let bob_uuid = bob.uuid;
+    alice
+        .wait_for(|event| match event {
+            ClientEvent::ConnectionAccepted(peer) if peer.uuid == bob_uuid => Some(()),
+            _ => None,
+        })
+        .await;
+    assert!(!alice.client.is_awaiting(bob.uuid).await);
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn requests_beyond_the_pending_limit_are_rejected() {
+    let server = TestServer::start(&[]).await;
+    let mut bob = server.connect("bob").await;
+    let identity = IdentityKey::generate(KeyType::Ed25519, 0).unwrap();
+    let request = |uuid| {
+        let (_, handshake) = crypto::new_handshake(&identity, DEFAULT_SUITE).unwrap();
+        let to = ClientDescription::to(uuid);
+        ServerBoundMessage::ConnectionRequest(to, identity.public(), handshake)
+    };
+
+    let mut requesters = Vec::new();
+    for _ in 0..50 {
+        let mut requester = RawPeer::connect(server.address).await;
+        requester.send(&request(bob.uuid)).await;
+        requesters.push(requester);
+    }
+    let pending = bob
+        .wait_for(|event| match event {
+            ClientEvent::ConnectionRequested { pending: 50, .. } => Some(50),
+            _ => None,
+        })
+        .await;
+    assert_eq!(pending, 50);
+
+    let mut latecomer = RawPeer::connect(server.address).await;
+    latecomer.send(&request(bob.uuid)).await;
+    let rejected = latecomer
+        .wait_for(|message| match message {
+            ClientBoundMessage::RequestRejected(by) => Some(by.uuid),
+            _ => None,
+        })
+        .await;
+    assert_eq!(rejected, bob.uuid);
+    let latecomer_uuid = latecomer.uuid;
+    bob.expect_none(Duration::from_millis(300), |event| match event {
+        ClientEvent::ConnectionRequested { from, .. } => from.uuid == latecomer_uuid,
+        _ => false,
+    })
+    .await;
+
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn stopping_a_running_client_leaves_the_server_at_once() {
+    let server = TestServer::start(&[]).await;
+    let mut bob = server.connect("bob").await;
+    let port = server.address.port().to_string();
+    let args = client::Args::parse_from([
+        "client",
+        "--address",
+        "127.0.0.1",
+        "--port",
+        &port,
+        "--key-type",
+        "ed25519",
+        "--name",
+        "alice",
+        "--simple-ui",
+    ]);
+    let alice = Arc::new(Client::new(args).await.unwrap());
+    let alice_uuid = alice.uuid().await.unwrap();
+    bob.wait_for_peer(alice_uuid).await;
+
+    let scripts = tempfile::tempdir().unwrap();
+    let script = scripts.path().join("alice");
+    std::fs::write(&script, "sleep 60000").unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let running = tokio::spawn({
+        let alice = alice.clone();
+        async move {
+            let run = alice.run_script(&script);
+            let stopped = async {
+                let _ = stopped.await;
+            };
+            alice.run_until(run, stopped).await
+        }
+    });
+    stop.send(()).unwrap();
+    tokio::time::timeout(TIMEOUT, running)
+        .await
+        .expect("the client should stop without waiting for the script")
+        .unwrap()
+        .expect("stopping isn't an error");
+
+    // A dropped connection would hold alice's uuid for a resume instead
+    bob.wait_for(|event| match event {
+        ClientEvent::PeerLeft(uuid) if *uuid == alice_uuid => Some(()),
+        _ => None,
+    })
+    .await;
+
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_message_to_the_echo_server_comes_back_the_same() {
+    let server = TestServer::start(&["--echo"]).await;
+    let mut alice = server.connect("alice").await;
+
+    // Nobody else is connected, so the only peer is the mirror
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    let echo = loop {
+        let peers = alice.client.peers().await;
+        if let Some(echo) = peers.iter().find(|peer| peer.name == "echo") {
+            break echo.uuid;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "the mirror was never listed");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert!(alice.client.request_connection(echo).await.unwrap());
+    alice
+        .wait_for(|event| match event {
+            ClientEvent::ConnectionAccepted(peer) if peer.uuid == echo => Some(()),
+            _ => None,
+        })
+        .await;
+
+    for text in ["hello mirror", "and a second one"] {
+        alice.client.send_to(echo, text, None).await.unwrap();
+        assert_eq!(alice.wait_for_message(echo).await, text);
+    }
+
+    alice.shut_down().await;
+    server.shut_down().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn clients_chat_over_a_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ycnbts.sock");
+    let args = ycnbts::server::Args::parse_from(["server", "--socket", path.to_str().unwrap()]);
+    let mut server = ycnbts::server::Server::new(args)
+        .await
+        .expect("the server should start");
+    assert_eq!(server.socket_path(), Some(path.as_path()));
+    let (stop, stopped) = oneshot::channel::<()>();
+    let task = tokio::spawn(async move {
+        server
+            .run_until(async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+
+    let mut alice = common::TestClient::connect_unix(&path, &["--name", "alice"]).await;
+    let mut bob = common::TestClient::connect_unix(&path, &["--name", "bob"]).await;
+    open_session(&mut alice, &mut bob).await;
+    alice.client.send_to(bob.uuid, "over the socket", None).await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "over the socket");
+    bob.client.send_to(alice.uuid, "and back", None).await.unwrap();
+    assert_eq!(alice.wait_for_message(bob.uuid).await, "and back");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    let _ = stop.send(());
+    tokio::time::timeout(TIMEOUT, task)
+        .await
+        .expect("the server should shut down in time")
+        .unwrap();
+    assert!(!path.exists(), "the socket file should be removed on shutdown");
+}
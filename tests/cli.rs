@@ -0,0 +1,19 @@
+//! Runs the built binary the way a user would, for what only `main` decides
+
+use std::process::{Command, Stdio};
+
+#[test]
+fn a_client_without_a_terminal_exits_with_an_error() {
+    // Nothing listens on the port, which doesn't matter: the check comes
+    // before connecting
+    let output = Command::new(env!("CARGO_BIN_EXE_ycnbts"))
+        .args(["client", "--address", "127.0.0.1", "--port", "1"])
+        .stdin(Stdio::null())
+        .output()
+        .expect("the binary should run");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "stderr: {}", stderr);
+    assert!(stderr.contains("needs a terminal on stdin"), "stderr: {}", stderr);
+    assert!(!stdout.contains("panicked") && !stderr.contains("panicked"));
+}
@@ -0,0 +1,304 @@
+//! Runs a server and clients in the test's own process, talking over
+//! loopback like the real thing
+
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use clap::Parser;
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+use ycnbts::{
+    client::{self, Client, ClientEvent},
+    server,
+    shared::{
+        framing::{self, WireFormat},
+        messages::{ClientBoundMessage, ServerBoundMessage, PROTOCOL_VERSION},
+        Result,
+    },
+};
+
+/// How long a test waits for something to happen before failing
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server on an ephemeral loopback port
+pub struct TestServer {
+    /// The first of `addresses`
+    pub address: SocketAddr,
+    /// Every address listened on, in the order asked for
+    pub addresses: Vec<SocketAddr>,
+    stop: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server with `options` on top of the address and port
+    pub async fn start(options: &[&str]) -> Self {
+        Self::start_at(&["127.0.0.1"], options).await
+    }
+
+    /// Starts a server listening on an ephemeral port at each of `addresses`
+    pub async fn start_at(addresses: &[&str], options: &[&str]) -> Self {
+        let mut command = vec!["server", "--port", "0"];
+        for address in addresses {
+            command.extend(["--address", address]);
+        }
+        let args = server::Args::parse_from(command.iter().chain(options));
+        let mut server = server::Server::new(args)
+            .await
+            .expect("the server should start");
+        let addresses = server.local_addrs().expect("the server should be listening");
+        let (stop, stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            server
+                .run_until(async {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        TestServer {
+            address: addresses[0],
+            addresses,
+            stop: Some(stop),
+            task,
+        }
+    }
+
+    /// Connects a client that advertises `name`
+    pub async fn connect(&self, name: &str) -> TestClient {
+        TestClient::connect(self.address, &["--name", name]).await
+    }
+
+    /// Connects a client with `options`, such as a name or none
+    pub async fn connect_with(&self, options: &[&str]) -> TestClient {
+        TestClient::connect(self.address, options).await
+    }
+
+    /// Shuts the server down as Ctrl-C would, without waiting for it
+    pub fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+
+    /// Shuts the server down and waits for it to close every connection
+    pub async fn shut_down(mut self) {
+        self.stop();
+        tokio::time::timeout(TIMEOUT, &mut self.task)
+            .await
+            .expect("the server should shut down in time")
+            .expect("the server task shouldn't panic");
+    }
+}
+
+/// A client whose connection is handled in the background, with the events
+/// it publishes kept for the test to wait on
+pub struct TestClient {
+    pub client: Arc<Client>,
+    pub uuid: Uuid,
+    events: broadcast::Receiver<ClientEvent>,
+    connection: JoinHandle<Result<()>>,
+}
+
+impl TestClient {
+    pub async fn connect(address: SocketAddr, options: &[&str]) -> Self {
+        let host = address.ip().to_string();
+        let port = address.port().to_string();
+        Self::start(&["--address", &host, "--port", &port], options).await
+    }
+
+    /// Connects to a server listening on the Unix socket at `path`
+    pub async fn connect_unix(path: &Path, options: &[&str]) -> Self {
+        let path = path.to_str().expect("the socket path should be UTF-8");
+        Self::start(&["--socket", path], options).await
+    }
+
+    /// Connects to the server `target` names, with `options`
+    async fn start(target: &[&str], options: &[&str]) -> Self {
+        // Nobody answers prompts in a test, as with --json-events
+        let command = [
+            "--key-type",
+            "ed25519",
+            "--no-color",
+            "--simple-ui",
+            "--json-events",
+        ];
+        let command = ["client"].iter().chain(target).chain(&command).chain(options);
+        let args = client::Args::parse_from(command);
+        let client = Arc::new(Client::new(args).await.expect("the client should connect"));
+        let uuid = client.uuid().await.expect("the server should assign a uuid");
+        // Subscribed before anything is read, so no event is missed
+        let events = client.subscribe();
+        let connection = tokio::spawn({
+            let client = client.clone();
+            async move { client.run_connection().await }
+        });
+        TestClient {
+            client,
+            uuid,
+            events,
+            connection,
+        }
+    }
+
+    /// Waits for the first event `matches` picks out, skipping the others
+    pub async fn wait_for<T>(&mut self, mut matches: impl FnMut(&ClientEvent) -> Option<T>) -> T {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        loop {
+            let event = tokio::time::timeout_at(deadline, self.events.recv())
+                .await
+                .expect("timed out waiting for an event");
+            match event {
+                Ok(event) => {
+                    if let Some(found) = matches(&event) {
+                        return found;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => panic!("the client is gone"),
+            }
+        }
+    }
+
+    /// Waits until `peer` is in our peer list
+    pub async fn wait_for_peer(&mut self, peer: Uuid) {
+        // Polled, since the initial list arrives without an event
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        while !self.client.peers().await.iter().any(|known| known.uuid == peer) {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "timed out waiting for {} to be listed",
+                peer
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Waits for a message from `from`, returning its text
+    pub async fn wait_for_message(&mut self, from: Uuid) -> String {
+        self.wait_for(|event| match event {
+            ClientEvent::MessageReceived { from: sender, text, .. } if sender.uuid == from => {
+                Some(text.clone())
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    /// Checks that no event `matches` picks out arrives within `wait`
+    pub async fn expect_none(&mut self, wait: Duration, mut matches: impl FnMut(&ClientEvent) -> bool) {
+        let deadline = tokio::time::Instant::now() + wait;
+        while let Ok(event) = tokio::time::timeout_at(deadline, self.events.recv()).await {
+            if let Ok(event) = event {
+                assert!(!matches(&event), "unexpected event: {:?}", event);
+            }
+        }
+    }
+
+    /// Resolves once the connection task has finished, with its result
+    pub async fn finished(&mut self) -> Result<()> {
+        tokio::time::timeout(TIMEOUT, &mut self.connection)
+            .await
+            .expect("the connection should end in time")
+            .expect("the connection task shouldn't panic")
+    }
+
+    /// Leaves the server and stops handling the connection
+    pub async fn shut_down(self) {
+        self.client.shut_down().await;
+        self.connection.abort();
+    }
+}
+
+/// Has `from` ask `to` for a session and `to` accept, returning once both
+/// have it open
+pub async fn open_session(from: &mut TestClient, to: &mut TestClient) {
+    from.wait_for_peer(to.uuid).await;
+    to.wait_for_peer(from.uuid).await;
+    assert!(from
+        .client
+        .request_connection(to.uuid)
+        .await
+        .expect("the request should be sent"));
+    let requester = from.uuid;
+    to.wait_for(|event| match event {
+        ClientEvent::ConnectionRequested { from, .. } if from.uuid == requester => Some(()),
+        _ => None,
+    })
+    .await;
+    to.client
+        .accept(from.uuid)
+        .await
+        .expect("the request should be accepted");
+    let accepter = to.uuid;
+    from.wait_for(|event| match event {
+        ClientEvent::ConnectionAccepted(peer) if peer.uuid == accepter => Some(()),
+        _ => None,
+    })
+    .await;
+}
+
+/// A bare connection that speaks frames directly, for playing a peer the
+/// real client can't be, such as one with an RSA key small enough to
+/// generate quickly
+pub struct RawPeer {
+    pub stream: TcpStream,
+    pub uuid: Uuid,
+}
+
+impl RawPeer {
+    /// Connects and exchanges hellos, stopping once the uuid is assigned
+    pub async fn connect(address: SocketAddr) -> Self {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        framing::read_hello_frame(&mut stream).await.unwrap().unwrap();
+        let hello = ServerBoundMessage::ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            resume_token: None,
+        };
+        let mut peer = RawPeer {
+            stream,
+            uuid: Uuid::nil(),
+        };
+        peer.send(&hello).await;
+        let Some(ClientBoundMessage::SetUuid(uuid, _)) = peer.next().await else {
+            panic!("the server should assign a uuid first");
+        };
+        peer.uuid = uuid;
+        peer
+    }
+
+    pub async fn send(&mut self, message: &ServerBoundMessage) {
+        framing::write_frame(&mut self.stream, WireFormat::Bincode, message)
+            .await
+            .expect("the frame should be sent");
+    }
+
+    /// The next message from the server, or `None` once it has closed the
+    /// connection
+    pub async fn next(&mut self) -> Option<ClientBoundMessage> {
+        let frame = tokio::time::timeout(TIMEOUT, framing::read_frame(&mut self.stream))
+            .await
+            .expect("timed out waiting for a frame");
+        match frame {
+            Ok(Some(frame)) => Some(WireFormat::Bincode.decode(&frame).unwrap()),
+            Ok(None) | Err(_) => None,
+        }
+    }
+
+    /// Waits for the first message `matches` picks out, skipping the others
+    pub async fn wait_for<T>(
+        &mut self,
+        mut matches: impl FnMut(ClientBoundMessage) -> Option<T>,
+    ) -> T {
+        loop {
+            let message = self.next().await.expect("the server closed the connection");
+            if let Some(found) = matches(message) {
+                return found;
+            }
+        }
+    }
+}
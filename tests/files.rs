@@ -0,0 +1,104 @@
+mod common;
+
+use common::{open_session, TestServer, TIMEOUT};
+use ycnbts::client::ClientEvent;
+
+#[tokio::test]
+async fn a_file_larger_than_the_send_window_arrives_whole() {
+    let server = TestServer::start(&[]).await;
+    let downloads = tempfile::tempdir().unwrap();
+    let uploads = tempfile::tempdir().unwrap();
+    let download_dir = downloads.path().to_str().unwrap();
+    let mut alice = server.connect("alice").await;
+    let mut bob = server
+        .connect_with(&["--name", "bob", "--download-dir", download_dir])
+        .await;
+    open_session(&mut alice, &mut bob).await;
+
+    // Several windows' worth of chunks, so the rest wait on bob's acks
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let path = uploads.path().join("big.bin");
+    std::fs::write(&path, &data).unwrap();
+    alice
+        .client
+        .handle_action(&format!("open {}", bob.uuid))
+        .await
+        .unwrap();
+    alice
+        .client
+        .handle_action(&format!("sendfile {}", path.display()))
+        .await
+        .unwrap();
+
+    let alice_uuid = alice.uuid;
+    let id = bob
+        .wait_for(|event| match event {
+            ClientEvent::FileOffered { from, id, .. } if from.uuid == alice_uuid => Some(*id),
+            _ => None,
+        })
+        .await;
+    bob.client.answer_file(alice.uuid, id, true).await.unwrap();
+
+    let saved = bob
+        .wait_for(|event| match event {
+            ClientEvent::FileSaved { path, .. } => Some(path.clone()),
+            ClientEvent::FileFailed { error, .. } => panic!("the transfer failed: {}", error),
+            _ => None,
+        })
+        .await;
+    assert_eq!(saved, downloads.path().join("big.bin"));
+    assert!(std::fs::read(&saved).unwrap() == data);
+    alice
+        .wait_for(|event| matches!(event, ClientEvent::FileSent { .. }).then_some(()))
+        .await;
+    // Still connected after the transfer
+    tokio::time::timeout(TIMEOUT, alice.client.send_to(bob.uuid, "got it?", None))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "got it?");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_declined_file_is_reported_to_the_sender() {
+    let server = TestServer::start(&[]).await;
+    let uploads = tempfile::tempdir().unwrap();
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+
+    let path = uploads.path().join("small.txt");
+    std::fs::write(&path, "hello").unwrap();
+    alice
+        .client
+        .handle_action(&format!("open {}", bob.uuid))
+        .await
+        .unwrap();
+    alice
+        .client
+        .handle_action(&format!("sendfile {}", path.display()))
+        .await
+        .unwrap();
+    let id = bob
+        .wait_for(|event| match event {
+            ClientEvent::FileOffered { id, .. } => Some(*id),
+            _ => None,
+        })
+        .await;
+    bob.client.answer_file(alice.uuid, id, false).await.unwrap();
+    let declined = alice
+        .wait_for(|event| match event {
+            ClientEvent::FileDeclined { path, .. } => Some(path.clone()),
+            _ => None,
+        })
+        .await;
+    assert_eq!(declined, path);
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
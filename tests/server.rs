@@ -0,0 +1,222 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{open_session, TestClient, TestServer};
+use ycnbts::client::ClientEvent;
+
+#[tokio::test]
+async fn serves_over_ipv6() {
+    let server = TestServer::start_at(&["::1"], &[]).await;
+    assert!(server.address.is_ipv6());
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+    alice.client.send_to(bob.uuid, "over v6", None).await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "over v6");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn re_advertising_renames_rather_than_duplicates() {
+    let server = TestServer::start(&[]).await;
+    let alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    bob.wait_for_peer(alice.uuid).await;
+
+    alice.client.advertise("alicia".to_string()).await.unwrap();
+    let alice_uuid = alice.uuid;
+    let name = bob
+        .wait_for(|event| match event {
+            ClientEvent::PeerRenamed(uuid, name) if *uuid == alice_uuid => Some(name.clone()),
+            _ => None,
+        })
+        .await;
+    assert_eq!(name, "alicia");
+    let entries: Vec<_> = bob
+        .client
+        .peers()
+        .await
+        .into_iter()
+        .filter(|peer| peer.uuid == alice.uuid)
+        .collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "alicia");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn clients_connecting_at_once_each_list_all_the_others() {
+    const CLIENTS: usize = 20;
+    let server = TestServer::start(&[]).await;
+    let mut connecting = tokio::task::JoinSet::new();
+    for i in 0..CLIENTS {
+        let address = server.address;
+        let name = format!("client{}", i);
+        connecting.spawn(async move { TestClient::connect(address, &["--name", &name]).await });
+    }
+    let mut clients = Vec::new();
+    while let Some(client) = connecting.join_next().await {
+        clients.push(client.unwrap());
+    }
+
+    let uuids: Vec<_> = clients.iter().map(|client| client.uuid).collect();
+    for client in &mut clients {
+        // Our own advertisement comes back to us too, so we're listed
+        for &uuid in &uuids {
+            client.wait_for_peer(uuid).await;
+        }
+        let listed: Vec<_> = client.client.peers().await.iter().map(|peer| peer.uuid).collect();
+        let mut sorted = listed.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), listed.len(), "nobody is listed twice");
+        assert_eq!(listed.len(), CLIENTS);
+    }
+
+    for client in clients {
+        client.shut_down().await;
+    }
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_relayed_message_is_audited_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    let server = TestServer::start(&["--audit-log", path.to_str().unwrap()]).await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+    open_session(&mut alice, &mut bob).await;
+    alice.client.send_to(bob.uuid, "on the record", None).await.unwrap();
+    bob.wait_for_message(alice.uuid).await;
+
+    // Records are written in order, so once bob's departure is there the
+    // message's record would be too
+    let bob_uuid = bob.uuid.to_string();
+    bob.shut_down().await;
+    let deadline = tokio::time::Instant::now() + common::TIMEOUT;
+    let records = loop {
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let gone = records
+            .iter()
+            .any(|record| record["event"] == "disconnected" && record["uuid"] == bob_uuid);
+        if gone {
+            break records;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "bob's departure was never audited");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    let messages: Vec<_> = records.iter().filter(|record| record["event"] == "message").collect();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["from_uuid"], alice.uuid.to_string());
+    assert_eq!(messages[0]["to_uuid"], bob_uuid);
+    assert!(messages[0]["bytes"].as_u64().unwrap() > 0);
+
+    alice.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn clients_on_different_listeners_share_one_server() {
+    let server = TestServer::start_at(&["127.0.0.1", "127.0.0.1"], &[]).await;
+    assert_eq!(server.addresses.len(), 2);
+    assert_ne!(server.addresses[0].port(), server.addresses[1].port());
+    let mut alice = TestClient::connect(server.addresses[0], &["--name", "alice"]).await;
+    let mut bob = TestClient::connect(server.addresses[1], &["--name", "bob"]).await;
+
+    open_session(&mut alice, &mut bob).await;
+    alice.client.send_to(bob.uuid, "across listeners", None).await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "across listeners");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}
+
+#[tokio::test]
+async fn a_name_in_use_is_refused_until_another_is_chosen_or_it_is_freed() {
+    let server = TestServer::start(&["--unique-names"]).await;
+    let alice = server.connect("alice").await;
+    let mut bob = server.connect_with(&[]).await;
+    let mut carol = server.connect("carol").await;
+    carol.wait_for_peer(alice.uuid).await;
+
+    bob.client.advertise("alice".to_string()).await.unwrap();
+    let taken = bob
+        .wait_for(|event| match event {
+            ClientEvent::NameTaken(name) => Some(name.clone()),
+            _ => None,
+        })
+        .await;
+    assert_eq!(taken, "alice");
+
+    bob.client.advertise("bob".to_string()).await.unwrap();
+    let bob_uuid = bob.uuid;
+    let joined = carol
+        .wait_for(|event| match event {
+            ClientEvent::PeerJoined(peer) if peer.uuid == bob_uuid => Some(peer.name.clone()),
+            _ => None,
+        })
+        .await;
+    assert_eq!(joined, "bob");
+
+    // Leaving frees the name
+    let alice_uuid = alice.uuid;
+    alice.shut_down().await;
+    carol
+        .wait_for(|event| match event {
+            ClientEvent::PeerLeft(uuid) if *uuid == alice_uuid => Some(()),
+            _ => None,
+        })
+        .await;
+    bob.client.advertise("alice".to_string()).await.unwrap();
+    let renamed = carol
+        .wait_for(|event| match event {
+            ClientEvent::PeerRenamed(uuid, name) if *uuid == bob_uuid => Some(name.clone()),
+            _ => None,
+        })
+        .await;
+    assert_eq!(renamed, "alice");
+
+    bob.shut_down().await;
+    carol.shut_down().await;
+    server.shut_down().await;
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn a_plain_and_a_tls_listener_feed_the_same_clients() {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.signing_key.serialize_pem()).unwrap();
+    let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+    let tls = ["--tls-address", "127.0.0.1", "--tls-cert", cert, "--tls-key", key];
+    let server = TestServer::start_at(&["127.0.0.1"], &tls).await;
+    assert_eq!(server.addresses.len(), 2);
+
+    let mut alice = server.connect("alice").await;
+    let tls_options = ["--name", "bob", "--tls", "--tls-ca", cert];
+    let mut bob = TestClient::connect(server.addresses[1], &tls_options).await;
+    open_session(&mut alice, &mut bob).await;
+    alice.client.send_to(bob.uuid, "to tls", None).await.unwrap();
+    assert_eq!(bob.wait_for_message(alice.uuid).await, "to tls");
+    bob.client.send_to(alice.uuid, "from tls", None).await.unwrap();
+    assert_eq!(alice.wait_for_message(bob.uuid).await, "from tls");
+
+    alice.shut_down().await;
+    bob.shut_down().await;
+    server.shut_down().await;
+}